@@ -0,0 +1,92 @@
+//! Instruction-level difference of bytecode.
+
+#![experimental]
+
+use std::io::IoResult;
+
+use bytecode::Module;
+use ir::diff::diff_slices;
+
+/// An edit turning one instruction stream into another, with byte offsets.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Edit {
+    /// The instructions at the offsets of both streams are the same.
+    Equal(u64, u64),
+    /// The instruction at the offset of the old stream is removed.
+    Deleted(u64, u8, i64),
+    /// The instruction at the offset of the new stream is added.
+    Inserted(u64, u8, i64),
+}
+
+/// Return the shortest list of edits turning `a` into `b`, in order of offsets.
+///
+/// # Error
+///
+/// If either stream is malformed, then this function will return `Err`.
+///
+/// ```rust
+/// use std::io::MemWriter;
+/// use whitebase::bytecode;
+/// use whitebase::bytecode::{diff, ByteCodeWriter};
+/// use whitebase::bytecode::diff::{Equal, Deleted, Inserted};
+///
+/// let mut a = MemWriter::new();
+/// a.write_push(1).unwrap();
+/// a.write_exit().unwrap();
+/// let mut b = MemWriter::new();
+/// b.write_dup().unwrap();
+/// b.write_exit().unwrap();
+/// assert_eq!(diff(a.get_ref(), b.get_ref()), Ok(vec!(
+///     Deleted(0, bytecode::CMD_PUSH, 1),
+///     Inserted(0, bytecode::CMD_DUP, 0),
+///     Equal(9, 1),
+/// )));
+/// ```
+pub fn diff(a: &[u8], b: &[u8]) -> IoResult<Vec<Edit>> {
+    let a = try!(Module::from_bytes(a.to_vec()));
+    let b = try!(Module::from_bytes(b.to_vec()));
+    Ok(diff_slices(a.as_slice(), b.as_slice()).move_iter().map(|edit| match edit {
+        (Some(i), Some(j)) => Equal(a.offset(i).unwrap(), b.offset(j).unwrap()),
+        (Some(i), None) => {
+            let (opcode, n) = a.get(i).unwrap();
+            Deleted(a.offset(i).unwrap(), opcode, n)
+        },
+        (None, Some(j)) => {
+            let (opcode, n) = b.get(j).unwrap();
+            Inserted(b.offset(j).unwrap(), opcode, n)
+        },
+        (None, None) => unreachable!(),
+    }).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use super::{diff, Equal, Deleted, Inserted};
+
+    #[test]
+    fn test_diff() {
+        let mut a = MemWriter::new();
+        a.write_mark(1).unwrap();
+        a.write_push(2).unwrap();
+        a.write_putn().unwrap();
+        a.write_jump(1).unwrap();
+        let mut b = MemWriter::new();
+        b.write_mark(1).unwrap();
+        b.write_putn().unwrap();
+        b.write_jump(1).unwrap();
+        b.write_exit().unwrap();
+        assert_eq!(diff(a.get_ref(), b.get_ref()), Ok(vec!(
+            Equal(0, 0),
+            Deleted(9, bytecode::CMD_PUSH, 2),
+            Equal(18, 9),
+            Equal(19, 10),
+            Inserted(19, bytecode::CMD_EXIT, 0),
+        )));
+        assert_eq!(diff(a.get_ref(), a.get_ref()).unwrap().len(), 4);
+        assert!(diff(a.get_ref(), [bytecode::CMD_PUSH]).is_err());
+    }
+}
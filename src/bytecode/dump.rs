@@ -0,0 +1,133 @@
+//! Annotated hex dump of bytecode.
+
+#![experimental]
+
+use std::io::IoResult;
+
+use bytecode::{decode, has_operand, lower};
+use bytecode::ext::ExtRegistry;
+use ir::Instruction;
+use ir::text::mnemonic;
+
+/// Print each instruction as its offset, raw bytes, mnemonic and operand, like `objdump`.
+///
+/// Instructions are written in the textual form of `ir::text`, and a superinstruction
+/// as the instructions it fuses.
+///
+/// Bytes which are not an opcode are printed as `??`, and an operand cut off by the end
+/// of the stream as `(truncated)`, so that the dump goes on to the end.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use std::str::from_utf8;
+/// use whitebase::bytecode::{dump, ByteCodeWriter};
+///
+/// let mut bcw = MemWriter::new();
+/// bcw.write_push(1).unwrap();
+/// bcw.write_dup().unwrap();
+/// let mut output = MemWriter::new();
+/// dump(&mut MemReader::new(bcw.unwrap()), &mut output).unwrap();
+/// assert_eq!(from_utf8(output.get_ref()).unwrap(),
+///            "00000000  33 00 00 00 00 00 00 00 01  push 1\n\
+///             00000009  34                          dup\n");
+/// ```
+pub fn dump<R: Reader, W: Writer>(reader: &mut R, writer: &mut W) -> IoResult<()> {
+    dump_with_extensions(reader, writer, &ExtRegistry::new())
+}
+
+/// Print the dump as `dump` does, with extension instructions by their registered names.
+pub fn dump_with_extensions<R: Reader, W: Writer>(reader: &mut R, writer: &mut W,
+                                                  extensions: &ExtRegistry) -> IoResult<()> {
+    let code = try!(reader.read_to_end());
+    let mut offset = 0u;
+    while offset < code.len() {
+        let opcode = code[offset];
+        let end = if has_operand(opcode) { offset + 9 } else { offset + 1 };
+        let bytes = code.slice(offset, if end < code.len() { end } else { code.len() });
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", *b)).collect();
+        try!(write!(writer, "{:08x}  {:<26}  ", offset, hex.connect(" ")));
+        if end > code.len() {
+            try!(writer.write_str("(truncated)"));
+        } else {
+            let n = bytes.slice_from(1).iter().fold(0i64, |n, &b| (n << 8) | b as i64);
+            match extensions.format(opcode, n).or_else(|| describe(opcode, n)) {
+                Some(text) => try!(writer.write_str(text.as_slice())),
+                None => try!(writer.write_str("??")),
+            }
+        }
+        try!(writer.write_str("\n"));
+        offset = end;
+    }
+    Ok(())
+}
+
+// Instruction in the textual form of `ir::text`, lowering superinstructions.
+fn describe(opcode: u8, n: i64) -> Option<String> {
+    let text = |inst: Instruction| match mnemonic(&inst) {
+        (name, Some(n)) => format!("{} {}", name, n),
+        (name, None) => name.to_string(),
+    };
+    match (decode(opcode, n), lower(opcode, n)) {
+        (Some(inst), _) => Some(text(inst)),
+        (None, Some(((first, m), (second, _)))) => match (decode(first, m), decode(second, 0)) {
+            (Some(a), Some(b)) => Some(format!("{}; {}", text(a), text(b))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+    use std::str::from_utf8;
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use bytecode::ext::ExtRegistry;
+    use super::{dump, dump_with_extensions};
+
+    fn dumped(code: Vec<u8>) -> String {
+        let mut output = MemWriter::new();
+        dump(&mut MemReader::new(code), &mut output).unwrap();
+        from_utf8(output.get_ref()).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut bcw = MemWriter::new();
+        bcw.write_jumpn(-1).unwrap();
+        bcw.write_exit_status(3).unwrap();
+        bcw.write_ext(0xF1, 2).unwrap();
+        bcw.write_push_add(4).unwrap();
+        bcw.write_fork().unwrap();
+        let lines: Vec<String> = dumped(bcw.unwrap()).as_slice().lines().map(|l| l.to_string()).collect();
+        assert_eq!(lines, vec!(
+            "00000000  7a ff ff ff ff ff ff ff ff  jn -1".to_string(),
+            "00000009  76 00 00 00 00 00 00 00 03  exit 3".to_string(),
+            "00000012  f1 00 00 00 00 00 00 00 02  extf1 2".to_string(),
+            "0000001b  84 00 00 00 00 00 00 00 04  push 4; add".to_string(),
+            "00000024  ff 00 00 00 00 00 00 00 00  fork".to_string(),
+        ));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut registry = ExtRegistry::new();
+        registry.register(0xF1, "SHL", 1);
+        let mut bcw = MemWriter::new();
+        bcw.write_ext(0xF1, 2).unwrap();
+        let mut output = MemWriter::new();
+        dump_with_extensions(&mut MemReader::new(bcw.unwrap()), &mut output, &registry).unwrap();
+        assert_eq!(from_utf8(output.get_ref()).unwrap(), "00000000  f1 00 00 00 00 00 00 00 02  SHL 2\n");
+    }
+
+    #[test]
+    fn test_broken() {
+        let code = vec!(0x00, bytecode::CMD_RETURN, bytecode::CMD_PUSH, 0x00, 0x01);
+        assert_eq!(dumped(code), concat!(
+            "00000000  00                          ??\n",
+            "00000001  79                          ret\n",
+            "00000002  33 00 01                    (truncated)\n").to_string());
+    }
+}
@@ -0,0 +1,147 @@
+//! Registry of extension instructions.
+//!
+//! Opcodes from `CMD_EXT_MIN` are reserved for instructions defined outside this
+//! crate. They are always encoded with an operand, so that readers can skip them
+//! without knowing them, and a registry only gives them names for writers and
+//! disassemblers.
+
+#![experimental]
+
+use std::ascii::StrAsciiExt;
+use std::collections::HashMap;
+use std::io::{InvalidInput, IoError, IoResult};
+
+use bytecode::{is_extension, ByteCodeWriter, CMD_FORK};
+
+/// A registered extension instruction.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct ExtInfo {
+    /// Mnemonic in uppercase.
+    pub name: String,
+    /// Number of meaningful operands, 0 or 1. The operand of an instruction of
+    /// arity 0 is written as 0 and not shown by disassemblers.
+    pub arity: uint,
+}
+
+/// A mapping of extension opcodes to their names and arity.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use whitebase::bytecode::ByteCodeReader;
+/// use whitebase::bytecode::ext::ExtRegistry;
+///
+/// let mut registry = ExtRegistry::new();
+/// registry.register(0xF1, "SQRT", 0);
+/// let mut writer = MemWriter::new();
+/// registry.write(&mut writer, "SQRT", 0).unwrap();
+/// let (opcode, n) = MemReader::new(writer.unwrap()).read_inst().unwrap();
+/// assert_eq!(registry.format(opcode, n), Some("SQRT".to_string()));
+/// ```
+#[deriving(Clone, Show)]
+pub struct ExtRegistry {
+    entries: HashMap<u8, ExtInfo>,
+}
+
+impl ExtRegistry {
+    /// Create an empty `ExtRegistry`.
+    pub fn new() -> ExtRegistry { ExtRegistry { entries: HashMap::new() } }
+
+    /// Register an extension instruction, replacing the one of the same opcode.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the opcode is not reserved for extensions or is `CMD_FORK`, the arity
+    /// is more than 1, or the name is already registered for another opcode.
+    pub fn register(&mut self, opcode: u8, name: &str, arity: uint) {
+        if !is_extension(opcode) { fail!("opcode {:x} is not reserved for extensions", opcode) }
+        if opcode == CMD_FORK { fail!("opcode {:x} is reserved for FORK", opcode) }
+        if arity > 1 { fail!("extension instructions take at most 1 operand") }
+        let name = name.to_ascii_upper();
+        match self.opcode(name.as_slice()) {
+            Some(other) if other != opcode => fail!("{} is already registered as {:x}", name, other),
+            _ => (),
+        }
+        self.entries.insert(opcode, ExtInfo { name: name, arity: arity });
+    }
+
+    /// Get the registered instruction of the opcode.
+    pub fn get<'a>(&'a self, opcode: u8) -> Option<&'a ExtInfo> { self.entries.find(&opcode) }
+
+    /// Find the opcode of the name, ignoring case.
+    pub fn opcode(&self, name: &str) -> Option<u8> {
+        let name = name.to_ascii_upper();
+        self.entries.iter().find(|&(_, info)| info.name == name).map(|(&opcode, _)| opcode)
+    }
+
+    /// Write the named instruction.
+    ///
+    /// # Error
+    ///
+    /// If the name is not registered, or an operand other than 0 is given to an
+    /// instruction of arity 0, then this function will return `Err`.
+    pub fn write<W: ByteCodeWriter>(&self, writer: &mut W, name: &str, n: i64) -> IoResult<()> {
+        let opcode = match self.opcode(name) {
+            Some(opcode) => opcode,
+            None => return Err(IoError {
+                kind: InvalidInput,
+                desc: "unknown extension instruction",
+                detail: Some(name.to_string()),
+            }),
+        };
+        if self.get(opcode).unwrap().arity == 0 && n != 0 {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "extension instruction takes no operand",
+                detail: Some(name.to_string()),
+            })
+        }
+        writer.write_ext(opcode, n)
+    }
+
+    /// Format the instruction in assembly syntax, or `None` if the opcode is not registered.
+    pub fn format(&self, opcode: u8, n: i64) -> Option<String> {
+        self.get(opcode).map(|info| {
+            if info.arity == 0 { info.name.clone() } else { format!("{} {}", info.name, n) }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode::ByteCodeReader;
+    use super::{ExtInfo, ExtRegistry};
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ExtRegistry::new();
+        registry.register(0xF1, "sqrt", 0);
+        registry.register(0xF2, "Shl", 1);
+        assert_eq!(registry.get(0xF1), Some(&ExtInfo { name: "SQRT".to_string(), arity: 0 }));
+        assert_eq!(registry.opcode("SHL"), Some(0xF2));
+        assert_eq!(registry.opcode("SHR"), None);
+
+        let mut writer = MemWriter::new();
+        registry.write(&mut writer, "shl", 3).unwrap();
+        assert!(registry.write(&mut writer, "sqrt", 1).is_err());
+        assert!(registry.write(&mut writer, "shr", 1).is_err());
+        let (opcode, n) = MemReader::new(writer.unwrap()).read_inst().unwrap();
+        assert_eq!(registry.format(opcode, n), Some("SHL 3".to_string()));
+        assert_eq!(registry.format(0xF3, 0), None);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_not_reserved() {
+        ExtRegistry::new().register(0x01, "BAD", 0);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_duplicate_name() {
+        let mut registry = ExtRegistry::new();
+        registry.register(0xF1, "DUP2", 0);
+        registry.register(0xF2, "DUP2", 0);
+    }
+}
@@ -0,0 +1,124 @@
+//! HMAC-SHA-256 for signing bytecode images.
+
+#![experimental]
+
+/// Length of a digest and a signature in bytes.
+pub static DIGEST_LEN: uint = 32;
+
+static BLOCK_LEN: uint = 64;
+
+static H0: [u32, ..8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+static K: [u32, ..64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 digest of the bytes.
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    let mut message = bytes.to_vec();
+    let bits = bytes.len() as u64 * 8;
+    message.push(0x80);
+    while message.len() % BLOCK_LEN != 56 { message.push(0); }
+    for i in range(0u, 8) { message.push((bits >> (56 - 8 * i)) as u8); }
+
+    let mut h = H0;
+    for block in message.as_slice().chunks(BLOCK_LEN) {
+        let mut w = [0u32, ..64];
+        for i in range(0u, 16) {
+            w[i] = block.slice(4 * i, 4 * i + 4).iter().fold(0, |n, &b| (n << 8) | b as u32);
+        }
+        for i in range(16u, 64) {
+            let s0 = rotr(w[i - 15], 7) ^ rotr(w[i - 15], 18) ^ (w[i - 15] >> 3);
+            let s1 = rotr(w[i - 2], 17) ^ rotr(w[i - 2], 19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+        }
+        let mut v = h;
+        for i in range(0u, 64) {
+            let s1 = rotr(v[4], 6) ^ rotr(v[4], 11) ^ rotr(v[4], 25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7] + s1 + ch + K[i] + w[i];
+            let s0 = rotr(v[0], 2) ^ rotr(v[0], 13) ^ rotr(v[0], 22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0 + maj;
+            v = [t1 + t2, v[0], v[1], v[2], v[3] + t1, v[4], v[5], v[6]];
+        }
+        for i in range(0u, 8) { h[i] += v[i]; }
+    }
+
+    let mut digest = Vec::with_capacity(DIGEST_LEN);
+    for &n in h.iter() {
+        for shift in [24u, 16, 8, 0].iter() { digest.push((n >> *shift) as u8); }
+    }
+    digest
+}
+
+/// HMAC-SHA-256 of the message with the key.
+///
+/// ```rust
+/// use whitebase::bytecode::hmac::{hmac_sha256, DIGEST_LEN};
+///
+/// let mac = hmac_sha256(b"key", b"message");
+/// assert_eq!(mac.len(), DIGEST_LEN);
+/// assert!(mac != hmac_sha256(b"other key", b"message"));
+/// ```
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut key = if key.len() > BLOCK_LEN { sha256(key) } else { key.to_vec() };
+    key.grow(BLOCK_LEN - key.len(), &0);
+    let mut inner: Vec<u8> = key.iter().map(|&b| b ^ 0x36).collect();
+    inner.push_all(message);
+    let mut outer: Vec<u8> = key.iter().map(|&b| b ^ 0x5c).collect();
+    outer.push_all(sha256(inner.as_slice()).as_slice());
+    sha256(outer.as_slice())
+}
+
+/// Compare MACs in time independent of where they differ.
+pub fn verify(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len() &&
+        expected.iter().zip(actual.iter()).fold(0u8, |d, (&a, &b)| d | (a ^ b)) == 0
+}
+
+fn rotr(n: u32, shift: uint) -> u32 { (n >> shift) | (n << (32 - shift)) }
+
+#[cfg(test)]
+mod test {
+    use super::{hmac_sha256, sha256, verify};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", *b)).collect::<Vec<String>>().concat()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(hex(sha256(b"").as_slice()),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string());
+        assert_eq!(hex(sha256(b"abc").as_slice()),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string());
+        assert_eq!(hex(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").as_slice()),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1".to_string());
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        assert_eq!(hex(hmac_sha256(b"Jefe", b"what do ya want for nothing?").as_slice()),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843".to_string());
+        let key = Vec::from_elem(131, 0xaau8);
+        assert_eq!(hex(hmac_sha256(key.as_slice(), b"Test Using Larger Than Block-Size Key - Hash Key First").as_slice()),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54".to_string());
+    }
+
+    #[test]
+    fn test_verify() {
+        assert!(verify([1, 2, 3], [1, 2, 3]));
+        assert!(!verify([1, 2, 3], [1, 2, 4]));
+        assert!(!verify([1, 2, 3], [1, 2]));
+    }
+}
@@ -0,0 +1,921 @@
+//! Bytecode images with an integrity checksum.
+//!
+//! An image is laid out as follows, with integers in big endian:
+//!
+//! * magic bytes `WBC\0`
+//! * format version (1 byte)
+//! * flags (1 byte), `FLAG_LEB128` if operands are encoded in signed LEB128
+//!   and `FLAG_DEFLATE` if the instruction stream is compressed
+//! * length of the instruction stream as written (8 bytes)
+//! * instruction stream
+//! * sections if `FLAG_SECTIONS` is set, each of a nonzero tag (1 byte),
+//!   length (8 bytes) and data, terminated by a tag 0
+//! * CRC-32 of all the preceding bytes (4 bytes)
+//!
+//! # Stability
+//!
+//! Neither the layout nor opcodes change within a format version. A version which
+//! renumbers opcodes records the changes, so that instruction streams of older versions
+//! are upgraded as they are read. A bare instruction stream without the header, as
+//! written before images were introduced, is read as version 0 by `Image::read_compatible`.
+
+#![experimental]
+
+use flate::{deflate_bytes, inflate_bytes};
+use std::collections::{HashMap, TreeMap};
+use std::io::{BufReader, EndOfFile, InvalidInput, IoError, IoResult, MemReader, MemWriter, OtherIoError};
+use std::io::standard_error;
+
+use bytecode::{has_operand, hmac, leb128, opcode, ByteCodeReader, Module};
+use ir::Program;
+
+/// Magic bytes at the head of an image.
+pub static MAGIC: &'static [u8] = b"WBC\x00";
+/// Format version written by this module.
+pub static VERSION: u8 = 2;
+
+/// Flag of operands encoded in signed LEB128 instead of 8 bytes.
+pub static FLAG_LEB128: u8 = 0b0000_0001;
+/// Flag of sections following the instruction stream.
+pub static FLAG_SECTIONS: u8 = 0b0000_0010;
+/// Flag of the instruction stream compressed with deflate, after encoding operands.
+pub static FLAG_DEFLATE: u8 = 0b0000_0100;
+
+/// Section of names of labels, as label (8 bytes), length (4 bytes) and UTF-8 name.
+pub static SECTION_LABEL_NAMES: u8 = 1;
+
+/// Section of source positions, as length of the file name (4 bytes) and UTF-8 name
+/// followed by offset (8 bytes), line (4 bytes) and column (4 bytes) of instructions.
+pub static SECTION_SOURCE_MAP: u8 = 2;
+
+/// Section of data loaded into the heap at startup, as address (8 bytes), kind (1 byte,
+/// 0 for bytes and 1 for integers), count (4 bytes) and values (1 or 8 bytes each) of segments.
+pub static SECTION_DATA: u8 = 3;
+
+/// Section of byte offsets following "MARK" by label, as label (8 bytes) and offset (8 bytes).
+pub static SECTION_LABEL_INDEX: u8 = 4;
+
+/// Section of HMAC-SHA-256 (32 bytes) over the instruction stream and the other sections.
+pub static SECTION_SIGNATURE: u8 = 5;
+
+/// Names of numeric labels.
+pub type LabelNames = TreeMap<i64, String>;
+
+/// A position in source code of the instruction at an offset.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct SourcePosition {
+    /// Offset of the instruction in the instruction stream.
+    pub offset: u64,
+    /// Line number, starting from 1.
+    pub line: uint,
+    /// Column number in characters, starting from 1.
+    pub column: uint,
+}
+
+/// Positions in a source file of instructions, sorted by offset.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct SourceMap {
+    /// Name of the source file, empty if unknown.
+    pub file: String,
+    /// Positions of instructions.
+    pub positions: Vec<SourcePosition>,
+}
+
+/// Values of a data segment.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Data {
+    /// A byte string, stored one byte per address.
+    Bytes(Vec<u8>),
+    /// An array of integers, stored one per address.
+    Integers(Vec<i64>),
+}
+
+/// Values stored in consecutive heap addresses from `address`.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct DataSegment {
+    /// First address of the values.
+    pub address: i64,
+    /// Values.
+    pub data: Data,
+}
+
+impl DataSegment {
+    /// Create a segment of a byte string.
+    pub fn bytes(address: i64, bytes: &[u8]) -> DataSegment {
+        DataSegment { address: address, data: Bytes(bytes.to_vec()) }
+    }
+
+    /// Create a segment of an integer array.
+    pub fn integers(address: i64, values: &[i64]) -> DataSegment {
+        DataSegment { address: address, data: Integers(values.to_vec()) }
+    }
+
+    /// Get the values as stored in the heap.
+    pub fn values(&self) -> Vec<i64> {
+        match self.data {
+            Bytes(ref bytes) => bytes.iter().map(|&b| b as i64).collect(),
+            Integers(ref values) => values.clone(),
+        }
+    }
+}
+
+impl SourceMap {
+    /// Create a source map from the source locations of the program.
+    pub fn from_program(file: &str, program: &Program) -> SourceMap {
+        let mut positions = Vec::new();
+        let mut offset = 0;
+        for (i, inst) in program.iter().enumerate() {
+            match program.location(i) {
+                Some(loc) => positions.push(SourcePosition { offset: offset, line: loc.line, column: loc.column }),
+                None => (),
+            }
+            offset += if has_operand(opcode(inst)) { 9 } else { 1 };
+        }
+        SourceMap { file: file.to_string(), positions: positions }
+    }
+
+    /// Find the position of the instruction at the offset, or the nearest one before it.
+    ///
+    /// ```rust
+    /// use whitebase::bytecode::image::{SourceMap, SourcePosition};
+    ///
+    /// let map = SourceMap {
+    ///     file: "hello.ws".to_string(),
+    ///     positions: vec!(SourcePosition { offset: 0, line: 1, column: 1 },
+    ///                     SourcePosition { offset: 9, line: 2, column: 1 }),
+    /// };
+    /// assert_eq!(map.find(10).unwrap().line, 2);
+    /// ```
+    pub fn find<'a>(&'a self, offset: u64) -> Option<&'a SourcePosition> {
+        self.positions.iter().take_while(|pos| pos.offset <= offset).last()
+    }
+
+    /// Describe the position found by `find` as `file:line:column`,
+    /// or `line:column` if the file is unknown.
+    ///
+    /// ```rust
+    /// use whitebase::bytecode::image::{SourceMap, SourcePosition};
+    ///
+    /// let map = SourceMap {
+    ///     file: "hello.ws".to_string(),
+    ///     positions: vec!(SourcePosition { offset: 9, line: 2, column: 3 }),
+    /// };
+    /// assert_eq!(map.describe(12), Some("hello.ws:2:3".to_string()));
+    /// assert_eq!(map.describe(0), None);
+    /// ```
+    pub fn describe(&self, offset: u64) -> Option<String> {
+        self.find(offset).map(|pos| {
+            if self.file.is_empty() {
+                format!("{}:{}", pos.line, pos.column)
+            } else {
+                format!("{}:{}:{}", self.file, pos.line, pos.column)
+            }
+        })
+    }
+}
+
+pub type ImageResult<T> = Result<T, ImageError>;
+
+/// A list specifying errors of reading an image.
+#[deriving(PartialEq, Show)]
+pub enum ImageError {
+    /// The stream doesn't start with `MAGIC`.
+    BadMagic,
+    /// The image is written in an unknown format version.
+    UnsupportedVersion(u8),
+    /// The stream ended in the middle of the image.
+    Truncated,
+    /// The checksum doesn't match, with the stored and computed values.
+    Corrupted(u32, u32),
+    /// The image has no signature.
+    Unsigned,
+    /// The signature doesn't match the key, i.e. the image is tampered or signed by another key.
+    BadSignature,
+    /// I/O error occurred.
+    ImageIoError(IoError),
+}
+
+/// How operands are encoded in an image.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Encoding {
+    /// 8 bytes in big endian, as the virtual machine reads.
+    Fixed,
+    /// Signed LEB128, which is much shorter for small operands.
+    Leb128,
+}
+
+/// An instruction stream sealed with a checksum.
+///
+/// The instruction stream is kept with fixed size operands and uncompressed regardless
+/// of `encoding` and `compressed`, which only affect the written image.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use whitebase::bytecode::image::Image;
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(1), ir::PutNumber, ir::Exit));
+/// let mut writer = MemWriter::new();
+/// Image::from_program(&program).unwrap().write(&mut writer).unwrap();
+/// let image = Image::read(&mut MemReader::new(writer.unwrap())).unwrap();
+/// assert_eq!(Program::from_bytecode(&mut image.reader()).unwrap(), program);
+/// ```
+#[deriving(PartialEq, Clone, Show)]
+pub struct Image {
+    /// Instruction stream.
+    pub code: Vec<u8>,
+    /// Encoding of operands in the written image.
+    pub encoding: Encoding,
+    /// Whether the instruction stream is compressed in the written image.
+    pub compressed: bool,
+    /// Data of optional sections by tag.
+    pub sections: TreeMap<u8, Vec<u8>>,
+}
+
+impl Image {
+    /// Create a new `Image` of the instruction stream.
+    pub fn new(code: Vec<u8>) -> Image { Image { code: code, encoding: Fixed, compressed: false, sections: TreeMap::new() } }
+
+    /// Create a new `Image` of the program, with a `SECTION_SOURCE_MAP` if any
+    /// instruction has a source location.
+    pub fn from_program(program: &Program) -> IoResult<Image> {
+        let mut image = Image::new(try!(program.to_bytecode()));
+        let map = SourceMap::from_program("", program);
+        if !map.positions.is_empty() { image.set_source_map(&map); }
+        Ok(image)
+    }
+
+    /// Read an image, verifying its checksum.
+    pub fn read<R: Reader>(reader: &mut R) -> ImageResult<Image> {
+        let mut bytes = Vec::new();
+        let header = try!(read_exact(reader, &mut bytes, MAGIC.len() as u64 + 10));
+        if header.slice_to(MAGIC.len()) != MAGIC { return Err(BadMagic) }
+        let version = header[MAGIC.len()];
+        if version == 0 || version > VERSION { return Err(UnsupportedVersion(version)) }
+        let flags = header[MAGIC.len() + 1];
+        let encoding = if flags & FLAG_LEB128 != 0 { Leb128 } else { Fixed };
+        let compressed = flags & FLAG_DEFLATE != 0;
+        let len = be_u64(header.slice_from(MAGIC.len() + 2));
+        let code = try!(read_exact(reader, &mut bytes, len));
+        let mut sections = TreeMap::new();
+        if flags & FLAG_SECTIONS != 0 {
+            loop {
+                let tag = try!(read_exact(reader, &mut bytes, 1))[0];
+                if tag == 0 { break }
+                let len = be_u64(try!(read_exact(reader, &mut bytes, 8)).as_slice());
+                sections.insert(tag, try!(read_exact(reader, &mut bytes, len)));
+            }
+        }
+        let actual = crc32(bytes.as_slice());
+        let stored = be_u64(try!(read_exact(reader, &mut bytes, 4)).as_slice()) as u32;
+        if stored != actual { return Err(Corrupted(stored, actual)) }
+        let code = if compressed {
+            match inflate_bytes(code.as_slice()) {
+                Some(code) => code.as_slice().to_vec(),
+                None => return Err(ImageIoError(IoError {
+                    kind: InvalidInput,
+                    desc: "invalid compressed stream",
+                    detail: None,
+                })),
+            }
+        } else { code };
+        let code = try!(upgrade(code, version, encoding.clone()).map_err(|e| ImageIoError(e)));
+        let code = match encoding {
+            Fixed => code,
+            Leb128 => try!(reencode(code, Leb128, Fixed).map_err(|e| ImageIoError(e))),
+        };
+        Ok(Image { code: code, encoding: encoding, compressed: compressed, sections: sections })
+    }
+
+    /// Read an image as `read` does, or a bare instruction stream without the header,
+    /// which is taken as format version 0.
+    ///
+    /// ```rust
+    /// use std::io::MemReader;
+    /// use whitebase::bytecode::image::Image;
+    /// use whitebase::ir;
+    /// use whitebase::ir::Program;
+    ///
+    /// let program = Program::from_vec(vec!(ir::StackPush(1), ir::Exit));
+    /// let bare = program.to_bytecode().unwrap();
+    /// let image = Image::read_compatible(&mut MemReader::new(bare.clone())).unwrap();
+    /// assert_eq!(image.code, bare);
+    /// ```
+    pub fn read_compatible<R: Reader>(reader: &mut R) -> ImageResult<Image> {
+        let bytes = try!(reader.read_to_end().map_err(|e| ImageIoError(e)));
+        if bytes.as_slice().starts_with(MAGIC) {
+            Image::read(&mut MemReader::new(bytes))
+        } else {
+            Ok(Image::new(try!(upgrade(bytes, 0, Fixed).map_err(|e| ImageIoError(e)))))
+        }
+    }
+
+    /// Read an image as `read` does, verifying its signature with the key.
+    ///
+    /// ```rust
+    /// use std::io::{MemReader, MemWriter};
+    /// use whitebase::bytecode::image::{BadSignature, Image};
+    ///
+    /// let mut image = Image::new(vec!(0x33, 0x75));
+    /// image.sign(b"secret");
+    /// let mut writer = MemWriter::new();
+    /// image.write(&mut writer).unwrap();
+    /// let bytes = writer.unwrap();
+    /// assert!(Image::read_signed(&mut MemReader::new(bytes.clone()), b"secret").is_ok());
+    /// assert_eq!(Image::read_signed(&mut MemReader::new(bytes), b"guess"), Err(BadSignature));
+    /// ```
+    pub fn read_signed<R: Reader>(reader: &mut R, key: &[u8]) -> ImageResult<Image> {
+        let image = try!(Image::read(reader));
+        try!(image.verify(key));
+        Ok(image)
+    }
+
+    /// Write the image with its checksum.
+    pub fn write<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        let (code, flags) = match self.encoding {
+            Fixed => (self.code.clone(), 0),
+            Leb128 => (try!(reencode(self.code.clone(), Fixed, Leb128)), FLAG_LEB128),
+        };
+        let (code, flags) = if self.compressed {
+            match deflate_bytes(code.as_slice()) {
+                Some(code) => (code.as_slice().to_vec(), flags | FLAG_DEFLATE),
+                None => return Err(standard_error(OtherIoError)),
+            }
+        } else { (code, flags) };
+        let flags = if self.sections.is_empty() { flags } else { flags | FLAG_SECTIONS };
+        let mut bytes = MemWriter::new();
+        try!(bytes.write(MAGIC));
+        try!(bytes.write_u8(VERSION));
+        try!(bytes.write_u8(flags));
+        try!(bytes.write_be_u64(code.len() as u64));
+        try!(bytes.write(code.as_slice()));
+        if !self.sections.is_empty() {
+            for (&tag, data) in self.sections.iter() {
+                try!(bytes.write_u8(tag));
+                try!(bytes.write_be_u64(data.len() as u64));
+                try!(bytes.write(data.as_slice()));
+            }
+            try!(bytes.write_u8(0));
+        }
+        let bytes = bytes.unwrap();
+        try!(writer.write(bytes.as_slice()));
+        writer.write_be_u32(crc32(bytes.as_slice()))
+    }
+
+    /// Create a reader of the instruction stream, which the virtual machine can run.
+    pub fn reader(&self) -> MemReader { MemReader::new(self.code.clone()) }
+
+    /// Get the data of a section.
+    pub fn section<'a>(&'a self, tag: u8) -> Option<&'a [u8]> {
+        self.sections.find(&tag).map(|data| data.as_slice())
+    }
+
+    /// Set the data of a section, replacing the existing one.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the tag is 0, which terminates sections.
+    pub fn set_section(&mut self, tag: u8, data: Vec<u8>) {
+        assert!(tag != 0);
+        self.sections.insert(tag, data);
+    }
+
+    /// Get the names of labels from `SECTION_LABEL_NAMES`, empty if the section is absent.
+    ///
+    /// # Error
+    ///
+    /// If the section is malformed, then this function will return `Err`.
+    pub fn label_names(&self) -> IoResult<LabelNames> {
+        let mut names = TreeMap::new();
+        let mut reader = match self.section(SECTION_LABEL_NAMES) {
+            Some(data) => BufReader::new(data),
+            None => return Ok(names),
+        };
+        while !reader.eof() {
+            let label = try!(reader.read_be_i64());
+            let len = try!(reader.read_be_u32());
+            let name = try!(read_chunked(&mut reader, len as u64));
+            match String::from_utf8(name) {
+                Ok(name) => { names.insert(label, name); },
+                Err(_) => return Err(standard_error(InvalidInput)),
+            }
+        }
+        Ok(names)
+    }
+
+    /// Store the names of labels in `SECTION_LABEL_NAMES`.
+    pub fn set_label_names(&mut self, names: &LabelNames) {
+        let mut writer = MemWriter::new();
+        for (label, name) in names.iter() {
+            writer.write_be_i64(*label).unwrap();
+            writer.write_be_u32(name.len() as u32).unwrap();
+            writer.write_str(name.as_slice()).unwrap();
+        }
+        self.set_section(SECTION_LABEL_NAMES, writer.unwrap());
+    }
+
+    /// Get the source map from `SECTION_SOURCE_MAP`, `None` if the section is absent.
+    ///
+    /// # Error
+    ///
+    /// If the section is malformed, then this function will return `Err`.
+    pub fn source_map(&self) -> IoResult<Option<SourceMap>> {
+        let mut reader = match self.section(SECTION_SOURCE_MAP) {
+            Some(data) => BufReader::new(data),
+            None => return Ok(None),
+        };
+        let len = try!(reader.read_be_u32());
+        let file = match String::from_utf8(try!(read_chunked(&mut reader, len as u64))) {
+            Ok(file) => file,
+            Err(_) => return Err(standard_error(InvalidInput)),
+        };
+        let mut positions = Vec::new();
+        while !reader.eof() {
+            let offset = try!(reader.read_be_u64());
+            let line = try!(reader.read_be_u32());
+            let column = try!(reader.read_be_u32());
+            positions.push(SourcePosition { offset: offset, line: line as uint, column: column as uint });
+        }
+        Ok(Some(SourceMap { file: file, positions: positions }))
+    }
+
+    /// Store the source map in `SECTION_SOURCE_MAP`.
+    pub fn set_source_map(&mut self, map: &SourceMap) {
+        let mut writer = MemWriter::new();
+        writer.write_be_u32(map.file.len() as u32).unwrap();
+        writer.write_str(map.file.as_slice()).unwrap();
+        for pos in map.positions.iter() {
+            writer.write_be_u64(pos.offset).unwrap();
+            writer.write_be_u32(pos.line as u32).unwrap();
+            writer.write_be_u32(pos.column as u32).unwrap();
+        }
+        self.set_section(SECTION_SOURCE_MAP, writer.unwrap());
+    }
+
+    /// Get the data segments from `SECTION_DATA`, empty if the section is absent.
+    ///
+    /// # Error
+    ///
+    /// If the section is malformed, then this function will return `Err`.
+    pub fn data(&self) -> IoResult<Vec<DataSegment>> {
+        let mut segments = Vec::new();
+        let mut reader = match self.section(SECTION_DATA) {
+            Some(data) => BufReader::new(data),
+            None => return Ok(segments),
+        };
+        while !reader.eof() {
+            let address = try!(reader.read_be_i64());
+            let kind = try!(reader.read_u8());
+            let count = try!(reader.read_be_u32());
+            let data = match kind {
+                0 => Bytes(try!(read_chunked(&mut reader, count as u64))),
+                1 => {
+                    let mut values = Vec::new();
+                    for _ in range(0, count) { values.push(try!(reader.read_be_i64())); }
+                    Integers(values)
+                },
+                _ => return Err(standard_error(InvalidInput)),
+            };
+            segments.push(DataSegment { address: address, data: data });
+        }
+        Ok(segments)
+    }
+
+    /// Store the data segments in `SECTION_DATA`.
+    pub fn set_data(&mut self, segments: &[DataSegment]) {
+        let mut writer = MemWriter::new();
+        for segment in segments.iter() {
+            writer.write_be_i64(segment.address).unwrap();
+            match segment.data {
+                Bytes(ref bytes) => {
+                    writer.write_u8(0).unwrap();
+                    writer.write_be_u32(bytes.len() as u32).unwrap();
+                    writer.write(bytes.as_slice()).unwrap();
+                },
+                Integers(ref values) => {
+                    writer.write_u8(1).unwrap();
+                    writer.write_be_u32(values.len() as u32).unwrap();
+                    for &n in values.iter() { writer.write_be_i64(n).unwrap(); }
+                },
+            }
+        }
+        self.set_section(SECTION_DATA, writer.unwrap());
+    }
+
+    /// Get the label index from `SECTION_LABEL_INDEX`, `None` if the section is absent.
+    ///
+    /// # Error
+    ///
+    /// If the section is malformed, then this function will return `Err`.
+    pub fn label_index(&self) -> IoResult<Option<HashMap<i64, u64>>> {
+        let mut reader = match self.section(SECTION_LABEL_INDEX) {
+            Some(data) => BufReader::new(data),
+            None => return Ok(None),
+        };
+        let mut index = HashMap::new();
+        while !reader.eof() {
+            let label = try!(reader.read_be_i64());
+            index.insert(label, try!(reader.read_be_u64()));
+        }
+        Ok(Some(index))
+    }
+
+    /// Store the label index of the instruction stream in `SECTION_LABEL_INDEX`,
+    /// so that the machine needn't search labels.
+    ///
+    /// The index must be stored again whenever the instruction stream is modified.
+    pub fn index_labels(&mut self) -> IoResult<()> {
+        let index = try!(Module::from_bytes(self.code.clone())).label_index();
+        let index: TreeMap<i64, u64> = index.move_iter().collect();
+        let mut writer = MemWriter::new();
+        for (&label, &offset) in index.iter() {
+            try!(writer.write_be_i64(label));
+            try!(writer.write_be_u64(offset));
+        }
+        self.set_section(SECTION_LABEL_INDEX, writer.unwrap());
+        Ok(())
+    }
+
+    /// Store the signature of the instruction stream and the other sections with the key
+    /// in `SECTION_SIGNATURE`.
+    ///
+    /// The image must be signed again whenever it is modified, so that signing should
+    /// be done last.
+    pub fn sign(&mut self, key: &[u8]) {
+        let signature = hmac::hmac_sha256(key, self.signed_bytes().as_slice());
+        self.set_section(SECTION_SIGNATURE, signature);
+    }
+
+    /// Verify the signature with the key.
+    ///
+    /// The signature covers the instruction stream as held, so that it doesn't depend
+    /// on `encoding` and `compressed`.
+    pub fn verify(&self, key: &[u8]) -> ImageResult<()> {
+        let signature = match self.section(SECTION_SIGNATURE) {
+            Some(signature) => signature,
+            None => return Err(Unsigned),
+        };
+        let expected = hmac::hmac_sha256(key, self.signed_bytes().as_slice());
+        if hmac::verify(expected.as_slice(), signature) { Ok(()) } else { Err(BadSignature) }
+    }
+
+    // Bytes the signature is computed over, each part prefixed with its length.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut writer = MemWriter::new();
+        writer.write_be_u64(self.code.len() as u64).unwrap();
+        writer.write(self.code.as_slice()).unwrap();
+        for (&tag, data) in self.sections.iter().filter(|&(&tag, _)| tag != SECTION_SIGNATURE) {
+            writer.write_u8(tag).unwrap();
+            writer.write_be_u64(data.len() as u64).unwrap();
+            writer.write(data.as_slice()).unwrap();
+        }
+        writer.unwrap()
+    }
+}
+
+// Opcodes renumbered by format versions, as the version and the values before and after,
+// in order of versions. Version 2 moved "FORK" into the extension range, where it takes
+// an operand of 0.
+static OPCODE_CHANGES: [(u8, u8, u8), ..1] = [(2, 0x77, 0xFF)];
+
+/// Renumber opcodes of an instruction stream written in the format version to the current ones.
+fn upgrade(code: Vec<u8>, version: u8, encoding: Encoding) -> IoResult<Vec<u8>> {
+    if !OPCODE_CHANGES.iter().any(|&(v, _, _)| v > version) { return Ok(code) }
+    let mut reader = MemReader::new(code);
+    let mut writer = MemWriter::new();
+    while !reader.eof() {
+        let old = try!(reader.read_u8());
+        let mut opcode = old;
+        for &(v, from, to) in OPCODE_CHANGES.iter() {
+            if v > version && opcode == from { opcode = to; }
+        }
+        let n = if has_operand(old) {
+            try!(match encoding {
+                Fixed => reader.read_be_i64(),
+                Leb128 => leb128::read_signed(&mut reader),
+            })
+        } else { 0 };
+        try!(writer.write_u8(opcode));
+        if has_operand(opcode) {
+            try!(match encoding {
+                Fixed => writer.write_be_i64(n),
+                Leb128 => leb128::write_signed(&mut writer, n),
+            });
+        }
+    }
+    Ok(writer.unwrap())
+}
+
+/// Convert the encoding of operands in an instruction stream.
+fn reencode(code: Vec<u8>, from: Encoding, to: Encoding) -> IoResult<Vec<u8>> {
+    let mut reader = MemReader::new(code);
+    let mut writer = MemWriter::new();
+    while !reader.eof() {
+        let (opcode, n) = match from {
+            Fixed => try!(reader.read_inst()),
+            Leb128 => {
+                let opcode = try!(reader.read_u8());
+                (opcode, if has_operand(opcode) { try!(leb128::read_signed(&mut reader)) } else { 0 })
+            },
+        };
+        try!(writer.write_u8(opcode));
+        if has_operand(opcode) {
+            try!(match to {
+                Fixed => writer.write_be_i64(n),
+                Leb128 => leb128::write_signed(&mut writer, n),
+            });
+        }
+    }
+    Ok(writer.unwrap())
+}
+
+// Read bytes, also appending them to `read` for the checksum.
+fn read_exact<R: Reader>(reader: &mut R, read: &mut Vec<u8>, len: u64) -> ImageResult<Vec<u8>> {
+    match read_chunked(reader, len) {
+        Ok(bytes) => {
+            read.push_all(bytes.as_slice());
+            Ok(bytes)
+        },
+        Err(IoError { kind: EndOfFile, .. }) => Err(Truncated),
+        Err(e) => Err(ImageIoError(e)),
+    }
+}
+
+// Read `len` bytes in chunks, so that a corrupt length fails at the end of the
+// stream instead of allocating the whole length at once.
+fn read_chunked<R: Reader>(reader: &mut R, len: u64) -> IoResult<Vec<u8>> {
+    static CHUNK: uint = 64 * 1024;
+    let mut bytes = Vec::new();
+    let mut left = len;
+    while left > 0 {
+        let n = if left < CHUNK as u64 { left as uint } else { CHUNK };
+        try!(reader.push_at_least(n, n, &mut bytes));
+        left -= n as u64;
+    }
+    Ok(bytes)
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+/// CRC-32 (IEEE 802.3) of the bytes.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &b in bytes.iter() {
+        crc ^= b as u32;
+        for _ in range(0u, 8) {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use ir;
+    use ir::{Program, SourceLocation};
+    use super::{crc32, BadMagic, BadSignature, Corrupted, Image, Leb128, Truncated, Unsigned, UnsupportedVersion};
+    use super::{DataSegment, LabelNames, SourceMap, SourcePosition};
+    use super::{FLAG_SECTIONS, MAGIC, SECTION_DATA, SECTION_LABEL_NAMES, SECTION_SOURCE_MAP, VERSION};
+
+    fn image_bytes() -> Vec<u8> {
+        let mut writer = MemWriter::new();
+        Image::new(vec!(0x33, 0x75)).write(&mut writer).unwrap();
+        writer.unwrap()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let bytes = image_bytes();
+        assert_eq!(bytes.len(), 4 + 2 + 8 + 2 + 4);
+        assert_eq!(Image::read(&mut MemReader::new(bytes)), Ok(Image::new(vec!(0x33, 0x75))));
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = image_bytes();
+
+        let mut corrupted = bytes.clone();
+        *corrupted.get_mut(14) = 0x34;
+        match Image::read(&mut MemReader::new(corrupted)) {
+            Err(Corrupted(stored, actual)) => assert!(stored != actual),
+            other => fail!("unexpected {}", other),
+        }
+
+        let truncated = bytes.slice_to(bytes.len() - 1).to_vec();
+        assert_eq!(Image::read(&mut MemReader::new(truncated)), Err(Truncated));
+
+        let mut version = bytes.clone();
+        *version.get_mut(4) = 9;
+        assert_eq!(Image::read(&mut MemReader::new(version)), Err(UnsupportedVersion(9)));
+
+        let mut version = bytes.clone();
+        *version.get_mut(4) = 0;
+        assert_eq!(Image::read(&mut MemReader::new(version)), Err(UnsupportedVersion(0)));
+
+        assert_eq!(Image::read(&mut MemReader::new(vec!(0x33, 0x75))), Err(Truncated));
+        assert_eq!(Image::read(&mut MemReader::new(Vec::from_elem(20, 0u8))), Err(BadMagic));
+
+        let mut huge = bytes.slice_to(6).to_vec();
+        huge.push_all([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x33]);
+        assert_eq!(Image::read(&mut MemReader::new(huge)), Err(Truncated));
+    }
+
+    #[test]
+    fn test_leb128() {
+        let mut code = MemWriter::new();
+        code.write_push(1).unwrap();
+        code.write_push(-1000).unwrap();
+        code.write_add().unwrap();
+        code.write_jump(3).unwrap();
+        let code = code.unwrap();
+
+        let mut image = Image::new(code.clone());
+        image.encoding = Leb128;
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let bytes = writer.unwrap();
+        assert_eq!(bytes.len(), 4 + 2 + 8 + (2 + 3 + 1 + 2) + 4);
+        let read = Image::read(&mut MemReader::new(bytes)).unwrap();
+        assert_eq!(read.code, code);
+        assert_eq!(read.encoding, Leb128);
+    }
+
+    #[test]
+    fn test_label_names() {
+        let mut names = LabelNames::new();
+        names.insert(1, "main".to_string());
+        names.insert(-2, "loop".to_string());
+        let mut image = Image::new(vec!(0x33, 0x75));
+        image.set_label_names(&names);
+        image.set_section(9, vec!(1, 2, 3));
+
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let read = Image::read(&mut MemReader::new(writer.unwrap())).unwrap();
+        assert_eq!(read, image);
+        assert_eq!(read.label_names(), Ok(names));
+        assert_eq!(read.section(9), Some(&[1u8, 2, 3]));
+        assert_eq!(Image::new(vec!()).label_names(), Ok(LabelNames::new()));
+
+        let mut image = Image::new(vec!());
+        image.set_section(SECTION_LABEL_NAMES, vec!(0, 0, 0, 0, 0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFF, 0x61));
+        assert!(image.label_names().is_err());
+
+        let mut huge = MAGIC.to_vec();
+        huge.push_all([VERSION, FLAG_SECTIONS, 0, 0, 0, 0, 0, 0, 0, 0, SECTION_LABEL_NAMES]);
+        huge.push_all([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x61]);
+        assert_eq!(Image::read(&mut MemReader::new(huge)), Err(Truncated));
+    }
+
+    #[test]
+    fn test_source_map() {
+        let mut program = Program::new();
+        program.push(ir::Mark(1));
+        program.push_located(ir::StackPush(1), SourceLocation { line: 2, column: 1, token: "SSSTN".to_string() });
+        program.push_located(ir::PutNumber, SourceLocation { line: 2, column: 6, token: "TNST".to_string() });
+        let image = Image::from_program(&program).unwrap();
+
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let read = Image::read(&mut MemReader::new(writer.unwrap())).unwrap();
+        let map = read.source_map().unwrap().unwrap();
+        assert_eq!(map, SourceMap {
+            file: "".to_string(),
+            positions: vec!(SourcePosition { offset: 9, line: 2, column: 1 },
+                            SourcePosition { offset: 18, line: 2, column: 6 }),
+        });
+        assert_eq!(map.find(0), None);
+        assert_eq!(map.find(10).unwrap().column, 1);
+        assert_eq!(map.find(18).unwrap().column, 6);
+
+        let plain = Image::from_program(&Program::from_vec(vec!(ir::Exit))).unwrap();
+        assert_eq!(plain.source_map(), Ok(None));
+
+        let mut image = Image::new(vec!());
+        image.set_section(SECTION_SOURCE_MAP, vec!(0xFF, 0xFF, 0xFF, 0xFF, 0x61));
+        assert!(image.source_map().is_err());
+    }
+
+    #[test]
+    fn test_data() {
+        let segments = vec!(DataSegment::bytes(0, b"ab"), DataSegment::integers(-3, [-1, 1 << 40]));
+        let mut image = Image::new(vec!());
+        image.set_data(segments.as_slice());
+
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let read = Image::read(&mut MemReader::new(writer.unwrap())).unwrap();
+        assert_eq!(read.data(), Ok(segments));
+        assert_eq!(Image::new(vec!()).data(), Ok(vec!()));
+
+        let mut image = Image::new(vec!());
+        image.set_section(SECTION_DATA, vec!(0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFF));
+        assert!(image.data().is_err());
+    }
+
+    #[test]
+    fn test_compressed() {
+        let mut code = MemWriter::new();
+        for _ in range(0u, 100) {
+            code.write_push(72).unwrap();
+            code.write_putc().unwrap();
+        }
+        let code = code.unwrap();
+
+        let mut image = Image::new(code.clone());
+        image.compressed = true;
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let bytes = writer.unwrap();
+        assert!(bytes.len() < code.len() / 4);
+        let read = Image::read(&mut MemReader::new(bytes)).unwrap();
+        assert_eq!(read.code, code);
+        assert!(read.compressed);
+
+        image.encoding = Leb128;
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        assert_eq!(Image::read(&mut MemReader::new(writer.unwrap())), Ok(image));
+    }
+
+    #[test]
+    fn test_label_index() {
+        let program = Program::from_vec(vec!(ir::Call(2), ir::Exit, ir::Mark(2), ir::Mark(-1), ir::Return));
+        let mut image = Image::from_program(&program).unwrap();
+        assert_eq!(image.label_index(), Ok(None));
+        image.index_labels().unwrap();
+
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let read = Image::read(&mut MemReader::new(writer.unwrap())).unwrap();
+        let index = read.label_index().unwrap().unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.find(&2), Some(&19));
+        assert_eq!(index.find(&-1), Some(&28));
+    }
+
+    #[test]
+    fn test_read_compatible() {
+        let bytes = image_bytes();
+        assert_eq!(Image::read_compatible(&mut MemReader::new(bytes)), Ok(Image::new(vec!(0x33, 0x75))));
+        assert_eq!(Image::read_compatible(&mut MemReader::new(vec!(0x34, 0x75))), Ok(Image::new(vec!(0x34, 0x75))));
+        assert_eq!(Image::read_compatible(&mut MemReader::new(vec!())), Ok(Image::new(vec!())));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut code = MemWriter::new();
+        code.write_fork().unwrap();
+        code.write_exit().unwrap();
+        let code = code.unwrap();
+
+        let mut old = MAGIC.to_vec();
+        old.push_all([1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0x77, bytecode::CMD_EXIT]);
+        let crc = crc32(old.as_slice());
+        old.push_all([(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+        assert_eq!(Image::read(&mut MemReader::new(old)).unwrap().code, code);
+
+        let bare = vec!(bytecode::CMD_PUSH, 0, 0, 0, 0, 0, 0, 0, 0x77, 0x77, bytecode::CMD_EXIT);
+        let mut expected = MemWriter::new();
+        expected.write_push(0x77).unwrap();
+        expected.write_fork().unwrap();
+        expected.write_exit().unwrap();
+        assert_eq!(Image::read_compatible(&mut MemReader::new(bare)).unwrap().code, expected.unwrap());
+    }
+
+    #[test]
+    fn test_signature() {
+        let mut image = Image::new(vec!(0x33, 0x75));
+        image.set_data([DataSegment::bytes(0, b"ok")]);
+        assert_eq!(image.verify(b"key"), Err(Unsigned));
+        image.sign(b"key");
+        image.encoding = Leb128;
+        image.compressed = true;
+
+        let mut writer = MemWriter::new();
+        image.write(&mut writer).unwrap();
+        let bytes = writer.unwrap();
+        assert_eq!(Image::read_signed(&mut MemReader::new(bytes.clone()), b"key"), Ok(image.clone()));
+        assert_eq!(Image::read_signed(&mut MemReader::new(bytes), b"other"), Err(BadSignature));
+
+        let mut tampered = image.clone();
+        tampered.set_data([DataSegment::bytes(0, b"no")]);
+        assert_eq!(tampered.verify(b"key"), Err(BadSignature));
+        let mut tampered = image.clone();
+        *tampered.code.get_mut(0) = 0x34;
+        assert_eq!(tampered.verify(b"key"), Err(BadSignature));
+    }
+}
@@ -0,0 +1,68 @@
+//! Signed LEB128 encoding of operands.
+
+#![experimental]
+
+use std::io::{InvalidInput, IoError, IoResult};
+
+/// Write a number in signed LEB128, 1 byte for -64 to 63 and at most 10 bytes.
+pub fn write_signed<W: Writer>(writer: &mut W, n: i64) -> IoResult<()> {
+    let mut n = n;
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        if done { return writer.write_u8(byte) }
+        try!(writer.write_u8(byte | 0x80));
+    }
+}
+
+/// Read a number in signed LEB128.
+///
+/// # Error
+///
+/// If the number doesn't fit in 64 bits, or an I/O error occurs, then this function will return `Err`.
+pub fn read_signed<R: Reader>(reader: &mut R) -> IoResult<i64> {
+    let mut n = 0i64;
+    let mut shift = 0u;
+    loop {
+        let byte = try!(reader.read_u8());
+        // the 10th byte holds the highest bit, and the rest must extend its sign
+        if shift == 63 && byte != 0x00 && byte != 0x7F {
+            return Err(IoError { kind: InvalidInput, desc: "operand overflow", detail: None })
+        }
+        n |= (byte & 0x7F) as i64 << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 { n |= -1 << shift; }
+            return Ok(n)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+    use std::io::{MemReader, MemWriter};
+
+    use super::{read_signed, write_signed};
+
+    #[test]
+    fn test_signed() {
+        let cases = [(0i64, 1u), (63, 1), (-64, 1), (64, 2), (-65, 2), (1000, 2), (i64::MAX, 10), (i64::MIN, 10)];
+        for &(n, len) in cases.iter() {
+            let mut writer = MemWriter::new();
+            write_signed(&mut writer, n).unwrap();
+            let bytes = writer.unwrap();
+            assert_eq!(bytes.len(), len);
+            assert_eq!(read_signed(&mut MemReader::new(bytes)), Ok(n));
+        }
+        assert!(read_signed(&mut MemReader::new(Vec::from_elem(11, 0x80u8))).is_err());
+
+        let mut overflow = Vec::from_elem(9, 0xFFu8);
+        overflow.push(0x01);
+        assert!(read_signed(&mut MemReader::new(overflow)).is_err());
+        let mut overflow = Vec::from_elem(9, 0x80u8);
+        overflow.push(0x7E);
+        assert!(read_signed(&mut MemReader::new(overflow)).is_err());
+    }
+}
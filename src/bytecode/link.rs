@@ -0,0 +1,221 @@
+//! Linker of bytecode images.
+
+#![experimental]
+
+use std::collections::HashMap;
+use std::io::{IoError, MemWriter};
+
+use bytecode;
+use bytecode::{has_operand, Module};
+use bytecode::image::{Image, LabelNames};
+
+pub type LinkResult<T> = Result<T, LinkError>;
+
+/// A list specifying errors of linking.
+#[deriving(PartialEq, Show)]
+pub enum LinkError {
+    /// The named label is marked in more than one image.
+    DuplicateSymbol(String),
+    /// The named label is used but marked in no image.
+    UndefinedSymbol(String),
+    /// I/O error occurred, e.g. an image is malformed.
+    LinkIoError(IoError),
+}
+
+/// Concatenates images into one, renumbering labels so that those of different
+/// images never collide.
+///
+/// Labels named in `SECTION_LABEL_NAMES` of an image are local to it unless
+/// externals are resolved, in which case labels of the same name refer to the
+/// same "MARK" across images. Names are kept in the linked image, while other
+/// sections are dropped since they don't survive relocation.
+///
+/// ```rust
+/// use std::io::MemWriter;
+/// use whitebase::bytecode::{ByteCodeWriter, Module};
+/// use whitebase::bytecode::image::{Image, LabelNames};
+/// use whitebase::bytecode::link::Linker;
+///
+/// let mut main = MemWriter::new();
+/// main.write_call(0).unwrap();
+/// main.write_exit().unwrap();
+/// let mut main = Image::new(main.unwrap());
+/// let mut names = LabelNames::new();
+/// names.insert(0, "greet".to_string());
+/// main.set_label_names(&names);
+///
+/// let mut lib = MemWriter::new();
+/// lib.write_mark(7).unwrap();
+/// lib.write_return().unwrap();
+/// let mut lib = Image::new(lib.unwrap());
+/// let mut names = LabelNames::new();
+/// names.insert(7, "greet".to_string());
+/// lib.set_label_names(&names);
+///
+/// let mut linker = Linker::with_externals();
+/// linker.add(main);
+/// linker.add(lib);
+/// let module = Module::from_bytes(linker.link().unwrap().code).unwrap();
+/// let (_, call) = module.get(0).unwrap();
+/// let (_, mark) = module.get(2).unwrap();
+/// assert_eq!(call, mark);
+/// ```
+pub struct Linker {
+    images: Vec<Image>,
+    externals: bool,
+}
+
+impl Linker {
+    /// Create an empty `Linker` which keeps all labels local to their image.
+    pub fn new() -> Linker { Linker { images: Vec::new(), externals: false } }
+
+    /// Create an empty `Linker` which resolves named labels across images.
+    pub fn with_externals() -> Linker { Linker { images: Vec::new(), externals: true } }
+
+    /// Append an image, whose code follows those already added.
+    pub fn add(&mut self, image: Image) {
+        self.images.push(image);
+    }
+
+    /// Link the images added, renumbering labels from 0 in order of appearance.
+    pub fn link(&self) -> LinkResult<Image> {
+        let mut modules = Vec::new();
+        for image in self.images.iter() {
+            let module = try!(Module::from_bytes(image.code.clone()).map_err(|e| LinkIoError(e)));
+            let names = try!(image.label_names().map_err(|e| LinkIoError(e)));
+            modules.push((module, names));
+        }
+
+        let mut symbols: HashMap<String, i64> = HashMap::new();
+        let mut marked: HashMap<String, uint> = HashMap::new();
+        let mut linked_names = LabelNames::new();
+        let mut next = 0i64;
+        let mut writer = MemWriter::new();
+        for (i, &(ref module, ref names)) in modules.iter().enumerate() {
+            let mut relocation: HashMap<i64, i64> = HashMap::new();
+            for &(opcode, n) in module.as_slice().iter() {
+                if !is_label(opcode) { try!(write(&mut writer, opcode, n)); continue }
+                let name = names.find(&n);
+                let label = match relocation.find_copy(&n) {
+                    Some(label) => label,
+                    None => {
+                        let label = match name {
+                            Some(name) if self.externals => *symbols.find_or_insert_with(name.clone(), |_| {
+                                next += 1;
+                                next - 1
+                            }),
+                            _ => { next += 1; next - 1 },
+                        };
+                        relocation.insert(n, label);
+                        match name {
+                            Some(name) => { linked_names.insert(label, name.clone()); },
+                            None => (),
+                        }
+                        label
+                    },
+                };
+                if opcode == bytecode::CMD_MARK && self.externals {
+                    match name {
+                        Some(name) => match marked.find_copy(name) {
+                            Some(j) if j != i => return Err(DuplicateSymbol(name.clone())),
+                            _ => { marked.insert(name.clone(), i); },
+                        },
+                        None => (),
+                    }
+                }
+                try!(write(&mut writer, opcode, label));
+            }
+        }
+        if self.externals {
+            let mut undefined: Vec<&String> = symbols.keys().filter(|name| !marked.contains_key(*name)).collect();
+            undefined.sort();
+            match undefined.head() {
+                Some(name) => return Err(UndefinedSymbol((**name).clone())),
+                None => (),
+            }
+        }
+
+        let mut image = Image::new(writer.unwrap());
+        if !linked_names.is_empty() { image.set_label_names(&linked_names); }
+        Ok(image)
+    }
+}
+
+fn is_label(opcode: u8) -> bool {
+    opcode == bytecode::CMD_MARK || opcode == bytecode::CMD_CALL || opcode == bytecode::CMD_JUMP ||
+        opcode == bytecode::CMD_JUMPZ || opcode == bytecode::CMD_JUMPN
+}
+
+fn write(writer: &mut MemWriter, opcode: u8, n: i64) -> LinkResult<()> {
+    let result = writer.write_u8(opcode).and_then(|_| {
+        if has_operand(opcode) { writer.write_be_i64(n) } else { Ok(()) }
+    });
+    result.map_err(|e| LinkIoError(e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use bytecode;
+    use bytecode::{ByteCodeWriter, Module};
+    use bytecode::image::{Image, LabelNames};
+    use super::{Linker, DuplicateSymbol, UndefinedSymbol};
+
+    fn image(marks: &[i64], calls: &[i64], names: &[(i64, &str)]) -> Image {
+        let mut bcw = MemWriter::new();
+        for &n in calls.iter() { bcw.write_call(n).unwrap(); }
+        for &n in marks.iter() {
+            bcw.write_mark(n).unwrap();
+            bcw.write_return().unwrap();
+        }
+        let mut image = Image::new(bcw.unwrap());
+        let mut label_names = LabelNames::new();
+        for &(n, name) in names.iter() { label_names.insert(n, name.to_string()); }
+        if !label_names.is_empty() { image.set_label_names(&label_names); }
+        image
+    }
+
+    fn labels(image: &Image) -> Vec<(u8, i64)> {
+        Module::from_bytes(image.code.clone()).unwrap().as_slice().iter()
+            .filter(|&&(opcode, _)| opcode != bytecode::CMD_RETURN).map(|i| *i).collect()
+    }
+
+    #[test]
+    fn test_local() {
+        let mut linker = Linker::new();
+        linker.add(image([1], [1], [(1, "f")]));
+        linker.add(image([1], [1], [(1, "f")]));
+        let linked = linker.link().unwrap();
+        assert_eq!(labels(&linked), vec!(
+            (bytecode::CMD_CALL, 0), (bytecode::CMD_MARK, 0),
+            (bytecode::CMD_CALL, 1), (bytecode::CMD_MARK, 1),
+        ));
+        let names = linked.label_names().unwrap();
+        assert_eq!(names.find(&0), Some(&"f".to_string()));
+        assert_eq!(names.find(&1), Some(&"f".to_string()));
+    }
+
+    #[test]
+    fn test_externals() {
+        let mut linker = Linker::with_externals();
+        linker.add(image([3], [5, 3], [(5, "lib")]));
+        linker.add(image([2, 5], [], [(2, "lib")]));
+        assert_eq!(labels(&linker.link().unwrap()), vec!(
+            (bytecode::CMD_CALL, 0), (bytecode::CMD_CALL, 1), (bytecode::CMD_MARK, 1),
+            (bytecode::CMD_MARK, 0), (bytecode::CMD_MARK, 2),
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut linker = Linker::with_externals();
+        linker.add(image([], [1], [(1, "missing")]));
+        assert_eq!(linker.link(), Err(UndefinedSymbol("missing".to_string())));
+
+        let mut linker = Linker::with_externals();
+        linker.add(image([1], [], [(1, "twice")]));
+        linker.add(image([2], [], [(2, "twice")]));
+        assert_eq!(linker.link(), Err(DuplicateSymbol("twice".to_string())));
+    }
+}
@@ -7,6 +7,12 @@ use std::io::{EndOfFile, InvalidInput, IoError, IoResult, standard_error};
 use ir;
 use ir::Instruction;
 
+pub use self::diff::diff;
+pub use self::dump::dump;
+pub use self::module::Module;
+pub use self::validate::{validate, Summary, ValidationError};
+pub use self::validate::{InvalidOpcode, MissingOperand, DuplicateMark, UndefinedTarget, ValidationIoError};
+
 pub static IMP_STACK: u8      = 0b0011 << 4;
 pub static IMP_ARITHMETIC: u8 = 0b1000 << 4;
 pub static IMP_HEAP: u8       = 0b1010 << 4;
@@ -38,11 +44,160 @@ pub static CMD_PUTN: u8     = IMP_IO + 0b0010;
 pub static CMD_GETC: u8     = IMP_IO + 0b1000;
 pub static CMD_GETN: u8     = IMP_IO + 0b1010;
 
+/// "EXIT" with a status operand, which has no Whitespace encoding.
+pub static CMD_EXIT_STATUS: u8 = IMP_FLOW + 0b0110;
+/// No operation, which pads patched code and has no Whitespace encoding.
+pub static CMD_NOP: u8      = IMP_STACK + 0b0000;
+/// "PUSH" followed by "ADD", which has no Whitespace encoding.
+pub static CMD_PUSH_ADD: u8 = IMP_ARITHMETIC + 0b0100;
+/// "PUSH" followed by "RETRIEVE", which has no Whitespace encoding.
+pub static CMD_RETRIEVE_AT: u8 = IMP_HEAP + 0b0100;
+/// "PUSH" followed by "STORE", which has no Whitespace encoding.
+pub static CMD_STORE_CONST: u8 = IMP_HEAP + 0b0101;
+
+/// Lowest opcode reserved for extension instructions, which take an operand.
+/// See `ext::ExtRegistry` for naming them.
+pub static CMD_EXT_MIN: u8  = 0xF0;
+/// Fork the current thread. The last extension opcode is reserved for it, and
+/// its operand is written as 0.
+pub static CMD_FORK: u8     = 0xFF;
+
+/// Return true if the opcode is reserved for extension instructions, including "FORK".
+pub fn is_extension(opcode: u8) -> bool {
+    opcode >= CMD_EXT_MIN
+}
+
+/// Return true if the opcode is followed by an operand.
+pub fn has_operand(opcode: u8) -> bool {
+    opcode == CMD_PUSH || opcode == CMD_COPY || opcode == CMD_SLIDE ||
+        opcode == CMD_MARK || opcode == CMD_CALL || opcode == CMD_JUMP ||
+        opcode == CMD_JUMPZ || opcode == CMD_JUMPN || opcode == CMD_EXIT_STATUS ||
+        opcode == CMD_PUSH_ADD || opcode == CMD_RETRIEVE_AT || opcode == CMD_STORE_CONST ||
+        is_extension(opcode)
+}
+
+/// Return true if the byte is an opcode which the virtual machine can execute.
+pub fn is_opcode(opcode: u8) -> bool {
+    decode(opcode, 0).is_some() || lower(opcode, 0).is_some()
+}
+
+/// Create the error of an unknown opcode at the offset in the stream, which
+/// `read_inst` and `disassemble` return.
+///
+/// ```rust
+/// use std::io::MemReader;
+/// use whitebase::bytecode::ByteCodeReader;
+///
+/// let err = MemReader::new(vec!(0x34, 0x1f)).disassemble().nth(1).unwrap().unwrap_err();
+/// assert_eq!(err.desc, "unknown opcode");
+/// assert_eq!(err.detail, Some("0x1f at offset 1".to_string()));
+/// ```
+pub fn unknown_opcode(opcode: u8, offset: u64) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "unknown opcode",
+        detail: Some(format!("0x{:02x} at offset {}", opcode, offset)),
+    }
+}
+
+/// Return the opcode an instruction is encoded to.
+pub fn opcode(inst: &Instruction) -> u8 {
+    match *inst {
+        ir::StackPush(_)      => CMD_PUSH,
+        ir::StackDuplicate    => CMD_DUP,
+        ir::StackCopy(_)      => CMD_COPY,
+        ir::StackSwap         => CMD_SWAP,
+        ir::StackDiscard      => CMD_DISCARD,
+        ir::StackSlide(_)     => CMD_SLIDE,
+        ir::Addition          => CMD_ADD,
+        ir::Subtraction       => CMD_SUB,
+        ir::Multiplication    => CMD_MUL,
+        ir::Division          => CMD_DIV,
+        ir::Modulo            => CMD_MOD,
+        ir::HeapStore         => CMD_STORE,
+        ir::HeapRetrieve      => CMD_RETRIEVE,
+        ir::Mark(_)           => CMD_MARK,
+        ir::Call(_)           => CMD_CALL,
+        ir::Jump(_)           => CMD_JUMP,
+        ir::JumpIfZero(_)     => CMD_JUMPZ,
+        ir::JumpIfNegative(_) => CMD_JUMPN,
+        ir::Return            => CMD_RETURN,
+        ir::Exit              => CMD_EXIT,
+        ir::PutCharactor      => CMD_PUTC,
+        ir::PutNumber         => CMD_PUTN,
+        ir::GetCharactor      => CMD_GETC,
+        ir::GetNumber         => CMD_GETN,
+        ir::Nop               => CMD_NOP,
+        ir::ExitWith(_)       => CMD_EXIT_STATUS,
+        ir::Extension(op, _)  => op,
+    }
+}
+
+/// Return the instruction an opcode and its operand are decoded to,
+/// or `None` if the opcode has no instruction.
+pub fn decode(opcode: u8, n: i64) -> Option<Instruction> {
+    let inst = match opcode {
+        CMD_PUSH     => ir::StackPush(n),
+        CMD_DUP      => ir::StackDuplicate,
+        CMD_COPY     => ir::StackCopy(n),
+        CMD_SWAP     => ir::StackSwap,
+        CMD_DISCARD  => ir::StackDiscard,
+        CMD_SLIDE    => ir::StackSlide(n),
+        CMD_ADD      => ir::Addition,
+        CMD_SUB      => ir::Subtraction,
+        CMD_MUL      => ir::Multiplication,
+        CMD_DIV      => ir::Division,
+        CMD_MOD      => ir::Modulo,
+        CMD_STORE    => ir::HeapStore,
+        CMD_RETRIEVE => ir::HeapRetrieve,
+        CMD_MARK     => ir::Mark(n),
+        CMD_CALL     => ir::Call(n),
+        CMD_JUMP     => ir::Jump(n),
+        CMD_JUMPZ    => ir::JumpIfZero(n),
+        CMD_JUMPN    => ir::JumpIfNegative(n),
+        CMD_RETURN   => ir::Return,
+        CMD_EXIT     => ir::Exit,
+        CMD_PUTC     => ir::PutCharactor,
+        CMD_PUTN     => ir::PutNumber,
+        CMD_GETC     => ir::GetCharactor,
+        CMD_GETN     => ir::GetNumber,
+        CMD_NOP      => ir::Nop,
+        CMD_EXIT_STATUS => ir::ExitWith(n),
+        _ if is_extension(opcode) => ir::Extension(opcode, n),
+        _            => return None,
+    };
+    Some(inst)
+}
+
+/// Return the core opcodes and operands a superinstruction is lowered to,
+/// or `None` if the opcode is not a superinstruction.
+///
+/// ```rust
+/// use whitebase::bytecode;
+///
+/// assert_eq!(bytecode::lower(bytecode::CMD_PUSH_ADD, 2),
+///            Some(((bytecode::CMD_PUSH, 2), (bytecode::CMD_ADD, 0))));
+/// assert_eq!(bytecode::lower(bytecode::CMD_ADD, 0), None);
+/// ```
+pub fn lower(opcode: u8, n: i64) -> Option<((u8, i64), (u8, i64))> {
+    let second = match opcode {
+        CMD_PUSH_ADD    => CMD_ADD,
+        CMD_RETRIEVE_AT => CMD_RETRIEVE,
+        CMD_STORE_CONST => CMD_STORE,
+        _               => return None,
+    };
+    Some(((CMD_PUSH, n), (second, 0)))
+}
+
 #[experimental]
 /// Bytecodes writer.
 pub trait ByteCodeWriter {
     /// Compile a instruction to bytecodes.
     fn assemble<I: Iterator<IoResult<Instruction>>>(&mut self, &mut I) -> IoResult<()>;
+    /// Compile instructions held in memory to bytecodes.
+    fn assemble_slice(&mut self, insts: &[Instruction]) -> IoResult<()> {
+        self.assemble(&mut insts.iter().map(|inst| Ok(inst.clone())))
+    }
     /// Writes a push instruction.
     fn write_push(&mut self, n: i64) -> IoResult<()>;
     /// Writes a duplicate instruction.
@@ -91,6 +246,24 @@ pub trait ByteCodeWriter {
     fn write_getc(&mut self) -> IoResult<()>;
     /// Writes a number get instruction.
     fn write_getn(&mut self) -> IoResult<()>;
+    /// Writes an exit instruction with a status.
+    fn write_exit_status(&mut self, status: i64) -> IoResult<()>;
+    /// Writes a fork instruction.
+    fn write_fork(&mut self) -> IoResult<()>;
+    /// Writes a no operation instruction.
+    fn write_nop(&mut self) -> IoResult<()>;
+    /// Writes a fused push and addition instruction.
+    fn write_push_add(&mut self, n: i64) -> IoResult<()>;
+    /// Writes a fused retrieve instruction from the address.
+    fn write_retrieve_at(&mut self, addr: i64) -> IoResult<()>;
+    /// Writes a fused store instruction of the value.
+    fn write_store_const(&mut self, n: i64) -> IoResult<()>;
+    /// Writes an extension instruction.
+    ///
+    /// # Error
+    ///
+    /// If the opcode is not reserved for extensions, then this function will return `Err`.
+    fn write_ext(&mut self, opcode: u8, n: i64) -> IoResult<()>;
 }
 
 impl<W: Writer> ByteCodeWriter for W {
@@ -121,6 +294,9 @@ impl<W: Writer> ByteCodeWriter for W {
                 Ok(ir::PutNumber)         => self.write_putn(),
                 Ok(ir::GetCharactor)      => self.write_getc(),
                 Ok(ir::GetNumber)         => self.write_getn(),
+                Ok(ir::Nop)               => self.write_nop(),
+                Ok(ir::ExitWith(n))       => self.write_exit_status(n),
+                Ok(ir::Extension(op, n))  => self.write_ext(op, n),
                 Err(e)                      => Err(e),
             });
         }
@@ -230,56 +406,85 @@ impl<W: Writer> ByteCodeWriter for W {
     fn write_getn(&mut self) -> IoResult<()> {
         self.write_u8(CMD_GETN)
     }
+
+    fn write_exit_status(&mut self, status: i64) -> IoResult<()> {
+        try!(self.write_u8(CMD_EXIT_STATUS));
+        self.write_be_i64(status)
+    }
+
+    fn write_fork(&mut self) -> IoResult<()> {
+        self.write_ext(CMD_FORK, 0)
+    }
+
+    fn write_nop(&mut self) -> IoResult<()> {
+        self.write_u8(CMD_NOP)
+    }
+
+    fn write_push_add(&mut self, n: i64) -> IoResult<()> {
+        try!(self.write_u8(CMD_PUSH_ADD));
+        self.write_be_i64(n)
+    }
+
+    fn write_retrieve_at(&mut self, addr: i64) -> IoResult<()> {
+        try!(self.write_u8(CMD_RETRIEVE_AT));
+        self.write_be_i64(addr)
+    }
+
+    fn write_store_const(&mut self, n: i64) -> IoResult<()> {
+        try!(self.write_u8(CMD_STORE_CONST));
+        self.write_be_i64(n)
+    }
+
+    fn write_ext(&mut self, opcode: u8, n: i64) -> IoResult<()> {
+        if !is_extension(opcode) { return Err(standard_error(InvalidInput)) }
+        try!(self.write_u8(opcode));
+        self.write_be_i64(n)
+    }
 }
 
 #[experimental]
 /// An iterator that convert to IR from bytes on each iteration, `read_inst()` encounters `EndOfFile`.
 pub struct Instructions<'r, T> {
-    reader: &'r mut T
+    reader: &'r mut T,
+    // the second instruction of a lowered superinstruction
+    pending: Option<Instruction>,
 }
 
 impl<'r, B: ByteCodeReader> Iterator<IoResult<Instruction>> for Instructions<'r, B> {
     fn next(&mut self) -> Option<IoResult<Instruction>> {
+        match self.pending.take() {
+            Some(inst) => return Some(Ok(inst)),
+            None => (),
+        }
         match self.reader.read_inst() {
-            Ok((CMD_PUSH, n))     => Some(Ok(ir::StackPush(n))),
-            Ok((CMD_DUP, _))      => Some(Ok(ir::StackDuplicate)),
-            Ok((CMD_COPY, n))     => Some(Ok(ir::StackCopy(n))),
-            Ok((CMD_SWAP, _))     => Some(Ok(ir::StackSwap)),
-            Ok((CMD_DISCARD, _))  => Some(Ok(ir::StackDiscard)),
-            Ok((CMD_SLIDE, n))    => Some(Ok(ir::StackSlide(n))),
-            Ok((CMD_ADD, _))      => Some(Ok(ir::Addition)),
-            Ok((CMD_SUB, _))      => Some(Ok(ir::Subtraction)),
-            Ok((CMD_MUL, _))      => Some(Ok(ir::Multiplication)),
-            Ok((CMD_DIV, _))      => Some(Ok(ir::Division)),
-            Ok((CMD_MOD, _))      => Some(Ok(ir::Modulo)),
-            Ok((CMD_STORE, _))    => Some(Ok(ir::HeapStore)),
-            Ok((CMD_RETRIEVE, _)) => Some(Ok(ir::HeapRetrieve)),
-            Ok((CMD_MARK, n))     => Some(Ok(ir::Mark(n))),
-            Ok((CMD_CALL, n))     => Some(Ok(ir::Call(n))),
-            Ok((CMD_JUMP, n))     => Some(Ok(ir::Jump(n))),
-            Ok((CMD_JUMPZ, n))    => Some(Ok(ir::JumpIfZero(n))),
-            Ok((CMD_JUMPN, n))    => Some(Ok(ir::JumpIfNegative(n))),
-            Ok((CMD_RETURN, _))   => Some(Ok(ir::Return)),
-            Ok((CMD_EXIT, _))     => Some(Ok(ir::Exit)),
-            Ok((CMD_PUTC, _))     => Some(Ok(ir::PutCharactor)),
-            Ok((CMD_PUTN, _))     => Some(Ok(ir::PutNumber)),
-            Ok((CMD_GETC, _))     => Some(Ok(ir::GetCharactor)),
-            Ok((CMD_GETN, _))     => Some(Ok(ir::GetNumber)),
+            Ok((opcode, n)) => match (decode(opcode, n), lower(opcode, n)) {
+                (Some(inst), _) => Some(Ok(inst)),
+                (None, Some(((first, m), (second, 0)))) => {
+                    self.pending = decode(second, 0);
+                    decode(first, m).map(|inst| Ok(inst))
+                },
+                _ => {
+                    let len = if has_operand(opcode) { 9 } else { 1 };
+                    Some(self.reader.tell().and_then(|pos| Err(unknown_opcode(opcode, pos - len))))
+                },
+            },
             Err(IoError { kind: EndOfFile, ..}) => None,
             Err(e) => Some(Err(e)),
-            _ => Some(Err(standard_error(InvalidInput))),
         }
     }
 }
 
 #[experimental]
 /// Bytecodes reader.
+///
+/// Streams which can't seek, like pipes, can be read through `stream::StreamReader`.
 pub trait ByteCodeReader: Reader + Seek {
     /// Read the next instruction bytes from the underlying stream.
     ///
     /// # Error
     ///
     /// If an I/O error occurs, or EOF, then this function will return `Err`.
+    /// An unknown opcode is reported by `unknown_opcode`.
     fn read_inst(&mut self) -> IoResult<(u8, i64)>;
 
     /// Create an iterator that convert to IR from bytes on each iteration
@@ -289,15 +494,20 @@ pub trait ByteCodeReader: Reader + Seek {
     ///
     /// Any error other than `EndOfFile` that is produced by the underlying Reader
     /// is returned by the iterator and should be handled by the caller.
+    /// Superinstructions are lowered to their core instructions, and opcodes
+    /// which have no IR instruction are reported by `unknown_opcode`.
     fn disassemble<'r>(&'r mut self) -> Instructions<'r, Self> {
-        Instructions { reader: self }
+        Instructions { reader: self, pending: None }
     }
 }
 
 impl<R: Reader + Seek> ByteCodeReader for R {
     fn read_inst(&mut self) -> IoResult<(u8, i64)> {
         match self.read_u8() {
-            Ok(n) if n == CMD_PUSH || n == CMD_COPY || n == CMD_SLIDE || n == CMD_MARK || n == CMD_CALL || n == CMD_JUMP || n == CMD_JUMPZ || n == CMD_JUMPN => {
+            // only an unknown opcode needs the offset, so that streams which can't
+            // tell their position are read without it
+            Ok(n) if !is_opcode(n) => Err(unknown_opcode(n, try!(self.tell()) - 1)),
+            Ok(n) if has_operand(n) => {
                 Ok((n, try!(self.read_be_i64())))
             },
             Ok(n) => Ok((n, 0)),
@@ -306,6 +516,19 @@ impl<R: Reader + Seek> ByteCodeReader for R {
     }
 }
 
+pub mod diff;
+pub mod dump;
+pub mod ext;
+pub mod hmac;
+pub mod image;
+pub mod leb128;
+pub mod link;
+pub mod module;
+pub mod offset;
+pub mod patch;
+pub mod stream;
+pub mod validate;
+
 #[cfg(test)]
 mod test {
     use std::io::{IoResult, MemReader, MemWriter};
@@ -339,6 +562,11 @@ mod test {
         writer.write_putn().unwrap();
         writer.write_getc().unwrap();
         writer.write_getn().unwrap();
+        writer.write_exit_status(2).unwrap();
+        writer.write_fork().unwrap();
+        writer.write_nop().unwrap();
+        writer.write_ext(0xF1, -3).unwrap();
+        assert!(writer.write_ext(0x01, 0).is_err());
 
         let mut reader = MemReader::new(writer.unwrap());
         assert_eq!(reader.read_inst(), Ok((super::CMD_PUSH, -1)));
@@ -365,6 +593,50 @@ mod test {
         assert_eq!(reader.read_inst(), Ok((super::CMD_PUTN, 0)));
         assert_eq!(reader.read_inst(), Ok((super::CMD_GETC, 0)));
         assert_eq!(reader.read_inst(), Ok((super::CMD_GETN, 0)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_EXIT_STATUS, 2)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_FORK, 0)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_NOP, 0)));
+        assert_eq!(reader.read_inst(), Ok((0xF1, -3)));
+    }
+
+    #[test]
+    fn test_assemble_slice() {
+        let mut writer = MemWriter::new();
+        writer.assemble_slice([ir::StackPush(3), ir::PutNumber, ir::Exit]).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        assert_eq!(reader.read_inst(), Ok((super::CMD_PUSH, 3)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_PUTN, 0)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_EXIT, 0)));
+        assert!(reader.eof());
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut reader = MemReader::new(vec!(super::CMD_DUP, 0x00));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_DUP, 0)));
+        assert_eq!(reader.read_inst(), Err(super::unknown_opcode(0x00, 1)));
+
+        let mut writer = MemWriter::new();
+        writer.write_dup().unwrap();
+        writer.write_u8(0x00).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        let mut it = reader.disassemble();
+        assert_eq!(it.next(), Some(Ok(ir::StackDuplicate)));
+        assert_eq!(it.next(), Some(Err(super::unknown_opcode(0x00, 1))));
+    }
+
+    #[test]
+    fn test_disassemble_fused() {
+        let mut writer = MemWriter::new();
+        writer.write_push_add(2).unwrap();
+        writer.write_retrieve_at(3).unwrap();
+        writer.write_store_const(4).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        assert_eq!(reader.disassemble().collect::<Vec<IoResult<ir::Instruction>>>(), vec!(
+            Ok(ir::StackPush(2)), Ok(ir::Addition),
+            Ok(ir::StackPush(3)), Ok(ir::HeapRetrieve),
+            Ok(ir::StackPush(4)), Ok(ir::HeapStore),
+        ));
     }
 
     #[test]
@@ -396,6 +668,10 @@ mod test {
                 Ok(ir::PutNumber),
                 Ok(ir::GetCharactor),
                 Ok(ir::GetNumber),
+                Ok(ir::Nop),
+                Ok(ir::ExitWith(9)),
+                Ok(ir::Extension(super::CMD_FORK, 0)),
+                Ok(ir::Extension(0xF1, -3)),
                 );
             let mut it = vec.move_iter();
             writer.assemble(&mut it).unwrap();
@@ -425,6 +701,13 @@ mod test {
         assert_eq!(reader.read_inst(), Ok((super::CMD_PUTN, 0)));
         assert_eq!(reader.read_inst(), Ok((super::CMD_GETC, 0)));
         assert_eq!(reader.read_inst(), Ok((super::CMD_GETN, 0)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_NOP, 0)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_EXIT_STATUS, 9)));
+        assert_eq!(reader.read_inst(), Ok((super::CMD_FORK, 0)));
+        assert_eq!(reader.read_inst(), Ok((0xF1, -3)));
+
+        let mut writer = MemWriter::new();
+        assert!(writer.assemble_slice([ir::Extension(0x01, 0)]).is_err());
     }
 
     #[test]
@@ -454,6 +737,10 @@ mod test {
         writer.write_putn().unwrap();
         writer.write_getc().unwrap();
         writer.write_getn().unwrap();
+        writer.write_nop().unwrap();
+        writer.write_exit_status(2).unwrap();
+        writer.write_fork().unwrap();
+        writer.write_ext(0xF1, -3).unwrap();
 
         let mut reader = MemReader::new(writer.unwrap());
         let mut it = reader.disassemble();
@@ -481,6 +768,10 @@ mod test {
         assert_eq!(it.next().unwrap(), Ok(ir::PutNumber));
         assert_eq!(it.next().unwrap(), Ok(ir::GetCharactor));
         assert_eq!(it.next().unwrap(), Ok(ir::GetNumber));
+        assert_eq!(it.next().unwrap(), Ok(ir::Nop));
+        assert_eq!(it.next().unwrap(), Ok(ir::ExitWith(2)));
+        assert_eq!(it.next().unwrap(), Ok(ir::Extension(super::CMD_FORK, 0)));
+        assert_eq!(it.next().unwrap(), Ok(ir::Extension(0xF1, -3)));
         assert!(it.next().is_none());
     }
 }
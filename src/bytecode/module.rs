@@ -0,0 +1,199 @@
+//! Bytecode decoded at once.
+
+#![experimental]
+
+use std::collections::HashMap;
+use std::io::{InvalidInput, IoError, IoResult, MemReader, standard_error};
+
+use bytecode::{decode, lower, ByteCodeReader};
+use bytecode::image::Image;
+use ir::Program;
+use machine::LabelIndex;
+
+/// An instruction stream decoded into opcodes and operands, with the positions
+/// of labels.
+///
+/// The virtual machine can run it without searching labels, and decompilers
+/// can index instructions instead of seeking.
+///
+/// ```rust
+/// use std::io::MemWriter;
+/// use std::io::util::{NullReader, NullWriter};
+/// use whitebase::bytecode;
+/// use whitebase::bytecode::{ByteCodeWriter, Module};
+/// use whitebase::machine::Machine;
+///
+/// let mut bcw = MemWriter::new();
+/// bcw.write_jump(1).unwrap();
+/// bcw.write_mark(1).unwrap();
+/// bcw.write_exit().unwrap();
+///
+/// let module = Module::from_bytes(bcw.unwrap()).unwrap();
+/// assert_eq!(module.as_slice(), &[(bytecode::CMD_JUMP, 1), (bytecode::CMD_MARK, 1), (bytecode::CMD_EXIT, 0)]);
+/// assert_eq!(module.label(1), Some(2));
+///
+/// let mut machine = Machine::new(NullReader, NullWriter);
+/// assert!(machine.run_module(&module).is_ok());
+/// ```
+#[deriving(Clone, Show)]
+pub struct Module {
+    code: Vec<u8>,
+    instructions: Vec<(u8, i64)>,
+    offsets: Vec<u64>,
+    labels: HashMap<i64, uint>,
+}
+
+impl Module {
+    /// Decode the whole stream, which needn't seek.
+    pub fn read<R: Reader>(reader: &mut R) -> IoResult<Module> {
+        Module::from_bytes(try!(reader.read_to_end()))
+    }
+
+    /// Decode the instruction stream.
+    ///
+    /// # Error
+    ///
+    /// If an operand is cut off, then this function will return `Err`.
+    pub fn from_bytes(code: Vec<u8>) -> IoResult<Module> {
+        let mut instructions = Vec::new();
+        let mut offsets = Vec::new();
+        let mut labels = HashMap::new();
+        {
+            let mut reader = MemReader::new(code.clone());
+            while !reader.eof() {
+                offsets.push(try!(reader.tell()));
+                let (opcode, n) = try!(reader.read_inst());
+                if opcode == super::CMD_MARK { labels.find_or_insert(n, instructions.len() + 1); }
+                instructions.push((opcode, n));
+            }
+        }
+        Ok(Module { code: code, instructions: instructions, offsets: offsets, labels: labels })
+    }
+
+    /// Decode the instruction stream of the image, checking the label index
+    /// in `SECTION_LABEL_INDEX` if stored.
+    ///
+    /// # Error
+    ///
+    /// If the label index doesn't match the labels of the instruction stream,
+    /// e.g. it wasn't stored again after the stream was modified, then this
+    /// function will return `Err`.
+    pub fn from_image(image: &Image) -> IoResult<Module> {
+        let module = try!(Module::from_bytes(image.code.clone()));
+        match try!(image.label_index()) {
+            Some(index) if index != module.label_index() => Err(IoError {
+                kind: InvalidInput,
+                desc: "stale label index",
+                detail: None,
+            }),
+            _ => Ok(module),
+        }
+    }
+
+    /// Number of instructions.
+    pub fn len(&self) -> uint { self.instructions.len() }
+
+    /// Get the opcode and operand of an instruction.
+    pub fn get(&self, index: uint) -> Option<(u8, i64)> { self.instructions.as_slice().get(index).map(|i| *i) }
+
+    /// Get the opcodes and operands of all instructions.
+    pub fn as_slice<'a>(&'a self) -> &'a [(u8, i64)] { self.instructions.as_slice() }
+
+    /// Byte offset of an instruction in the stream.
+    pub fn offset(&self, index: uint) -> Option<u64> { self.offsets.as_slice().get(index).map(|o| *o) }
+
+    /// Index of the instruction following the first "MARK" of the label.
+    pub fn label(&self, label: i64) -> Option<uint> { self.labels.find_copy(&label) }
+
+    /// Byte offsets following "MARK" by label, for `Machine::run_with_index`.
+    pub fn label_index(&self) -> LabelIndex {
+        let end = self.code.len() as u64;
+        self.labels.iter().map(|(&label, &index)| {
+            (label, self.offset(index).unwrap_or(end))
+        }).collect()
+    }
+
+    /// Get the instruction stream.
+    pub fn as_bytes<'a>(&'a self) -> &'a [u8] { self.code.as_slice() }
+
+    /// Create a reader of the instruction stream.
+    pub fn reader(&self) -> MemReader { MemReader::new(self.code.clone()) }
+
+    /// Convert to a program.
+    ///
+    /// # Error
+    ///
+    /// If an opcode has no instruction of the intermediate representation,
+    /// then this function will return `Err`. Superinstructions are lowered
+    /// to their core instructions.
+    pub fn to_program(&self) -> IoResult<Program> {
+        let mut program = Program::new();
+        for &(opcode, n) in self.instructions.iter() {
+            match (decode(opcode, n), lower(opcode, n)) {
+                (Some(inst), _) => program.push(inst),
+                (None, Some(((first, m), (second, _)))) => {
+                    program.push(decode(first, m).unwrap());
+                    program.push(decode(second, 0).unwrap());
+                },
+                _ => return Err(standard_error(InvalidInput)),
+            }
+        }
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use bytecode::image::Image;
+    use ir;
+    use ir::Program;
+    use super::Module;
+
+    #[test]
+    fn test_module() {
+        let mut bcw = MemWriter::new();
+        bcw.write_mark(7).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_mark(8).unwrap();
+        bcw.write_mark(7).unwrap();
+        bcw.write_exit().unwrap();
+        let module = Module::from_bytes(bcw.unwrap()).unwrap();
+
+        assert_eq!(module.len(), 5);
+        assert_eq!(module.get(1), Some((bytecode::CMD_PUSH, 1)));
+        assert_eq!(module.get(5), None);
+        assert_eq!(module.offset(2), Some(18));
+        assert_eq!(module.label(7), Some(1));
+        assert_eq!(module.label(8), Some(3));
+        assert_eq!(module.label(9), None);
+        let index = module.label_index();
+        assert_eq!(index.find_copy(&7), Some(9));
+        assert_eq!(index.find_copy(&8), Some(27));
+        assert_eq!(module.to_program(), Ok(Program::from_vec(vec!(
+            ir::Mark(7), ir::StackPush(1), ir::Mark(8), ir::Mark(7), ir::Exit,
+        ))));
+
+        let mut bcw = MemWriter::new();
+        bcw.write_fork().unwrap();
+        assert!(Module::from_bytes(bcw.unwrap()).unwrap().to_program().is_err());
+        assert!(Module::from_bytes(vec!(bytecode::CMD_PUSH, 0)).is_err());
+    }
+
+    #[test]
+    fn test_from_image() {
+        let program = Program::from_vec(vec!(ir::Jump(1), ir::Mark(1), ir::Exit));
+        let mut image = Image::from_program(&program).unwrap();
+        assert_eq!(Module::from_image(&image).unwrap().label(1), Some(2));
+        image.index_labels().unwrap();
+        assert_eq!(Module::from_image(&image).unwrap().label(1), Some(2));
+
+        image.code.insert(0, bytecode::CMD_DUP);
+        assert!(Module::from_image(&image).is_err());
+        image.index_labels().unwrap();
+        assert!(Module::from_image(&image).is_ok());
+    }
+}
@@ -0,0 +1,100 @@
+//! Writing bytecode with the offsets of instructions.
+
+#![experimental]
+
+use std::io::IoResult;
+
+use bytecode::ByteCodeWriter;
+use ir::Instruction;
+
+/// A writer which counts the bytes written, so that tools building jump tables
+/// or patching code know where each instruction landed.
+///
+/// As a `Writer`, it is also a `ByteCodeWriter`.
+///
+/// ```rust
+/// use std::io::MemWriter;
+/// use whitebase::bytecode::ByteCodeWriter;
+/// use whitebase::bytecode::offset::OffsetWriter;
+/// use whitebase::ir;
+///
+/// let mut writer = OffsetWriter::new(MemWriter::new());
+/// assert_eq!(writer.write_inst(&ir::StackPush(1)), Ok(0));
+/// assert_eq!(writer.record(|w| w.write_dup()), Ok(9));
+/// assert_eq!(writer.position(), 10);
+/// ```
+pub struct OffsetWriter<W> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Writer> OffsetWriter<W> {
+    /// Create a new `OffsetWriter` writing to the stream from offset 0.
+    pub fn new(inner: W) -> OffsetWriter<W> { OffsetWriter { inner: inner, pos: 0 } }
+
+    /// Get the offset of the next byte to be written.
+    pub fn position(&self) -> u64 { self.pos }
+
+    /// Get the underlying stream.
+    pub fn get_ref<'a>(&'a self) -> &'a W { &self.inner }
+
+    /// Unwrap the underlying stream.
+    pub fn unwrap(self) -> W { self.inner }
+
+    /// Write by the function, returning the offset where it started writing.
+    pub fn record(&mut self, f: |&mut OffsetWriter<W>| -> IoResult<()>) -> IoResult<u64> {
+        let pos = self.pos;
+        try!(f(self));
+        Ok(pos)
+    }
+
+    /// Write an instruction, returning its offset.
+    pub fn write_inst(&mut self, inst: &Instruction) -> IoResult<u64> {
+        self.record(|w| w.assemble_slice([inst.clone()]))
+    }
+
+    /// Write instructions, returning their offsets.
+    pub fn write_insts(&mut self, insts: &[Instruction]) -> IoResult<Vec<u64>> {
+        let mut offsets = Vec::with_capacity(insts.len());
+        for inst in insts.iter() {
+            offsets.push(try!(self.write_inst(inst)));
+        }
+        Ok(offsets)
+    }
+}
+
+impl<W: Writer> Writer for OffsetWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        try!(self.inner.write(buf));
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> { self.inner.flush() }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter, SeekSet};
+
+    use bytecode;
+    use bytecode::{ByteCodeReader, ByteCodeWriter};
+    use ir;
+    use super::OffsetWriter;
+
+    #[test]
+    fn test_offsets() {
+        let mut writer = OffsetWriter::new(MemWriter::new());
+        let insts = [ir::Mark(1), ir::StackDuplicate, ir::Jump(1)];
+        assert_eq!(writer.write_insts(insts), Ok(vec!(0, 9, 10)));
+        assert_eq!(writer.record(|w| { try!(w.write_push(1)); w.write_exit_status(0) }), Ok(19));
+        assert_eq!(writer.position(), 37);
+        assert_eq!(writer.get_ref().get_ref().len(), 37);
+
+        let mut reader = MemReader::new(writer.unwrap().unwrap());
+        reader.seek(10, SeekSet).unwrap();
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_JUMP, 1)));
+        reader.seek(19, SeekSet).unwrap();
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 1)));
+    }
+}
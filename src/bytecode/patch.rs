@@ -0,0 +1,126 @@
+//! In-place patching of bytecode.
+
+#![experimental]
+
+use std::io::{BufReader, BufWriter, InvalidInput, IoError, IoResult};
+
+use bytecode;
+use bytecode::{has_operand, is_opcode, ByteCodeReader};
+
+/// Replace the instruction at the offset with another, padding the rest of its bytes
+/// with "NOP", so that the offsets of the following instructions are kept.
+///
+/// # Error
+///
+/// If the offset is not the start of an instruction, the opcode is unknown, or
+/// the new instruction is longer than the old one, then this function will return `Err`.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use whitebase::bytecode;
+/// use whitebase::bytecode::{ByteCodeReader, ByteCodeWriter};
+/// use whitebase::bytecode::patch::patch;
+///
+/// let mut bcw = MemWriter::new();
+/// bcw.write_push(1).unwrap();
+/// bcw.write_putn().unwrap();
+/// let mut code = bcw.unwrap();
+/// patch(code.as_mut_slice(), 0, bytecode::CMD_DUP, 0).unwrap();
+///
+/// let mut reader = MemReader::new(code);
+/// assert_eq!(reader.read_inst(), Ok((bytecode::CMD_DUP, 0)));
+/// assert_eq!(reader.read_inst(), Ok((bytecode::CMD_NOP, 0)));
+/// assert_eq!(reader.tell(), Ok(2));
+/// ```
+pub fn patch(code: &mut [u8], offset: u64, opcode: u8, n: i64) -> IoResult<()> {
+    if !is_opcode(opcode) { return Err(invalid_patch("unknown opcode")) }
+    let old_len = try!(instruction_len(code, offset));
+    let new_len = if has_operand(opcode) { 9 } else { 1 };
+    if new_len > old_len { return Err(invalid_patch("instruction doesn't fit")) }
+    let offset = offset as uint;
+    let mut writer = BufWriter::new(code.mut_slice(offset, offset + old_len));
+    try!(writer.write_u8(opcode));
+    if has_operand(opcode) { try!(writer.write_be_i64(n)); }
+    for _ in range(new_len, old_len) {
+        try!(writer.write_u8(bytecode::CMD_NOP));
+    }
+    Ok(())
+}
+
+/// Replace the instruction at the offset with "NOP"s.
+///
+/// # Error
+///
+/// If the offset is not the start of an instruction, then this function will return `Err`.
+pub fn erase(code: &mut [u8], offset: u64) -> IoResult<()> {
+    patch(code, offset, bytecode::CMD_NOP, 0)
+}
+
+// Length of the instruction at the offset, decoding from the start to find boundaries.
+fn instruction_len(code: &[u8], offset: u64) -> IoResult<uint> {
+    let mut reader = BufReader::new(code);
+    loop {
+        let pos = try!(reader.tell());
+        if pos > offset || reader.eof() { return Err(invalid_patch("not the start of an instruction")) }
+        try!(reader.read_inst());
+        if pos == offset { return Ok((try!(reader.tell()) - pos) as uint) }
+    }
+}
+
+fn invalid_patch(detail: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid patch",
+        detail: Some(detail.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode;
+    use bytecode::{ByteCodeReader, ByteCodeWriter};
+    use machine::{Machine, QueueIo};
+    use super::{erase, patch};
+
+    fn code() -> Vec<u8> {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_push(2).unwrap();
+        bcw.write_add().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.unwrap()
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut code = code();
+        patch(code.as_mut_slice(), 9, bytecode::CMD_PUSH, 40).unwrap();
+        patch(code.as_mut_slice(), 18, bytecode::CMD_SUB, 0).unwrap();
+        assert_eq!(code.len(), 20);
+
+        let mut vm = Machine::with_io(QueueIo::new());
+        vm.set_implicit_exit(true);
+        assert!(vm.run(&mut MemReader::new(code.clone())).is_ok());
+        assert_eq!(vm.io().take_output(), "-39".to_string());
+
+        erase(code.as_mut_slice(), 0).unwrap();
+        let mut reader = MemReader::new(code);
+        for _ in range(0u, 9) {
+            assert_eq!(reader.read_inst(), Ok((bytecode::CMD_NOP, 0)));
+        }
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 40)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut code = code();
+        let original = code.clone();
+        assert!(patch(code.as_mut_slice(), 1, bytecode::CMD_DUP, 0).is_err());
+        assert!(patch(code.as_mut_slice(), 18, bytecode::CMD_PUSH, 1).is_err());
+        assert!(patch(code.as_mut_slice(), 18, 0x00, 0).is_err());
+        assert!(patch(code.as_mut_slice(), 20, bytecode::CMD_DUP, 0).is_err());
+        assert_eq!(code, original);
+    }
+}
@@ -0,0 +1,173 @@
+//! Running bytecode from streams which can't seek.
+
+#![experimental]
+
+use std::io::{EndOfFile, InvalidInput, IoError, IoResult, SeekCur, SeekEnd, SeekSet, SeekStyle};
+use std::io::standard_error;
+use std::uint;
+
+// Number of bytes read from the underlying stream at once.
+static CHUNK_SIZE: uint = 4096;
+
+/// A reader buffering everything read from a stream, so that it can seek
+/// backward as `ByteCodeReader` requires.
+///
+/// The stream is read only as far as the virtual machine reads or seeks, so a program
+/// can be run while it's still coming from a pipe or a network connection.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use std::io::util::{NullReader, NullWriter};
+/// use whitebase::bytecode::ByteCodeWriter;
+/// use whitebase::bytecode::stream::StreamReader;
+/// use whitebase::machine::Machine;
+///
+/// let mut bcw = MemWriter::new();
+/// bcw.write_push(1).unwrap();
+/// bcw.write_exit().unwrap();
+///
+/// // any `Reader`, e.g. `std::io::stdin()`
+/// let mut program = StreamReader::new(MemReader::new(bcw.unwrap()));
+/// let mut machine = Machine::new(NullReader, NullWriter);
+/// assert!(machine.run(&mut program).is_ok());
+/// ```
+pub struct StreamReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: uint,
+    eof: bool,
+}
+
+impl<R: Reader> StreamReader<R> {
+    /// Create a new `StreamReader` reading from the stream.
+    pub fn new(inner: R) -> StreamReader<R> {
+        StreamReader { inner: inner, buf: Vec::new(), pos: 0, eof: false }
+    }
+
+    /// Get the bytes read from the stream so far.
+    pub fn get_ref<'a>(&'a self) -> &'a [u8] { self.buf.as_slice() }
+
+    /// Unwrap the underlying stream.
+    pub fn unwrap(self) -> R { self.inner }
+
+    // Read from the stream once, returning false at the end of the stream.
+    fn fill_once(&mut self) -> IoResult<bool> {
+        if self.eof { return Ok(false) }
+        let mut chunk = [0u8, ..CHUNK_SIZE];
+        match self.inner.read(chunk) {
+            Ok(n) => {
+                self.buf.push_all(chunk.slice_to(n));
+                Ok(true)
+            },
+            Err(IoError { kind: EndOfFile, .. }) => {
+                self.eof = true;
+                Ok(false)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // Read from the stream until `len` bytes are buffered or the stream ends.
+    fn fill(&mut self, len: uint) -> IoResult<()> {
+        while self.buf.len() < len {
+            if !try!(self.fill_once()) { break }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Reader> Reader for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.pos >= self.buf.len() {
+            if !try!(self.fill_once()) { return Err(standard_error(EndOfFile)) }
+        }
+        let available = self.buf.slice_from(self.pos);
+        let n = if available.len() < buf.len() { available.len() } else { buf.len() };
+        for (dst, src) in buf.mut_iter().zip(available.slice_to(n).iter()) {
+            *dst = *src;
+        }
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: Reader> Seek for StreamReader<R> {
+    fn tell(&self) -> IoResult<u64> { Ok(self.pos as u64) }
+
+    fn seek(&mut self, pos: i64, style: SeekStyle) -> IoResult<()> {
+        let base = match style {
+            SeekSet => 0,
+            SeekCur => self.pos as i64,
+            SeekEnd => {
+                try!(self.fill(uint::MAX));
+                self.buf.len() as i64
+            },
+        };
+        let to = base + pos;
+        if to < 0 { return Err(standard_error(InvalidInput)) }
+        try!(self.fill(to as uint));
+        self.pos = to as uint;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufWriter, IoResult, MemReader, MemWriter, SeekCur, SeekEnd, SeekSet};
+    use std::io::util::NullReader;
+
+    use bytecode::ByteCodeWriter;
+    use machine::Machine;
+    use super::StreamReader;
+
+    // A stream giving one byte at a time, which can't seek.
+    struct Trickle(MemReader);
+
+    impl Reader for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            let Trickle(ref mut inner) = *self;
+            inner.read(buf.mut_slice_to(1))
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut reader = StreamReader::new(Trickle(MemReader::new(vec!(1, 2, 3, 4, 5))));
+        assert_eq!(reader.read_u8(), Ok(1));
+        assert_eq!(reader.get_ref(), &[1]);
+        reader.seek(3, SeekSet).unwrap();
+        assert_eq!(reader.read_u8(), Ok(4));
+        reader.seek(-4, SeekCur).unwrap();
+        assert_eq!(reader.read_u8(), Ok(1));
+        reader.seek(-1, SeekEnd).unwrap();
+        assert_eq!(reader.tell(), Ok(4));
+        assert_eq!(reader.read_u8(), Ok(5));
+        assert!(reader.read_u8().is_err());
+        assert!(reader.seek(-1, SeekSet).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        // count down from 3 with a backward jump
+        let mut bcw = MemWriter::new();
+        bcw.write_push(3).unwrap();
+        bcw.write_mark(0).unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_jumpz(1).unwrap();
+        bcw.write_jump(0).unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut buf = [0u8, ..3];
+        {
+            let mut program = StreamReader::new(Trickle(MemReader::new(bcw.unwrap())));
+            let mut machine = Machine::new(NullReader, BufWriter::new(&mut buf));
+            assert_eq!(machine.run(&mut program), Ok(0));
+        }
+        assert!(buf == [51, 50, 49]);
+    }
+}
@@ -0,0 +1,123 @@
+//! Static validation of bytecode.
+
+#![experimental]
+
+use std::collections::HashMap;
+use std::io::{EndOfFile, IoError, MemReader};
+
+use bytecode;
+use bytecode::{has_operand, is_opcode};
+
+/// A problem found by validation. Positions are byte offsets in the stream.
+#[deriving(PartialEq, Show)]
+pub enum ValidationError {
+    /// The byte at the position is not an opcode.
+    InvalidOpcode(u64, u8),
+    /// The stream ended in the operand of the instruction at the position.
+    MissingOperand(u64, u8),
+    /// The label is marked at both positions.
+    DuplicateMark(i64, u64, u64),
+    /// The jump or call at the position targets the label, which is never marked.
+    UndefinedTarget(u64, i64),
+    /// I/O error occurred.
+    ValidationIoError(IoError),
+}
+
+/// Facts about valid bytecode.
+#[deriving(PartialEq, Clone, Show)]
+pub struct Summary {
+    /// Size of the stream in bytes.
+    pub bytes: u64,
+    /// Number of instructions.
+    pub instructions: uint,
+    /// Number of labels marked.
+    pub labels: uint,
+    /// Whether any "EXIT" instruction is included.
+    pub has_exit: bool,
+}
+
+/// Check the bytecode without executing it, returning the first problem found.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use whitebase::bytecode::{validate, ByteCodeWriter, UndefinedTarget};
+///
+/// let mut bcw = MemWriter::new();
+/// bcw.write_push(1).unwrap();
+/// bcw.write_jumpz(3).unwrap();
+/// bcw.write_exit().unwrap();
+/// assert_eq!(validate(&mut MemReader::new(bcw.unwrap())), Err(UndefinedTarget(9, 3)));
+/// ```
+pub fn validate<R: Reader>(reader: &mut R) -> Result<Summary, ValidationError> {
+    let code = match reader.read_to_end() {
+        Ok(code) => code,
+        Err(e) => return Err(ValidationIoError(e)),
+    };
+    let bytes = code.len() as u64;
+    let mut reader = MemReader::new(code);
+    let mut marks: HashMap<i64, u64> = HashMap::new();
+    let mut targets: Vec<(u64, i64)> = Vec::new();
+    let mut summary = Summary { bytes: bytes, instructions: 0, labels: 0, has_exit: false };
+    loop {
+        let pos = reader.tell().unwrap();
+        let opcode = match reader.read_u8() {
+            Ok(opcode) => opcode,
+            Err(IoError { kind: EndOfFile, .. }) => break,
+            Err(e) => return Err(ValidationIoError(e)),
+        };
+        if !is_opcode(opcode) { return Err(InvalidOpcode(pos, opcode)) }
+        let n = if has_operand(opcode) {
+            match reader.read_be_i64() {
+                Ok(n) => n,
+                Err(IoError { kind: EndOfFile, .. }) => return Err(MissingOperand(pos, opcode)),
+                Err(e) => return Err(ValidationIoError(e)),
+            }
+        } else { 0 };
+        summary.instructions += 1;
+        match opcode {
+            bytecode::CMD_MARK => match marks.find_copy(&n) {
+                Some(first) => return Err(DuplicateMark(n, first, pos)),
+                None => { marks.insert(n, pos); },
+            },
+            bytecode::CMD_CALL | bytecode::CMD_JUMP | bytecode::CMD_JUMPZ | bytecode::CMD_JUMPN => targets.push((pos, n)),
+            bytecode::CMD_EXIT | bytecode::CMD_EXIT_STATUS => summary.has_exit = true,
+            _ => (),
+        }
+    }
+    for &(pos, label) in targets.iter() {
+        if !marks.contains_key(&label) { return Err(UndefinedTarget(pos, label)) }
+    }
+    summary.labels = marks.len();
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use super::{validate, DuplicateMark, InvalidOpcode, MissingOperand, Summary};
+
+    #[test]
+    fn test_validate() {
+        let mut bcw = MemWriter::new();
+        bcw.write_call(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_return().unwrap();
+        let summary = validate(&mut MemReader::new(bcw.unwrap()));
+        assert_eq!(summary, Ok(Summary { bytes: 20, instructions: 4, labels: 1, has_exit: true }));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(validate(&mut MemReader::new(vec!(bytecode::CMD_DUP, 0x00))), Err(InvalidOpcode(1, 0x00)));
+        assert_eq!(validate(&mut MemReader::new(vec!(bytecode::CMD_PUSH, 0, 0))), Err(MissingOperand(0, bytecode::CMD_PUSH)));
+
+        let mut bcw = MemWriter::new();
+        bcw.write_mark(1).unwrap();
+        bcw.write_mark(1).unwrap();
+        assert_eq!(validate(&mut MemReader::new(bcw.unwrap())), Err(DuplicateMark(1, 0, 9)));
+    }
+}
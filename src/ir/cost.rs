@@ -0,0 +1,169 @@
+//! Static cost estimation of programs.
+
+#![experimental]
+
+use bytecode;
+use ir;
+use ir::{Instruction, Program};
+use machine::CostTable;
+
+/// Estimated cost of a basic block.
+#[deriving(PartialEq, Clone, Show)]
+pub struct BlockCost {
+    /// Index of the first instruction.
+    pub start: uint,
+    /// Number of instructions.
+    pub len: uint,
+    /// Cost of executing the block once.
+    pub cost: u64,
+}
+
+/// Static size and estimated cost of a program.
+#[deriving(PartialEq, Clone, Show)]
+pub struct CostEstimate {
+    /// Number of instructions.
+    pub instructions: uint,
+    /// Size of the bytecodes in bytes.
+    pub bytecode_size: uint,
+    /// Size of the Whitespace source in characters.
+    pub whitespace_size: uint,
+    /// Basic blocks in order. A block starts at "MARK" or after flow control.
+    pub blocks: Vec<BlockCost>,
+}
+
+/// Estimate a program with the weighted cost model of the virtual machine.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::cost::estimate;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(1), ir::PutNumber, ir::Exit));
+/// let estimate = estimate(&program);
+/// assert_eq!(estimate.whitespace_size, 12);
+/// assert_eq!(estimate.blocks[0].cost, 8);
+/// ```
+pub fn estimate(program: &Program) -> CostEstimate {
+    estimate_with(program, &CostTable::weighted())
+}
+
+/// Estimate a program with the cost model.
+pub fn estimate_with(program: &Program, table: &CostTable) -> CostEstimate {
+    let mut estimate = CostEstimate {
+        instructions: program.len(),
+        bytecode_size: 0,
+        whitespace_size: 0,
+        blocks: Vec::new(),
+    };
+    let mut block = BlockCost { start: 0, len: 0, cost: 0 };
+    for (i, inst) in program.iter().enumerate() {
+        estimate.bytecode_size += bytecode_size(inst);
+        estimate.whitespace_size += whitespace_size(inst);
+        match *inst {
+            ir::Mark(_) if block.len > 0 => {
+                estimate.blocks.push(block);
+                block = BlockCost { start: i, len: 0, cost: 0 };
+            },
+            _ => (),
+        }
+        block.len += 1;
+        block.cost += table.cost(bytecode::opcode(inst));
+        match *inst {
+            ir::Call(_) | ir::Jump(_) | ir::JumpIfZero(_) | ir::JumpIfNegative(_) |
+            ir::Return | ir::Exit | ir::ExitWith(_) => {
+                estimate.blocks.push(block);
+                block = BlockCost { start: i + 1, len: 0, cost: 0 };
+            },
+            _ => (),
+        }
+    }
+    if block.len > 0 { estimate.blocks.push(block); }
+    estimate
+}
+
+/// Size of an instruction as bytecodes in bytes.
+pub fn bytecode_size(inst: &Instruction) -> uint {
+    if operand(inst).is_some() { 9 } else { 1 }
+}
+
+/// Size of an instruction as Whitespace source in characters, 0 for those
+/// which have no Whitespace encoding.
+pub fn whitespace_size(inst: &Instruction) -> uint {
+    let command = match *inst {
+        ir::Nop | ir::ExitWith(_) | ir::Extension(..) => return 0,
+        ir::StackPush(_) | ir::StackDuplicate | ir::StackSwap | ir::StackDiscard => 2,
+        ir::StackCopy(_) | ir::StackSlide(_) | ir::HeapStore | ir::HeapRetrieve |
+        ir::Mark(_) | ir::Call(_) | ir::Jump(_) | ir::JumpIfZero(_) | ir::JumpIfNegative(_) |
+        ir::Return | ir::Exit => 3,
+        _ => 4,
+    };
+    match operand(inst) {
+        // sign, binary digits and terminator
+        Some(n) => command + 2 + binary_digits(n),
+        None => command,
+    }
+}
+
+fn operand(inst: &Instruction) -> Option<i64> {
+    match *inst {
+        ir::StackPush(n) | ir::StackCopy(n) | ir::StackSlide(n) |
+        ir::Mark(n) | ir::Call(n) | ir::Jump(n) | ir::JumpIfZero(n) | ir::JumpIfNegative(n) |
+        ir::ExitWith(n) | ir::Extension(_, n) => Some(n),
+        _ => None,
+    }
+}
+
+fn binary_digits(n: i64) -> uint {
+    let mut m = if n < 0 { (-(n + 1)) as u64 + 1 } else { n as u64 };
+    let mut digits = 1;
+    while m > 1 {
+        m >>= 1;
+        digits += 1;
+    }
+    digits
+}
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+
+    use ir;
+    use ir::Program;
+    use super::{estimate, whitespace_size, BlockCost};
+
+    #[test]
+    fn test_estimate() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(5),
+            ir::Mark(1),
+            ir::StackPush(1),
+            ir::Subtraction,
+            ir::StackDuplicate,
+            ir::JumpIfZero(2),
+            ir::Jump(1),
+            ir::Mark(2),
+            ir::Exit,
+        ));
+        let estimate = estimate(&program);
+        assert_eq!(estimate.instructions, 9);
+        assert_eq!(estimate.bytecode_size, 9 * 6 + 3);
+        assert_eq!(estimate.blocks, vec!(
+            BlockCost { start: 0, len: 1, cost: 1 },
+            BlockCost { start: 1, len: 5, cost: 8 },
+            BlockCost { start: 6, len: 1, cost: 2 },
+            BlockCost { start: 7, len: 2, cost: 4 },
+        ));
+    }
+
+    #[test]
+    fn test_whitespace_size() {
+        assert_eq!(whitespace_size(&ir::StackPush(0)), 5);
+        assert_eq!(whitespace_size(&ir::StackPush(-5)), 7);
+        assert_eq!(whitespace_size(&ir::StackPush(i64::MIN)), 68);
+        assert_eq!(whitespace_size(&ir::Mark(2)), 7);
+        assert_eq!(whitespace_size(&ir::Addition), 4);
+        assert_eq!(whitespace_size(&ir::Return), 3);
+        assert_eq!(whitespace_size(&ir::ExitWith(1)), 0);
+        assert_eq!(whitespace_size(&ir::Extension(0xF1, 1)), 0);
+    }
+}
@@ -0,0 +1,118 @@
+//! Instruction-level difference of programs.
+
+#![experimental]
+
+use ir::{Instruction, Program};
+
+/// An edit turning one program into another.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Edit {
+    /// The instructions at the positions of both programs are the same.
+    Equal(uint, uint),
+    /// The instruction at the position of the old program is removed.
+    Deleted(uint, Instruction),
+    /// The instruction at the position of the new program is added.
+    Inserted(uint, Instruction),
+}
+
+/// Return the shortest list of edits turning `a` into `b`, in order of positions.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::diff::{diff, Equal, Deleted, Inserted};
+///
+/// let a = Program::from_vec(vec!(ir::StackPush(1), ir::PutNumber, ir::Exit));
+/// let b = Program::from_vec(vec!(ir::StackPush(2), ir::PutNumber, ir::Exit));
+/// assert_eq!(diff(&a, &b), vec!(
+///     Deleted(0, ir::StackPush(1)),
+///     Inserted(0, ir::StackPush(2)),
+///     Equal(1, 1),
+///     Equal(2, 2),
+/// ));
+/// ```
+pub fn diff(a: &Program, b: &Program) -> Vec<Edit> {
+    let (a, b) = (a.as_slice(), b.as_slice());
+    diff_slices(a, b).move_iter().map(|edit| match edit {
+        (Some(i), Some(j)) => Equal(i, j),
+        (Some(i), None) => Deleted(i, a[i].clone()),
+        (None, Some(j)) => Inserted(j, b[j].clone()),
+        (None, None) => unreachable!(),
+    }).collect()
+}
+
+/// Return the shortest list of edits turning `a` into `b` as pairs of positions,
+/// with `None` for the slice an element is missing from.
+pub fn diff_slices<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(Option<uint>, Option<uint>)> {
+    let (n, m) = (a.len(), b.len());
+    // lcs[i * (m + 1) + j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = Vec::from_elem((n + 1) * (m + 1), 0u);
+    for i in range(0, n).rev() {
+        for j in range(0, m).rev() {
+            let len = if a[i] == b[j] {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                let (down, right) = (lcs[(i + 1) * (m + 1) + j], lcs[i * (m + 1) + j + 1]);
+                if down >= right { down } else { right }
+            };
+            lcs.as_mut_slice()[i * (m + 1) + j] = len;
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0u, 0u);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            edits.push((Some(i), Some(j)));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+            edits.push((Some(i), None));
+            i += 1;
+        } else {
+            edits.push((None, Some(j)));
+            j += 1;
+        }
+    }
+    edits
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::{diff, Equal, Deleted, Inserted};
+
+    #[test]
+    fn test_diff() {
+        let a = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::StackPush(2),
+            ir::Addition,
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        let b = Program::from_vec(vec!(
+            ir::StackPush(3),
+            ir::PutNumber,
+            ir::Exit,
+            ir::Return,
+        ));
+        assert_eq!(diff(&a, &b), vec!(
+            Deleted(0, ir::StackPush(1)),
+            Deleted(1, ir::StackPush(2)),
+            Deleted(2, ir::Addition),
+            Inserted(0, ir::StackPush(3)),
+            Equal(3, 1),
+            Equal(4, 2),
+            Inserted(3, ir::Return),
+        ));
+    }
+
+    #[test]
+    fn test_diff_same() {
+        let a = Program::from_vec(vec!(ir::StackPush(1), ir::Exit));
+        assert_eq!(diff(&a, &a.clone()), vec!(Equal(0, 0), Equal(1, 1)));
+        assert_eq!(diff(&Program::new(), &Program::new()), vec!());
+    }
+}
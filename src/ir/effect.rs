@@ -0,0 +1,78 @@
+//! Stack effects of instructions.
+
+#![experimental]
+
+use ir;
+use ir::Instruction;
+
+/// How an instruction uses the stack.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct StackEffect {
+    /// Number of values which must be on the stack.
+    pub requires: uint,
+    /// Number of values removed.
+    pub pops: uint,
+    /// Number of values added after removing.
+    pub pushes: uint,
+}
+
+impl StackEffect {
+    /// Change of the stack depth, when at least `requires` values are on the stack.
+    pub fn delta(&self) -> i64 { self.pushes as i64 - self.pops as i64 }
+
+    /// Stack depth after the instruction runs with `depth` values.
+    pub fn apply(&self, depth: uint) -> uint {
+        (if depth > self.pops { depth - self.pops } else { 0 }) + self.pushes
+    }
+}
+
+/// Return the stack effect of an instruction.
+///
+/// "COPY n" requires n + 1 values and "SLIDE n" removes n values under the top,
+/// which is counted as popping n + 1 values and pushing 1. Negative operands fail
+/// at runtime and are treated as 0. Extension instructions, whose effects are
+/// unknown, are assumed to leave the stack as it is.
+///
+/// ```rust
+/// use whitebase::ir;
+///
+/// let effect = ir::stack_effect(&ir::StackSlide(2));
+/// assert_eq!((effect.requires, effect.delta()), (2, -2));
+/// ```
+pub fn stack_effect(inst: &Instruction) -> StackEffect {
+    let (requires, pops, pushes) = match *inst {
+        ir::StackPush(_)                              => (0, 0, 1),
+        ir::StackDuplicate                            => (1, 0, 1),
+        ir::StackCopy(n) if n >= 0                    => (n as uint + 1, 0, 1),
+        ir::StackCopy(_)                              => (1, 0, 1),
+        ir::StackSwap                                 => (2, 2, 2),
+        ir::StackSlide(n) if n > 0                    => (n as uint, n as uint + 1, 1),
+        ir::StackSlide(_)                             => (1, 1, 1),
+        ir::Addition | ir::Subtraction | ir::Multiplication |
+        ir::Division | ir::Modulo                     => (2, 2, 1),
+        ir::HeapStore                                 => (2, 2, 0),
+        ir::HeapRetrieve                              => (1, 1, 1),
+        ir::StackDiscard | ir::JumpIfZero(_) | ir::JumpIfNegative(_) |
+        ir::PutCharactor | ir::PutNumber |
+        ir::GetCharactor | ir::GetNumber              => (1, 1, 0),
+        ir::Mark(_) | ir::Call(_) | ir::Jump(_) | ir::Return | ir::Exit |
+        ir::ExitWith(_) | ir::Extension(..) | ir::Nop => (0, 0, 0),
+    };
+    StackEffect { requires: requires, pops: pops, pushes: pushes }
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use super::stack_effect;
+
+    #[test]
+    fn test_stack_effect() {
+        assert_eq!(stack_effect(&ir::StackCopy(3)).requires, 4);
+        assert_eq!(stack_effect(&ir::StackSwap).delta(), 0);
+        assert_eq!(stack_effect(&ir::Addition).delta(), -1);
+        assert_eq!(stack_effect(&ir::HeapStore).apply(5), 3);
+        assert_eq!(stack_effect(&ir::StackSlide(3)).apply(3), 1);
+        assert_eq!(stack_effect(&ir::Exit).requires, 0);
+    }
+}
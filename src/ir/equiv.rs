@@ -0,0 +1,103 @@
+//! Testing programs for the same behavior.
+
+#![experimental]
+
+use std::os::num_cpus;
+
+use ir::Program;
+use machine::{Builder, CostTable};
+use machine::batch::{run_all_with, Output};
+
+/// Gas budget of each run by `check`, so that a looping program can't hang the check.
+pub static DEFAULT_GAS: u64 = 1000000;
+
+/// Runs of two programs on an input with different results.
+#[deriving(PartialEq, Show)]
+pub struct Mismatch {
+    /// Index of the input.
+    pub input: uint,
+    /// Run of the first program.
+    pub left: Output,
+    /// Run of the second program.
+    pub right: Output,
+}
+
+/// Run both programs on each input in isolated machines and compare their outputs
+/// and exit status, returning the inputs on which they differ.
+///
+/// Each run is limited by `DEFAULT_GAS` with every instruction costing 1.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::equiv;
+/// use whitebase::ir::opt::fold_constants;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(2), ir::StackPush(3), ir::Multiplication, ir::PutNumber, ir::Exit));
+/// assert!(equiv::check(&program, &fold_constants(&program), &[vec!()]).is_ok());
+/// ```
+pub fn check(a: &Program, b: &Program, inputs: &[Vec<u8>]) -> Result<(), Vec<Mismatch>> {
+    check_with(Builder::new().gas(DEFAULT_GAS, CostTable::new()), a, b, inputs)
+}
+
+/// Like `check`, running on machines built by `builder`.
+pub fn check_with(builder: Builder, a: &Program, b: &Program, inputs: &[Vec<u8>]) -> Result<(), Vec<Mismatch>> {
+    let run = |program: &Program| {
+        let bytecode = program.to_bytecode().unwrap();
+        run_all_with(builder.clone(), num_cpus(), bytecode, inputs.to_vec())
+    };
+    let (left, right) = (run(a), run(b));
+    let mismatches: Vec<Mismatch> = left.move_iter().zip(right.move_iter()).enumerate()
+        .filter(|&(_, (ref l, ref r))| l != r)
+        .map(|(i, (l, r))| Mismatch { input: i, left: l, right: r })
+        .collect();
+    if mismatches.is_empty() { Ok(()) } else { Err(mismatches) }
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use machine::OutOfGas;
+    use super::check;
+
+    #[test]
+    fn test_check() {
+        let double = Program::from_vec(vec!(
+            ir::StackPush(0),
+            ir::GetNumber,
+            ir::StackPush(0),
+            ir::HeapRetrieve,
+            ir::StackPush(2),
+            ir::Multiplication,
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        let square = Program::from_vec(vec!(
+            ir::StackPush(0),
+            ir::GetNumber,
+            ir::StackPush(0),
+            ir::HeapRetrieve,
+            ir::StackDuplicate,
+            ir::Multiplication,
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        let inputs = [b"0\n".to_vec(), b"2\n".to_vec(), b"3\n".to_vec()];
+        assert!(check(&double, &double, inputs.as_slice()).is_ok());
+
+        let mismatches = check(&double, &square, inputs.as_slice()).unwrap_err();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].input, 2);
+        assert_eq!(mismatches[0].left.output, b"6".to_vec());
+        assert_eq!(mismatches[0].right.output, b"9".to_vec());
+    }
+
+    #[test]
+    fn test_check_loop() {
+        let looping = Program::from_vec(vec!(ir::Mark(1), ir::Jump(1)));
+        let exiting = Program::from_vec(vec!(ir::Exit));
+        let mismatches = check(&looping, &exiting, &[vec!()]).unwrap_err();
+        assert_eq!(mismatches[0].left.result, Err(OutOfGas));
+    }
+}
@@ -0,0 +1,165 @@
+//! Evaluation of straight-line instructions.
+
+#![experimental]
+
+use std::collections::HashMap;
+
+use ir;
+use ir::Instruction;
+
+/// An error of evaluation, at the index of the failed instruction.
+#[deriving(PartialEq, Clone, Show)]
+pub enum EvalError {
+    /// The instruction popped the empty stack.
+    StackUnderflow(uint),
+    /// Division or modulo by zero.
+    ZeroDivision(uint),
+    /// The result of arithmetic overflowed.
+    Overflow(uint),
+    /// The instruction does flow control or I/O.
+    Unsupported(uint),
+}
+
+/// An evaluator of straight-line instructions without I/O, keeping
+/// the stack and heap between evaluations.
+///
+/// Arithmetic is checked, so an overflow is an error rather than wrapping
+/// as on the virtual machine.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::eval::Evaluator;
+///
+/// let mut evaluator = Evaluator::new();
+/// evaluator.eval(&[ir::StackPush(1), ir::StackPush(6), ir::StackPush(7), ir::Multiplication]).unwrap();
+/// evaluator.eval(&[ir::HeapStore, ir::StackPush(1), ir::HeapRetrieve]).unwrap();
+/// assert_eq!(evaluator.stack(), &[42]);
+/// ```
+pub struct Evaluator {
+    stack: Vec<i64>,
+    heap: HashMap<i64, i64>,
+}
+
+impl Evaluator {
+    /// Create a new `Evaluator` with the empty stack and heap.
+    pub fn new() -> Evaluator {
+        Evaluator { stack: Vec::new(), heap: HashMap::new() }
+    }
+
+    /// Get the stack, the top at the end.
+    pub fn stack(&self) -> &[i64] { self.stack.as_slice() }
+
+    /// Get a value in the heap, 0 if never stored.
+    pub fn heap_get(&self, addr: i64) -> i64 { self.heap.find_copy(&addr).unwrap_or(0) }
+
+    /// Evaluate instructions. On error, the stack and heap are left as they were
+    /// just before the failed instruction.
+    pub fn eval(&mut self, insts: &[Instruction]) -> Result<(), EvalError> {
+        for (i, inst) in insts.iter().enumerate() {
+            try!(self.step(i, inst));
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, i: uint, inst: &Instruction) -> Result<(), EvalError> {
+        let len = self.stack.len();
+        // index of the nth value from the top
+        let pick = |n: i64| if n >= 0 && (n as uint) < len { Ok(len - 1 - n as uint) } else { Err(StackUnderflow(i)) };
+        match *inst {
+            ir::StackPush(n) => self.stack.push(n),
+            ir::StackDuplicate => { let n = self.stack[try!(pick(0))]; self.stack.push(n) },
+            ir::StackCopy(n) => { let n = self.stack[try!(pick(n))]; self.stack.push(n) },
+            ir::StackSwap => { try!(pick(1)); self.stack.as_mut_slice().swap(len - 1, len - 2) },
+            ir::StackDiscard => { try!(pick(0)); self.stack.pop(); },
+            ir::StackSlide(n) => {
+                // as on the virtual machine, n values are required including the top
+                if n < 0 || n as uint > len || len == 0 { return Err(StackUnderflow(i)) }
+                let top = self.stack.pop().unwrap();
+                let rest = if len > n as uint { len - 1 - n as uint } else { 0 };
+                self.stack.truncate(rest);
+                self.stack.push(top);
+            },
+            ir::Addition | ir::Subtraction | ir::Multiplication | ir::Division | ir::Modulo => {
+                try!(pick(1));
+                let (y, x) = (self.stack[len - 2], self.stack[len - 1]);
+                let n = match *inst {
+                    ir::Addition => y.checked_add(&x),
+                    ir::Subtraction => y.checked_sub(&x),
+                    ir::Multiplication => y.checked_mul(&x),
+                    _ if x == 0 => return Err(ZeroDivision(i)),
+                    ir::Division => y.checked_div(&x),
+                    _ if x == -1 => Some(0),
+                    _ => Some(y % x),
+                };
+                match n {
+                    Some(n) => { self.stack.truncate(len - 2); self.stack.push(n) },
+                    None => return Err(Overflow(i)),
+                }
+            },
+            ir::HeapStore => {
+                try!(pick(1));
+                let (addr, val) = (self.stack[len - 2], self.stack[len - 1]);
+                self.stack.truncate(len - 2);
+                self.heap.insert(addr, val);
+            },
+            ir::HeapRetrieve => {
+                try!(pick(0));
+                let addr = self.stack.pop().unwrap();
+                let val = self.heap_get(addr);
+                self.stack.push(val);
+            },
+            ir::Nop => (),
+            _ => return Err(Unsupported(i)),
+        }
+        Ok(())
+    }
+}
+
+/// Evaluate instructions on the empty stack and heap, returning the stack.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::eval::{eval, ZeroDivision};
+///
+/// assert_eq!(eval(&[ir::StackPush(7), ir::StackPush(2), ir::Modulo]), Ok(vec!(1)));
+/// assert_eq!(eval(&[ir::StackPush(7), ir::StackPush(0), ir::Division]), Err(ZeroDivision(2)));
+/// ```
+pub fn eval(insts: &[Instruction]) -> Result<Vec<i64>, EvalError> {
+    let mut evaluator = Evaluator::new();
+    try!(evaluator.eval(insts));
+    Ok(evaluator.stack)
+}
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+
+    use ir;
+    use super::{eval, Overflow, StackUnderflow, Unsupported};
+
+    #[test]
+    fn test_stack() {
+        let insts = [
+            ir::StackPush(1),
+            ir::StackPush(2),
+            ir::StackPush(3),
+            ir::StackCopy(2),
+            ir::StackSwap,
+            ir::StackSlide(2),
+            ir::StackDuplicate,
+            ir::Nop,
+        ];
+        assert_eq!(eval(insts), Ok(vec!(1, 3, 3)));
+        assert_eq!(eval(&[ir::StackPush(1), ir::StackCopy(1)]), Err(StackUnderflow(1)));
+        assert_eq!(eval(&[ir::StackPush(1), ir::StackSlide(1)]), Ok(vec!(1)));
+        assert_eq!(eval(&[ir::StackPush(1), ir::StackSlide(2)]), Err(StackUnderflow(1)));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval(&[ir::StackPush(i64::MAX), ir::StackPush(1), ir::Addition]), Err(Overflow(2)));
+        assert_eq!(eval(&[ir::StackPush(i64::MIN), ir::StackPush(-1), ir::Modulo]), Ok(vec!(0)));
+        assert_eq!(eval(&[ir::StackPush(1), ir::PutNumber]), Err(Unsupported(1)));
+        assert_eq!(eval(&[ir::Addition]), Err(StackUnderflow(0)));
+    }
+}
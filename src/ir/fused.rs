@@ -0,0 +1,156 @@
+//! Superinstructions executed natively by the virtual machine.
+
+#![experimental]
+
+use std::io::IoResult;
+
+use bytecode::ByteCodeWriter;
+use ir;
+use ir::{Instruction, Program};
+
+/// An instruction which may fuse a sequence of core instructions.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub enum FusedInstruction {
+    /// A core instruction.
+    Plain(Instruction),
+    /// "PUSH" and "ADD".
+    PushAdd(i64),
+    /// "PUSH" and "RETRIEVE".
+    RetrieveAt(i64),
+    /// "PUSH" of an address, "PUSH" of a value and "STORE".
+    StoreConst(i64, i64),
+}
+
+/// Fuse sequences of core instructions into superinstructions.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::fused::{fuse, lower, Plain, PushAdd};
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(1), ir::Addition, ir::PutNumber));
+/// let fused = fuse(&program);
+/// assert_eq!(fused, vec!(PushAdd(1), Plain(ir::PutNumber)));
+/// assert_eq!(lower(fused.as_slice()), program);
+/// ```
+pub fn fuse(program: &Program) -> Vec<FusedInstruction> {
+    let insts = program.as_slice();
+    let mut out = Vec::with_capacity(insts.len());
+    let mut i = 0;
+    while i < insts.len() {
+        let fused = match insts.slice_from(i) {
+            [ir::StackPush(a), ir::StackPush(v), ir::HeapStore, ..] => Some((StoreConst(a, v), 3)),
+            [ir::StackPush(n), ir::Addition, ..]     => Some((PushAdd(n), 2)),
+            [ir::StackPush(n), ir::HeapRetrieve, ..] => Some((RetrieveAt(n), 2)),
+            _ => None,
+        };
+        match fused {
+            Some((inst, len)) => { out.push(inst); i += len; },
+            None => { out.push(Plain(insts[i].clone())); i += 1; },
+        }
+    }
+    out
+}
+
+/// Expand superinstructions into core instructions.
+pub fn lower(insts: &[FusedInstruction]) -> Program {
+    let mut program = Program::new();
+    for inst in insts.iter() {
+        match *inst {
+            Plain(ref inst) => program.push(inst.clone()),
+            PushAdd(n) => {
+                program.push(ir::StackPush(n));
+                program.push(ir::Addition);
+            },
+            RetrieveAt(n) => {
+                program.push(ir::StackPush(n));
+                program.push(ir::HeapRetrieve);
+            },
+            StoreConst(a, v) => {
+                program.push(ir::StackPush(a));
+                program.push(ir::StackPush(v));
+                program.push(ir::HeapStore);
+            },
+        }
+    }
+    program
+}
+
+/// Write bytecodes with superinstructions, which only the virtual machine can execute.
+pub fn write_native<W: ByteCodeWriter>(insts: &[FusedInstruction], writer: &mut W) -> IoResult<()> {
+    for inst in insts.iter() {
+        try!(match *inst {
+            Plain(ref inst)  => writer.assemble_slice([inst.clone()]),
+            PushAdd(n)       => writer.write_push_add(n),
+            RetrieveAt(n)    => writer.write_retrieve_at(n),
+            StoreConst(a, v) => writer.write_push(a).and_then(|()| writer.write_store_const(v)),
+        });
+    }
+    Ok(())
+}
+
+/// Write standard bytecodes, lowering superinstructions.
+pub fn write_standard<W: ByteCodeWriter>(insts: &[FusedInstruction], writer: &mut W) -> IoResult<()> {
+    lower(insts).write_bytecode(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode;
+    use bytecode::ByteCodeReader;
+    use ir;
+    use ir::Program;
+    use super::{fuse, lower, write_native, write_standard, Plain, PushAdd, RetrieveAt, StoreConst};
+
+    #[test]
+    fn test_fuse() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::StackPush(2),
+            ir::Addition,
+            ir::StackPush(3),
+            ir::HeapRetrieve,
+            ir::StackPush(4),
+            ir::StackPush(5),
+            ir::StackPush(6),
+            ir::HeapStore,
+            ir::Exit,
+        ));
+        let fused = fuse(&program);
+        assert_eq!(fused, vec!(
+            Plain(ir::StackPush(1)),
+            PushAdd(2),
+            RetrieveAt(3),
+            Plain(ir::StackPush(4)),
+            StoreConst(5, 6),
+            Plain(ir::Exit),
+        ));
+        assert_eq!(lower(fused.as_slice()), program);
+    }
+
+    #[test]
+    fn test_write() {
+        let insts = vec!(PushAdd(2), RetrieveAt(3), StoreConst(4, 5), Plain(ir::Exit));
+
+        let mut writer = MemWriter::new();
+        write_native(insts.as_slice(), &mut writer).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH_ADD, 2)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_RETRIEVE_AT, 3)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 4)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_STORE_CONST, 5)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_EXIT, 0)));
+
+        let mut writer = MemWriter::new();
+        write_standard(insts.as_slice(), &mut writer).unwrap();
+        let decoded = Program::from_bytecode(&mut MemReader::new(writer.unwrap())).unwrap();
+        assert_eq!(decoded, lower(insts.as_slice()));
+
+        let mut writer = MemWriter::new();
+        write_native(insts.as_slice(), &mut writer).unwrap();
+        let decoded = Program::from_bytecode(&mut MemReader::new(writer.unwrap())).unwrap();
+        assert_eq!(decoded, lower(insts.as_slice()));
+    }
+}
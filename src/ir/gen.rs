@@ -0,0 +1,179 @@
+//! Random generation of programs for fuzzing and property tests.
+
+#![experimental]
+
+use std::cmp::min;
+use std::collections::TreeMap;
+use std::rand::Rng;
+
+use ir;
+use ir::{Instruction, Program};
+
+/// Generate a random program of about `size` instructions, ending with "EXIT".
+///
+/// Jumps only go forward to labels marked exactly once, so the program always terminates,
+/// and every path keeps enough values on the stack, so it passes `verify::verify`.
+/// Divisors are pushed right before "DIV" and "MOD" as positive values, and "PUTC"
+/// outputs printable ASCII. Input instructions are not generated.
+///
+/// ```rust
+/// use std::rand::task_rng;
+/// use whitebase::ir::gen::random_program;
+/// use whitebase::ir::verify::verify;
+///
+/// let program = random_program(&mut task_rng(), 30);
+/// assert_eq!(verify(&program), Ok(()));
+/// ```
+pub fn random_program<R: Rng>(rng: &mut R, size: uint) -> Program {
+    let mut program = Program::new();
+    let mut depth = 0u;
+    // labels jumped to but not marked yet, with the smallest stack depth at the jumps
+    let mut pending: TreeMap<i64, uint> = TreeMap::new();
+    let mut next_label = 0i64;
+    while program.len() < size {
+        let literal = rng.gen_range(-256i64, 256);
+        match rng.gen_range(0u, 16) {
+            0 | 1 => { program.push(ir::StackPush(literal)); depth += 1; },
+            2 if depth >= 1 => { program.push(ir::StackDuplicate); depth += 1; },
+            3 if depth >= 1 => { program.push(ir::StackCopy(rng.gen_range(0, depth) as i64)); depth += 1; },
+            4 if depth >= 2 => program.push(ir::StackSwap),
+            5 if depth >= 1 => { program.push(ir::StackDiscard); depth -= 1; },
+            6 if depth >= 1 => {
+                let n = rng.gen_range(0, depth);
+                program.push(ir::StackSlide(n as i64));
+                depth -= n;
+            },
+            7 if depth >= 2 => {
+                let op = *rng.choose(&[ir::Addition, ir::Subtraction, ir::Multiplication]).unwrap();
+                program.push(op);
+                depth -= 1;
+            },
+            8 if depth >= 1 => {
+                program.push(ir::StackPush(literal.abs() + 1));
+                program.push(if rng.gen() { ir::Division } else { ir::Modulo });
+            },
+            9 if depth >= 2 => { program.push(ir::HeapStore); depth -= 2; },
+            10 if depth >= 1 => program.push(ir::HeapRetrieve),
+            11 => {
+                program.push(ir::StackPush(rng.gen_range(32, 127)));
+                program.push(ir::PutCharactor);
+            },
+            12 if depth >= 1 => { program.push(ir::PutNumber); depth -= 1; },
+            13 if depth >= 1 => {
+                depth -= 1;
+                let label = pick_label(rng, &pending, &mut next_label);
+                jump_to(&mut pending, label, depth);
+                program.push(if rng.gen() { ir::JumpIfZero(label) } else { ir::JumpIfNegative(label) });
+            },
+            14 => {
+                let label = pick_label(rng, &pending, &mut next_label);
+                jump_to(&mut pending, label, depth);
+                program.push(ir::Jump(label));
+            },
+            15 if !pending.is_empty() => {
+                let labels: Vec<i64> = pending.keys().map(|l| *l).collect();
+                let label = *rng.choose(labels.as_slice()).unwrap();
+                depth = min(depth, pending.pop(&label).unwrap());
+                program.push(ir::Mark(label));
+            },
+            _ => (),
+        }
+    }
+    for (label, _) in pending.move_iter() {
+        program.push(ir::Mark(label));
+    }
+    program.push(ir::Exit);
+    program
+}
+
+fn pick_label<R: Rng>(rng: &mut R, pending: &TreeMap<i64, uint>, next_label: &mut i64) -> i64 {
+    if pending.is_empty() || rng.gen_weighted_bool(2) {
+        *next_label += 1;
+        *next_label - 1
+    } else {
+        let labels: Vec<i64> = pending.keys().map(|l| *l).collect();
+        *rng.choose(labels.as_slice()).unwrap()
+    }
+}
+
+fn jump_to(pending: &mut TreeMap<i64, uint>, label: i64, depth: uint) {
+    let known = match pending.find(&label) { Some(d) => *d, None => depth };
+    pending.insert(label, min(known, depth));
+}
+
+/// Generate a random instruction with a random operand, which may be invalid in a program.
+pub fn random_instruction<R: Rng>(rng: &mut R) -> Instruction {
+    let n = rng.gen_range(-256i64, 256);
+    match rng.gen_range(0u, 24) {
+        0  => ir::StackPush(n),
+        1  => ir::StackDuplicate,
+        2  => ir::StackCopy(n),
+        3  => ir::StackSwap,
+        4  => ir::StackDiscard,
+        5  => ir::StackSlide(n),
+        6  => ir::Addition,
+        7  => ir::Subtraction,
+        8  => ir::Multiplication,
+        9  => ir::Division,
+        10 => ir::Modulo,
+        11 => ir::HeapStore,
+        12 => ir::HeapRetrieve,
+        13 => ir::Mark(n),
+        14 => ir::Call(n),
+        15 => ir::Jump(n),
+        16 => ir::JumpIfZero(n),
+        17 => ir::JumpIfNegative(n),
+        18 => ir::Return,
+        19 => ir::Exit,
+        20 => ir::PutCharactor,
+        21 => ir::PutNumber,
+        22 => ir::GetCharactor,
+        _  => ir::GetNumber,
+    }
+}
+
+#[cfg(feature = "quickcheck")]
+mod arbitrary {
+    use quickcheck::{Arbitrary, Gen};
+
+    use ir::{Instruction, Program};
+    use super::{random_instruction, random_program};
+
+    impl Arbitrary for Instruction {
+        fn arbitrary<G: Gen>(g: &mut G) -> Instruction { random_instruction(g) }
+    }
+
+    impl Arbitrary for Program {
+        fn arbitrary<G: Gen>(g: &mut G) -> Program {
+            let size = g.size();
+            random_program(g, size)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemReader;
+    use std::rand::{SeedableRng, StdRng};
+
+    use ir::Program;
+    use ir::verify::verify;
+    use machine::{Machine, QueueIo};
+    use super::random_program;
+
+    #[test]
+    fn test_random_program() {
+        let seed: &[uint] = &[1, 2, 3, 4];
+        let mut rng: StdRng = SeedableRng::from_seed(seed);
+        for _ in range(0u, 50) {
+            let program = random_program(&mut rng, 40);
+            assert!(program.len() >= 40);
+            assert_eq!(verify(&program), Ok(()));
+
+            let bytes = program.to_bytecode().unwrap();
+            assert_eq!(Program::from_bytecode(&mut MemReader::new(bytes.clone())).unwrap(), program);
+            let mut vm = Machine::with_io(QueueIo::new());
+            assert_eq!(vm.run(&mut MemReader::new(bytes)), Ok(0));
+        }
+    }
+}
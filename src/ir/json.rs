@@ -0,0 +1,175 @@
+//! JSON form of programs.
+//!
+//! A program is an object with the format version, the metadata of string pairs,
+//! and the instructions as a list of `[mnemonic]` or `[mnemonic, operand]`,
+//! using the mnemonics of `ir::text`.
+//!
+//! ```text
+//! {"version":1,"metadata":{"source":"hello.ws"},"instructions":[["push",42],["putn"],["exit"]]}
+//! ```
+
+#![experimental]
+
+use std::collections::TreeMap;
+use std::io::{InvalidInput, IoError, IoResult};
+use serialize::json;
+use serialize::json::Json;
+
+use ir::Program;
+use ir::text::{instruction, mnemonic};
+
+/// Version of the JSON form written by this module.
+pub static VERSION: u64 = 1;
+
+/// Metadata attached to a program.
+pub type Metadata = TreeMap<String, String>;
+
+/// Convert a program and its metadata to JSON.
+pub fn to_json(program: &Program, metadata: &Metadata) -> Json {
+    let insts = program.iter().map(|inst| {
+        let (name, arg) = mnemonic(inst);
+        let mut list = vec!(json::String(name.to_string()));
+        match arg {
+            Some(n) => list.push(json::I64(n)),
+            None => (),
+        }
+        json::List(list)
+    }).collect();
+    let mut meta = TreeMap::new();
+    for (key, value) in metadata.iter() {
+        meta.insert(key.clone(), json::String(value.clone()));
+    }
+    let mut object = TreeMap::new();
+    object.insert("version".to_string(), json::U64(VERSION));
+    object.insert("metadata".to_string(), json::Object(meta));
+    object.insert("instructions".to_string(), json::List(insts));
+    json::Object(object)
+}
+
+/// Convert JSON to a program and its metadata. A missing "metadata" is read as empty.
+///
+/// # Error
+///
+/// Returns `InvalidInput` if the JSON doesn't have the expected structure
+/// or the version is unsupported.
+pub fn from_json(value: &Json) -> IoResult<(Program, Metadata)> {
+    let object = match *value {
+        json::Object(ref object) => object,
+        _ => return Err(format_error("expected object")),
+    };
+    match object.find(&"version".to_string()).and_then(|v| v.as_u64()) {
+        Some(VERSION) => (),
+        _ => return Err(format_error("unsupported version")),
+    }
+
+    let mut metadata = TreeMap::new();
+    match object.find(&"metadata".to_string()) {
+        Some(&json::Object(ref meta)) => for (key, value) in meta.iter() {
+            match *value {
+                json::String(ref s) => { metadata.insert(key.clone(), s.clone()); },
+                _ => return Err(format_error("expected string metadata")),
+            }
+        },
+        Some(&json::Null) | None => (),
+        Some(_) => return Err(format_error("expected metadata object")),
+    }
+
+    let insts = match object.find(&"instructions".to_string()) {
+        Some(&json::List(ref insts)) => insts,
+        _ => return Err(format_error("expected instructions list")),
+    };
+    let mut program = Program::new();
+    for inst in insts.iter() {
+        let parsed = match *inst {
+            json::List(ref list) => match list.as_slice() {
+                [json::String(ref name)] => instruction(name.as_slice(), None),
+                [json::String(ref name), ref arg] => match arg.as_i64() {
+                    Some(n) => instruction(name.as_slice(), Some(n)),
+                    None => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        match parsed {
+            Some(inst) => program.push(inst),
+            None => return Err(format_error("invalid instruction")),
+        }
+    }
+    Ok((program, metadata))
+}
+
+/// Return a program and its metadata as a JSON string.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::json;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(42), ir::PutNumber, ir::Exit));
+/// let source = json::to_string(&program, &json::Metadata::new());
+/// assert_eq!(json::from_str(source.as_slice()), Ok((program, json::Metadata::new())));
+/// ```
+pub fn to_string(program: &Program, metadata: &Metadata) -> String {
+    to_json(program, metadata).to_string()
+}
+
+/// Parse a JSON string to a program and its metadata.
+pub fn from_str(source: &str) -> IoResult<(Program, Metadata)> {
+    match json::from_str(source) {
+        Ok(value) => from_json(&value),
+        Err(e) => Err(IoError {
+            kind: InvalidInput,
+            desc: "invalid json",
+            detail: Some(e.to_string()),
+        }),
+    }
+}
+
+fn format_error(detail: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid program format",
+        detail: Some(detail.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::InvalidInput;
+
+    use ir;
+    use ir::Program;
+    use super::{from_str, to_string, Metadata};
+
+    #[test]
+    fn test_roundtrip() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(-1),
+            ir::StackPush(42),
+            ir::JumpIfZero(-1),
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        let mut metadata = Metadata::new();
+        metadata.insert("source".to_string(), "hello.ws".to_string());
+        let source = to_string(&program, &metadata);
+        assert_eq!(from_str(source.as_slice()), Ok((program, metadata)));
+    }
+
+    #[test]
+    fn test_from_str() {
+        let source = r#"{"version":1,"instructions":[["push",1],["putn"],["exit"]]}"#;
+        let program = Program::from_vec(vec!(ir::StackPush(1), ir::PutNumber, ir::Exit));
+        assert_eq!(from_str(source), Ok((program, Metadata::new())));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(from_str("[]").unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str(r#"{"version":2,"instructions":[]}"#).unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str(r#"{"version":1,"instructions":[["push"]]}"#).unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str(r#"{"version":1,"instructions":[["pop"]]}"#).unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str("{").unwrap_err().kind, InvalidInput);
+    }
+}
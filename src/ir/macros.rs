@@ -0,0 +1,219 @@
+//! User-defined templates expanded into core instructions.
+
+#![experimental]
+
+use std::collections::HashMap;
+use std::from_str::FromStr;
+use std::io::{InvalidInput, IoError, IoResult};
+
+use ir;
+use ir::{Instruction, Program};
+use ir::text::instruction;
+
+/// An instruction which may invoke a macro.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub enum MacroInstruction {
+    /// A core instruction.
+    Plain(Instruction),
+    /// Invoke the named macro with arguments.
+    Invoke(String, Vec<i64>),
+}
+
+#[deriving(PartialEq, Clone, Show)]
+enum Operand {
+    Number(i64),
+    Param(uint),
+    Local(String),
+}
+
+#[deriving(PartialEq, Clone, Show)]
+struct Macro {
+    arity: uint,
+    body: Vec<(String, Option<Operand>)>,
+}
+
+/// Named macros with parameters.
+///
+/// A body is written in the instruction syntax of `ir::text` without the header.
+/// An operand is a number, `$name` for a parameter, or `@name` for a label local
+/// to each expansion.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::macros::{MacroTable, Plain, Invoke};
+///
+/// let mut macros = MacroTable::new();
+/// macros.define("SET", &["addr", "value"], "push $addr\npush $value\nstore").unwrap();
+/// let program = macros.expand(&[Invoke("SET".to_string(), vec!(1, 42)), Plain(ir::Exit)]).unwrap();
+/// assert_eq!(program, Program::from_vec(vec!(ir::StackPush(1), ir::StackPush(42), ir::HeapStore, ir::Exit)));
+/// ```
+#[deriving(PartialEq, Clone, Show)]
+pub struct MacroTable {
+    macros: HashMap<String, Macro>,
+}
+
+impl MacroTable {
+    /// Create an empty `MacroTable`.
+    pub fn new() -> MacroTable {
+        MacroTable { macros: HashMap::new() }
+    }
+
+    /// Define a macro, replacing any macro of the same name.
+    ///
+    /// # Error
+    ///
+    /// Returns `InvalidInput` if a line of the body is not an instruction
+    /// or refers to an unknown parameter.
+    pub fn define(&mut self, name: &str, params: &[&str], body: &str) -> IoResult<()> {
+        let mut insts = Vec::new();
+        for line in body.lines() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") { continue }
+            let words: Vec<&str> = line.words().collect();
+            let (mnemonic, operand) = match words.as_slice() {
+                [mnemonic] => (mnemonic, None),
+                [mnemonic, arg] if arg.starts_with("$") => match params.iter().position(|p| *p == arg.slice_from(1)) {
+                    Some(i) => (mnemonic, Some(Param(i))),
+                    None => return Err(macro_error(format!("unknown parameter {} in {}", arg, name))),
+                },
+                [mnemonic, arg] if arg.starts_with("@") => (mnemonic, Some(Local(arg.slice_from(1).to_string()))),
+                [mnemonic, arg] => match FromStr::from_str(arg) {
+                    Some(n) => (mnemonic, Some(Number(n))),
+                    None => return Err(macro_error(format!("expected number, but {}", arg))),
+                },
+                _ => return Err(macro_error(format!("invalid instruction: {}", line))),
+            };
+            if instruction(mnemonic, operand.as_ref().map(|_| 0)).is_none() {
+                return Err(macro_error(format!("invalid instruction: {}", line)));
+            }
+            insts.push((mnemonic.to_string(), operand));
+        }
+        self.macros.insert(name.to_string(), Macro { arity: params.len(), body: insts });
+        Ok(())
+    }
+
+    /// Return true if the macro is defined.
+    pub fn contains(&self, name: &str) -> bool {
+        self.macros.contains_key(&name.to_string())
+    }
+
+    /// Expand macro invocations into core instructions.
+    ///
+    /// Local labels are numbered from the largest label of the core instructions plus 1,
+    /// freshly for each expansion.
+    ///
+    /// # Error
+    ///
+    /// Returns `InvalidInput` if a macro is not defined or is given the wrong number of arguments.
+    pub fn expand(&self, insts: &[MacroInstruction]) -> IoResult<Program> {
+        let mut next_label = insts.iter().filter_map(|inst| match *inst {
+            Plain(ir::Mark(n)) | Plain(ir::Call(n)) | Plain(ir::Jump(n)) |
+            Plain(ir::JumpIfZero(n)) | Plain(ir::JumpIfNegative(n)) => Some(n + 1),
+            _ => None,
+        }).max().unwrap_or(0);
+        let mut program = Program::new();
+        for inst in insts.iter() {
+            let (name, args) = match *inst {
+                Plain(ref inst) => { program.push(inst.clone()); continue },
+                Invoke(ref name, ref args) => (name, args),
+            };
+            let def = match self.macros.find(name) {
+                Some(def) => def,
+                None => return Err(macro_error(format!("undefined macro {}", name))),
+            };
+            if def.arity != args.len() {
+                return Err(macro_error(format!("{} takes {} arguments, but {} given", name, def.arity, args.len())));
+            }
+            let mut locals: HashMap<String, i64> = HashMap::new();
+            for &(ref mnemonic, ref operand) in def.body.iter() {
+                let value = match *operand {
+                    Some(Number(n)) => Some(n),
+                    Some(Param(i)) => Some(args[i]),
+                    Some(Local(ref label)) => Some(match locals.find_copy(label) {
+                        Some(n) => n,
+                        None => {
+                            locals.insert(label.clone(), next_label);
+                            next_label += 1;
+                            next_label - 1
+                        },
+                    }),
+                    None => None,
+                };
+                program.push(instruction(mnemonic.as_slice(), value).unwrap());
+            }
+        }
+        Ok(program)
+    }
+}
+
+fn macro_error(detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "macro error",
+        detail: Some(detail),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::InvalidInput;
+
+    use ir;
+    use ir::Program;
+    use super::{MacroTable, Plain, Invoke};
+
+    #[test]
+    fn test_expand() {
+        let mut macros = MacroTable::new();
+        macros.define("COUNTDOWN", &["n"], "
+            push $n
+            mark @loop
+            dup
+            putn
+            push 1
+            sub
+            dup
+            jz @end
+            jump @loop
+            mark @end
+            discard
+        ").unwrap();
+        assert!(macros.contains("COUNTDOWN"));
+        let insts = vec!(
+            Plain(ir::Mark(3)),
+            Invoke("COUNTDOWN".to_string(), vec!(2)),
+            Invoke("COUNTDOWN".to_string(), vec!(5)),
+            Plain(ir::Exit),
+        );
+        let body = |n: i64, start: i64| vec!(
+            ir::StackPush(n),
+            ir::Mark(start),
+            ir::StackDuplicate,
+            ir::PutNumber,
+            ir::StackPush(1),
+            ir::Subtraction,
+            ir::StackDuplicate,
+            ir::JumpIfZero(start + 1),
+            ir::Jump(start),
+            ir::Mark(start + 1),
+            ir::StackDiscard,
+        );
+        let mut expected = vec!(ir::Mark(3));
+        expected.push_all(body(2, 4).as_slice());
+        expected.push_all(body(5, 6).as_slice());
+        expected.push(ir::Exit);
+        assert_eq!(macros.expand(insts.as_slice()), Ok(Program::from_vec(expected)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut macros = MacroTable::new();
+        assert_eq!(macros.define("BAD", &[], "push $x").unwrap_err().kind, InvalidInput);
+        assert_eq!(macros.define("BAD", &[], "pop").unwrap_err().kind, InvalidInput);
+        assert_eq!(macros.define("BAD", &[], "add 1").unwrap_err().kind, InvalidInput);
+        macros.define("ONE", &["x"], "push $x").unwrap();
+        assert_eq!(macros.expand(&[Invoke("ONE".to_string(), vec!())]).unwrap_err().kind, InvalidInput);
+        assert_eq!(macros.expand(&[Invoke("TWO".to_string(), vec!(1))]).unwrap_err().kind, InvalidInput);
+    }
+}
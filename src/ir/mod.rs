@@ -2,6 +2,12 @@
 
 #![stable]
 
+pub use self::diff::diff;
+pub use self::effect::{stack_effect, StackEffect};
+pub use self::pass::{Pass, PassManager};
+pub use self::program::{Program, SourceLocation};
+pub use self::stats::stats;
+
 #[allow(missing_doc)]
 #[deriving(PartialEq, Eq, Clone, Hash, Show)]
 pub enum Instruction {
@@ -29,4 +35,27 @@ pub enum Instruction {
     PutNumber,
     GetCharactor,
     GetNumber,
+    Nop,
+    ExitWith(i64),
+    Extension(u8, i64),
 }
+
+pub mod cost;
+pub mod diff;
+pub mod effect;
+pub mod equiv;
+pub mod eval;
+pub mod fused;
+pub mod gen;
+pub mod json;
+pub mod macros;
+pub mod opt;
+pub mod partial;
+pub mod pass;
+pub mod program;
+pub mod pseudo;
+pub mod stats;
+pub mod structure;
+pub mod symbolic;
+pub mod text;
+pub mod verify;
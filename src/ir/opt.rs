@@ -0,0 +1,1060 @@
+//! Optimization passes on the intermediate representation.
+
+#![experimental]
+
+use std::collections::{HashMap, HashSet};
+use std::i64;
+use std::iter::AdditiveIterator;
+
+use bytecode;
+use ir;
+use ir::{stack_effect, Instruction, Program};
+use ir::cost::whitespace_size;
+use machine::CostTable;
+
+/// What a rewriting pass optimizes for when it has a choice.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Tradeoff {
+    /// Fewer execution cost by `CostTable::weighted`.
+    Speed,
+    /// Shorter Whitespace source.
+    Size,
+}
+
+/// Remove redundant instruction sequences.
+///
+/// The following patterns are rewritten until none is left:
+///
+/// * "PUSH" or "DUP" followed by "DISCARD" is removed.
+/// * "SWAP" followed by "SWAP" is removed.
+/// * "JUMP" to a label marked right after it is removed.
+/// * Two "PUSH" followed by "ADD", "SUB", "MUL", "DIV" or "MOD" is folded into a "PUSH",
+///   unless the operation overflows or divides by zero.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::peephole;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(3), ir::StackPush(3), ir::Addition, ir::PutNumber));
+/// assert_eq!(peephole(&program), Program::from_vec(vec!(ir::StackPush(6), ir::PutNumber)));
+/// ```
+pub fn peephole(program: &Program) -> Program {
+    let mut out: Vec<Instruction> = Vec::with_capacity(program.len());
+    for inst in program.iter() {
+        out.push(inst.clone());
+        while reduce(&mut out) {}
+    }
+    Program::from_vec(out)
+}
+
+/// Evaluate instructions on statically known stack values at compile time.
+///
+/// Values pushed by "PUSH" are tracked through stack manipulation and arithmetic
+/// within a basic block, and emitted as "PUSH" only before an instruction
+/// which needs them at runtime. A conditional jump on a known value becomes
+/// "JUMP" or is removed. Operations which overflow or divide by zero are left to runtime.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::fold_constants;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(2), ir::StackDuplicate, ir::Multiplication, ir::PutNumber));
+/// assert_eq!(fold_constants(&program), Program::from_vec(vec!(ir::StackPush(4), ir::PutNumber)));
+/// ```
+pub fn fold_constants(program: &Program) -> Program {
+    let mut out = Vec::with_capacity(program.len());
+    let mut known: Vec<i64> = Vec::new();
+    for inst in program.iter() {
+        let len = known.len();
+        let folded = match *inst {
+            ir::StackPush(n) => { known.push(n); true },
+            ir::StackDuplicate if len >= 1 => { let n = known[len - 1]; known.push(n); true },
+            ir::StackCopy(n) if n >= 0 && (n as uint) < len => { let v = known[len - 1 - n as uint]; known.push(v); true },
+            ir::StackSwap if len >= 2 => { known.as_mut_slice().swap(len - 1, len - 2); true },
+            ir::StackDiscard if len >= 1 => { known.pop(); true },
+            ir::StackSlide(n) if n >= 0 && (n as uint) < len => {
+                let top = known.pop().unwrap();
+                let rest = len - 1 - n as uint;
+                known.truncate(rest);
+                known.push(top);
+                true
+            },
+            ir::JumpIfZero(label) | ir::JumpIfNegative(label) if len >= 1 => {
+                let n = known.pop().unwrap();
+                let taken = match *inst { ir::JumpIfZero(_) => n == 0, _ => n < 0 };
+                if taken {
+                    flush(&mut known, &mut out);
+                    out.push(ir::Jump(label));
+                }
+                true
+            },
+            ref op if len >= 2 => match fold(op, known[len - 2], known[len - 1]) {
+                Some(n) => {
+                    known.truncate(len - 2);
+                    known.push(n);
+                    true
+                },
+                None => false,
+            },
+            _ => false,
+        };
+        if !folded {
+            flush(&mut known, &mut out);
+            out.push(inst.clone());
+        }
+    }
+    flush(&mut known, &mut out);
+    Program::from_vec(out)
+}
+
+/// Renumber labels densely from 0 in order of first appearance.
+///
+/// Semantics are preserved, and Whitespace output gets shorter since
+/// the length of a label depends on its magnitude.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::compact_labels;
+///
+/// let program = Program::from_vec(vec!(ir::Mark(1000), ir::Jump(1000)));
+/// assert_eq!(compact_labels(&program), Program::from_vec(vec!(ir::Mark(0), ir::Jump(0))));
+/// ```
+pub fn compact_labels(program: &Program) -> Program {
+    let mut labels: HashMap<i64, i64> = HashMap::new();
+    program.iter().map(|inst| {
+        let renumber = |label: i64| {
+            let next = labels.len() as i64;
+            *labels.find_or_insert(label, next)
+        };
+        match *inst {
+            ir::Mark(label)           => ir::Mark(renumber(label)),
+            ir::Call(label)           => ir::Call(renumber(label)),
+            ir::Jump(label)           => ir::Jump(renumber(label)),
+            ir::JumpIfZero(label)     => ir::JumpIfZero(renumber(label)),
+            ir::JumpIfNegative(label) => ir::JumpIfNegative(renumber(label)),
+            ref other                 => other.clone(),
+        }
+    }).collect()
+}
+
+/// Rewrite a program into a canonical form, so that equivalent programs
+/// from different frontends compare equal.
+///
+/// "COPY 0" becomes "DUP", "SLIDE 0" is removed, the program is reduced by `peephole`,
+/// and labels are renumbered by `compact_labels`.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::normalize;
+///
+/// let a = Program::from_vec(vec!(ir::Mark(7), ir::StackCopy(0), ir::Jump(7)));
+/// let b = Program::from_vec(vec!(ir::Mark(3), ir::StackDuplicate, ir::StackSlide(0), ir::Jump(3)));
+/// assert_eq!(normalize(&a), normalize(&b));
+/// ```
+pub fn normalize(program: &Program) -> Program {
+    let canonical: Program = program.iter().filter_map(|inst| match *inst {
+        ir::StackCopy(0)  => Some(ir::StackDuplicate),
+        ir::StackSlide(0) => None,
+        ref other         => Some(other.clone()),
+    }).collect();
+    compact_labels(&peephole(&canonical))
+}
+
+/// Remove "MARK" of labels which no "CALL", "JUMP", "JUMPZ" or "JUMPN" targets.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::remove_unused_labels;
+///
+/// let program = Program::from_vec(vec!(ir::Mark(1), ir::Mark(2), ir::Jump(2)));
+/// assert_eq!(remove_unused_labels(&program), Program::from_vec(vec!(ir::Mark(2), ir::Jump(2))));
+/// ```
+pub fn remove_unused_labels(program: &Program) -> Program {
+    let targets: HashSet<i64> = program.iter().filter_map(|inst| match *inst {
+        ir::Call(label) | ir::Jump(label) | ir::JumpIfZero(label) | ir::JumpIfNegative(label) => Some(label),
+        _ => None,
+    }).collect();
+    program.iter().filter(|inst| match **inst {
+        ir::Mark(label) => targets.contains(&label),
+        _ => true,
+    }).map(|inst| inst.clone()).collect()
+}
+
+/// Replace "CALL" of a small subroutine with its body.
+///
+/// A subroutine is inlined if it's a "MARK" followed by at most `threshold`
+/// instructions other than flow control, and a "RETURN". The subroutine itself is kept.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::inline_calls;
+///
+/// let program = Program::from_vec(vec!(ir::Call(1), ir::Exit, ir::Mark(1), ir::PutNumber, ir::Return));
+/// assert_eq!(inline_calls(&program, 4), Program::from_vec(vec!(ir::PutNumber, ir::Exit, ir::Mark(1), ir::PutNumber, ir::Return)));
+/// ```
+pub fn inline_calls(program: &Program, threshold: uint) -> Program {
+    let insts = program.as_slice();
+    let mut marked: HashMap<i64, uint> = HashMap::new();
+    for inst in insts.iter() {
+        match *inst {
+            ir::Mark(label) => *marked.find_or_insert(label, 0) += 1,
+            _ => (),
+        }
+    }
+    let mut bodies: HashMap<i64, &[Instruction]> = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        let label = match *inst {
+            ir::Mark(label) if marked.find_copy(&label) == Some(1) => label,
+            _ => continue,
+        };
+        let start = i + 1;
+        let mut end = start;
+        while end < insts.len() && end - start <= threshold && !is_flow(&insts[end]) { end += 1; }
+        if end < insts.len() && end - start <= threshold && insts[end] == ir::Return {
+            bodies.insert(label, insts.slice(start, end));
+        }
+    }
+    let mut out = Vec::with_capacity(insts.len());
+    for inst in insts.iter() {
+        let body = match *inst {
+            ir::Call(label) => bodies.find_copy(&label),
+            _ => None,
+        };
+        match body {
+            Some(body) => out.push_all(body),
+            None => out.push(inst.clone()),
+        }
+    }
+    Program::from_vec(out)
+}
+
+/// Rewrite multiplications and divisions by constants into cheaper sequences.
+///
+/// Multiplications by a constant, written as "PUSH n" and "MUL", as "DUP" and "ADD"
+/// repeated to double, or as n - 1 "DUP" followed by n - 1 "ADD", are replaced by
+/// whichever of those forms costs the least for `tradeoff`. Multiplication by 0 or 1,
+/// and division and modulo by 1 are simplified. Ties keep the original sequence.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::{reduce_strength, Size};
+///
+/// let program = Program::from_vec(vec!(
+///     ir::StackDuplicate, ir::Addition, ir::StackDuplicate, ir::Addition,
+///     ir::StackDuplicate, ir::Addition, ir::PutNumber,
+/// ));
+/// assert_eq!(reduce_strength(&program, Size), Program::from_vec(vec!(
+///     ir::StackPush(8), ir::Multiplication, ir::PutNumber,
+/// )));
+/// ```
+pub fn reduce_strength(program: &Program, tradeoff: Tradeoff) -> Program {
+    let table = CostTable::weighted();
+    let cost = |insts: &[Instruction]| -> uint {
+        insts.iter().map(|inst| match tradeoff {
+            Speed => table.cost(bytecode::opcode(inst)) as uint,
+            Size => whitespace_size(inst),
+        }).sum()
+    };
+    let insts = program.as_slice();
+    let mut out = Vec::with_capacity(insts.len());
+    let mut i = 0;
+    while i < insts.len() {
+        let (len, candidates) = match strength_pattern(insts.slice_from(i)) {
+            Some((len, c)) => (len, strength_forms(c)),
+            None => (1, Vec::new()),
+        };
+        let original = insts.slice(i, i + len);
+        let mut best = original.to_vec();
+        for form in candidates.move_iter() {
+            if cost(form.as_slice()) < cost(best.as_slice()) { best = form; }
+        }
+        out.push_all(best.as_slice());
+        i += len;
+    }
+    Program::from_vec(out)
+}
+
+/// Match a multiplication by a constant, or a division or modulo by 1, at the head
+/// of `insts`, returning its length and the multiplier.
+fn strength_pattern(insts: &[Instruction]) -> Option<(uint, i64)> {
+    match insts {
+        [ir::StackPush(n), ir::Multiplication, ..] => return Some((2, n)),
+        [ir::StackPush(1), ir::Division, ..] => return Some((2, 1)),
+        [ir::StackPush(1), ir::Modulo, ..] => return Some((2, 0)),
+        _ => (),
+    }
+    let dups = insts.iter().take_while(|inst| **inst == ir::StackDuplicate).count();
+    if dups == 0 || dups >= 63 { return None }
+    let adds = insts.slice_from(dups).iter().take_while(|inst| **inst == ir::Addition).count();
+    if adds < dups { return None }
+    if dups > 1 { return Some((dups * 2, dups as i64 + 1)) }
+    // "DUP" and "ADD" repeated doubles each time
+    let mut k = 1;
+    while k < 62 && insts.slice_from(k * 2).starts_with(&[ir::StackDuplicate, ir::Addition]) { k += 1; }
+    Some((k * 2, 1 << k))
+}
+
+/// Sequences multiplying the top of the stack by `n`.
+fn strength_forms(n: i64) -> Vec<Vec<Instruction>> {
+    match n {
+        0 => return vec!(vec!(ir::StackDiscard, ir::StackPush(0))),
+        1 => return vec!(vec!()),
+        _ => (),
+    }
+    let mut forms = vec!(vec!(ir::StackPush(n), ir::Multiplication));
+    if n > 1 && n & (n - 1) == 0 {
+        let mut doubling = Vec::new();
+        for _ in range(0, n.trailing_zeros()) {
+            doubling.push(ir::StackDuplicate);
+            doubling.push(ir::Addition);
+        }
+        forms.push(doubling);
+    }
+    if n > 2 && n <= 8 {
+        let mut adding = Vec::from_elem(n as uint - 1, ir::StackDuplicate);
+        adding.grow(n as uint - 1, &ir::Addition);
+        forms.push(adding);
+    }
+    forms
+}
+
+/// Rewrite output of constant numbers between "PUTN" and "PUTC" of its digits.
+///
+/// For `Speed`, "PUSH n" followed by "PUTN" becomes "PUSH" and "PUTC" of each character
+/// of n, so no number is formatted at runtime. For `Size`, "PUSH" and "PUTC" of
+/// characters forming a decimal number become "PUSH n" and "PUTN" when it's shorter.
+/// The default number format of the virtual machine is assumed.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::{rewrite_number_output, Size, Speed};
+///
+/// let number = Program::from_vec(vec!(ir::StackPush(-7), ir::PutNumber));
+/// let digits = Program::from_vec(vec!(ir::StackPush(45), ir::PutCharactor, ir::StackPush(55), ir::PutCharactor));
+/// assert_eq!(rewrite_number_output(&number, Speed), digits);
+/// assert_eq!(rewrite_number_output(&digits, Size), number);
+/// ```
+pub fn rewrite_number_output(program: &Program, tradeoff: Tradeoff) -> Program {
+    let insts = program.as_slice();
+    let mut out = Vec::with_capacity(insts.len());
+    let mut i = 0;
+    while i < insts.len() {
+        match (tradeoff, insts.slice_from(i)) {
+            (Speed, [ir::StackPush(n), ir::PutNumber, ..]) => {
+                for c in n.to_string().as_slice().chars() {
+                    out.push(ir::StackPush(c as i64));
+                    out.push(ir::PutCharactor);
+                }
+                i += 2;
+                continue
+            },
+            (Size, _) => match number_output(insts.slice_from(i)) {
+                Some((len, n)) => {
+                    out.push(ir::StackPush(n));
+                    out.push(ir::PutNumber);
+                    i += len;
+                    continue
+                },
+                None => (),
+            },
+            _ => (),
+        }
+        out.push(insts[i].clone());
+        i += 1;
+    }
+    Program::from_vec(out)
+}
+
+/// Match "PUSH" and "PUTC" of the longest decimal number at the head of `insts`,
+/// returning its length and the number if "PUTN" is shorter.
+fn number_output(insts: &[Instruction]) -> Option<(uint, i64)> {
+    let mut digits = String::new();
+    let mut len = 0;
+    loop {
+        match insts.slice_from(len) {
+            [ir::StackPush(c), ir::PutCharactor, ..] if (c == '-' as i64 && len == 0) ||
+                                                        ('0' as i64 <= c && c <= '9' as i64) => {
+                digits.push_char(c as u8 as char);
+                len += 2;
+            },
+            _ => break,
+        }
+    }
+    // shorten to the longest prefix written the same by "PUTN"
+    while len > 0 {
+        let prefix = digits.as_slice().slice_to(len / 2);
+        match from_str::<i64>(prefix) {
+            Some(n) if n.to_string().as_slice() == prefix => {
+                let putn = [ir::StackPush(n), ir::PutNumber];
+                let size = |insts: &[Instruction]| -> uint { insts.iter().map(|inst| whitespace_size(inst)).sum() };
+                return if size(&putn) < size(insts.slice_to(len)) { Some((len, n)) } else { None }
+            },
+            _ => len -= 2,
+        }
+    }
+    None
+}
+
+/// Hoist loads of a heap address which a loop never writes out of the loop.
+///
+/// A loop is a "MARK" and a backward "JUMP" to it followed by the "MARK" all exits
+/// jump to, without "CALL" or "RETURN", which is entered only by falling into it
+/// and has the same stack depth at the head and exits. The address most loaded
+/// by "PUSH" and "RETRIEVE" in the loop, which no "STORE", "GETC" or "GETN" in
+/// the loop writes, is retrieved once before the loop, copied from the stack
+/// inside it and discarded at the exit.
+///
+/// A store to an address not known statically may write any address except
+/// those in `pinned`, which are assumed to be written only at constant addresses,
+/// like the pointer of Brainfuck programs.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::opt::hoist_invariant_loads;
+///
+/// let program = Program::from_vec(vec!(
+///     ir::Mark(0),
+///     ir::StackPush(-1), ir::HeapRetrieve, ir::HeapRetrieve, ir::JumpIfZero(1),
+///     ir::StackPush(-1), ir::HeapRetrieve, ir::StackPush(0), ir::HeapStore,
+///     ir::Jump(0),
+///     ir::Mark(1),
+///     ir::Exit,
+/// ));
+/// assert_eq!(hoist_invariant_loads(&program, &[-1]), Program::from_vec(vec!(
+///     ir::StackPush(-1), ir::HeapRetrieve,
+///     ir::Mark(0),
+///     ir::StackCopy(0), ir::HeapRetrieve, ir::JumpIfZero(1),
+///     ir::StackCopy(0), ir::StackPush(0), ir::HeapStore,
+///     ir::Jump(0),
+///     ir::Mark(1),
+///     ir::StackDiscard,
+///     ir::Exit,
+/// )));
+/// ```
+pub fn hoist_invariant_loads(program: &Program, pinned: &[i64]) -> Program {
+    let mut insts = program.as_slice().to_vec();
+    let mut tried = HashSet::new();
+    loop {
+        // outer loops first, so that loads are hoisted as far as possible
+        let mut loops = find_loops(insts.as_slice());
+        loops.sort_by(|&(h1, j1), &(h2, j2)| (j2 - h2).cmp(&(j1 - h1)));
+        let next = loops.move_iter().find(|&(h, _)| !tried.contains(&insts[h]));
+        let (h, j) = match next {
+            Some(found) => found,
+            None => break,
+        };
+        tried.insert(insts[h].clone());
+        match hoist_load(insts.as_slice(), h, j, pinned) {
+            Some(hoisted) => insts = hoisted,
+            None => (),
+        }
+    }
+    Program::from_vec(insts)
+}
+
+/// Find loops as pairs of the positions of the "MARK" and the backward "JUMP"
+/// followed by a "MARK".
+fn find_loops(insts: &[Instruction]) -> Vec<(uint, uint)> {
+    let mut marks: HashMap<i64, uint> = HashMap::new();
+    let mut loops = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Mark(label) => { marks.find_or_insert(label, i); },
+            ir::Jump(label) => match (marks.find_copy(&label), insts.get(i + 1)) {
+                (Some(h), Some(&ir::Mark(_))) => loops.push((h, i)),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    loops
+}
+
+/// Hoist the most loaded invariant address out of the loop from `h` to `j`.
+fn hoist_load(insts: &[Instruction], h: uint, j: uint, pinned: &[i64]) -> Option<Vec<Instruction>> {
+    let mut marks: HashMap<i64, uint> = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Mark(label) => { marks.find_or_insert(label, i); },
+            _ => (),
+        }
+    }
+    let exit = j + 1;
+    let inside = |i: uint| h <= i && i <= j;
+    let target = |label: &i64| marks.find_copy(label);
+
+    // the loop and its exit are reached only through the loop
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Call(ref label) | ir::Jump(ref label) |
+            ir::JumpIfZero(ref label) | ir::JumpIfNegative(ref label) if !inside(i) => match target(label) {
+                Some(t) if inside(t) || t == exit => return None,
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    // stack depth relative to the head, which must not go below it
+    let mut depths: Vec<Option<uint>> = Vec::from_elem(j + 1 - h, None);
+    let mut work = vec!((h, 0u));
+    loop {
+        let (i, depth) = match work.pop() {
+            Some(item) => item,
+            None => break,
+        };
+        match depths[i - h] {
+            Some(known) if known == depth => continue,
+            Some(_) => return None,
+            None => depths.as_mut_slice()[i - h] = Some(depth),
+        }
+        let effect = stack_effect(&insts[i]);
+        if effect.requires > depth || effect.pops > depth { return None }
+        let after = effect.apply(depth);
+        match insts[i] {
+            // extensions may write the heap
+            ir::Call(_) | ir::Return | ir::Extension(..) => return None,
+            ir::Exit | ir::ExitWith(_) => (),
+            ir::Jump(ref label) | ir::JumpIfZero(ref label) | ir::JumpIfNegative(ref label) => {
+                match target(label) {
+                    Some(t) if t == exit && after == 0 => (),
+                    Some(t) if inside(t) => work.push((t, after)),
+                    _ => return None,
+                }
+                if insts[i] != ir::Jump(*label) { work.push((i + 1, after)); }
+            },
+            _ => work.push((i + 1, after)),
+        }
+    }
+
+    // addresses loaded and written, tracking constants within basic blocks
+    let mut loads: HashMap<i64, uint> = HashMap::new();
+    let mut written = HashSet::new();
+    let mut unknown_written = false;
+    let mut known: Vec<Option<i64>> = Vec::new();
+    for i in range(h, j + 1) {
+        let depth = match depths[i - h] {
+            Some(depth) => depth,
+            None => continue,
+        };
+        if i == h || is_flow(&insts[i]) || is_flow(&insts[i - 1]) {
+            known = Vec::from_elem(depth, None);
+        }
+        let addr = match insts[i] {
+            ir::HeapStore => Some(top(known.as_slice(), 1)),
+            ir::GetCharactor | ir::GetNumber => Some(top(known.as_slice(), 0)),
+            ir::StackPush(n) if insts[i + 1] == ir::HeapRetrieve => {
+                *loads.find_or_insert(n, 0) += 1;
+                None
+            },
+            _ => None,
+        };
+        match addr {
+            Some(Some(n)) => { written.insert(n); },
+            Some(None) => unknown_written = true,
+            None => (),
+        }
+        track(&mut known, &insts[i]);
+    }
+
+    let mut invariant: Vec<(uint, i64)> = loads.iter()
+        .filter(|&(n, _)| !written.contains(n) && !(unknown_written && !pinned.contains(n)))
+        .map(|(n, count)| (*count, -*n))
+        .collect();
+    invariant.sort();
+    let addr = match invariant.pop() {
+        Some((_, n)) => -n,
+        None => return None,
+    };
+
+    let mut out = insts.slice_to(h).to_vec();
+    out.push(ir::StackPush(addr));
+    out.push(ir::HeapRetrieve);
+    let mut i = h;
+    while i <= j {
+        match (&insts[i], depths[i - h]) {
+            (&ir::StackPush(n), Some(depth)) if n == addr && insts[i + 1] == ir::HeapRetrieve => {
+                out.push(ir::StackCopy(depth as i64));
+                i += 2;
+            },
+            (inst, _) => {
+                out.push(inst.clone());
+                i += 1;
+            },
+        }
+    }
+    out.push(insts[exit].clone());
+    out.push(ir::StackDiscard);
+    out.push_all(insts.slice_from(exit + 1));
+    Some(out)
+}
+
+/// The statically known value `n` below the top of the stack.
+fn top(known: &[Option<i64>], n: uint) -> Option<i64> {
+    if n < known.len() { known[known.len() - 1 - n] } else { None }
+}
+
+/// Track statically known values on the stack through an instruction.
+fn track(known: &mut Vec<Option<i64>>, inst: &Instruction) {
+    let len = known.len();
+    match *inst {
+        ir::StackPush(n) => known.push(Some(n)),
+        ir::StackDuplicate => { let n = top(known.as_slice(), 0); known.push(n) },
+        ir::StackCopy(n) if n >= 0 => { let n = top(known.as_slice(), n as uint); known.push(n) },
+        ir::StackSwap if len >= 2 => known.as_mut_slice().swap(len - 1, len - 2),
+        ir::Addition | ir::Subtraction | ir::Multiplication | ir::Division | ir::Modulo if len >= 2 => {
+            let n = match (known[len - 2], known[len - 1]) {
+                (Some(y), Some(x)) => fold(inst, y, x),
+                _ => None,
+            };
+            known.truncate(len - 2);
+            known.push(n);
+        },
+        ref other => {
+            let effect = stack_effect(other);
+            let rest = if len > effect.pops { len - effect.pops } else { 0 };
+            known.truncate(rest);
+            for _ in range(0, effect.pushes) { known.push(None); }
+        },
+    }
+}
+
+fn is_flow(inst: &Instruction) -> bool {
+    // extensions are barriers too, since "FORK" branches and the others have unknown effects
+    match *inst {
+        ir::Mark(_) | ir::Call(_) | ir::Jump(_) | ir::JumpIfZero(_) |
+        ir::JumpIfNegative(_) | ir::Return | ir::Exit | ir::ExitWith(_) |
+        ir::Extension(..) => true,
+        _ => false,
+    }
+}
+
+fn flush(known: &mut Vec<i64>, out: &mut Vec<Instruction>) {
+    out.extend(known.iter().map(|n| ir::StackPush(*n)));
+    known.clear();
+}
+
+fn reduce(out: &mut Vec<Instruction>) -> bool {
+    let len = out.len();
+    if len < 2 { return false }
+    let folded = match out.slice_from(len - 2) {
+        [ir::StackPush(_), ir::StackDiscard] | [ir::StackDuplicate, ir::StackDiscard] | [ir::StackSwap, ir::StackSwap] => {
+            out.truncate(len - 2);
+            return true
+        },
+        _ if len < 3 => None,
+        _ => match out.slice_from(len - 3) {
+            [ir::StackPush(y), ir::StackPush(x), ref op] => fold(op, y, x),
+            _ => None,
+        },
+    };
+    match folded {
+        Some(n) => {
+            out.truncate(len - 3);
+            out.push(ir::StackPush(n));
+            true
+        },
+        None => remove_jump_to_next(out),
+    }
+}
+
+fn fold(op: &Instruction, y: i64, x: i64) -> Option<i64> {
+    match *op {
+        ir::Addition       => y.checked_add(&x),
+        ir::Subtraction    => y.checked_sub(&x),
+        ir::Multiplication => y.checked_mul(&x),
+        ir::Division       => y.checked_div(&x),
+        ir::Modulo if x != 0 && !(x == -1 && y == i64::MIN) => Some(y % x),
+        _ => None,
+    }
+}
+
+fn remove_jump_to_next(out: &mut Vec<Instruction>) -> bool {
+    let label = match out.last() {
+        Some(&ir::Mark(label)) => label,
+        _ => return false,
+    };
+    let mut i = out.len() - 1;
+    while i > 0 {
+        i -= 1;
+        match out[i] {
+            ir::Mark(_) => continue,
+            ir::Jump(target) if target == label => {
+                out.remove(i);
+                return true
+            },
+            _ => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::{compact_labels, fold_constants, inline_calls, normalize, peephole, reduce_strength, remove_unused_labels};
+    use super::{hoist_invariant_loads, rewrite_number_output, Size, Speed};
+    use ir::equiv;
+
+    #[test]
+    fn test_peephole() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::StackPush(2),
+            ir::Addition,
+            ir::StackDiscard,
+            ir::StackSwap,
+            ir::StackSwap,
+            ir::Jump(1),
+            ir::Mark(2),
+            ir::Mark(1),
+            ir::StackPush(7),
+            ir::StackPush(0),
+            ir::Division,
+            ir::StackDuplicate,
+            ir::StackDiscard,
+            ir::Exit,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::Mark(2),
+            ir::Mark(1),
+            ir::StackPush(7),
+            ir::StackPush(0),
+            ir::Division,
+            ir::Exit,
+        ));
+        assert_eq!(peephole(&program), expected);
+    }
+
+    #[test]
+    fn test_peephole_keeps_jump() {
+        let program = Program::from_vec(vec!(ir::Jump(1), ir::Mark(2), ir::Exit, ir::Mark(1)));
+        assert_eq!(peephole(&program), program);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(2),
+            ir::StackDuplicate,
+            ir::Multiplication,
+            ir::StackPush(1),
+            ir::StackSwap,
+            ir::Subtraction,
+            ir::PutNumber,
+            ir::StackPush(5),
+            ir::StackPush(0),
+            ir::Modulo,
+            ir::Mark(1),
+            ir::StackPush(0),
+            ir::JumpIfZero(1),
+            ir::StackPush(1),
+            ir::JumpIfNegative(1),
+            ir::Exit,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::StackPush(-3),
+            ir::PutNumber,
+            ir::StackPush(5),
+            ir::StackPush(0),
+            ir::Modulo,
+            ir::Mark(1),
+            ir::Jump(1),
+            ir::Exit,
+        ));
+        assert_eq!(fold_constants(&program), expected);
+    }
+
+    #[test]
+    fn test_compact_labels() {
+        let program = Program::from_vec(vec!(
+            ir::Call(-42),
+            ir::Exit,
+            ir::Mark(300),
+            ir::JumpIfZero(-42),
+            ir::Jump(300),
+            ir::Mark(-42),
+            ir::JumpIfNegative(7),
+            ir::Return,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::Call(0),
+            ir::Exit,
+            ir::Mark(1),
+            ir::JumpIfZero(0),
+            ir::Jump(1),
+            ir::Mark(0),
+            ir::JumpIfNegative(2),
+            ir::Return,
+        ));
+        assert_eq!(compact_labels(&program), expected);
+    }
+
+    #[test]
+    fn test_inline_calls() {
+        let program = Program::from_vec(vec!(
+            ir::Call(1),
+            ir::Call(2),
+            ir::Call(3),
+            ir::Exit,
+            ir::Mark(1),
+            ir::StackPush(1),
+            ir::PutNumber,
+            ir::Return,
+            ir::Mark(2),
+            ir::StackPush(2),
+            ir::StackPush(2),
+            ir::Addition,
+            ir::PutNumber,
+            ir::Return,
+            ir::Mark(3),
+            ir::Call(1),
+            ir::Return,
+        ));
+        let mut expected = vec!(
+            ir::StackPush(1),
+            ir::PutNumber,
+            ir::Call(2),
+            ir::Call(3),
+        );
+        expected.push_all(program.as_slice().slice_from(3));
+        assert_eq!(inline_calls(&program, 3), Program::from_vec(expected));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(-5),
+            ir::StackCopy(0),
+            ir::StackCopy(1),
+            ir::StackSlide(0),
+            ir::StackSwap,
+            ir::StackSwap,
+            ir::Jump(10),
+            ir::Mark(10),
+            ir::JumpIfZero(-5),
+            ir::Exit,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::Mark(0),
+            ir::StackDuplicate,
+            ir::StackCopy(1),
+            ir::Mark(1),
+            ir::JumpIfZero(0),
+            ir::Exit,
+        ));
+        assert_eq!(normalize(&program), expected);
+    }
+
+    #[test]
+    fn test_remove_unused_labels() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(1),
+            ir::Call(2),
+            ir::JumpIfZero(3),
+            ir::Mark(2),
+            ir::Mark(3),
+            ir::JumpIfNegative(4),
+            ir::Mark(4),
+            ir::Mark(-1),
+            ir::Exit,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::Call(2),
+            ir::JumpIfZero(3),
+            ir::Mark(2),
+            ir::Mark(3),
+            ir::JumpIfNegative(4),
+            ir::Mark(4),
+            ir::Exit,
+        ));
+        assert_eq!(remove_unused_labels(&program), expected);
+    }
+
+    #[test]
+    fn test_reduce_strength() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(0),
+            ir::Multiplication,
+            ir::StackPush(1),
+            ir::Multiplication,
+            ir::StackPush(1),
+            ir::Division,
+            ir::StackPush(3),
+            ir::Multiplication,
+            ir::PutNumber,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::StackDiscard,
+            ir::StackPush(0),
+            ir::StackPush(3),
+            ir::Multiplication,
+            ir::PutNumber,
+        ));
+        assert_eq!(reduce_strength(&program, Speed), expected);
+
+        let program = Program::from_vec(vec!(
+            ir::StackDuplicate,
+            ir::StackDuplicate,
+            ir::Addition,
+            ir::Addition,
+            ir::StackDuplicate,
+            ir::Addition,
+            ir::PutNumber,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::StackPush(3),
+            ir::Multiplication,
+            ir::StackDuplicate,
+            ir::Addition,
+            ir::PutNumber,
+        ));
+        assert_eq!(reduce_strength(&program, Speed), expected);
+        assert_eq!(reduce_strength(&program, Size), expected);
+        assert_eq!(reduce_strength(&expected, Speed), expected);
+    }
+
+    #[test]
+    fn test_hoist_invariant_loads() {
+        // ptr = 0; cell[0] = 3; [-] with a nested loop; print cell[0]
+        let program = Program::from_vec(vec!(
+            ir::StackPush(-1),
+            ir::StackPush(0),
+            ir::HeapStore,
+            ir::StackPush(0),
+            ir::StackPush(3),
+            ir::HeapStore,
+            ir::Mark(0),
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::HeapRetrieve,
+            ir::JumpIfZero(1),
+            ir::Mark(2),
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::HeapRetrieve,
+            ir::JumpIfZero(3),
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::StackDuplicate,
+            ir::HeapRetrieve,
+            ir::StackPush(1),
+            ir::Subtraction,
+            ir::HeapStore,
+            ir::Jump(2),
+            ir::Mark(3),
+            ir::Jump(0),
+            ir::Mark(1),
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::HeapRetrieve,
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        assert_eq!(hoist_invariant_loads(&program, &[]), program);
+
+        let hoisted = hoist_invariant_loads(&program, &[-1]);
+        let expected = Program::from_vec(vec!(
+            ir::StackPush(-1),
+            ir::StackPush(0),
+            ir::HeapStore,
+            ir::StackPush(0),
+            ir::StackPush(3),
+            ir::HeapStore,
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::Mark(0),
+            ir::StackCopy(0),
+            ir::HeapRetrieve,
+            ir::JumpIfZero(1),
+            ir::Mark(2),
+            ir::StackCopy(0),
+            ir::HeapRetrieve,
+            ir::JumpIfZero(3),
+            ir::StackCopy(0),
+            ir::StackDuplicate,
+            ir::HeapRetrieve,
+            ir::StackPush(1),
+            ir::Subtraction,
+            ir::HeapStore,
+            ir::Jump(2),
+            ir::Mark(3),
+            ir::Jump(0),
+            ir::Mark(1),
+            ir::StackDiscard,
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::HeapRetrieve,
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        assert_eq!(hoisted, expected);
+        assert!(equiv::check(&program, &hoisted, &[vec!()]).is_ok());
+
+        // the pointer is written in the loop
+        let moving = Program::from_vec(vec!(
+            ir::Mark(0),
+            ir::StackPush(-1),
+            ir::HeapRetrieve,
+            ir::JumpIfZero(1),
+            ir::StackPush(-1),
+            ir::StackPush(0),
+            ir::HeapStore,
+            ir::Jump(0),
+            ir::Mark(1),
+            ir::Exit,
+        ));
+        assert_eq!(hoist_invariant_loads(&moving, &[-1]), moving);
+    }
+
+    #[test]
+    fn test_rewrite_number_output() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(120),
+            ir::PutNumber,
+            ir::StackPush(10),
+            ir::PutCharactor,
+        ));
+        let digits = Program::from_vec(vec!(
+            ir::StackPush(49),
+            ir::PutCharactor,
+            ir::StackPush(50),
+            ir::PutCharactor,
+            ir::StackPush(48),
+            ir::PutCharactor,
+            ir::StackPush(10),
+            ir::PutCharactor,
+        ));
+        assert_eq!(rewrite_number_output(&program, Speed), digits);
+        assert_eq!(rewrite_number_output(&digits, Size), program);
+
+        // "007" is not written by "PUTN", but "0" is
+        let zeros = Program::from_vec(vec!(
+            ir::StackPush(48),
+            ir::PutCharactor,
+            ir::StackPush(48),
+            ir::PutCharactor,
+            ir::StackPush(55),
+            ir::PutCharactor,
+        ));
+        let expected = Program::from_vec(vec!(
+            ir::StackPush(0),
+            ir::PutNumber,
+            ir::StackPush(0),
+            ir::PutNumber,
+            ir::StackPush(7),
+            ir::PutNumber,
+        ));
+        assert_eq!(rewrite_number_output(&zeros, Size), expected);
+    }
+}
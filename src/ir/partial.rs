@@ -0,0 +1,158 @@
+//! Partial evaluation of programs on a fixed input.
+
+#![experimental]
+
+use std::io::{BufReader, IoResult, MemWriter};
+
+use ir;
+use ir::Program;
+use ir::equiv::DEFAULT_GAS;
+use machine::{Builder, CostTable, MachineIo, MachineResult, StreamIo};
+
+/// Specialize a program to a fixed input, returning a residual program which
+/// writes the same output without reading input, and the exit status of the run.
+///
+/// Everything depending on the input is evaluated away, so the residual program
+/// is a sequence of "PUTC" of the output characters. The run is limited by `DEFAULT_GAS`
+/// with every instruction costing 1, and errors of the run, including reading past
+/// the input, are returned.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::partial;
+///
+/// let program = Program::from_vec(vec!(
+///     ir::StackPush(0), ir::GetNumber,
+///     ir::StackPush(0), ir::HeapRetrieve, ir::StackPush(2), ir::Multiplication,
+///     ir::PutNumber, ir::Exit,
+/// ));
+/// let (residual, status) = partial::specialize(&program, b"21\n").unwrap();
+/// assert_eq!(residual, Program::from_vec(vec!(
+///     ir::StackPush(52), ir::PutCharactor,
+///     ir::StackPush(50), ir::PutCharactor,
+///     ir::Exit,
+/// )));
+/// assert_eq!(status, 0);
+/// ```
+pub fn specialize(program: &Program, input: &[u8]) -> MachineResult<(Program, i64)> {
+    specialize_with(Builder::new().gas(DEFAULT_GAS, CostTable::new()), program, input)
+}
+
+/// Like `specialize`, running on a machine built by `builder`.
+///
+/// The residual program writes the characters the program wrote with "PUTC" and
+/// "PUTN", so it reproduces the output when run with the character encoding of `builder`.
+pub fn specialize_with(builder: Builder, program: &Program, input: &[u8]) -> MachineResult<(Program, i64)> {
+    let bytecode = program.to_bytecode().unwrap();
+    let io = OutputLog { input: StreamIo::new(BufReader::new(input), MemWriter::new()), chars: Vec::new() };
+    let mut machine = builder.build(io);
+    let status = try!(machine.run(&mut BufReader::new(bytecode.as_slice())));
+    Ok((residual(machine.io().chars.as_slice()), status))
+}
+
+// Reads the fixed input and keeps the values written, before they are encoded.
+struct OutputLog<'a> {
+    input: StreamIo<BufReader<'a>, MemWriter>,
+    chars: Vec<i64>,
+}
+
+impl<'a> MachineIo for OutputLog<'a> {
+    fn get_char(&mut self) -> IoResult<char> { self.input.get_char() }
+
+    fn get_number(&mut self) -> IoResult<i64> { self.input.get_number() }
+
+    fn put_char(&mut self, c: char) -> IoResult<()> {
+        self.chars.push(c as i64);
+        Ok(())
+    }
+
+    fn put_byte(&mut self, b: u8) -> IoResult<()> {
+        self.chars.push(b as i64);
+        Ok(())
+    }
+
+    fn put_number(&mut self, n: i64) -> IoResult<()> {
+        self.chars.extend(n.to_string().as_slice().chars().map(|c| c as i64));
+        Ok(())
+    }
+}
+
+/// Build a program writing `output`, duplicating the value of repeated characters.
+fn residual(output: &[i64]) -> Program {
+    let mut insts = Vec::new();
+    let mut i = 0;
+    while i < output.len() {
+        let c = output[i];
+        let run = output.slice_from(i).iter().take_while(|&&b| b == c).count();
+        insts.push(ir::StackPush(c));
+        for _ in range(1, run) { insts.push(ir::StackDuplicate); }
+        for _ in range(0, run) { insts.push(ir::PutCharactor); }
+        i += run;
+    }
+    insts.push(ir::Exit);
+    Program::from_vec(insts)
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use ir::equiv;
+    use machine::{EndOfInput, OutOfGas};
+    use super::specialize;
+
+    #[test]
+    fn test_specialize() {
+        let echo = Program::from_vec(vec!(
+            ir::Mark(0),
+            ir::StackPush(0),
+            ir::GetCharactor,
+            ir::StackPush(0),
+            ir::HeapRetrieve,
+            ir::StackDuplicate,
+            ir::StackPush(10),
+            ir::Subtraction,
+            ir::JumpIfZero(1),
+            ir::PutCharactor,
+            ir::Jump(0),
+            ir::Mark(1),
+            ir::Exit,
+        ));
+        let (residual, status) = specialize(&echo, b"aab\n").unwrap();
+        assert_eq!(status, 0);
+        assert_eq!(residual, Program::from_vec(vec!(
+            ir::StackPush(97),
+            ir::StackDuplicate,
+            ir::PutCharactor,
+            ir::PutCharactor,
+            ir::StackPush(98),
+            ir::PutCharactor,
+            ir::Exit,
+        )));
+        assert!(equiv::check(&echo, &residual, &[b"aab\n".to_vec()]).is_ok());
+        assert_eq!(specialize(&echo, b"aa"), Err(EndOfInput));
+    }
+
+    #[test]
+    fn test_specialize_latin1() {
+        let echo = Program::from_vec(vec!(
+            ir::StackPush(0),
+            ir::GetCharactor,
+            ir::StackPush(0),
+            ir::HeapRetrieve,
+            ir::PutCharactor,
+            ir::Exit,
+        ));
+        let input = "\u00e9".as_bytes();
+        let (residual, _) = specialize(&echo, input).unwrap();
+        assert_eq!(residual, Program::from_vec(vec!(ir::StackPush(0xE9), ir::PutCharactor, ir::Exit)));
+        assert!(equiv::check(&echo, &residual, &[input.to_vec()]).is_ok());
+    }
+
+    #[test]
+    fn test_specialize_loop() {
+        let looping = Program::from_vec(vec!(ir::Mark(0), ir::Jump(0)));
+        assert_eq!(specialize(&looping, b""), Err(OutOfGas));
+    }
+}
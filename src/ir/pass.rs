@@ -0,0 +1,210 @@
+//! Ordered pipeline of passes over programs.
+
+#![experimental]
+
+use time::precise_time_ns;
+
+use ir::{opt, Program};
+
+/// A transformation of programs run by `PassManager`.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::{Pass, PassManager, Program};
+///
+/// struct StripMarks;
+///
+/// impl Pass for StripMarks {
+///     fn name(&self) -> &str { "strip-marks" }
+///     fn run(&mut self, program: &Program) -> Program {
+///         program.iter().filter(|inst| match **inst { ir::Mark(_) => false, _ => true }).map(|inst| inst.clone()).collect()
+///     }
+/// }
+///
+/// let mut manager = PassManager::new();
+/// manager.add(StripMarks);
+/// let (program, stats) = manager.run(&Program::from_vec(vec!(ir::Mark(1), ir::Exit)));
+/// assert_eq!(program, Program::from_vec(vec!(ir::Exit)));
+/// assert_eq!(stats[0].instructions_after, 1);
+/// ```
+pub trait Pass {
+    /// Name of the pass shown in statistics.
+    fn name(&self) -> &str;
+
+    /// Transform the program.
+    fn run(&mut self, program: &Program) -> Program;
+}
+
+/// Statistics of a pass run by `PassManager`.
+#[deriving(PartialEq, Clone, Show)]
+pub struct PassStats {
+    /// Name of the pass.
+    pub name: String,
+    /// Number of instructions before the pass.
+    pub instructions_before: uint,
+    /// Number of instructions after the pass.
+    pub instructions_after: uint,
+    /// Elapsed time of the pass in nanoseconds.
+    pub time_ns: u64,
+}
+
+/// Runs passes in the order they were added.
+pub struct PassManager {
+    passes: Vec<Box<Pass + 'static>>,
+}
+
+impl PassManager {
+    /// Create an empty `PassManager`.
+    pub fn new() -> PassManager {
+        PassManager { passes: Vec::new() }
+    }
+
+    /// Create a `PassManager` with the built-in optimizations.
+    pub fn with_optimizations() -> PassManager {
+        let mut manager = PassManager::new();
+        manager.add(FoldConstants);
+        manager.add(Peephole);
+        manager.add(RemoveUnusedLabels);
+        manager.add(CompactLabels);
+        manager
+    }
+
+    /// Append a pass.
+    pub fn add<P: Pass + 'static>(&mut self, pass: P) {
+        self.passes.push(box pass as Box<Pass + 'static>);
+    }
+
+    /// Return the number of passes.
+    pub fn len(&self) -> uint { self.passes.len() }
+
+    /// Run all passes, returning the transformed program and statistics of each pass.
+    pub fn run(&mut self, program: &Program) -> (Program, Vec<PassStats>) {
+        let mut current = program.clone();
+        let mut stats = Vec::with_capacity(self.passes.len());
+        for pass in self.passes.mut_iter() {
+            let start = precise_time_ns();
+            let next = pass.run(&current);
+            stats.push(PassStats {
+                name: pass.name().to_string(),
+                instructions_before: current.len(),
+                instructions_after: next.len(),
+                time_ns: precise_time_ns() - start,
+            });
+            current = next;
+        }
+        (current, stats)
+    }
+}
+
+/// `opt::peephole` as a pass.
+pub struct Peephole;
+
+impl Pass for Peephole {
+    fn name(&self) -> &str { "peephole" }
+    fn run(&mut self, program: &Program) -> Program { opt::peephole(program) }
+}
+
+/// `opt::fold_constants` as a pass.
+pub struct FoldConstants;
+
+impl Pass for FoldConstants {
+    fn name(&self) -> &str { "fold-constants" }
+    fn run(&mut self, program: &Program) -> Program { opt::fold_constants(program) }
+}
+
+/// `opt::compact_labels` as a pass.
+pub struct CompactLabels;
+
+impl Pass for CompactLabels {
+    fn name(&self) -> &str { "compact-labels" }
+    fn run(&mut self, program: &Program) -> Program { opt::compact_labels(program) }
+}
+
+/// `opt::remove_unused_labels` as a pass.
+pub struct RemoveUnusedLabels;
+
+impl Pass for RemoveUnusedLabels {
+    fn name(&self) -> &str { "remove-unused-labels" }
+    fn run(&mut self, program: &Program) -> Program { opt::remove_unused_labels(program) }
+}
+
+/// `opt::normalize` as a pass.
+pub struct Normalize;
+
+impl Pass for Normalize {
+    fn name(&self) -> &str { "normalize" }
+    fn run(&mut self, program: &Program) -> Program { opt::normalize(program) }
+}
+
+/// `opt::inline_calls` as a pass.
+pub struct InlineCalls {
+    /// Maximum number of instructions of an inlined subroutine.
+    pub threshold: uint,
+}
+
+impl Pass for InlineCalls {
+    fn name(&self) -> &str { "inline-calls" }
+    fn run(&mut self, program: &Program) -> Program { opt::inline_calls(program, self.threshold) }
+}
+
+/// `opt::reduce_strength` as a pass.
+pub struct ReduceStrength {
+    /// What the rewriting optimizes for.
+    pub tradeoff: opt::Tradeoff,
+}
+
+impl Pass for ReduceStrength {
+    fn name(&self) -> &str { "reduce-strength" }
+    fn run(&mut self, program: &Program) -> Program { opt::reduce_strength(program, self.tradeoff) }
+}
+
+/// `opt::rewrite_number_output` as a pass.
+pub struct RewriteNumberOutput {
+    /// What the rewriting optimizes for.
+    pub tradeoff: opt::Tradeoff,
+}
+
+impl Pass for RewriteNumberOutput {
+    fn name(&self) -> &str { "rewrite-number-output" }
+    fn run(&mut self, program: &Program) -> Program { opt::rewrite_number_output(program, self.tradeoff) }
+}
+
+/// `opt::hoist_invariant_loads` as a pass.
+pub struct HoistInvariantLoads {
+    /// Addresses written only at constant addresses.
+    pub pinned: Vec<i64>,
+}
+
+impl Pass for HoistInvariantLoads {
+    fn name(&self) -> &str { "hoist-invariant-loads" }
+    fn run(&mut self, program: &Program) -> Program { opt::hoist_invariant_loads(program, self.pinned.as_slice()) }
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::PassManager;
+
+    #[test]
+    fn test_with_optimizations() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::StackPush(2),
+            ir::Addition,
+            ir::PutNumber,
+            ir::Jump(100),
+            ir::Mark(100),
+            ir::Exit,
+        ));
+        let mut manager = PassManager::with_optimizations();
+        let (optimized, stats) = manager.run(&program);
+        assert_eq!(optimized, Program::from_vec(vec!(ir::StackPush(3), ir::PutNumber, ir::Exit)));
+        let names: Vec<&str> = stats.iter().map(|s| s.name.as_slice()).collect();
+        assert_eq!(names, vec!("fold-constants", "peephole", "remove-unused-labels", "compact-labels"));
+        assert_eq!(stats[0].instructions_before, 7);
+        assert_eq!(stats[0].instructions_after, 5);
+        assert_eq!(stats[1].instructions_after, 4);
+        assert_eq!(stats[2].instructions_after, 3);
+    }
+}
@@ -0,0 +1,243 @@
+//! In-memory container of instructions.
+
+#![experimental]
+
+use std::from_str::FromStr;
+use std::hash;
+use std::io::{EndOfFile, InvalidInput, IoError, IoResult, MemWriter};
+use std::iter::FromIterator;
+use std::slice::Items;
+
+use bytecode::{ByteCodeReader, ByteCodeWriter};
+use ir::Instruction;
+
+/// Position in source code an instruction was parsed from.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub struct SourceLocation {
+    /// Line number, starting from 1.
+    pub line: uint,
+    /// Column number in characters, starting from 1.
+    pub column: uint,
+    /// Original text of the instruction.
+    pub token: String,
+}
+
+/// A sequence of instructions held in memory.
+///
+/// Programs are compared and hashed by their instructions, ignoring source locations.
+///
+/// ```rust
+/// use std::io::MemReader;
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+///
+/// let program: Program = vec!(ir::StackPush(1), ir::PutNumber, ir::Exit).move_iter().collect();
+/// let bytes = program.to_bytecode().unwrap();
+/// let decoded = Program::from_bytecode(&mut MemReader::new(bytes)).unwrap();
+/// assert_eq!(decoded, program);
+/// assert_eq!(decoded[1], ir::PutNumber);
+/// ```
+#[deriving(Clone, Show)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    // shorter than instructions if the last ones have no location
+    locations: Vec<Option<SourceLocation>>,
+}
+
+impl Program {
+    /// Create an empty `Program`.
+    pub fn new() -> Program {
+        Program { instructions: Vec::new(), locations: Vec::new() }
+    }
+
+    /// Create a new `Program` with instructions.
+    pub fn from_vec(instructions: Vec<Instruction>) -> Program {
+        Program { instructions: instructions, locations: Vec::new() }
+    }
+
+    /// Read all instructions from bytecodes until EOF.
+    ///
+    /// # Error
+    ///
+    /// Returns the first error other than `EndOfFile` produced by the reader.
+    pub fn from_bytecode<B: ByteCodeReader>(reader: &mut B) -> IoResult<Program> {
+        let mut instructions = Vec::new();
+        for inst in reader.disassemble() {
+            instructions.push(try!(inst));
+        }
+        Ok(Program::from_vec(instructions))
+    }
+
+    /// Write all instructions as bytecodes.
+    pub fn write_bytecode<W: ByteCodeWriter>(&self, writer: &mut W) -> IoResult<()> {
+        writer.assemble_slice(self.instructions.as_slice())
+    }
+
+    /// Compile all instructions to bytecodes.
+    pub fn to_bytecode(&self) -> IoResult<Vec<u8>> {
+        let mut writer = MemWriter::new();
+        try!(self.write_bytecode(&mut writer));
+        Ok(writer.unwrap())
+    }
+
+    /// Write source locations of instructions, one per line as
+    /// index, line, column and token separated by a space.
+    pub fn write_debug_map<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        for (i, loc) in self.locations.iter().enumerate() {
+            match *loc {
+                Some(ref loc) => try!(write!(writer, "{} {} {} {}\n", i, loc.line, loc.column, loc.token)),
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read source locations written by `write_debug_map`.
+    ///
+    /// # Error
+    ///
+    /// Returns `InvalidInput` if a line is malformed or its index is out of bounds.
+    pub fn read_debug_map<B: Buffer>(&mut self, reader: &mut B) -> IoResult<()> {
+        loop {
+            let line = match reader.read_line() {
+                Ok(line) => line,
+                Err(ref e) if e.kind == EndOfFile => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let line = line.as_slice().trim_right_chars('\n');
+            let fields: Vec<&str> = line.splitn(' ', 3).collect();
+            let parsed: Vec<Option<uint>> = fields.iter().take(3).map(|f| FromStr::from_str(*f)).collect();
+            match (fields.len(), parsed.as_slice()) {
+                (4, [Some(i), Some(line), Some(column)]) if i < self.instructions.len() => {
+                    let loc = SourceLocation { line: line, column: column, token: fields[3].to_string() };
+                    self.locations.grow_set(i, &None, Some(loc));
+                },
+                _ => return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "invalid debug map",
+                    detail: Some(line.to_string()),
+                }),
+            }
+        }
+    }
+
+    /// Append an instruction.
+    pub fn push(&mut self, inst: Instruction) {
+        self.instructions.push(inst);
+    }
+
+    /// Append an instruction with its source location.
+    pub fn push_located(&mut self, inst: Instruction, loc: SourceLocation) {
+        self.locations.grow_set(self.instructions.len(), &None, Some(loc));
+        self.instructions.push(inst);
+    }
+
+    /// Return the source location of the instruction at the index, if known.
+    pub fn location(&self, index: uint) -> Option<&SourceLocation> {
+        self.locations.as_slice().get(index).and_then(|loc| loc.as_ref())
+    }
+
+    /// Return the instruction at the index, or `None` if out of bounds.
+    pub fn get(&self, index: uint) -> Option<&Instruction> { self.instructions.as_slice().get(index) }
+
+    /// Return an iterator over the instructions.
+    pub fn iter<'a>(&'a self) -> Items<'a, Instruction> { self.instructions.iter() }
+
+    /// Return the instructions as a slice.
+    pub fn as_slice<'a>(&'a self) -> &'a [Instruction] { self.instructions.as_slice() }
+
+    /// Return the instructions as a mutable vector, discarding source locations,
+    /// which could not follow instructions inserted or removed.
+    pub fn as_mut_vec<'a>(&'a mut self) -> &'a mut Vec<Instruction> {
+        self.locations.clear();
+        &mut self.instructions
+    }
+
+    /// Unwrap this `Program`, returning the instructions.
+    pub fn unwrap(self) -> Vec<Instruction> { self.instructions }
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Program) -> bool { self.instructions == other.instructions }
+}
+
+impl Eq for Program {}
+
+impl<S: hash::Writer> hash::Hash<S> for Program {
+    fn hash(&self, state: &mut S) { self.instructions.hash(state) }
+}
+
+impl Collection for Program {
+    fn len(&self) -> uint { self.instructions.len() }
+}
+
+impl Index<uint, Instruction> for Program {
+    fn index<'a>(&'a self, index: &uint) -> &'a Instruction { &self.instructions[*index] }
+}
+
+impl FromIterator<Instruction> for Program {
+    fn from_iter<T: Iterator<Instruction>>(iter: T) -> Program {
+        Program::from_vec(iter.collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{InvalidInput, MemReader, MemWriter};
+
+    use bytecode::ByteCodeWriter;
+    use ir;
+    use super::{Program, SourceLocation};
+
+    #[test]
+    fn test_roundtrip() {
+        let mut program = Program::new();
+        program.push(ir::Mark(-1));
+        program.push(ir::StackPush(42));
+        program.push(ir::JumpIfZero(-1));
+        program.push(ir::Exit);
+
+        let bytes = program.to_bytecode().unwrap();
+        let decoded = Program::from_bytecode(&mut MemReader::new(bytes)).unwrap();
+        assert_eq!(decoded, program);
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(decoded[1], ir::StackPush(42));
+        assert_eq!(decoded.get(4), None);
+        assert_eq!(decoded.iter().filter(|inst| **inst == ir::Exit).count(), 1);
+    }
+
+    #[test]
+    fn test_from_bytecode_error() {
+        let mut bcw = MemWriter::new();
+        bcw.write_fork().unwrap();
+        let bytes = bcw.unwrap();
+        let err = Program::from_bytecode(&mut MemReader::new(bytes)).unwrap_err();
+        assert_eq!(err.kind, InvalidInput);
+    }
+
+    #[test]
+    fn test_debug_map() {
+        let mut program = Program::new();
+        program.push(ir::StackPush(1));
+        program.push_located(ir::PutNumber, SourceLocation { line: 2, column: 3, token: "TNST".to_string() });
+        assert_eq!(program.location(0), None);
+        assert_eq!(program.location(1).unwrap().line, 2);
+
+        let mut writer = MemWriter::new();
+        program.write_debug_map(&mut writer).unwrap();
+        let map = writer.unwrap();
+        assert_eq!(map.as_slice(), b"1 2 3 TNST\n");
+
+        let mut decoded = Program::from_vec(vec!(ir::StackPush(1), ir::PutNumber));
+        assert_eq!(decoded, program);
+        decoded.read_debug_map(&mut MemReader::new(map)).unwrap();
+        assert_eq!(decoded.location(1), program.location(1));
+
+        let err = decoded.read_debug_map(&mut MemReader::new(b"2 1 1 S\n".to_vec())).unwrap_err();
+        assert_eq!(err.kind, InvalidInput);
+
+        decoded.as_mut_vec().insert(0, ir::Nop);
+        assert_eq!(decoded.location(1), None);
+        assert_eq!(decoded.location(2), None);
+    }
+}
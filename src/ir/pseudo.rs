@@ -0,0 +1,89 @@
+//! Pseudo instructions expanded to core instructions.
+
+#![experimental]
+
+use ir;
+use ir::{Instruction, Program};
+
+/// An instruction of the higher-level layer.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub enum PseudoInstruction {
+    /// A core instruction, kept as is.
+    Core(Instruction),
+    /// Output the string.
+    PrintString(String),
+    /// Push 0 and the characters of the string in reverse order,
+    /// so that the first character is on the top of the stack.
+    PushString(String),
+    /// Store the value at the heap address.
+    SetHeap(i64, i64),
+}
+
+/// Lower pseudo instructions to core instructions.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::pseudo::{expand, Core, PrintString};
+///
+/// let insts = vec!(PrintString("Hi".to_string()), Core(ir::Exit));
+/// assert_eq!(expand(insts.as_slice()), Program::from_vec(vec!(
+///     ir::StackPush(72), ir::PutCharactor,
+///     ir::StackPush(105), ir::PutCharactor,
+///     ir::Exit,
+/// )));
+/// ```
+pub fn expand(insts: &[PseudoInstruction]) -> Program {
+    let mut program = Program::new();
+    for inst in insts.iter() {
+        match *inst {
+            Core(ref inst) => program.push(inst.clone()),
+            PrintString(ref s) => for c in s.as_slice().chars() {
+                program.push(ir::StackPush(c as i64));
+                program.push(ir::PutCharactor);
+            },
+            PushString(ref s) => {
+                program.push(ir::StackPush(0));
+                for c in s.as_slice().chars().rev() {
+                    program.push(ir::StackPush(c as i64));
+                }
+            },
+            SetHeap(addr, value) => {
+                program.push(ir::StackPush(addr));
+                program.push(ir::StackPush(value));
+                program.push(ir::HeapStore);
+            },
+        }
+    }
+    program
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::{expand, Core, PrintString, PushString, SetHeap};
+
+    #[test]
+    fn test_expand() {
+        let insts = vec!(
+            SetHeap(1, 42),
+            PushString("ab".to_string()),
+            PrintString("".to_string()),
+            PrintString("c".to_string()),
+            Core(ir::Exit),
+        );
+        let expected = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::StackPush(42),
+            ir::HeapStore,
+            ir::StackPush(0),
+            ir::StackPush(98),
+            ir::StackPush(97),
+            ir::StackPush(99),
+            ir::PutCharactor,
+            ir::Exit,
+        ));
+        assert_eq!(expand(insts.as_slice()), expected);
+    }
+}
@@ -0,0 +1,124 @@
+//! Static statistics of programs.
+
+#![experimental]
+
+use std::collections::{HashSet, TreeMap};
+use std::io::{MemReader, MemWriter};
+
+use ir;
+use ir::Program;
+use ir::text::mnemonic;
+use syntax::{Assembly, Decompiler, DT, Whitespace};
+
+/// Statistics returned by `stats`.
+#[deriving(PartialEq, Clone, Show)]
+pub struct ProgramStats {
+    /// Number of instructions.
+    pub instructions: uint,
+    /// Number of instructions by mnemonic of `ir::text`.
+    pub counts: TreeMap<&'static str, uint>,
+    /// Number of distinct labels marked.
+    pub labels: uint,
+    /// Largest magnitude of values pushed.
+    pub max_literal: u64,
+    /// Size of the bytecodes in bytes.
+    pub bytecode_size: uint,
+    /// Size of the Whitespace source in bytes, or `None` if the program has
+    /// instructions which Whitespace can't encode, like "EXIT" with a status.
+    pub whitespace_size: Option<uint>,
+    /// Size of the DT source in bytes, or `None` as for `whitespace_size`.
+    pub dt_size: Option<uint>,
+    /// Size of the assembly source in bytes, or `None` if the program has extension
+    /// instructions, which have no names without a registry.
+    pub assembly_size: Option<uint>,
+}
+
+/// Collect statistics of a program.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(-7), ir::PutNumber, ir::StackPush(2), ir::PutNumber, ir::Exit));
+/// let stats = ir::stats(&program);
+/// assert_eq!(stats.counts.find(&"putn"), Some(&2));
+/// assert_eq!(stats.max_literal, 7);
+/// ```
+pub fn stats(program: &Program) -> ProgramStats {
+    let mut counts = TreeMap::new();
+    let mut labels = HashSet::new();
+    let mut max_literal = 0u64;
+    for inst in program.iter() {
+        let (name, _) = mnemonic(inst);
+        let count = match counts.find(&name) { Some(n) => *n, None => 0u };
+        counts.insert(name, count + 1);
+        match *inst {
+            ir::Mark(label) => { labels.insert(label); },
+            ir::StackPush(n) => {
+                let magnitude = if n < 0 { (-(n + 1)) as u64 + 1 } else { n as u64 };
+                if magnitude > max_literal { max_literal = magnitude; }
+            },
+            _ => (),
+        }
+    }
+    let bytecode = program.to_bytecode().unwrap();
+    ProgramStats {
+        instructions: program.len(),
+        counts: counts,
+        labels: labels.len(),
+        max_literal: max_literal,
+        bytecode_size: bytecode.len(),
+        whitespace_size: encoded_size(&Whitespace::new(), bytecode.as_slice()),
+        dt_size: encoded_size(&DT::new(), bytecode.as_slice()),
+        assembly_size: encoded_size(&Assembly::new(), bytecode.as_slice()),
+    }
+}
+
+fn encoded_size<D: Decompiler>(decompiler: &D, bytecode: &[u8]) -> Option<uint> {
+    let mut writer = MemWriter::new();
+    match decompiler.decompile(&mut MemReader::new(bytecode.to_vec()), &mut writer) {
+        Ok(()) => Some(writer.unwrap().len()),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::stats;
+
+    #[test]
+    fn test_stats() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(1),
+            ir::StackPush(1),
+            ir::StackPush(-300),
+            ir::Mark(2),
+            ir::Mark(1),
+            ir::Jump(1),
+            ir::Exit,
+        ));
+        let stats = stats(&program);
+        assert_eq!(stats.instructions, 7);
+        assert_eq!(stats.counts.find(&"mark"), Some(&3));
+        assert_eq!(stats.counts.find(&"push"), Some(&2));
+        assert_eq!(stats.counts.find(&"add"), None);
+        assert_eq!(stats.labels, 2);
+        assert_eq!(stats.max_literal, 300);
+        assert_eq!(stats.bytecode_size, 6 * 9 + 1);
+        assert_eq!(stats.whitespace_size, Some(5 + 13 + 6 + 7 + 6 + 6 + 3));
+        assert_eq!(stats.assembly_size, Some("MARK 1\nPUSH 1\nPUSH -300\nMARK 2\nMARK 1\nJUMP 1\nEXIT\n".len()));
+        assert!(stats.dt_size > stats.whitespace_size);
+
+        let exiting = super::stats(&Program::from_vec(vec!(ir::ExitWith(1))));
+        assert_eq!(exiting.whitespace_size, None);
+        assert_eq!(exiting.dt_size, None);
+        assert_eq!(exiting.assembly_size, Some("EXIT 1\n".len()));
+
+        let extended = super::stats(&Program::from_vec(vec!(ir::Extension(0xF1, 0), ir::Exit)));
+        assert_eq!(extended.counts.find(&"extf1"), Some(&1));
+        assert_eq!(extended.bytecode_size, 9 + 1);
+        assert_eq!(extended.assembly_size, None);
+    }
+}
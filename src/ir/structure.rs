@@ -0,0 +1,324 @@
+//! Structured control flow reconstructed from labels and jumps.
+
+#![experimental]
+
+use std::collections::HashMap;
+
+use ir;
+use ir::{Instruction, Program};
+
+/// Condition on the value popped from the stack.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub enum Condition {
+    /// The value is 0.
+    Zero,
+    /// The value is not 0.
+    NonZero,
+    /// The value is less than 0.
+    Negative,
+    /// The value is 0 or more.
+    NonNegative,
+}
+
+/// A node of the structured program.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub enum Node {
+    /// An instruction other than "MARK", "JUMP", "JUMPZ" and "JUMPN".
+    Simple(Instruction),
+    /// Run the head, pop a value, and leave the loop unless it meets the condition.
+    /// Otherwise run the body and repeat.
+    While(Vec<Node>, Condition, Vec<Node>),
+    /// Repeat the body forever.
+    Loop(Vec<Node>),
+    /// Pop a value, and run the first branch if it meets the condition or the second otherwise.
+    If(Condition, Vec<Node>, Vec<Node>),
+    /// "MARK" which couldn't be structured.
+    Label(i64),
+    /// "JUMP" which couldn't be structured.
+    Goto(i64),
+    /// "JUMPZ" or "JUMPN" which couldn't be structured, jumping if the value meets the condition.
+    GotoIf(Condition, i64),
+}
+
+struct Labels {
+    marks: HashMap<i64, Vec<uint>>,
+    refs: HashMap<i64, Vec<uint>>,
+}
+
+impl Labels {
+    fn new(insts: &[Instruction]) -> Labels {
+        let mut labels = Labels { marks: HashMap::new(), refs: HashMap::new() };
+        for (i, inst) in insts.iter().enumerate() {
+            match *inst {
+                ir::Mark(l) => labels.marks.find_or_insert(l, Vec::new()).push(i),
+                ir::Call(l) | ir::Jump(l) | ir::JumpIfZero(l) | ir::JumpIfNegative(l) => {
+                    labels.refs.find_or_insert(l, Vec::new()).push(i);
+                },
+                _ => (),
+            }
+        }
+        labels
+    }
+
+    // Position of the mark of a label marked once and referenced only from `from`.
+    fn single(&self, label: i64, from: uint) -> Option<uint> {
+        match (self.marks.find(&label), self.refs.find(&label)) {
+            (Some(marks), Some(refs)) if marks.len() == 1 && refs.as_slice() == [from].as_slice() => Some(marks[0]),
+            _ => None,
+        }
+    }
+
+    // Position of the only reference to a label marked only at `at`.
+    fn single_ref(&self, label: i64, at: uint) -> Option<uint> {
+        match (self.marks.find(&label), self.refs.find(&label)) {
+            (Some(marks), Some(refs)) if marks.as_slice() == [at].as_slice() && refs.len() == 1 => Some(refs[0]),
+            _ => None,
+        }
+    }
+}
+
+/// Lift a program into loops and conditionals.
+///
+/// A label is structured only if it's marked once and targeted by one jump,
+/// in the shapes compilers emit for loops and conditionals. Anything else is kept
+/// as `Label`, `Goto` and `GotoIf`, so the result always has the same behavior.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::structure::{lift, If, NonZero, Simple};
+///
+/// let program = Program::from_vec(vec!(ir::JumpIfZero(1), ir::PutNumber, ir::Mark(1), ir::Exit));
+/// assert_eq!(lift(&program), vec!(If(NonZero, vec!(Simple(ir::PutNumber)), vec!()), Simple(ir::Exit)));
+/// ```
+pub fn lift(program: &Program) -> Vec<Node> {
+    let insts = program.as_slice();
+    lift_range(insts, &Labels::new(insts), 0, insts.len())
+}
+
+fn lift_range(insts: &[Instruction], labels: &Labels, start: uint, end: uint) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut i = start;
+    while i < end {
+        let (node, next) = match insts[i] {
+            ir::Mark(head) => match labels.single_ref(head, i) {
+                Some(j) if j > i && j < end && insts[j] == ir::Jump(head) => lift_loop(insts, labels, i, j, end),
+                _ => (Label(head), i + 1),
+            },
+            ir::JumpIfZero(label) | ir::JumpIfNegative(label) => {
+                let cond = if insts[i] == ir::JumpIfZero(label) { NonZero } else { NonNegative };
+                match labels.single(label, i) {
+                    Some(m) if m > i && m < end => lift_if(insts, labels, cond, i, m, end),
+                    _ => (GotoIf(negate(cond), label), i + 1),
+                }
+            },
+            ir::Jump(label) => (Goto(label), i + 1),
+            ref inst => (Simple(inst.clone()), i + 1),
+        };
+        nodes.push(node);
+        i = next;
+    }
+    nodes
+}
+
+// The loop from "MARK" at `i` to "JUMP" at `j`.
+fn lift_loop(insts: &[Instruction], labels: &Labels, i: uint, j: uint, end: uint) -> (Node, uint) {
+    // a conditional jump out of the loop to the mark right after it
+    if j + 1 < end {
+        match insts[j + 1] {
+            ir::Mark(exit) => match labels.single_ref(exit, j + 1) {
+                Some(k) if k > i && k < j => {
+                    let cond = match insts[k] {
+                        ir::JumpIfZero(l) if l == exit => Some(NonZero),
+                        ir::JumpIfNegative(l) if l == exit => Some(NonNegative),
+                        _ => None,
+                    };
+                    match cond {
+                        Some(cond) => {
+                            let head = lift_range(insts, labels, i + 1, k);
+                            let body = lift_range(insts, labels, k + 1, j);
+                            return (While(head, cond, body), j + 2)
+                        },
+                        None => (),
+                    }
+                },
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    (Loop(lift_range(insts, labels, i + 1, j)), j + 1)
+}
+
+// The conditional from the jump at `i` over to "MARK" at `m`.
+fn lift_if(insts: &[Instruction], labels: &Labels, cond: Condition, i: uint, m: uint, end: uint) -> (Node, uint) {
+    // "JUMP" over the else branch at the end of the then branch
+    if m > i + 1 {
+        match insts[m - 1] {
+            ir::Jump(join) => match labels.single(join, m - 1) {
+                Some(e) if e > m && e < end => {
+                    let then = lift_range(insts, labels, i + 1, m - 1);
+                    let other = lift_range(insts, labels, m + 1, e);
+                    return (If(cond, then, other), e + 1)
+                },
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    (If(cond, lift_range(insts, labels, i + 1, m), vec!()), m + 1)
+}
+
+fn negate(cond: Condition) -> Condition {
+    match cond {
+        Zero        => NonZero,
+        NonZero     => Zero,
+        Negative    => NonNegative,
+        NonNegative => Negative,
+    }
+}
+
+/// Flatten structured nodes back into a program.
+///
+/// Labels of loops and conditionals are numbered from the largest label
+/// of `Label`, `Goto`, `GotoIf` and "CALL" plus 1.
+pub fn lower(nodes: &[Node]) -> Program {
+    let mut next = max_label(nodes).map(|n| n + 1).unwrap_or(0);
+    let mut program = Program::new();
+    lower_into(nodes, &mut next, &mut program);
+    program
+}
+
+fn max_label(nodes: &[Node]) -> Option<i64> {
+    nodes.iter().filter_map(|node| match *node {
+        Label(l) | Goto(l) | GotoIf(_, l) | Simple(ir::Call(l)) => Some(l),
+        While(ref head, _, ref body) => match (max_label(head.as_slice()), max_label(body.as_slice())) {
+            (Some(a), Some(b)) => Some(if a > b { a } else { b }),
+            (a, b) => a.or(b),
+        },
+        Loop(ref body) => max_label(body.as_slice()),
+        If(_, ref then, ref other) => match (max_label(then.as_slice()), max_label(other.as_slice())) {
+            (Some(a), Some(b)) => Some(if a > b { a } else { b }),
+            (a, b) => a.or(b),
+        },
+        _ => None,
+    }).max()
+}
+
+// Jump to the label unless the popped value meets the condition.
+fn jump_unless(cond: Condition, label: i64, next: &mut i64, program: &mut Program) {
+    match cond {
+        NonZero     => program.push(ir::JumpIfZero(label)),
+        NonNegative => program.push(ir::JumpIfNegative(label)),
+        Zero | Negative => {
+            let skip = *next;
+            *next += 1;
+            program.push(if cond == Zero { ir::JumpIfZero(skip) } else { ir::JumpIfNegative(skip) });
+            program.push(ir::Jump(label));
+            program.push(ir::Mark(skip));
+        },
+    }
+}
+
+fn lower_into(nodes: &[Node], next: &mut i64, program: &mut Program) {
+    for node in nodes.iter() {
+        match *node {
+            Simple(ref inst) => program.push(inst.clone()),
+            Label(l) => program.push(ir::Mark(l)),
+            Goto(l) => program.push(ir::Jump(l)),
+            GotoIf(ref cond, l) => jump_unless(negate(cond.clone()), l, next, program),
+            While(ref head, ref cond, ref body) => {
+                let (top, exit) = (*next, *next + 1);
+                *next += 2;
+                program.push(ir::Mark(top));
+                lower_into(head.as_slice(), next, program);
+                jump_unless(cond.clone(), exit, next, program);
+                lower_into(body.as_slice(), next, program);
+                program.push(ir::Jump(top));
+                program.push(ir::Mark(exit));
+            },
+            Loop(ref body) => {
+                let top = *next;
+                *next += 1;
+                program.push(ir::Mark(top));
+                lower_into(body.as_slice(), next, program);
+                program.push(ir::Jump(top));
+            },
+            If(ref cond, ref then, ref other) => {
+                let (otherwise, join) = (*next, *next + 1);
+                *next += 2;
+                jump_unless(cond.clone(), otherwise, next, program);
+                lower_into(then.as_slice(), next, program);
+                if other.is_empty() {
+                    program.push(ir::Mark(otherwise));
+                } else {
+                    program.push(ir::Jump(join));
+                    program.push(ir::Mark(otherwise));
+                    lower_into(other.as_slice(), next, program);
+                    program.push(ir::Mark(join));
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, MemReader, MemWriter};
+
+    use ir;
+    use ir::{equiv, Program};
+    use syntax::{Brainfuck, Compiler};
+    use super::{lift, lower, If, Label, Loop, NonNegative, NonZero, Simple, While, Goto, GotoIf, Zero};
+
+    #[test]
+    fn test_lift() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(1),
+            ir::GetNumber,
+            ir::JumpIfNegative(2),
+            ir::StackPush(1),
+            ir::JumpIfZero(3),
+            ir::PutNumber,
+            ir::Jump(4),
+            ir::Mark(3),
+            ir::PutCharactor,
+            ir::Mark(4),
+            ir::Jump(1),
+            ir::Mark(2),
+            ir::Mark(5),
+            ir::Jump(5),
+        ));
+        assert_eq!(lift(&program), vec!(
+            While(vec!(Simple(ir::GetNumber)), NonNegative, vec!(
+                Simple(ir::StackPush(1)),
+                If(NonZero, vec!(Simple(ir::PutNumber)), vec!(Simple(ir::PutCharactor))),
+            )),
+            Loop(vec!()),
+        ));
+    }
+
+    #[test]
+    fn test_unstructured() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(1),
+            ir::JumpIfZero(1),
+            ir::Jump(2),
+            ir::Mark(2),
+            ir::Jump(2),
+        ));
+        let nodes = lift(&program);
+        assert_eq!(nodes, vec!(Label(1), GotoIf(Zero, 1), Goto(2), Label(2), Goto(2)));
+        assert_eq!(lower(nodes.as_slice()), program);
+    }
+
+    #[test]
+    fn test_lower_brainfuck() {
+        let source = "++[>+++[>++<-]<-]>>.,[-.]";
+        let mut writer = MemWriter::new();
+        Brainfuck::new().compile(&mut BufReader::new(source.as_bytes()), &mut writer).unwrap();
+        let program = Program::from_bytecode(&mut MemReader::new(writer.unwrap())).unwrap();
+        let lowered = lower(lift(&program).as_slice());
+        assert!(equiv::check(&program, &lowered, &[b"a".to_vec(), b"\x03".to_vec()]).is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+//! Instructions with named labels.
+
+#![experimental]
+
+use std::collections::{HashMap, HashSet};
+
+use bytecode::image::LabelNames;
+use ir;
+use ir::{Instruction, Program};
+
+/// An instruction whose label may be a name instead of a number.
+#[deriving(PartialEq, Eq, Clone, Hash, Show)]
+pub enum SymbolicInstruction {
+    /// An instruction without named labels.
+    Plain(Instruction),
+    /// "MARK" with a named label.
+    NamedMark(String),
+    /// "CALL" with a named label.
+    NamedCall(String),
+    /// "JUMP" with a named label.
+    NamedJump(String),
+    /// "JUMPZ" with a named label.
+    NamedJumpIfZero(String),
+    /// "JUMPN" with a named label.
+    NamedJumpIfNegative(String),
+}
+
+/// Map named labels to numeric labels, in order of first appearance.
+///
+/// Numbers are taken from 0 upward, skipping those already used by plain instructions.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::symbolic::{resolve, Plain, NamedMark, NamedJump};
+///
+/// let insts = vec!(Plain(ir::Mark(0)), NamedMark("loop".to_string()), NamedJump("loop".to_string()));
+/// assert_eq!(resolve(insts.as_slice()), Program::from_vec(vec!(ir::Mark(0), ir::Mark(1), ir::Jump(1))));
+/// ```
+pub fn resolve(insts: &[SymbolicInstruction]) -> Program {
+    let (program, _) = resolve_with_names(insts);
+    program
+}
+
+/// Map named labels to numeric labels as `resolve` does, also returning the names
+/// of the numbers, which can be stored in a bytecode image.
+///
+/// ```rust
+/// use whitebase::ir::symbolic::{resolve_with_names, NamedMark};
+///
+/// let (_, names) = resolve_with_names([NamedMark("main".to_string())]);
+/// assert_eq!(names.find(&0), Some(&"main".to_string()));
+/// ```
+pub fn resolve_with_names(insts: &[SymbolicInstruction]) -> (Program, LabelNames) {
+    let used: HashSet<i64> = insts.iter().filter_map(|inst| match *inst {
+        Plain(ir::Mark(n)) | Plain(ir::Call(n)) | Plain(ir::Jump(n)) |
+        Plain(ir::JumpIfZero(n)) | Plain(ir::JumpIfNegative(n)) => Some(n),
+        _ => None,
+    }).collect();
+    let mut names: HashMap<String, i64> = HashMap::new();
+    let mut next = 0i64;
+    let program = insts.iter().map(|inst| {
+        let label = |name: &String| {
+            match names.find_copy(name) {
+                Some(n) => return n,
+                None => (),
+            }
+            while used.contains(&next) { next += 1; }
+            names.insert(name.clone(), next);
+            next += 1;
+            next - 1
+        };
+        match *inst {
+            Plain(ref inst)                => inst.clone(),
+            NamedMark(ref name)            => ir::Mark(label(name)),
+            NamedCall(ref name)            => ir::Call(label(name)),
+            NamedJump(ref name)            => ir::Jump(label(name)),
+            NamedJumpIfZero(ref name)      => ir::JumpIfZero(label(name)),
+            NamedJumpIfNegative(ref name)  => ir::JumpIfNegative(label(name)),
+        }
+    }).collect();
+    let labels = names.move_iter().map(|(name, n)| (n, name)).collect();
+    (program, labels)
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::{resolve, resolve_with_names, Plain, NamedMark, NamedCall, NamedJump, NamedJumpIfZero, NamedJumpIfNegative};
+
+    #[test]
+    fn test_resolve() {
+        let insts = vec!(
+            NamedCall("main".to_string()),
+            Plain(ir::Exit),
+            NamedMark("main".to_string()),
+            Plain(ir::GetNumber),
+            NamedJumpIfZero("end".to_string()),
+            NamedJumpIfNegative("main".to_string()),
+            Plain(ir::Jump(1)),
+            NamedJump("end".to_string()),
+            Plain(ir::Mark(1)),
+            NamedMark("end".to_string()),
+            Plain(ir::Return),
+        );
+        let expected = Program::from_vec(vec!(
+            ir::Call(0),
+            ir::Exit,
+            ir::Mark(0),
+            ir::GetNumber,
+            ir::JumpIfZero(2),
+            ir::JumpIfNegative(0),
+            ir::Jump(1),
+            ir::Jump(2),
+            ir::Mark(1),
+            ir::Mark(2),
+            ir::Return,
+        ));
+        assert_eq!(resolve(insts.as_slice()), expected);
+
+        let (program, names) = resolve_with_names(insts.as_slice());
+        assert_eq!(program, expected);
+        assert_eq!(names.find(&0), Some(&"main".to_string()));
+        assert_eq!(names.find(&1), None);
+        assert_eq!(names.find(&2), Some(&"end".to_string()));
+    }
+}
@@ -0,0 +1,272 @@
+//! Canonical textual form of programs.
+//!
+//! The text starts with the header line `whitebase-ir 1`, naming the format version,
+//! followed by one instruction per line. Blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! whitebase-ir 1
+//! push 42
+//! putn
+//! exit
+//! ```
+
+#![experimental]
+
+use std::from_str::FromStr;
+use std::io::{BufReader, EndOfFile, InvalidInput, IoError, IoResult, MemWriter};
+
+use bytecode::{is_extension, CMD_EXT_MIN, CMD_FORK};
+use ir;
+use ir::{Instruction, Program};
+
+/// Version of the textual form written by this module.
+pub static VERSION: uint = 1;
+
+static HEADER: &'static str = "whitebase-ir";
+
+// Mnemonics of extension instructions by their opcodes from `bytecode::CMD_EXT_MIN`,
+// the last of which is "FORK".
+static EXTENSIONS: &'static [&'static str] = &[
+    "extf0", "extf1", "extf2", "extf3", "extf4", "extf5", "extf6", "extf7",
+    "extf8", "extf9", "extfa", "extfb", "extfc", "extfd", "extfe", "fork",
+];
+
+/// Write a program in the textual form.
+pub fn write<W: Writer>(program: &Program, output: &mut W) -> IoResult<()> {
+    try!(write!(output, "{} {}\n", HEADER, VERSION));
+    for inst in program.iter() {
+        try!(match mnemonic(inst) {
+            (name, Some(n)) => write!(output, "{} {}\n", name, n),
+            (name, None)    => output.write_line(name),
+        });
+    }
+    Ok(())
+}
+
+/// Read a program in the textual form.
+///
+/// # Error
+///
+/// Returns `InvalidInput` if the header is missing, the version is unsupported,
+/// or a line is not an instruction.
+pub fn read<B: Buffer>(input: &mut B) -> IoResult<Program> {
+    let mut program = Program::new();
+    let mut header = false;
+    let mut lineno = 0u;
+    loop {
+        let line = match input.read_line() {
+            Ok(line) => line,
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => return Err(e),
+        };
+        lineno += 1;
+        let line = line.as_slice().trim();
+        if line.len() == 0 || line.starts_with("#") { continue }
+        let words: Vec<&str> = line.words().collect();
+        if !header {
+            match words.as_slice() {
+                [name, version] if name == HEADER => {
+                    let parsed: Option<uint> = FromStr::from_str(version);
+                    if parsed != Some(VERSION) {
+                        return Err(syntax_error(lineno, format!("unsupported version {}", version)));
+                    }
+                },
+                _ => return Err(syntax_error(lineno, format!("expected header, but {}", line))),
+            }
+            header = true;
+            continue
+        }
+        program.push(try!(parse_instruction(words.as_slice(), lineno)));
+    }
+    if !header {
+        return Err(syntax_error(lineno, "missing header".to_string()));
+    }
+    Ok(program)
+}
+
+/// Return a program in the textual form.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::text;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(42), ir::PutNumber, ir::Exit));
+/// let source = text::to_string(&program);
+/// assert_eq!(source.as_slice(), "whitebase-ir 1\npush 42\nputn\nexit\n");
+/// assert_eq!(text::from_str(source.as_slice()), Ok(program));
+/// ```
+pub fn to_string(program: &Program) -> String {
+    let mut writer = MemWriter::new();
+    write(program, &mut writer).unwrap();
+    String::from_utf8(writer.unwrap()).unwrap()
+}
+
+/// Parse a program in the textual form.
+pub fn from_str(source: &str) -> IoResult<Program> {
+    read(&mut BufReader::new(source.as_bytes()))
+}
+
+/// Return the mnemonic and the operand of an instruction in the textual form.
+///
+/// Extension instructions are named by their opcodes like `extf1`, and "FORK"
+/// as `fork` without the operand.
+pub fn mnemonic(inst: &Instruction) -> (&'static str, Option<i64>) {
+    match *inst {
+        ir::StackPush(n)      => ("push", Some(n)),
+        ir::StackDuplicate    => ("dup", None),
+        ir::StackCopy(n)      => ("copy", Some(n)),
+        ir::StackSwap         => ("swap", None),
+        ir::StackDiscard      => ("discard", None),
+        ir::StackSlide(n)     => ("slide", Some(n)),
+        ir::Addition          => ("add", None),
+        ir::Subtraction       => ("sub", None),
+        ir::Multiplication    => ("mul", None),
+        ir::Division          => ("div", None),
+        ir::Modulo            => ("mod", None),
+        ir::HeapStore         => ("store", None),
+        ir::HeapRetrieve      => ("retrieve", None),
+        ir::Mark(n)           => ("mark", Some(n)),
+        ir::Call(n)           => ("call", Some(n)),
+        ir::Jump(n)           => ("jump", Some(n)),
+        ir::JumpIfZero(n)     => ("jz", Some(n)),
+        ir::JumpIfNegative(n) => ("jn", Some(n)),
+        ir::Return            => ("ret", None),
+        ir::Exit              => ("exit", None),
+        ir::PutCharactor      => ("putc", None),
+        ir::PutNumber         => ("putn", None),
+        ir::GetCharactor      => ("getc", None),
+        ir::GetNumber         => ("getn", None),
+        ir::Nop               => ("nop", None),
+        ir::ExitWith(n)       => ("exit", Some(n)),
+        ir::Extension(op, _) if op == CMD_FORK => ("fork", None),
+        ir::Extension(op, n) if is_extension(op) => (EXTENSIONS[(op - CMD_EXT_MIN) as uint], Some(n)),
+        // not an extension opcode, which can't be encoded either
+        ir::Extension(_, n)   => ("ext", Some(n)),
+    }
+}
+
+/// Build an instruction from a mnemonic and an operand, or `None` if they don't match any.
+pub fn instruction(mnemonic: &str, arg: Option<i64>) -> Option<Instruction> {
+    let inst = match (mnemonic, arg) {
+        ("push", Some(n))  => ir::StackPush(n),
+        ("dup", None)      => ir::StackDuplicate,
+        ("copy", Some(n))  => ir::StackCopy(n),
+        ("swap", None)     => ir::StackSwap,
+        ("discard", None)  => ir::StackDiscard,
+        ("slide", Some(n)) => ir::StackSlide(n),
+        ("add", None)      => ir::Addition,
+        ("sub", None)      => ir::Subtraction,
+        ("mul", None)      => ir::Multiplication,
+        ("div", None)      => ir::Division,
+        ("mod", None)      => ir::Modulo,
+        ("store", None)    => ir::HeapStore,
+        ("retrieve", None) => ir::HeapRetrieve,
+        ("mark", Some(n))  => ir::Mark(n),
+        ("call", Some(n))  => ir::Call(n),
+        ("jump", Some(n))  => ir::Jump(n),
+        ("jz", Some(n))    => ir::JumpIfZero(n),
+        ("jn", Some(n))    => ir::JumpIfNegative(n),
+        ("ret", None)      => ir::Return,
+        ("exit", None)     => ir::Exit,
+        ("putc", None)     => ir::PutCharactor,
+        ("putn", None)     => ir::PutNumber,
+        ("getc", None)     => ir::GetCharactor,
+        ("getn", None)     => ir::GetNumber,
+        ("nop", None)      => ir::Nop,
+        ("exit", Some(n))  => ir::ExitWith(n),
+        ("fork", None)     => ir::Extension(CMD_FORK, 0),
+        (name, Some(n))    => match EXTENSIONS.iter().position(|ext| *ext == name) {
+            Some(i) if i < EXTENSIONS.len() - 1 => ir::Extension(CMD_EXT_MIN + i as u8, n),
+            _ => return None,
+        },
+        _                  => return None,
+    };
+    Some(inst)
+}
+
+fn parse_instruction(words: &[&str], lineno: uint) -> IoResult<Instruction> {
+    let (name, arg) = match words {
+        [name] => (name, None),
+        [name, arg] => match FromStr::from_str(arg) {
+            Some(n) => (name, Some(n)),
+            None => return Err(syntax_error(lineno, format!("expected number, but {}", arg))),
+        },
+        _ => return Err(syntax_error(lineno, format!("unexpected operands: {}", words.connect(" ")))),
+    };
+    match instruction(name, arg) {
+        Some(inst) => Ok(inst),
+        None => Err(syntax_error(lineno, format!("invalid instruction: {}", words.connect(" ")))),
+    }
+}
+
+fn syntax_error(lineno: uint, detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "syntax error",
+        detail: Some(format!("line {}: {}", lineno, detail)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::InvalidInput;
+
+    use ir;
+    use ir::Program;
+    use super::{from_str, to_string};
+
+    #[test]
+    fn test_roundtrip() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(-1),
+            ir::StackDuplicate,
+            ir::StackCopy(2),
+            ir::StackSwap,
+            ir::StackDiscard,
+            ir::StackSlide(3),
+            ir::Addition,
+            ir::Subtraction,
+            ir::Multiplication,
+            ir::Division,
+            ir::Modulo,
+            ir::HeapStore,
+            ir::HeapRetrieve,
+            ir::Mark(1),
+            ir::Call(2),
+            ir::Jump(3),
+            ir::JumpIfZero(4),
+            ir::JumpIfNegative(5),
+            ir::Return,
+            ir::Exit,
+            ir::PutCharactor,
+            ir::PutNumber,
+            ir::GetCharactor,
+            ir::GetNumber,
+            ir::Nop,
+            ir::ExitWith(3),
+            ir::Extension(0xF1, -2),
+            ir::Extension(0xFF, 0),
+        ));
+        assert_eq!(from_str(to_string(&program).as_slice()), Ok(program));
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = "# generated\nwhitebase-ir 1\n\n  push 1 \n# done\nexit\n";
+        assert_eq!(from_str(source), Ok(Program::from_vec(vec!(ir::StackPush(1), ir::Exit))));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(from_str("push 1\n").unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str("whitebase-ir 2\n").unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str("").unwrap_err().kind, InvalidInput);
+        let err = from_str("whitebase-ir 1\npush\n").unwrap_err();
+        assert_eq!(err.kind, InvalidInput);
+        assert_eq!(err.detail, Some("line 2: invalid instruction: push".to_string()));
+        assert_eq!(from_str("whitebase-ir 1\npush x\n").unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str("whitebase-ir 1\nnop 1\n").unwrap_err().kind, InvalidInput);
+        assert_eq!(from_str("whitebase-ir 1\nfork 1\n").unwrap_err().kind, InvalidInput);
+    }
+}
@@ -0,0 +1,318 @@
+//! Static verification of programs.
+
+#![experimental]
+
+use std::collections::HashMap;
+
+use ir;
+use ir::{stack_effect, Program};
+
+/// A problem found by verification. Positions are indices of instructions.
+#[deriving(PartialEq, Clone, Show)]
+pub enum VerifyError {
+    /// The instruction at the position may pop the empty stack,
+    /// when the stack has the given minimum depth.
+    StackUnderflow(uint, uint),
+    /// The jump or call at the position targets the label, which is never marked.
+    UndefinedLabel(uint, i64),
+    /// The label is marked at both positions.
+    DuplicateLabel(i64, uint, uint),
+}
+
+/// Run all checks, returning the problems found.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::verify::{verify, StackUnderflow};
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(1), ir::Addition, ir::Exit));
+/// assert_eq!(verify(&program), Err(vec!(StackUnderflow(1, 1))));
+/// ```
+pub fn verify(program: &Program) -> Result<(), Vec<VerifyError>> {
+    let mut errors = check_labels(program);
+    errors.push_all(check_stack(program).as_slice());
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Report jumps and calls to labels which have no "MARK", and labels marked more than once.
+pub fn check_labels(program: &Program) -> Vec<VerifyError> {
+    let insts = program.as_slice();
+    let mut marks: HashMap<i64, uint> = HashMap::new();
+    let mut errors = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Mark(label) => match marks.find_copy(&label) {
+                Some(first) => errors.push(DuplicateLabel(label, first, i)),
+                None => { marks.insert(label, i); },
+            },
+            _ => (),
+        }
+    }
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Call(label) | ir::Jump(label) |
+            ir::JumpIfZero(label) | ir::JumpIfNegative(label) if !marks.contains_key(&label) => {
+                errors.push(UndefinedLabel(i, label));
+            },
+            _ => (),
+        }
+    }
+    errors
+}
+
+/// Walk the control flow graph tracking the minimum stack depth, and report
+/// instructions which may pop the empty stack.
+///
+/// "RETURN" is assumed to return to any "CALL", so a subroutine called with
+/// different stack depths is checked with the smallest one.
+pub fn check_stack(program: &Program) -> Vec<VerifyError> {
+    let insts = program.as_slice();
+    let mut marks: HashMap<i64, Vec<uint>> = HashMap::new();
+    let mut returns = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Mark(label) => marks.find_or_insert(label, Vec::new()).push(i),
+            ir::Call(_) => returns.push(i + 1),
+            _ => (),
+        }
+    }
+
+    let mut depths: Vec<Option<uint>> = Vec::from_elem(insts.len(), None);
+    let mut work = vec!((0u, 0u));
+    loop {
+        let (i, depth) = match work.pop() {
+            Some((i, _)) if i >= insts.len() => continue,
+            Some(item) => item,
+            None => break,
+        };
+        match depths[i] {
+            Some(known) if known <= depth => continue,
+            _ => depths.as_mut_slice()[i] = Some(depth),
+        }
+        let after = stack_effect(&insts[i]).apply(depth);
+        let targets = |label: &i64| marks.find(label).map(|v| v.clone()).unwrap_or(Vec::new());
+        match insts[i] {
+            ir::Exit | ir::ExitWith(_) => (),
+            ir::Return => work.extend(returns.iter().map(|r| (*r, after))),
+            ir::Jump(ref label) | ir::Call(ref label) => work.extend(targets(label).move_iter().map(|t| (t, after))),
+            ir::JumpIfZero(ref label) | ir::JumpIfNegative(ref label) => {
+                work.extend(targets(label).move_iter().map(|t| (t, after)));
+                work.push((i + 1, after));
+            },
+            _ => work.push((i + 1, after)),
+        }
+    }
+
+    let mut errors = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match depths[i] {
+            Some(depth) => {
+                if depth < stack_effect(inst).requires { errors.push(StackUnderflow(i, depth)); }
+            },
+            None => (),
+        }
+    }
+    errors
+}
+
+/// Report loops which obviously never terminate, by the position of the
+/// first instruction of each loop.
+///
+/// A loop is reported when it consists of only "MARK", "JUMP", and conditional
+/// jumps on a pushed constant, so it neither changes the state nor does I/O.
+/// These are warnings and not checked by `verify`.
+///
+/// ```rust
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::verify::check_termination;
+///
+/// let program = Program::from_vec(vec!(ir::StackPush(1), ir::Mark(0), ir::Jump(0)));
+/// assert_eq!(check_termination(&program), vec!(1));
+/// ```
+pub fn check_termination(program: &Program) -> Vec<uint> {
+    let insts = program.as_slice();
+    let mut marks: HashMap<i64, uint> = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match *inst {
+            ir::Mark(label) => { marks.find_or_insert(label, i); },
+            _ => (),
+        }
+    }
+    let jump = |label: i64| marks.find_copy(&label);
+
+    // the next instruction of each one which neither changes the state nor does I/O
+    let mut next: Vec<Option<uint>> = Vec::from_elem(insts.len(), None);
+    let mut preds: Vec<Vec<uint>> = Vec::from_fn(insts.len(), |_| Vec::new());
+    for i in range(0, insts.len()) {
+        let to = match (insts[i].clone(), insts.get(i + 1)) {
+            (ir::Mark(_), _) => Some(i + 1),
+            (ir::Jump(label), _) => jump(label),
+            (ir::StackPush(n), Some(&ir::JumpIfZero(label))) => if n == 0 { jump(label) } else { Some(i + 2) },
+            (ir::StackPush(n), Some(&ir::JumpIfNegative(label))) => if n < 0 { jump(label) } else { Some(i + 2) },
+            _ => None,
+        };
+        match to {
+            Some(j) if j < insts.len() => {
+                next.as_mut_slice()[i] = Some(j);
+                preds.get_mut(j).push(i);
+            },
+            _ => (),
+        }
+    }
+
+    // instructions reaching one which leaves the chain, by reverse search from them
+    let mut terminates = Vec::from_elem(insts.len(), false);
+    let mut work: Vec<uint> = range(0, insts.len()).filter(|&i| next[i].is_none()).collect();
+    for &i in work.iter() { terminates.as_mut_slice()[i] = true; }
+    loop {
+        let i = match work.pop() {
+            Some(i) => i,
+            None => break,
+        };
+        for &p in preds[i].iter() {
+            if !terminates[p] {
+                terminates.as_mut_slice()[p] = true;
+                work.push(p);
+            }
+        }
+    }
+
+    // the rest run into a loop, which is reported once from its first instruction
+    let mut seen = Vec::from_elem(insts.len(), false);
+    let mut loops = Vec::new();
+    for start in range(0, insts.len()) {
+        if terminates[start] || seen[start] { continue }
+        let mut path = Vec::new();
+        let mut i = start;
+        while !seen[i] {
+            seen.as_mut_slice()[i] = true;
+            path.push(i);
+            i = next[i].unwrap();
+        }
+        match path.iter().position(|&p| p == i) {
+            Some(k) => loops.push(*path.slice_from(k).iter().min().unwrap()),
+            None => (),
+        }
+    }
+    loops.sort();
+    loops
+}
+
+#[cfg(test)]
+mod test {
+    use ir;
+    use ir::Program;
+    use super::{check_labels, check_stack, check_termination, verify, DuplicateLabel, StackUnderflow, UndefinedLabel};
+
+    #[test]
+    fn test_straight_line() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::StackPush(2),
+            ir::StackSwap,
+            ir::StackCopy(1),
+            ir::StackSlide(2),
+            ir::PutNumber,
+            ir::StackDiscard,
+            ir::Exit,
+        ));
+        assert_eq!(check_stack(&program), vec!(StackUnderflow(6, 0)));
+    }
+
+    #[test]
+    fn test_branches() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(0),
+            ir::JumpIfZero(1),
+            ir::StackPush(5),
+            ir::Mark(1),
+            ir::PutNumber,
+            ir::Exit,
+        ));
+        assert_eq!(verify(&program), Err(vec!(StackUnderflow(4, 0))));
+    }
+
+    #[test]
+    fn test_subroutine() {
+        let program = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::Call(1),
+            ir::Call(1),
+            ir::Exit,
+            ir::Mark(1),
+            ir::PutNumber,
+            ir::Return,
+        ));
+        assert_eq!(verify(&program), Err(vec!(StackUnderflow(5, 0))));
+
+        let program = Program::from_vec(vec!(
+            ir::StackPush(1),
+            ir::Call(1),
+            ir::Exit,
+            ir::Mark(1),
+            ir::PutNumber,
+            ir::Return,
+        ));
+        assert_eq!(verify(&program), Ok(()));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let program = Program::from_vec(vec!(ir::Exit, ir::Addition));
+        assert_eq!(verify(&program), Ok(()));
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(1),
+            ir::Call(2),
+            ir::Jump(1),
+            ir::StackPush(0),
+            ir::JumpIfZero(3),
+            ir::Exit,
+        ));
+        assert_eq!(check_labels(&program), vec!(UndefinedLabel(1, 2), UndefinedLabel(4, 3)));
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(1),
+            ir::Mark(2),
+            ir::Mark(1),
+            ir::Jump(1),
+            ir::Mark(1),
+        ));
+        assert_eq!(verify(&program), Err(vec!(DuplicateLabel(1, 0, 2), DuplicateLabel(1, 0, 4))));
+    }
+
+    #[test]
+    fn test_termination() {
+        let program = Program::from_vec(vec!(
+            ir::Mark(0),
+            ir::GetCharactor,
+            ir::Jump(0),
+            ir::Mark(1),
+            ir::Mark(2),
+            ir::StackPush(0),
+            ir::JumpIfNegative(0),
+            ir::Jump(3),
+            ir::Mark(3),
+            ir::StackPush(0),
+            ir::JumpIfZero(1),
+            ir::Exit,
+        ));
+        assert_eq!(check_termination(&program), vec!(3));
+
+        let program = Program::from_vec(vec!(
+            ir::Mark(0),
+            ir::StackPush(1),
+            ir::JumpIfZero(0),
+            ir::Exit,
+        ));
+        assert_eq!(check_termination(&program), vec!());
+    }
+}
@@ -33,10 +33,15 @@ fn main() {
 #![crate_name="whitebase"]
 #![crate_type="rlib"]
 #![warn(missing_doc)]
-#![feature(phase, globs, macro_rules)]
+#![feature(phase, globs, macro_rules, default_type_params)]
 #![experimental]
 
 #[phase(plugin, link)] extern crate log;
+#[cfg(feature = "quickcheck")] extern crate quickcheck;
+extern crate flate;
+extern crate num;
+extern crate serialize;
+extern crate time;
 
 pub static VERSION_MAJOR: uint = 0;
 pub static VERSION_MINOR: uint = 1;
@@ -0,0 +1,122 @@
+//! Call-stack backtraces of the virtual machine.
+
+#![experimental]
+
+use std::fmt;
+use std::io::IoResult;
+
+use bytecode::image::SourceMap;
+
+/// A "CALL" frame which was active when an error occurred.
+#[deriving(PartialEq, Clone, Show)]
+pub struct Frame {
+    /// Position to return to by "RETURN".
+    pub return_to: u64,
+    /// Called label, or `None` if the frame was restored from a snapshot.
+    pub target: Option<i64>,
+}
+
+/// Call stack captured when execution failed, attached to the returned error.
+///
+/// ```text
+/// error at offset 56
+///   in label 2, returning to offset 28
+///   in label 1, returning to offset 9
+/// ```
+#[deriving(PartialEq, Clone)]
+pub struct Backtrace {
+    /// Position of the failed instruction.
+    pub pc: u64,
+    /// Active frames, innermost first.
+    pub frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    /// Write the backtrace as `Show` does, followed by positions in the source code
+    /// of the offsets found in the source map.
+    ///
+    /// ```text
+    /// error at offset 56 (hello.ws:9:1)
+    ///   in label 2, returning to offset 28 (hello.ws:5:1)
+    /// ```
+    pub fn write_with_source<W: Writer>(&self, output: &mut W, map: &SourceMap) -> IoResult<()> {
+        let at = |offset: u64| match map.describe(offset) {
+            Some(pos) => format!(" ({})", pos),
+            None => String::new(),
+        };
+        try!(writeln!(output, "error at offset {}{}", self.pc, at(self.pc)));
+        for frame in self.frames.iter() {
+            try!(match frame.target {
+                Some(label) => writeln!(output, "  in label {}, returning to offset {}{}",
+                                        label, frame.return_to, at(frame.return_to)),
+                None => writeln!(output, "  in unknown label, returning to offset {}{}",
+                                 frame.return_to, at(frame.return_to)),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Show for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "error at offset {}", self.pc));
+        for frame in self.frames.iter() {
+            try!(match frame.target {
+                Some(label) => writeln!(f, "  in label {}, returning to offset {}", label, frame.return_to),
+                None => writeln!(f, "  in unknown label, returning to offset {}", frame.return_to),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+    use std::io::util::{NullReader, NullWriter};
+    use std::str::from_utf8;
+
+    use bytecode::ByteCodeWriter;
+    use bytecode::image::{SourceMap, SourcePosition};
+    use machine::{Machine, ZeroDivision};
+    use super::{Backtrace, Frame};
+
+    #[test]
+    fn test_backtrace() {
+        let mut bcw = MemWriter::new();
+        bcw.write_call(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_call(2).unwrap();
+        bcw.write_return().unwrap();
+        bcw.write_mark(2).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_div().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = Machine::new(NullReader, NullWriter);
+        let err = vm.run(&mut bcr).unwrap_err();
+        assert_eq!(err, ZeroDivision);
+        let expected = Backtrace {
+            pc: 56,
+            frames: vec!(Frame { return_to: 28, target: Some(2) },
+                         Frame { return_to: 9, target: Some(1) }),
+        };
+        assert_eq!(err.backtrace(), Some(&expected));
+        assert_eq!(vm.backtrace(), Some(&expected));
+        assert_eq!(expected.to_string().as_slice(),
+                   "error at offset 56\n  in label 2, returning to offset 28\n  in label 1, returning to offset 9\n");
+
+        let map = SourceMap {
+            file: "div.ws".to_string(),
+            positions: vec!(SourcePosition { offset: 9, line: 2, column: 1 },
+                            SourcePosition { offset: 56, line: 7, column: 5 }),
+        };
+        let mut writer = MemWriter::new();
+        expected.write_with_source(&mut writer, &map).unwrap();
+        assert_eq!(from_utf8(writer.get_ref()).unwrap(),
+                   "error at offset 56 (div.ws:7:5)\n  in label 2, returning to offset 28 (div.ws:2:1)\n  \
+                    in label 1, returning to offset 9 (div.ws:2:1)\n");
+    }
+}
@@ -0,0 +1,112 @@
+//! Running a program over many inputs in parallel.
+
+#![experimental]
+
+use std::io::{BufReader, MemReader, MemWriter};
+use std::os::num_cpus;
+use std::sync::Arc;
+
+use machine::{Builder, MachineResult, OtherMachineError, StreamIo};
+
+/// Result of a run in a batch.
+#[deriving(PartialEq, Show)]
+pub struct Output {
+    /// Result of `Machine::run`.
+    pub result: MachineResult<i64>,
+    /// Bytes written by the program.
+    pub output: Vec<u8>,
+}
+
+/// Run the bytecode once for each input with the default options, using as many
+/// worker tasks as CPUs.
+///
+/// Each run gets an isolated machine. Outputs are returned in the order of `inputs`.
+///
+/// ```rust
+/// use std::io::MemWriter;
+/// use whitebase::bytecode::ByteCodeWriter;
+/// use whitebase::machine::batch;
+///
+/// let mut bcw = MemWriter::new();
+/// bcw.write_push(0).unwrap();
+/// bcw.write_getn().unwrap();
+/// bcw.write_push(0).unwrap();
+/// bcw.write_retrieve().unwrap();
+/// bcw.write_dup().unwrap();
+/// bcw.write_mul().unwrap();
+/// bcw.write_putn().unwrap();
+/// bcw.write_exit().unwrap();
+///
+/// let outputs = batch::run_all(bcw.unwrap(), vec!(b"3\n".to_vec(), b"12\n".to_vec()));
+/// assert_eq!(outputs[0].output, b"9".to_vec());
+/// assert_eq!(outputs[1].output, b"144".to_vec());
+/// ```
+pub fn run_all(program: Vec<u8>, inputs: Vec<Vec<u8>>) -> Vec<Output> {
+    run_all_with(Builder::new(), num_cpus(), program, inputs)
+}
+
+/// Run the bytecode once for each input on machines built by `builder`,
+/// using `workers` tasks.
+///
+/// The program is shared by all workers. If a worker task dies, each input it has not
+/// finished fails with `OtherMachineError` and no output.
+pub fn run_all_with(builder: Builder, workers: uint, program: Vec<u8>, inputs: Vec<Vec<u8>>) -> Vec<Output> {
+    let workers = if workers == 0 { 1 } else { workers };
+    let len = inputs.len();
+    let mut jobs = Vec::from_fn(workers, |_| Vec::new());
+    for (i, input) in inputs.move_iter().enumerate() {
+        jobs.get_mut(i % workers).push((i, input));
+    }
+    let program = Arc::new(program);
+    let (tx, rx) = channel();
+    for job in jobs.move_iter() {
+        let builder = builder.clone();
+        let program = program.clone();
+        let tx = tx.clone();
+        spawn(proc() {
+            for (i, input) in job.move_iter() {
+                let mut machine = builder.clone().build(StreamIo::new(MemReader::new(input), MemWriter::new()));
+                let result = machine.run(&mut BufReader::new(program.deref().as_slice()));
+                let (_, writer) = machine.io.unwrap();
+                tx.send((i, Output { result: result, output: writer.unwrap() }));
+            }
+        });
+    }
+    drop(tx);
+    let mut outputs = Vec::from_fn(len, |_| None);
+    for (i, output) in rx.iter() {
+        *outputs.get_mut(i) = Some(output);
+    }
+    outputs.move_iter().map(|o| match o {
+        Some(output) => output,
+        None => Output { result: Err(OtherMachineError), output: Vec::new() },
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Builder, EndOfInput};
+    use super::run_all_with;
+
+    #[test]
+    fn test_run_all() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_getc().unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+
+        let inputs = vec!(b"a".to_vec(), b"".to_vec(), b"c".to_vec());
+        let outputs = run_all_with(Builder::new(), 2, bcw.unwrap(), inputs);
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].result, Ok(0));
+        assert_eq!(outputs[0].output, b"a".to_vec());
+        assert_eq!(outputs[1].result, Err(EndOfInput));
+        assert_eq!(outputs[2].output, b"c".to_vec());
+    }
+}
@@ -0,0 +1,161 @@
+//! Builder for configuring a virtual machine.
+
+#![experimental]
+
+use machine::{Cell, CostTable, Machine, MachineIo, StdIo};
+use machine::{CharEncoding, EofBehavior, NumberFormat, UnderflowPolicy, Latin1, EofError, UnderflowError};
+use machine::io::stdio;
+
+/// A builder collecting execution options of `Machine`.
+///
+/// ```rust
+/// use whitebase::machine::{Builder, EofMinusOne, Utf8};
+///
+/// let machine = Builder::new()
+///     .char_encoding(Utf8)
+///     .eof_behavior(EofMinusOne)
+///     .build_stdio();
+/// ```
+#[deriving(Clone)]
+pub struct Builder {
+    number_format: NumberFormat,
+    char_encoding: CharEncoding,
+    eof_behavior: EofBehavior,
+    underflow: UnderflowPolicy,
+    prompt: Option<String>,
+    profiling: bool,
+    tail_calls: bool,
+    implicit_exit: bool,
+    time_slice: Option<uint>,
+    gas: Option<(u64, CostTable)>,
+}
+
+impl Builder {
+    /// Create a new `Builder` with the default options.
+    pub fn new() -> Builder {
+        Builder {
+            number_format: NumberFormat::new(),
+            char_encoding: Latin1,
+            eof_behavior: EofError,
+            underflow: UnderflowError,
+            prompt: None,
+            profiling: false,
+            tail_calls: false,
+            implicit_exit: false,
+            time_slice: None,
+            gas: None,
+        }
+    }
+
+    /// Set how "PUTN" formats numbers.
+    pub fn number_format(mut self, format: NumberFormat) -> Builder {
+        self.number_format = format;
+        self
+    }
+
+    /// Set how "PUTC" converts values to characters.
+    pub fn char_encoding(mut self, encoding: CharEncoding) -> Builder {
+        self.char_encoding = encoding;
+        self
+    }
+
+    /// Set what "GETC" and "GETN" do at the end of input.
+    pub fn eof_behavior(mut self, behavior: EofBehavior) -> Builder {
+        self.eof_behavior = behavior;
+        self
+    }
+
+    /// Set what instructions do when popping the empty stack.
+    pub fn underflow_policy(mut self, policy: UnderflowPolicy) -> Builder {
+        self.underflow = policy;
+        self
+    }
+
+    /// Write a prompt and flush output before "GETC" and "GETN" read input.
+    pub fn prompt(mut self, prompt: String) -> Builder {
+        self.prompt = Some(prompt);
+        self
+    }
+
+    /// Collect an execution profile.
+    pub fn profiling(mut self, enabled: bool) -> Builder {
+        self.profiling = enabled;
+        self
+    }
+
+    /// Reuse the current frame for "CALL" in tail position.
+    pub fn tail_calls(mut self, enabled: bool) -> Builder {
+        self.tail_calls = enabled;
+        self
+    }
+
+    /// Exit cleanly when running off the end of the program.
+    pub fn implicit_exit(mut self, enabled: bool) -> Builder {
+        self.implicit_exit = enabled;
+        self
+    }
+
+    /// Set the number of instructions a thread executes before switching to the next one.
+    pub fn time_slice(mut self, n: uint) -> Builder {
+        self.time_slice = Some(n);
+        self
+    }
+
+    /// Limit execution by a gas budget with the cost of each instruction.
+    pub fn gas(mut self, budget: u64, table: CostTable) -> Builder {
+        self.gas = Some((budget, table));
+        self
+    }
+
+    /// Create a new `Machine` with `MachineIo`.
+    pub fn build<I: MachineIo>(self, io: I) -> Machine<I> { self.build_cells(io) }
+
+    /// Create a new `Machine` of the cell type `C` with `MachineIo`.
+    pub fn build_cells<I: MachineIo, C: Cell>(self, io: I) -> Machine<I, C> {
+        let mut machine = Machine::with_io_cells(io);
+        machine.number_format = self.number_format;
+        machine.char_encoding = self.char_encoding;
+        machine.eof_behavior = self.eof_behavior;
+        machine.underflow = self.underflow;
+        machine.prompt = self.prompt;
+        if self.profiling { machine.enable_profiling(); }
+        machine.set_tail_calls(self.tail_calls);
+        machine.set_implicit_exit(self.implicit_exit);
+        match self.time_slice {
+            Some(n) => machine.set_time_slice(n),
+            None => (),
+        }
+        match self.gas {
+            Some((budget, table)) => machine.set_gas(budget, table),
+            None => (),
+        }
+        machine
+    }
+
+    /// Create a new `Machine` with stdin and stdout.
+    pub fn build_stdio(self) -> Machine<StdIo> {
+        self.build(stdio())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use machine::{Machine, QueueIo, RawByte, EofZero};
+    use super::Builder;
+
+    #[test]
+    fn test_build() {
+        let machine = Builder::new()
+            .char_encoding(RawByte)
+            .eof_behavior(EofZero)
+            .profiling(true)
+            .build(QueueIo::new());
+        assert_eq!(machine.char_encoding, RawByte);
+        assert_eq!(machine.eof_behavior, EofZero);
+        assert!(machine.profile().is_some());
+        assert!(machine.stack().is_empty());
+
+        let machine: Machine<QueueIo, i32> = Builder::new().eof_behavior(EofZero).build_cells(QueueIo::new());
+        assert_eq!(machine.eof_behavior, EofZero);
+    }
+}
@@ -0,0 +1,57 @@
+//! Cancellation of a running machine.
+
+#![experimental]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, SeqCst};
+
+/// A handle to cancel a running machine from another task.
+///
+/// Cancelling makes the machine fail with `Cancelled` before executing the
+/// next instruction. The handle stays cancelled until `reset`.
+#[deriving(Clone)]
+pub struct CancelHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Create a new `CancelHandle`.
+    pub fn new() -> CancelHandle {
+        CancelHandle { flag: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Request cancellation.
+    pub fn cancel(&self) { self.flag.store(true, SeqCst); }
+
+    /// Return true if cancellation was requested.
+    pub fn is_cancelled(&self) -> bool { self.flag.load(SeqCst) }
+
+    /// Clear the cancellation request, e.g. to resume the machine.
+    pub fn reset(&self) { self.flag.store(false, SeqCst); }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+    use std::io::util::{NullReader, NullWriter};
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, Cancelled};
+
+    #[test]
+    fn test_cancel() {
+        let mut bcw = MemWriter::new();
+        bcw.write_mark(1).unwrap();
+        bcw.write_jump(1).unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = Machine::new(NullReader, NullWriter);
+        let handle = vm.cancel_handle();
+        let remote = handle.clone();
+        spawn(proc() { remote.cancel(); });
+        assert_eq!(vm.run(&mut MemReader::new(program)), Err(Cancelled));
+        assert!(handle.is_cancelled());
+        handle.reset();
+        assert!(!vm.cancel_handle().is_cancelled());
+    }
+}
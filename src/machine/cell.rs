@@ -0,0 +1,174 @@
+//! Value types of the virtual machine.
+
+#![experimental]
+
+use std::fmt::Show;
+use std::i32;
+use std::io::{InvalidInput, IoError, IoResult};
+use num::bigint::BigInt;
+
+/// A value on the stack and in the heap of `Machine`.
+///
+/// Arithmetic follows the type, e.g. `i32` wraps around at 32 bits and `BigInt`
+/// never overflows. Operands of instructions and input values are `i64`, and
+/// converted by `wrap`. Values outside the range of `i64` are written by "PUTN"
+/// only with the plain number format.
+pub trait Cell: Signed + ToPrimitive + Ord + Clone + Show {
+    /// Convert an operand or input value, wrapping around if the type is narrower.
+    fn wrap(n: i64) -> Self;
+    /// Write the value in the binary format of snapshots and heap files.
+    fn write_cell<W: Writer>(&self, output: &mut W) -> IoResult<()>;
+    /// Read a value written by `write_cell`.
+    fn read_cell<R: Reader>(input: &mut R) -> IoResult<Self>;
+}
+
+fn invalid_cell(detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid cell value",
+        detail: Some(detail),
+    }
+}
+
+// i32 shares the format of i64, so that snapshots of small values can be read by either
+impl Cell for i32 {
+    fn wrap(n: i64) -> i32 { n as i32 }
+
+    fn write_cell<W: Writer>(&self, output: &mut W) -> IoResult<()> { output.write_be_i64(*self as i64) }
+
+    fn read_cell<R: Reader>(input: &mut R) -> IoResult<i32> {
+        match try!(input.read_be_i64()) {
+            n if i32::MIN as i64 <= n && n <= i32::MAX as i64 => Ok(n as i32),
+            n => Err(invalid_cell(format!("{} is out of range of i32", n))),
+        }
+    }
+}
+
+impl Cell for i64 {
+    fn wrap(n: i64) -> i64 { n }
+
+    fn write_cell<W: Writer>(&self, output: &mut W) -> IoResult<()> { output.write_be_i64(*self) }
+
+    fn read_cell<R: Reader>(input: &mut R) -> IoResult<i64> { input.read_be_i64() }
+}
+
+// written as the length and the decimal digits, since the size is not fixed
+impl Cell for BigInt {
+    fn wrap(n: i64) -> BigInt { FromPrimitive::from_i64(n).unwrap() }
+
+    fn write_cell<W: Writer>(&self, output: &mut W) -> IoResult<()> {
+        let digits = self.to_string();
+        try!(output.write_be_u64(digits.len() as u64));
+        output.write_str(digits.as_slice())
+    }
+
+    fn read_cell<R: Reader>(input: &mut R) -> IoResult<BigInt> {
+        let len = try!(input.read_be_u64());
+        let bytes = try!(input.read_exact(len as uint));
+        match String::from_utf8(bytes).ok().and_then(|s| from_str::<BigInt>(s.as_slice())) {
+            Some(n) => Ok(n),
+            None => Err(invalid_cell("malformed integer".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::TreeMap;
+    use std::i32;
+    use std::io::{IoResult, MemReader, MemWriter};
+    use num::bigint::BigInt;
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, MachineState, QueueIo};
+    use super::Cell;
+
+    fn program(build: |&mut MemWriter|) -> Vec<u8> {
+        let mut bcw = MemWriter::new();
+        build(&mut bcw);
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+        bcw.unwrap()
+    }
+
+    #[test]
+    fn test_wrap() {
+        let n: i32 = Cell::wrap(1 << 31);
+        assert_eq!(n, i32::MIN);
+        let n: i64 = Cell::wrap(-1);
+        assert_eq!(n, -1);
+        let n: BigInt = Cell::wrap(-(1 << 40));
+        assert_eq!(n.to_i64(), Some(-(1 << 40)));
+    }
+
+    #[test]
+    fn test_i32() {
+        let program = program(|bcw| {
+            bcw.write_push(0x7FFFFFFF).unwrap();
+            bcw.write_push(1).unwrap();
+            bcw.write_add().unwrap();
+        });
+        let mut vm: Machine<QueueIo, i32> = Machine::with_io_cells(QueueIo::new());
+        vm.run(&mut MemReader::new(program.clone())).unwrap();
+        assert_eq!(vm.io().take_output(), "-2147483648".to_string());
+
+        let mut vm = Machine::with_io(QueueIo::new());
+        vm.run(&mut MemReader::new(program)).unwrap();
+        assert_eq!(vm.io().take_output(), "2147483648".to_string());
+    }
+
+    #[test]
+    fn test_bigint() {
+        let program = program(|bcw| {
+            bcw.write_push(0).unwrap();
+            bcw.write_push(1 << 62).unwrap();
+            bcw.write_dup().unwrap();
+            bcw.write_mul().unwrap();
+            bcw.write_store().unwrap();
+            bcw.write_push(0).unwrap();
+            bcw.write_retrieve().unwrap();
+            bcw.write_push(3).unwrap();
+            bcw.write_div().unwrap();
+        });
+        let mut vm: Machine<QueueIo, BigInt> = Machine::with_io_cells(QueueIo::new());
+        vm.run(&mut MemReader::new(program)).unwrap();
+        assert_eq!(vm.io().take_output(), "7089215977519551322153637654828504405".to_string());
+        let expected: BigInt = from_str("21267647932558653966460912964485513216").unwrap();
+        assert_eq!(vm.heap_get(Cell::wrap(0)), Some(expected));
+    }
+
+    #[test]
+    fn test_readwrite() {
+        let mut heap: TreeMap<BigInt, BigInt> = TreeMap::new();
+        heap.insert(Cell::wrap(1), from_str::<BigInt>("-123456789012345678901234567890").unwrap());
+        let state: MachineState<BigInt> = MachineState {
+            stack: vec!(Cell::wrap(-1), Cell::wrap(1 << 62)),
+            heap: heap,
+            caller: vec!(9),
+            pc: 18,
+            threads: vec!(),
+            forks: 0,
+            slice: 0,
+        };
+        let mut writer = MemWriter::new();
+        state.write_to(&mut writer).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        assert_eq!(MachineState::read_from(&mut reader), Ok(state));
+
+        // i32 and i64 share the format, and i32 rejects values out of its range
+        let state: MachineState<i64> = MachineState {
+            stack: vec!(1, 1 << 40),
+            heap: TreeMap::new(),
+            caller: vec!(),
+            pc: 0,
+            threads: vec!(),
+            forks: 0,
+            slice: 0,
+        };
+        let mut writer = MemWriter::new();
+        state.write_to(&mut writer).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        let ret: IoResult<MachineState<i32>> = MachineState::read_from(&mut reader);
+        assert!(ret.is_err());
+    }
+}
@@ -0,0 +1,439 @@
+//! Debugger engine of the virtual machine.
+
+#![experimental]
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::{BufReader, EndOfFile, IoResult, MemReader, MemWriter, SeekSet};
+use std::str::from_utf8;
+
+use bytecode;
+use bytecode::ByteCodeReader;
+use bytecode::image::LabelNames;
+use machine::{Machine, MachineIo, MachineResult};
+use syntax::{Assembly, Decompiler};
+
+/// A location to stop execution at.
+#[deriving(PartialEq, Clone, Show)]
+pub enum Breakpoint {
+    /// Stop at "MARK" of the label, or after jumping to it.
+    AtLabel(i64),
+    /// Stop before the instruction at the offset.
+    AtOffset(u64),
+    /// Stop before any instruction, useful with a condition.
+    Anywhere,
+}
+
+/// A value of the machine state.
+#[deriving(PartialEq, Clone, Show)]
+pub enum Watch {
+    /// Value at the heap address, 0 if not stored.
+    HeapValue(i64),
+    /// Value on the top of the stack.
+    StackTop,
+    /// Number of values on the stack.
+    StackDepth,
+    /// Number of active "CALL"s.
+    CallDepth,
+}
+
+impl Watch {
+    /// Evaluate the value, or `None` if it does not exist.
+    pub fn evaluate<I: MachineIo>(&self, machine: &Machine<I>) -> Option<i64> {
+        match *self {
+            HeapValue(addr) => Some(machine.heap_get(addr).unwrap_or(0)),
+            StackTop        => machine.stack.last().map(|n| *n),
+            StackDepth      => machine.stack.len().to_i64(),
+            CallDepth       => machine.caller.len().to_i64(),
+        }
+    }
+}
+
+/// A comparison operator of `Condition`.
+#[deriving(PartialEq, Clone, Show)]
+pub enum Comparison {
+    /// `==`
+    CmpEq,
+    /// `!=`
+    CmpNe,
+    /// `<`
+    CmpLt,
+    /// `<=`
+    CmpLe,
+    /// `>`
+    CmpGt,
+    /// `>=`
+    CmpGe,
+}
+
+/// A predicate over the machine state, such as `heap[3] > 100`.
+#[deriving(PartialEq, Clone, Show)]
+pub struct Condition {
+    /// Value to compare.
+    pub watch: Watch,
+    /// Comparison operator.
+    pub comparison: Comparison,
+    /// Value to compare with.
+    pub value: i64,
+}
+
+impl Condition {
+    /// Create a new `Condition`.
+    pub fn new(watch: Watch, comparison: Comparison, value: i64) -> Condition {
+        Condition { watch: watch, comparison: comparison, value: value }
+    }
+
+    /// Return true if the condition holds. A value which does not exist never satisfies it.
+    pub fn holds<I: MachineIo>(&self, machine: &Machine<I>) -> bool {
+        match self.watch.evaluate(machine) {
+            Some(n) => match self.comparison {
+                CmpEq => n == self.value,
+                CmpNe => n != self.value,
+                CmpLt => n < self.value,
+                CmpLe => n <= self.value,
+                CmpGt => n > self.value,
+                CmpGe => n >= self.value,
+            },
+            None => false,
+        }
+    }
+}
+
+/// Why the debugger stopped.
+#[deriving(PartialEq, Show)]
+pub enum Stop {
+    /// The command completed and the program can continue.
+    Paused,
+    /// A breakpoint was reached; holds the breakpoint number.
+    BreakpointHit(uint),
+    /// The program has finished with the exit status.
+    Finished(i64),
+}
+
+/// A disassembled instruction.
+#[deriving(PartialEq, Clone, Show)]
+pub struct Line {
+    /// Offset of the instruction.
+    pub offset: u64,
+    /// Instruction in assembly syntax.
+    pub text: String,
+    /// True if the instruction is the next one to be executed.
+    pub current: bool,
+}
+
+/// An interactive debugger wrapping a `Machine` and its program.
+///
+/// Frontends build user interfaces on top of the commands `step`, `next` and
+/// `cont`, breakpoints, and inspection through `machine` and `disassemble`.
+pub struct Debugger<I> {
+    machine: Machine<I>,
+    program: MemReader,
+    listing: Vec<(u64, u8, i64)>,
+    labels: HashMap<i64, (u64, u64)>,
+    names: LabelNames,
+    breakpoints: Vec<Option<(Breakpoint, Option<Condition>)>>,
+    finished: Option<i64>,
+}
+
+impl<I: MachineIo> Debugger<I> {
+    /// Create a new `Debugger` stopped before the first instruction of the program.
+    pub fn new(machine: Machine<I>, program: Vec<u8>) -> IoResult<Debugger<I>> {
+        let mut reader = MemReader::new(program);
+        let mut listing = Vec::new();
+        let mut labels = HashMap::new();
+        loop {
+            let offset = try!(reader.tell());
+            match reader.read_inst() {
+                Ok((opcode, operand)) => {
+                    if opcode == bytecode::CMD_MARK {
+                        labels.insert(operand, (offset, try!(reader.tell())));
+                    }
+                    listing.push((offset, opcode, operand));
+                },
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(e) => return Err(e),
+            }
+        }
+        try!(reader.seek(0, SeekSet));
+        let mut machine = machine;
+        machine.index.clear();
+        machine.caller.clear();
+        machine.callees.clear();
+        machine.pc = 0;
+        Ok(Debugger {
+            machine: machine,
+            program: reader,
+            listing: listing,
+            labels: labels,
+            names: LabelNames::new(),
+            breakpoints: Vec::new(),
+            finished: None,
+        })
+    }
+
+    /// Get the machine.
+    pub fn machine(&self) -> &Machine<I> { &self.machine }
+
+    /// Get the machine mutably, e.g. to modify the stack or heap.
+    pub fn machine_mut(&mut self) -> &mut Machine<I> { &mut self.machine }
+
+    /// Get the offset of the next instruction.
+    pub fn pc(&self) -> u64 { self.machine.pc }
+
+    /// Set names of labels, e.g. from `Image::label_names`, which are shown in `disassemble`.
+    pub fn set_label_names(&mut self, names: LabelNames) { self.names = names; }
+
+    /// Find the label of the name.
+    pub fn find_label(&self, name: &str) -> Option<i64> {
+        self.names.iter().find(|&(_, n)| n.as_slice() == name).map(|(&label, _)| label)
+    }
+
+    /// Add a breakpoint, returning its number.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> uint {
+        self.breakpoints.push(Some((breakpoint, None)));
+        self.breakpoints.len() - 1
+    }
+
+    /// Add a breakpoint which stops only when the condition holds, returning its number.
+    pub fn add_conditional_breakpoint(&mut self, breakpoint: Breakpoint, condition: Condition) -> uint {
+        self.breakpoints.push(Some((breakpoint, Some(condition))));
+        self.breakpoints.len() - 1
+    }
+
+    /// Evaluate a value of the machine state.
+    pub fn evaluate(&self, watch: &Watch) -> Option<i64> {
+        watch.evaluate(&self.machine)
+    }
+
+    /// Remove a breakpoint. Returns false if no such breakpoint exists.
+    pub fn remove_breakpoint(&mut self, id: uint) -> bool {
+        if id >= self.breakpoints.len() || self.breakpoints[id].is_none() { return false }
+        *self.breakpoints.get_mut(id) = None;
+        true
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self) -> MachineResult<Stop> {
+        match self.finished {
+            Some(status) => return Ok(Finished(status)),
+            None => (),
+        }
+        try!(self.machine.seek_pc(&mut self.program));
+        let running = match self.machine.step(&mut self.program) {
+            Ok(running) => running,
+            Err(e) => return Err(self.machine.traced(e)),
+        };
+        if running {
+            Ok(Paused)
+        } else {
+            self.finished = Some(self.machine.exit_status);
+            Ok(Finished(self.machine.exit_status))
+        }
+    }
+
+    /// Execute one instruction, stepping over "CALL" until the subroutine returns.
+    pub fn next(&mut self) -> MachineResult<Stop> {
+        let is_call = match self.current() {
+            Some(&(_, opcode, _)) => opcode == bytecode::CMD_CALL,
+            None => false,
+        };
+        let depth = self.machine.caller.len();
+        loop {
+            match try!(self.step()) {
+                Paused if is_call && self.machine.caller.len() > depth => (),
+                Paused => return Ok(Paused),
+                stop => return Ok(stop),
+            }
+            match self.breakpoint_hit() {
+                Some(id) => return Ok(BreakpointHit(id)),
+                None => (),
+            }
+        }
+    }
+
+    /// Execute until a breakpoint is reached or the program finishes.
+    pub fn cont(&mut self) -> MachineResult<Stop> {
+        loop {
+            match try!(self.step()) {
+                Paused => (),
+                stop => return Ok(stop),
+            }
+            match self.breakpoint_hit() {
+                Some(id) => return Ok(BreakpointHit(id)),
+                None => (),
+            }
+        }
+    }
+
+    /// Disassemble `before` instructions before and `after` instructions after
+    /// the next instruction, including it.
+    pub fn disassemble(&self, before: uint, after: uint) -> Vec<Line> {
+        let pc = self.machine.pc;
+        let at = match self.listing.iter().position(|&(offset, _, _)| offset >= pc) {
+            Some(n) => n,
+            None => self.listing.len(),
+        };
+        let from = if at > before { at - before } else { 0 };
+        let to = min(at + after + 1, self.listing.len());
+        let code = self.program.get_ref();
+        range(from, to).map(|n| {
+            let (offset, opcode, operand) = self.listing[n];
+            let end = match self.listing.get(n + 1) {
+                Some(&(next, _, _)) => next as uint,
+                None => code.len(),
+            };
+            let text = mnemonic(code.slice(offset as uint, end));
+            let text = match self.names.find(&operand) {
+                Some(name) if is_flow(opcode) => format!("{} ; {}", text, name),
+                _ => text,
+            };
+            Line {
+                offset: offset,
+                text: text,
+                current: offset == pc,
+            }
+        }).collect()
+    }
+
+    fn current(&self) -> Option<&(u64, u8, i64)> {
+        let pc = self.machine.pc;
+        self.listing.iter().find(|&&(offset, _, _)| offset == pc)
+    }
+
+    fn breakpoint_hit(&self) -> Option<uint> {
+        let pc = self.machine.pc;
+        for (id, breakpoint) in self.breakpoints.iter().enumerate() {
+            let (location, condition) = match *breakpoint {
+                Some((ref location, ref condition)) => (location, condition),
+                None => continue,
+            };
+            let hit = match *location {
+                AtOffset(offset) => offset == pc,
+                AtLabel(label) => match self.labels.find(&label) {
+                    Some(&(mark, body)) => mark == pc || body == pc,
+                    None => false,
+                },
+                Anywhere => true,
+            };
+            let satisfied = match *condition {
+                Some(ref condition) => condition.holds(&self.machine),
+                None => true,
+            };
+            if hit && satisfied { return Some(id) }
+        }
+        None
+    }
+}
+
+fn is_flow(opcode: u8) -> bool {
+    match opcode {
+        bytecode::CMD_MARK | bytecode::CMD_CALL | bytecode::CMD_JUMP |
+        bytecode::CMD_JUMPZ | bytecode::CMD_JUMPN => true,
+        _ => false,
+    }
+}
+
+fn mnemonic(code: &[u8]) -> String {
+    let mut output = MemWriter::new();
+    match Assembly::new().decompile(&mut BufReader::new(code), &mut output) {
+        Ok(_) => from_utf8(output.get_ref()).unwrap_or("").trim_right().to_string(),
+        Err(_) => format!("OPCODE {:x}", code[0]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use bytecode::ByteCodeWriter;
+    use bytecode::image::LabelNames;
+    use machine::{Machine, QueueIo};
+    use super::{Debugger, AtLabel, AtOffset, Anywhere, Paused, BreakpointHit, Finished};
+    use super::{Condition, HeapValue, StackDepth, CmpGt, CmpEq};
+
+    fn program() -> Vec<u8> {
+        let mut bcw = MemWriter::new();
+        bcw.write_call(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(5).unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_return().unwrap();
+        bcw.unwrap()
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = Debugger::new(Machine::with_io(QueueIo::new()), program()).unwrap();
+        assert_eq!(debugger.add_breakpoint(AtLabel(1)), 0);
+        assert_eq!(debugger.cont(), Ok(BreakpointHit(0)));
+        assert_eq!(debugger.pc(), 19);
+        assert_eq!(debugger.step(), Ok(Paused));
+        assert_eq!(debugger.machine().stack(), [5].as_slice());
+
+        let lines = debugger.disassemble(1, 1);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].text, "PUSH 5".to_string());
+        assert_eq!(lines[1].text, "PUTN".to_string());
+        assert!(lines[1].current);
+
+        assert!(debugger.remove_breakpoint(0));
+        assert_eq!(debugger.cont(), Ok(Finished(0)));
+        assert_eq!(debugger.machine_mut().io().take_output(), "5".to_string());
+    }
+
+    #[test]
+    fn test_label_names() {
+        let mut debugger = Debugger::new(Machine::with_io(QueueIo::new()), program()).unwrap();
+        let mut names = LabelNames::new();
+        names.insert(1, "main".to_string());
+        debugger.set_label_names(names);
+        assert_eq!(debugger.find_label("main"), Some(1));
+        assert_eq!(debugger.find_label("loop"), None);
+
+        let lines = debugger.disassemble(0, 2);
+        assert_eq!(lines[0].text, "CALL 1 ; main".to_string());
+        assert_eq!(lines[1].text, "EXIT".to_string());
+        assert_eq!(lines[2].text, "MARK 1 ; main".to_string());
+    }
+
+    #[test]
+    fn test_next() {
+        let mut debugger = Debugger::new(Machine::with_io(QueueIo::new()), program()).unwrap();
+        assert_eq!(debugger.next(), Ok(Paused));
+        assert_eq!(debugger.pc(), 9);
+        assert_eq!(debugger.machine_mut().io().take_output(), "5".to_string());
+
+        debugger.add_breakpoint(AtOffset(9));
+        assert_eq!(debugger.next(), Ok(Finished(0)));
+    }
+
+    #[test]
+    fn test_conditional() {
+        let mut bcw = MemWriter::new();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_add().unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_push(10).unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_jumpn(1).unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut debugger = Debugger::new(Machine::with_io(QueueIo::new()), bcw.unwrap()).unwrap();
+        let id = debugger.add_conditional_breakpoint(AtLabel(1), Condition::new(HeapValue(0), CmpGt, 5));
+        assert_eq!(debugger.cont(), Ok(BreakpointHit(id)));
+        assert_eq!(debugger.pc(), 9);
+        assert_eq!(debugger.evaluate(&HeapValue(0)), Some(6));
+
+        debugger.remove_breakpoint(id);
+        let id = debugger.add_conditional_breakpoint(Anywhere, Condition::new(StackDepth, CmpEq, 2));
+        assert_eq!(debugger.cont(), Ok(BreakpointHit(id)));
+        assert_eq!(debugger.pc(), 27);
+    }
+}
@@ -0,0 +1,73 @@
+//! Extension instructions of the virtual machine.
+
+#![experimental]
+
+use std::collections::TreeMap;
+
+use machine::MachineResult;
+
+/// A handler of an extension instruction registered by `Machine::register_ext`.
+///
+/// The stack and heap hold values of the cell type `C` of the machine.
+///
+/// ```rust
+/// use std::collections::TreeMap;
+/// use whitebase::machine::{ExtHandler, MachineResult};
+///
+/// struct Double;
+///
+/// impl ExtHandler for Double {
+///     fn execute(&mut self, _: i64, stack: &mut Vec<i64>, _: &mut TreeMap<i64, i64>) -> MachineResult<()> {
+///         let n = stack.pop().unwrap_or(0);
+///         stack.push(n * 2);
+///         Ok(())
+///     }
+/// }
+///
+/// let mut machine = whitebase::machine::with_stdio();
+/// machine.register_ext(0xF1, Double);
+/// ```
+pub trait ExtHandler<C = i64> {
+    /// Execute the instruction with its operand.
+    fn execute(&mut self, operand: i64, stack: &mut Vec<C>, heap: &mut TreeMap<C, C>) -> MachineResult<()>;
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::TreeMap;
+    use std::io::{MemReader, MemWriter};
+    use std::io::util::{NullReader, NullWriter};
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, MachineResult, IllegalStackManipulation, OtherMachineError};
+    use super::ExtHandler;
+
+    struct Store;
+
+    impl ExtHandler for Store {
+        fn execute(&mut self, operand: i64, stack: &mut Vec<i64>, heap: &mut TreeMap<i64, i64>) -> MachineResult<()> {
+            match stack.pop() {
+                Some(n) => { heap.insert(operand, n); Ok(()) },
+                None => Err(IllegalStackManipulation),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ext() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(42).unwrap();
+        bcw.write_ext(0xF1, 3).unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run(&mut MemReader::new(program.clone())), Err(OtherMachineError));
+
+        let mut vm = Machine::new(NullReader, NullWriter);
+        vm.register_ext(0xF1, Store);
+        assert!(vm.run(&mut MemReader::new(program)).is_ok());
+        assert_eq!(vm.heap_get(3), Some(42));
+        assert!(vm.stack().is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+//! Gas metering of the virtual machine.
+
+#![experimental]
+
+use std::collections::HashMap;
+
+use bytecode;
+
+/// Cost of each instruction deducted from the gas budget.
+#[deriving(PartialEq, Clone, Show)]
+pub struct CostTable {
+    default: u64,
+    costs: HashMap<u8, u64>,
+}
+
+impl CostTable {
+    /// Create a `CostTable` where every instruction costs 1.
+    pub fn new() -> CostTable {
+        CostTable { default: 1, costs: HashMap::new() }
+    }
+
+    /// Create a `CostTable` weighted by instruction category:
+    /// stack 1, arithmetic 2, flow control 2, heap 3 and I/O 5.
+    pub fn weighted() -> CostTable {
+        let mut table = CostTable::new();
+        for &opcode in [bytecode::CMD_ADD, bytecode::CMD_SUB, bytecode::CMD_MUL,
+                        bytecode::CMD_DIV, bytecode::CMD_MOD, bytecode::CMD_PUSH_ADD].iter() {
+            table.set(opcode, 2);
+        }
+        for &opcode in [bytecode::CMD_MARK, bytecode::CMD_CALL, bytecode::CMD_JUMP,
+                        bytecode::CMD_JUMPZ, bytecode::CMD_JUMPN, bytecode::CMD_RETURN].iter() {
+            table.set(opcode, 2);
+        }
+        table.set(bytecode::CMD_STORE, 3);
+        table.set(bytecode::CMD_RETRIEVE, 3);
+        table.set(bytecode::CMD_RETRIEVE_AT, 3);
+        table.set(bytecode::CMD_STORE_CONST, 3);
+        for &opcode in [bytecode::CMD_PUTC, bytecode::CMD_PUTN,
+                        bytecode::CMD_GETC, bytecode::CMD_GETN].iter() {
+            table.set(opcode, 5);
+        }
+        table
+    }
+
+    /// Set the cost of an instruction.
+    pub fn set(&mut self, opcode: u8, cost: u64) {
+        self.costs.insert(opcode, cost);
+    }
+
+    /// Set the cost of instructions not set by `set`.
+    pub fn set_default(&mut self, cost: u64) {
+        self.default = cost;
+    }
+
+    /// Get the cost of an instruction.
+    pub fn cost(&self, opcode: u8) -> u64 {
+        match self.costs.find(&opcode) {
+            Some(cost) => *cost,
+            None => self.default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+    use std::io::util::{NullReader, NullWriter};
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, OutOfGas};
+    use super::CostTable;
+
+    #[test]
+    fn test_cost() {
+        let table = CostTable::weighted();
+        assert_eq!(table.cost(bytecode::CMD_PUSH), 1);
+        assert_eq!(table.cost(bytecode::CMD_STORE), 3);
+        assert_eq!(table.cost(bytecode::CMD_GETN), 5);
+    }
+
+    #[test]
+    fn test_gas() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_push(2).unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let mut bcr = MemReader::new(program);
+        let mut vm = Machine::new(NullReader, NullWriter);
+        vm.set_gas(4, CostTable::weighted());
+        assert_eq!(vm.run(&mut bcr), Err(OutOfGas));
+        assert_eq!(vm.gas(), Some(2));
+        assert_eq!(vm.stack(), [1, 2].as_slice());
+
+        vm.set_gas(4, CostTable::weighted());
+        assert_eq!(vm.resume(&mut bcr), Ok(0));
+        assert_eq!(vm.gas(), Some(0));
+        assert_eq!(vm.heap_get(1), Some(2));
+    }
+}
@@ -0,0 +1,635 @@
+//! I/O abstraction used by the virtual machine.
+
+#![experimental]
+
+use std::io::{BufferedReader, EndOfFile, InvalidInput, IoError, IoResult, PermissionDenied, ResourceUnavailable, standard_error};
+use std::char::from_digit;
+use std::io::process::{Command, InheritFd};
+use std::io::stdio::{StdReader, StdWriter, stdin, stdin_raw, stdout_raw};
+use std::mem::replace;
+use std::num::from_str_radix;
+
+fn invalid_number(line: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid number",
+        detail: Some(format!("expected number, but {}", line)),
+    }
+}
+
+/// What "GETN" does when the input line is not a number.
+#[deriving(PartialEq, Clone, Show)]
+pub enum ParseFailure {
+    /// Return an `InvalidInput` error.
+    Reject,
+    /// Discard the line and read the next one.
+    Retry,
+}
+
+fn check_radix(radix: uint) -> IoResult<()> {
+    if radix < 2 || radix > 36 {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "invalid radix",
+            detail: Some(format!("radix {} is not in 2 to 36", radix)),
+        })
+    }
+    Ok(())
+}
+
+/// How "GETN" parses an input line.
+#[deriving(PartialEq, Clone, Show)]
+pub struct NumberParser {
+    radix: uint,
+    trim_whitespace: bool,
+    allow_trailing: bool,
+    on_failure: ParseFailure,
+}
+
+impl NumberParser {
+    /// Create a new `NumberParser` which accepts only a decimal number followed by a line break.
+    pub fn new() -> NumberParser {
+        NumberParser {
+            radix: 10,
+            trim_whitespace: false,
+            allow_trailing: false,
+            on_failure: Reject,
+        }
+    }
+
+    /// Set the radix of the number, which is from 2 to 36.
+    ///
+    /// # Error
+    ///
+    /// Returns `InvalidInput` and keeps the previous radix if the radix is out of range.
+    pub fn set_radix(&mut self, radix: uint) -> IoResult<()> {
+        try!(check_radix(radix));
+        self.radix = radix;
+        Ok(())
+    }
+
+    /// Set whether whitespaces around the number are ignored.
+    pub fn set_trim_whitespace(&mut self, trim: bool) { self.trim_whitespace = trim; }
+
+    /// Set whether characters following the number are ignored.
+    pub fn set_allow_trailing(&mut self, allow: bool) { self.allow_trailing = allow; }
+
+    /// Set the behavior on parse failure.
+    pub fn set_on_failure(&mut self, on_failure: ParseFailure) { self.on_failure = on_failure; }
+
+    /// Parse a line, which may end with "\n" or "\r\n".
+    pub fn parse(&self, line: &str) -> Option<i64> {
+        let mut s = line.trim_right_chars(|c: char| c == '\n' || c == '\r');
+        if self.trim_whitespace { s = s.trim(); }
+        if self.allow_trailing {
+            let sign = if s.starts_with("-") || s.starts_with("+") { 1 } else { 0 };
+            match s.slice_from(sign).find(|c: char| c.to_digit(self.radix).is_none()) {
+                Some(n) => s = s.slice_to(sign + n),
+                None => (),
+            }
+        }
+        parse_integer(s, self.radix)
+    }
+}
+
+/// Parse a number in the radix, optionally preceded by "-" or "+".
+///
+/// The sign is parsed together with the digits, so the minimum value of `i64` is accepted.
+///
+/// ```rust
+/// use std::i64;
+/// use whitebase::machine::io::parse_integer;
+///
+/// assert_eq!(parse_integer("-8000000000000000", 16), Some(i64::MIN));
+/// assert_eq!(parse_integer("+12", 10), Some(12));
+/// assert_eq!(parse_integer("--12", 10), None);
+/// ```
+pub fn parse_integer(s: &str, radix: uint) -> Option<i64> {
+    let (negative, digits) = if s.starts_with("-") {
+        (true, s.slice_from(1))
+    } else if s.starts_with("+") {
+        (false, s.slice_from(1))
+    } else {
+        (false, s)
+    };
+    if digits.len() == 0 || digits.starts_with("-") || digits.starts_with("+") { return None }
+    from_str_radix::<i64>(if negative { s } else { digits }, radix)
+}
+
+/// Input and output for the I/O instructions of the machine.
+///
+/// Input methods may return an error of kind `ResourceUnavailable` to tell
+/// `Machine::run_steps` that no input is available yet.
+pub trait MachineIo {
+    /// Read a character for "GETC".
+    fn get_char(&mut self) -> IoResult<char>;
+    /// Read a number for "GETN".
+    fn get_number(&mut self) -> IoResult<i64>;
+    /// Write a character for "PUTC".
+    fn put_char(&mut self, c: char) -> IoResult<()>;
+    /// Write a raw byte for "PUTC" in `RawByte` mode.
+    fn put_byte(&mut self, b: u8) -> IoResult<()> { self.put_char(b as char) }
+    /// Write a number for "PUTN".
+    fn put_number(&mut self, n: i64) -> IoResult<()>;
+    /// Flush buffered output.
+    fn flush(&mut self) -> IoResult<()> { Ok(()) }
+}
+
+/// How "PUTC" converts a value to output.
+#[deriving(PartialEq, Clone, Show)]
+pub enum CharEncoding {
+    /// Treat a value in 0-255 as a Latin-1 character.
+    Latin1,
+    /// Treat a value as a Unicode scalar value and write it in UTF-8.
+    Utf8,
+    /// Write a value in 0-255 as a byte as is.
+    RawByte,
+}
+
+/// How "PUTN" formats a number.
+#[deriving(PartialEq, Clone, Show)]
+pub struct NumberFormat {
+    radix: uint,
+    width: uint,
+    separator: Option<String>,
+}
+
+impl NumberFormat {
+    /// Create a new `NumberFormat` which writes a decimal number without padding and separator.
+    pub fn new() -> NumberFormat {
+        NumberFormat { radix: 10, width: 0, separator: None }
+    }
+
+    /// Set the radix of the number, which is from 2 to 36.
+    ///
+    /// # Error
+    ///
+    /// Returns `InvalidInput` and keeps the previous radix if the radix is out of range.
+    pub fn set_radix(&mut self, radix: uint) -> IoResult<()> {
+        try!(check_radix(radix));
+        self.radix = radix;
+        Ok(())
+    }
+
+    /// Set the minimum number of digits, padded with zeros.
+    pub fn set_width(&mut self, width: uint) { self.width = width; }
+
+    /// Set the string written after each number.
+    pub fn set_separator(&mut self, separator: Option<String>) { self.separator = separator; }
+
+    /// Return true if numbers are written as is.
+    pub fn is_plain(&self) -> bool {
+        self.radix == 10 && self.width == 0 && self.separator.is_none()
+    }
+
+    /// Format a number.
+    pub fn format(&self, n: i64) -> String {
+        let radix = self.radix as u64;
+        // two's complement keeps the magnitude of i64::MIN representable
+        let mut m = if n < 0 { !(n as u64) + 1 } else { n as u64 };
+        let mut digits = Vec::new();
+        loop {
+            digits.push(from_digit((m % radix) as uint, self.radix).unwrap());
+            m /= radix;
+            if m == 0 { break }
+        }
+        while digits.len() < self.width { digits.push('0'); }
+        if n < 0 { digits.push('-'); }
+        digits.reverse();
+        let mut s = String::from_chars(digits.as_slice());
+        match self.separator {
+            Some(ref sep) => s.push_str(sep.as_slice()),
+            None => (),
+        }
+        s
+    }
+}
+
+/// `MachineIo` implementation on top of a `Buffer` and a `Writer`.
+pub struct StreamIo<B, W> {
+    input: B,
+    output: W,
+    parser: NumberParser,
+}
+
+/// `StreamIo` connected to stdin and stdout.
+pub type StdIo = StreamIo<BufferedReader<StdReader>, StdWriter>;
+
+/// Create a new `StreamIo` with stdin and stdout.
+pub fn stdio() -> StdIo {
+    StreamIo::new(stdin(), stdout_raw())
+}
+
+/// Guard switching the terminal on stdin to raw input mode.
+///
+/// In raw mode, "GETC" on `StdIo` reads each keystroke without waiting for a newline.
+/// The previous terminal settings are restored when the guard is dropped.
+///
+/// ```rust,no_run
+/// use whitebase::machine;
+/// use whitebase::machine::io::RawMode;
+///
+/// let _raw = RawMode::enable(false).unwrap();
+/// let mut machine = machine::with_stdio();
+/// ```
+pub struct RawMode {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> IoResult<String> {
+    let output = try!(Command::new("stty").args(args).stdin(InheritFd(0)).output());
+    if !output.status.success() {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "failed to change terminal mode",
+            detail: String::from_utf8(output.error).ok(),
+        });
+    }
+    Ok(String::from_utf8(output.output).ok().unwrap_or(String::new()))
+}
+
+impl RawMode {
+    /// Switch the terminal to raw input mode, echoing keystrokes if `echo` is true.
+    ///
+    /// # Error
+    ///
+    /// Fails if stdin is not a terminal, or `stty` is not available or fails.
+    pub fn enable(echo: bool) -> IoResult<RawMode> {
+        if !stdin_raw().isatty() {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "stdin is not a terminal",
+                detail: None,
+            });
+        }
+        let saved = try!(stty(["-g"]));
+        // restores the saved settings if switching fails halfway
+        let raw = RawMode { saved: saved.as_slice().trim().to_string() };
+        try!(stty(["-icanon", "min", "1", "time", "0", if echo { "echo" } else { "-echo" }]));
+        Ok(raw)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty([self.saved.as_slice()]);
+    }
+}
+
+impl<B: Buffer, W: Writer> StreamIo<B, W> {
+    /// Create a new `StreamIo` with input and output.
+    pub fn new(input: B, output: W) -> StreamIo<B, W> {
+        StreamIo { input: input, output: output, parser: NumberParser::new() }
+    }
+
+    /// Set how "GETN" parses input.
+    pub fn set_number_parser(&mut self, parser: NumberParser) { self.parser = parser; }
+
+    /// Unwrap this `StreamIo`, returning the underlying input and output.
+    pub fn unwrap(self) -> (B, W) { (self.input, self.output) }
+}
+
+impl<B: Buffer, W: Writer> MachineIo for StreamIo<B, W> {
+    fn get_char(&mut self) -> IoResult<char> {
+        self.input.read_char()
+    }
+
+    fn get_number(&mut self) -> IoResult<i64> {
+        loop {
+            let line = try!(self.input.read_line());
+            match self.parser.parse(line.as_slice()) {
+                Some(n) => return Ok(n),
+                None if self.parser.on_failure == Retry => continue,
+                None => return Err(invalid_number(line.as_slice())),
+            }
+        }
+    }
+
+    fn put_char(&mut self, c: char) -> IoResult<()> {
+        write!(self.output, "{}", c)
+    }
+
+    fn put_byte(&mut self, b: u8) -> IoResult<()> {
+        self.output.write_u8(b)
+    }
+
+    fn put_number(&mut self, n: i64) -> IoResult<()> {
+        write!(self.output, "{}", n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.output.flush()
+    }
+}
+
+/// `MachineIo` implementation dispatching to user-supplied closures.
+///
+/// ```rust
+/// use whitebase::machine::{CallbackIo, Machine};
+///
+/// let mut output = Vec::new();
+/// let io = CallbackIo::new(|| Ok('a'),
+///                          || Ok(1),
+///                          |c| { output.push(c); Ok(()) },
+///                          |_| Ok(()));
+/// let mut machine = Machine::with_io(io);
+/// ```
+pub struct CallbackIo<'a> {
+    get_char: ||: 'a -> IoResult<char>,
+    get_number: ||: 'a -> IoResult<i64>,
+    put_char: |char|: 'a -> IoResult<()>,
+    put_number: |i64|: 'a -> IoResult<()>,
+}
+
+impl<'a> CallbackIo<'a> {
+    /// Create a new `CallbackIo` with handlers for "GETC", "GETN", "PUTC" and "PUTN".
+    pub fn new(get_char: ||: 'a -> IoResult<char>,
+               get_number: ||: 'a -> IoResult<i64>,
+               put_char: |char|: 'a -> IoResult<()>,
+               put_number: |i64|: 'a -> IoResult<()>) -> CallbackIo<'a> {
+        CallbackIo {
+            get_char: get_char,
+            get_number: get_number,
+            put_char: put_char,
+            put_number: put_number,
+        }
+    }
+}
+
+impl<'a> MachineIo for CallbackIo<'a> {
+    fn get_char(&mut self) -> IoResult<char> { (self.get_char)() }
+
+    fn get_number(&mut self) -> IoResult<i64> { (self.get_number)() }
+
+    fn put_char(&mut self, c: char) -> IoResult<()> { (self.put_char)(c) }
+
+    fn put_number(&mut self, n: i64) -> IoResult<()> { (self.put_number)(n) }
+}
+
+/// Non-blocking `MachineIo` implementation with in-memory input queue and output buffer.
+///
+/// When the queue has no input, "GETC" and "GETN" suspend `Machine::run_steps`
+/// with `NeedsInput` until more input is fed.
+#[deriving(Clone)]
+pub struct QueueIo {
+    input: Vec<char>,
+    pos: uint,
+    closed: bool,
+    output: String,
+    parser: NumberParser,
+}
+
+impl QueueIo {
+    /// Create a new `QueueIo` with empty input.
+    pub fn new() -> QueueIo {
+        QueueIo {
+            input: Vec::new(),
+            pos: 0,
+            closed: false,
+            output: String::new(),
+            parser: NumberParser::new(),
+        }
+    }
+
+    /// Set how "GETN" parses input.
+    pub fn set_number_parser(&mut self, parser: NumberParser) { self.parser = parser; }
+
+    /// Append input.
+    pub fn feed(&mut self, input: &str) {
+        if self.pos == self.input.len() {
+            self.input.clear();
+            self.pos = 0;
+        }
+        self.input.extend(input.chars());
+    }
+
+    /// Mark the end of input. Reading past the remaining input then returns `EndOfFile`.
+    pub fn close(&mut self) { self.closed = true; }
+
+    /// Take the output written so far.
+    pub fn take_output(&mut self) -> String { replace(&mut self.output, String::new()) }
+
+    fn unavailable(&self) -> IoError {
+        if self.closed { standard_error(EndOfFile) } else { standard_error(ResourceUnavailable) }
+    }
+}
+
+impl MachineIo for QueueIo {
+    fn get_char(&mut self) -> IoResult<char> {
+        if self.pos < self.input.len() {
+            self.pos += 1;
+            Ok(self.input[self.pos - 1])
+        } else {
+            Err(self.unavailable())
+        }
+    }
+
+    fn get_number(&mut self) -> IoResult<i64> {
+        loop {
+            let line = {
+                let rest = self.input.slice_from(self.pos);
+                let len = match rest.position_elem(&'\n') {
+                    Some(n) => n + 1,
+                    None if self.closed && rest.len() > 0 => rest.len(),
+                    None => return Err(self.unavailable()),
+                };
+                String::from_chars(rest.slice_to(len))
+            };
+            self.pos += line.as_slice().char_len();
+            match self.parser.parse(line.as_slice()) {
+                Some(n) => return Ok(n),
+                None if self.parser.on_failure == Retry => continue,
+                None => return Err(invalid_number(line.as_slice())),
+            }
+        }
+    }
+
+    fn put_char(&mut self, c: char) -> IoResult<()> {
+        self.output.push_char(c);
+        Ok(())
+    }
+
+    fn put_number(&mut self, n: i64) -> IoResult<()> {
+        self.output.push_str(n.to_string().as_slice());
+        Ok(())
+    }
+}
+
+/// `MachineIo` implementation rejecting all I/O with `PermissionDenied`.
+///
+/// A machine with `NullIo` never touches host I/O, so it can evaluate untrusted
+/// programs without side effects. Use `QueueIo` instead to capture I/O in memory.
+#[deriving(Clone)]
+pub struct NullIo;
+
+fn io_denied() -> IoError {
+    IoError {
+        kind: PermissionDenied,
+        desc: "I/O is not allowed",
+        detail: None,
+    }
+}
+
+impl MachineIo for NullIo {
+    fn get_char(&mut self) -> IoResult<char> { Err(io_denied()) }
+
+    fn get_number(&mut self) -> IoResult<i64> { Err(io_denied()) }
+
+    fn put_char(&mut self, _: char) -> IoResult<()> { Err(io_denied()) }
+
+    fn put_byte(&mut self, _: u8) -> IoResult<()> { Err(io_denied()) }
+
+    fn put_number(&mut self, _: i64) -> IoResult<()> { Err(io_denied()) }
+}
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+    use std::io::{EndOfFile, InvalidInput, MemReader, MemWriter, PermissionDenied, ResourceUnavailable, standard_error};
+    use std::io::stdio::stdin_raw;
+    use super::{CallbackIo, MachineIo, NullIo, NumberFormat, NumberParser, QueueIo, RawMode, StreamIo, Retry};
+
+    #[test]
+    fn test_stream() {
+        let input = MemReader::new(b"a12\n-3\nx\n".to_vec());
+        let mut io = StreamIo::new(input, MemWriter::new());
+        assert_eq!(io.get_char(), Ok('a'));
+        assert_eq!(io.get_number(), Ok(12));
+        assert_eq!(io.get_number(), Ok(-3));
+        assert!(io.get_number().is_err());
+        io.put_char('b').unwrap();
+        io.put_number(-42).unwrap();
+        let (_, output) = io.unwrap();
+        assert_eq!(output.unwrap(), b"b-42".to_vec());
+    }
+
+    #[test]
+    fn test_callback() {
+        let mut chars = Vec::new();
+        let mut numbers = Vec::new();
+        {
+            let mut input = "ab".chars();
+            let mut io = CallbackIo::new(|| match input.next() {
+                                             Some(c) => Ok(c),
+                                             None => Err(standard_error(EndOfFile)),
+                                         },
+                                         || Ok(7),
+                                         |c| { chars.push(c); Ok(()) },
+                                         |n| { numbers.push(n); Ok(()) });
+            assert_eq!(io.get_char(), Ok('a'));
+            assert_eq!(io.get_char(), Ok('b'));
+            assert!(io.get_char().is_err());
+            assert_eq!(io.get_number(), Ok(7));
+            io.put_char('x').unwrap();
+            io.put_number(-1).unwrap();
+            io.put_char('y').unwrap();
+        }
+        assert_eq!(chars, vec!('x', 'y'));
+        assert_eq!(numbers, vec!(-1));
+    }
+
+    #[test]
+    fn test_null() {
+        let mut io = NullIo;
+        assert_eq!(io.get_char().unwrap_err().kind, PermissionDenied);
+        assert_eq!(io.get_number().unwrap_err().kind, PermissionDenied);
+        assert_eq!(io.put_char('a').unwrap_err().kind, PermissionDenied);
+        assert_eq!(io.put_byte(0).unwrap_err().kind, PermissionDenied);
+        assert_eq!(io.put_number(1).unwrap_err().kind, PermissionDenied);
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut io = QueueIo::new();
+        assert_eq!(io.get_char().unwrap_err().kind, ResourceUnavailable);
+        io.feed("a1");
+        assert_eq!(io.get_char(), Ok('a'));
+        assert_eq!(io.get_number().unwrap_err().kind, ResourceUnavailable);
+        io.feed("2\n3");
+        assert_eq!(io.get_number(), Ok(12));
+        io.close();
+        assert_eq!(io.get_number(), Ok(3));
+        assert_eq!(io.get_char().unwrap_err().kind, EndOfFile);
+        io.put_char('x').unwrap();
+        io.put_number(5).unwrap();
+        assert_eq!(io.take_output(), "x5".to_string());
+        assert_eq!(io.take_output(), "".to_string());
+    }
+
+    #[test]
+    fn test_number_parser() {
+        let mut parser = NumberParser::new();
+        assert_eq!(parser.parse("-12\n"), Some(-12));
+        assert_eq!(parser.parse(" 12\n"), None);
+        assert_eq!(parser.parse("12abc\n"), None);
+        assert_eq!(parser.parse("\n"), None);
+        assert_eq!(parser.parse("-12\r\n"), Some(-12));
+        assert_eq!(parser.parse("12\r"), Some(12));
+        parser.set_trim_whitespace(true);
+        assert_eq!(parser.parse(" +12 \n"), Some(12));
+        parser.set_allow_trailing(true);
+        assert_eq!(parser.parse("12abc\n"), Some(12));
+        assert_eq!(parser.parse("abc\n"), None);
+        parser.set_radix(16).unwrap();
+        assert_eq!(parser.parse("-1f\n"), Some(-31));
+        parser.set_radix(10).unwrap();
+        assert_eq!(parser.parse("-9223372036854775808\n"), Some(i64::MIN));
+        assert_eq!(parser.parse("-9223372036854775809\n"), None);
+        for radix in [0u, 1, 37].iter() {
+            assert_eq!(parser.set_radix(*radix).unwrap_err().kind, InvalidInput);
+        }
+        assert_eq!(parser.parse("12\n"), Some(12));
+    }
+
+    #[test]
+    fn test_crlf() {
+        let input = MemReader::new(b"12\r\n-3\r\n".to_vec());
+        let mut io = StreamIo::new(input, MemWriter::new());
+        assert_eq!(io.get_number(), Ok(12));
+        assert_eq!(io.get_number(), Ok(-3));
+    }
+
+    #[test]
+    fn test_raw_mode() {
+        if !stdin_raw().isatty() {
+            let err = RawMode::enable(false).err().unwrap();
+            assert_eq!(err.kind, InvalidInput);
+            assert_eq!(err.desc, "stdin is not a terminal");
+        }
+    }
+
+    #[test]
+    fn test_retry() {
+        let input = MemReader::new(b"x\n\n42\n".to_vec());
+        let mut io = StreamIo::new(input, MemWriter::new());
+        let mut parser = NumberParser::new();
+        parser.set_on_failure(Retry);
+        io.set_number_parser(parser);
+        assert_eq!(io.get_number(), Ok(42));
+        assert!(io.get_number().is_err());
+    }
+
+    #[test]
+    fn test_number_format() {
+        let mut format = NumberFormat::new();
+        assert!(format.is_plain());
+        assert_eq!(format.format(-120), "-120".to_string());
+        format.set_radix(16).unwrap();
+        format.set_width(4);
+        format.set_separator(Some("\n".to_string()));
+        assert!(!format.is_plain());
+        assert_eq!(format.format(255), "00ff\n".to_string());
+        assert_eq!(format.format(-255), "-00ff\n".to_string());
+        format.set_radix(2).unwrap();
+        format.set_width(0);
+        format.set_separator(None);
+        assert_eq!(format.format(0), "0".to_string());
+        assert_eq!(format.format(i64::MIN), format!("-1{}", "0".repeat(63)));
+        format.set_radix(36).unwrap();
+        assert_eq!(format.format(35), "z".to_string());
+        for radix in [0u, 1, 37].iter() {
+            assert_eq!(format.set_radix(*radix).unwrap_err().kind, InvalidInput);
+        }
+        assert_eq!(format.format(35), "z".to_string());
+    }
+}
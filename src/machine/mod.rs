@@ -0,0 +1,1930 @@
+//! A virtual machine that execute Whitebase bytecode.
+
+#![experimental]
+
+use std::char::from_u32;
+use std::collections::HashMap;
+use std::collections::{TreeMap, TreeSet};
+use std::collections::treemap::Entries;
+use std::mem::replace;
+use std::io::{BufferedReader, BufferedWriter, EndOfFile, File, InvalidInput, IoError, IoResult, ResourceUnavailable, SeekSet};
+use time::precise_time_ns;
+use bytecode;
+use bytecode::{ByteCodeReader, Module};
+use bytecode::image::Image;
+
+pub use self::backtrace::{Backtrace, Frame};
+pub use self::builder::Builder;
+pub use self::cancel::CancelHandle;
+pub use self::cell::Cell;
+pub use self::ext::ExtHandler;
+pub use self::gas::CostTable;
+pub use self::io::{CallbackIo, MachineIo, NullIo, NumberFormat, NumberParser, QueueIo, StdIo, StreamIo};
+pub use self::io::{CharEncoding, Latin1, Utf8, RawByte};
+pub use self::profile::Profile;
+pub use self::region::{HeapRegion, SharedBuffer};
+pub use self::session::Session;
+pub use self::state::{MachineState, ThreadState};
+pub use self::stats::{ExecutionStats, ImpStats};
+pub use self::transcript::{Recorder, Replay, Transcript};
+pub use self::underflow::UnderflowHandler;
+use self::thread::Thread;
+use self::travel::Journal;
+
+pub type MachineResult<T> = Result<T, MachineError>;
+
+/// Positions following "MARK" instructions by label, built while running a program.
+pub type LabelIndex = HashMap<i64, u64>;
+
+// Maximum number of instructions followed to detect a tail call.
+static MAX_TAIL_JUMPS: uint = 16;
+
+// Default number of instructions executed by a thread before switching to the next one.
+static DEFAULT_TIME_SLICE: uint = 100;
+
+/// A list specifying VM error.
+#[deriving(Show)]
+pub enum MachineError {
+    /// Empty stack poped.
+    IllegalStackManipulation,
+    /// Tried to jump unmarked position.
+    UndefinedLabel,
+    /// Divide by zero.
+    ZeroDivision,
+    /// "RETURN" instruction was executed without "CALL".
+    CallStackEmpty,
+    /// Program includes no "EXIT" instruction.
+    MissingExitInstruction,
+    /// Tried to put a value which is not a character.
+    InvalidCharacter,
+    /// "GETC" or "GETN" instruction reached the end of input.
+    EndOfInput,
+    /// I/O error occurred.
+    MachineIoError(IoError),
+    /// Gas budget was exhausted.
+    OutOfGas,
+    /// Execution was cancelled by `CancelHandle`.
+    Cancelled,
+    /// Program includes a byte which is not an opcode, at the offset.
+    UnknownOpcode(u8, u64),
+    /// Any runtime error not part of this list.
+    OtherMachineError,
+    /// Error returned by running a program, with the call stack when it occurred.
+    Traced(Box<MachineError>, Backtrace),
+}
+
+impl MachineError {
+    /// Get the error without the backtrace.
+    pub fn cause(&self) -> &MachineError {
+        match *self {
+            Traced(ref e, _) => e.cause(),
+            ref e => e,
+        }
+    }
+
+    /// Get the call stack captured when the error occurred.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match *self {
+            Traced(_, ref backtrace) => Some(backtrace),
+            _ => None,
+        }
+    }
+}
+
+/// Errors are compared by their causes, ignoring backtraces.
+impl PartialEq for MachineError {
+    fn eq(&self, other: &MachineError) -> bool {
+        match (self.cause(), other.cause()) {
+            (&IllegalStackManipulation, &IllegalStackManipulation) => true,
+            (&UndefinedLabel, &UndefinedLabel) => true,
+            (&ZeroDivision, &ZeroDivision) => true,
+            (&CallStackEmpty, &CallStackEmpty) => true,
+            (&MissingExitInstruction, &MissingExitInstruction) => true,
+            (&InvalidCharacter, &InvalidCharacter) => true,
+            (&EndOfInput, &EndOfInput) => true,
+            (&MachineIoError(ref a), &MachineIoError(ref b)) => a == b,
+            (&OutOfGas, &OutOfGas) => true,
+            (&Cancelled, &Cancelled) => true,
+            (&UnknownOpcode(a, m), &UnknownOpcode(b, n)) => a == b && m == n,
+            (&OtherMachineError, &OtherMachineError) => true,
+            _ => false,
+        }
+    }
+}
+
+/// What "GETC" and "GETN" do at the end of input.
+#[deriving(PartialEq, Clone, Show)]
+pub enum EofBehavior {
+    /// Fail with `EndOfInput`.
+    EofError,
+    /// Store -1.
+    EofMinusOne,
+    /// Store 0.
+    EofZero,
+}
+
+/// What instructions do when popping the empty stack.
+#[deriving(PartialEq, Clone, Show)]
+pub enum UnderflowPolicy {
+    /// Fail with `IllegalStackManipulation`.
+    UnderflowError,
+    /// Treat missing values as 0.
+    UnderflowZero,
+}
+
+/// Execution state returned by `Machine::run_steps`.
+#[deriving(PartialEq, Show)]
+pub enum StepOutcome {
+    /// The program has not finished yet.
+    Running,
+    /// "EXIT" instruction was executed.
+    Exited,
+    /// "GETC" or "GETN" instruction is waiting for input which is not available yet.
+    NeedsInput,
+}
+
+/// A virtual machine, whose stack and heap hold values of the cell type `C`.
+///
+/// `Machine::new`, `Machine::with_io` and `with_stdio` create a machine of `i64` cells
+/// with the default options, and `Machine::with_io_cells` a machine of another cell type.
+/// Use `Builder` to configure execution options.
+pub struct Machine<I, C = i64> {
+    stack: Vec<C>,
+    heap: TreeMap<C, C>,
+    regions: Vec<(i64, uint, Box<HeapRegion<C> + 'static>)>,
+    caller: Vec<u64>,
+    callees: Vec<i64>,
+    index: LabelIndex,
+    pc: u64,
+    exit_status: i64,
+    io: I,
+    number_format: NumberFormat,
+    char_encoding: CharEncoding,
+    eof_behavior: EofBehavior,
+    underflow: UnderflowPolicy,
+    underflow_handler: Option<Box<UnderflowHandler<C> + 'static>>,
+    prompt: Option<String>,
+    profile: Option<Profile>,
+    tail_calls: bool,
+    implicit_exit: bool,
+    threads: Vec<Thread<C>>,
+    forks: i64,
+    slice: uint,
+    time_slice: uint,
+    gas: Option<(u64, CostTable)>,
+    journal: Option<Journal<C>>,
+    stats: Option<(ExecutionStats, TreeSet<C>)>,
+    cancel: Option<CancelHandle>,
+    extensions: HashMap<u8, Box<ExtHandler<C> + 'static>>,
+    heap_path: Option<Path>,
+    backtrace: Option<Backtrace>,
+}
+
+/// Create a new `Machine` with stdin and stdout.
+pub fn with_stdio() -> Machine<StdIo> {
+    Machine::with_io(io::stdio())
+}
+
+/// Create a new sandboxed `Machine`, where any I/O instruction fails.
+pub fn sandboxed() -> Machine<NullIo> {
+    Machine::with_io(NullIo)
+}
+
+impl<B: Buffer, W: Writer> Machine<StreamIo<B, W>> {
+    /// Creates a new `Machine` with input and output.
+    pub fn new(stdin: B, stdout: W) -> Machine<StreamIo<B, W>> {
+        Machine::with_io(StreamIo::new(stdin, stdout))
+    }
+}
+
+impl<I: MachineIo + Clone, C: Cell> Machine<I, C> {
+    /// Create a copy of this machine to explore another path of execution.
+    ///
+    /// The copy has its own stack, heap, call stack, threads and I/O, so running
+    /// either machine does not affect the other. Heap regions mapped by `map_heap`,
+    /// extension handlers, the cancel handle and the persistent heap file are
+    /// not carried over, since they are shared with the host. Neither is
+    /// the underflow handler.
+    ///
+    /// ```rust
+    /// use std::io::{BufReader, MemWriter};
+    /// use whitebase::bytecode::ByteCodeWriter;
+    /// use whitebase::machine::{Machine, NeedsInput, QueueIo};
+    ///
+    /// let mut bcw = MemWriter::new();
+    /// bcw.write_push(0).unwrap();
+    /// bcw.write_getn().unwrap();
+    /// bcw.write_push(0).unwrap();
+    /// bcw.write_retrieve().unwrap();
+    /// bcw.write_putn().unwrap();
+    /// bcw.write_exit().unwrap();
+    /// let program = bcw.unwrap();
+    ///
+    /// let mut vm = Machine::with_io(QueueIo::new());
+    /// assert_eq!(vm.run_steps(&mut BufReader::new(program.as_slice()), 10), Ok(NeedsInput));
+    /// let mut other = vm.branch();
+    /// vm.io().feed("1\n");
+    /// other.io().feed("2\n");
+    /// vm.resume(&mut BufReader::new(program.as_slice())).unwrap();
+    /// other.resume(&mut BufReader::new(program.as_slice())).unwrap();
+    /// assert_eq!(vm.io().take_output(), "1".to_string());
+    /// assert_eq!(other.io().take_output(), "2".to_string());
+    /// ```
+    pub fn branch(&self) -> Machine<I, C> {
+        Machine {
+            stack: self.stack.clone(),
+            heap: self.heap.clone(),
+            regions: Vec::new(),
+            caller: self.caller.clone(),
+            callees: self.callees.clone(),
+            index: self.index.clone(),
+            pc: self.pc,
+            exit_status: self.exit_status,
+            io: self.io.clone(),
+            number_format: self.number_format.clone(),
+            char_encoding: self.char_encoding.clone(),
+            eof_behavior: self.eof_behavior.clone(),
+            underflow: self.underflow.clone(),
+            underflow_handler: None,
+            prompt: self.prompt.clone(),
+            profile: self.profile.clone(),
+            tail_calls: self.tail_calls,
+            implicit_exit: self.implicit_exit,
+            threads: self.threads.clone(),
+            forks: self.forks,
+            slice: self.slice,
+            time_slice: self.time_slice,
+            gas: self.gas.clone(),
+            journal: self.journal.clone(),
+            stats: self.stats.clone(),
+            cancel: None,
+            extensions: HashMap::new(),
+            heap_path: None,
+            backtrace: self.backtrace.clone(),
+        }
+    }
+}
+
+impl<I: MachineIo + Clone, C: Cell> Clone for Machine<I, C> {
+    /// Same as `branch`.
+    fn clone(&self) -> Machine<I, C> { self.branch() }
+}
+
+impl<I: MachineIo> Machine<I> {
+    /// Creates a new `Machine` with `MachineIo`.
+    pub fn with_io(io: I) -> Machine<I> { Machine::with_io_cells(io) }
+}
+
+impl<I: MachineIo, C: Cell> Machine<I, C> {
+    /// Creates a new `Machine` of the cell type `C` with `MachineIo`.
+    ///
+    /// ```rust
+    /// extern crate num;
+    /// extern crate whitebase;
+    ///
+    /// use num::bigint::BigInt;
+    /// use whitebase::machine::{Machine, QueueIo};
+    ///
+    /// fn main() {
+    ///     let machine: Machine<QueueIo, BigInt> = Machine::with_io_cells(QueueIo::new());
+    ///     assert!(machine.stack().is_empty());
+    /// }
+    /// ```
+    pub fn with_io_cells(io: I) -> Machine<I, C> {
+        Machine {
+            stack: Vec::new(),
+            heap: TreeMap::new(),
+            regions: Vec::new(),
+            caller: Vec::new(),
+            callees: Vec::new(),
+            index: HashMap::new(),
+            pc: 0,
+            exit_status: 0,
+            io: io,
+            number_format: NumberFormat::new(),
+            char_encoding: Latin1,
+            eof_behavior: EofError,
+            underflow: UnderflowError,
+            underflow_handler: None,
+            prompt: None,
+            profile: None,
+            tail_calls: false,
+            implicit_exit: false,
+            threads: Vec::new(),
+            forks: 0,
+            slice: 0,
+            time_slice: DEFAULT_TIME_SLICE,
+            gas: None,
+            journal: None,
+            stats: None,
+            cancel: None,
+            extensions: HashMap::new(),
+            heap_path: None,
+            backtrace: None,
+        }
+    }
+
+    /// Set what "GETC" and "GETN" do at the end of input.
+    pub fn set_eof_behavior(&mut self, behavior: EofBehavior) { self.eof_behavior = behavior; }
+
+    /// Set what instructions do when popping the empty stack.
+    pub fn set_underflow_policy(&mut self, policy: UnderflowPolicy) { self.underflow = policy; }
+
+    /// Call a handler when popping the empty stack, in place of the underflow policy.
+    pub fn set_underflow_handler<H: UnderflowHandler<C> + 'static>(&mut self, handler: H) {
+        self.underflow_handler = Some(box handler as Box<UnderflowHandler<C> + 'static>);
+    }
+
+    /// Set how "PUTC" converts values to characters.
+    pub fn set_char_encoding(&mut self, encoding: CharEncoding) { self.char_encoding = encoding; }
+
+    /// Set a prompt written before "GETC" and "GETN" read input.
+    ///
+    /// Buffered output is flushed before reading with or without a prompt, so that
+    /// interactive programs, e.g. on a TTY, do not appear to hang.
+    pub fn set_prompt(&mut self, prompt: Option<String>) { self.prompt = prompt; }
+
+    /// Set how "PUTN" formats numbers.
+    pub fn set_number_format(&mut self, format: NumberFormat) { self.number_format = format; }
+
+    /// Set whether "CALL" in tail position reuses the current frame.
+    ///
+    /// A "CALL" is in tail position if it is followed by "RETURN", possibly through
+    /// "JUMP"s to already marked labels. Such a call does not grow the call stack,
+    /// so deeply recursive programs run in constant call stack space.
+    pub fn set_tail_calls(&mut self, enabled: bool) { self.tail_calls = enabled; }
+
+    /// Set whether running off the end of the program exits cleanly
+    /// instead of failing with `MissingExitInstruction`.
+    pub fn set_implicit_exit(&mut self, enabled: bool) { self.implicit_exit = enabled; }
+
+    /// Set the number of instructions a thread created by "FORK" executes
+    /// before the machine switches to the next thread.
+    pub fn set_time_slice(&mut self, n: uint) { self.time_slice = n; }
+
+    /// Limit execution by a gas budget, deducting the cost of each executed instruction.
+    ///
+    /// An instruction costing more than the remaining gas fails with `OutOfGas`
+    /// without being executed, so the program can be resumed after refilling.
+    pub fn set_gas(&mut self, budget: u64, table: CostTable) { self.gas = Some((budget, table)); }
+
+    /// Get the remaining gas if gas metering is enabled.
+    pub fn gas(&self) -> Option<u64> { self.gas.as_ref().map(|&(gas, _)| gas) }
+
+    /// Record a snapshot every `interval` instructions and a journal of inputs,
+    /// so that the machine can go back to any prior instruction by `step_back`.
+    pub fn enable_time_travel(&mut self, interval: u64) { self.journal = Some(Journal::new(interval)); }
+
+    /// Get the number of instructions executed since time travel was enabled.
+    pub fn time_step(&self) -> Option<u64> { self.journal.as_ref().map(|j| j.step()) }
+
+    /// Go back `n` instructions.
+    pub fn step_back(&mut self, program: &mut ByteCodeReader, n: u64) -> MachineResult<()> {
+        let step = match self.time_step() {
+            Some(step) if step > n => step - n,
+            _ => 0,
+        };
+        self.travel_to(program, step)
+    }
+
+    /// Reconstruct the state after the given number of instructions.
+    ///
+    /// The nearest prior snapshot is restored, and the following instructions are
+    /// executed again, taking inputs from the journal and discarding outputs
+    /// which were already written. Fails with `OtherMachineError` if time travel
+    /// is not enabled.
+    pub fn travel_to(&mut self, program: &mut ByteCodeReader, step: u64) -> MachineResult<()> {
+        let state = match self.journal {
+            Some(ref mut journal) => match journal.rewind(step) {
+                Some(state) => state,
+                None => return Err(OtherMachineError),
+            },
+            None => return Err(OtherMachineError),
+        };
+        // the program is the same, so the label index stays valid
+        self.restore_state(state);
+        try!(self.seek_pc(program));
+        for _ in range(self.time_step().unwrap(), step) {
+            if !try!(self.step(program)) { break }
+        }
+        Ok(())
+    }
+
+    /// Get a handle to cancel execution from another task.
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        if self.cancel.is_none() { self.cancel = Some(CancelHandle::new()); }
+        self.cancel.as_ref().unwrap().clone()
+    }
+
+    /// Register a handler of the extension instruction, replacing the previous one.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the opcode is not reserved for extensions by `bytecode::is_extension`,
+    /// or is `bytecode::CMD_FORK`.
+    pub fn register_ext<H: ExtHandler<C> + 'static>(&mut self, opcode: u8, handler: H) {
+        if !bytecode::is_extension(opcode) { fail!("opcode {:x} is not reserved for extensions", opcode) }
+        if opcode == bytecode::CMD_FORK { fail!("opcode {:x} is reserved for FORK", opcode) }
+        self.extensions.insert(opcode, box handler as Box<ExtHandler<C> + 'static>);
+    }
+
+    /// Load the heap from a file, and write it back to the file whenever a run finishes,
+    /// including a run failed by an error.
+    ///
+    /// The file is created by the first flush if it does not exist.
+    pub fn with_persistent_heap(mut self, path: Path) -> MachineResult<Machine<I, C>> {
+        if path.exists() {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) => return Err(MachineIoError(err)),
+            };
+            match persist::read_heap(&mut BufferedReader::new(file)) {
+                Ok(heap) => self.heap = heap,
+                Err(err) => return Err(MachineIoError(err)),
+            }
+        }
+        self.heap_path = Some(path);
+        Ok(self)
+    }
+
+    /// Write the heap to the file attached by `with_persistent_heap`, if any.
+    pub fn flush_heap(&self) -> MachineResult<()> {
+        let path = match self.heap_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut writer = match File::create(path) {
+            Ok(file) => BufferedWriter::new(file),
+            Err(err) => return Err(MachineIoError(err)),
+        };
+        match persist::write_heap(&self.heap, &mut writer).and_then(|_| writer.flush()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(MachineIoError(err)),
+        }
+    }
+
+    /// Get values on the stack, bottom first.
+    pub fn stack(&self) -> &[C] { self.stack.as_slice() }
+
+    /// Push a value onto the stack, e.g. to pass arguments before running.
+    pub fn stack_push(&mut self, n: C) { self.stack.push(n); }
+
+    /// Iterate over stored heap entries in address order.
+    pub fn heap_iter<'a>(&'a self) -> Entries<'a, C, C> { self.heap.iter() }
+
+    /// Get a value stored at the address, including regions mapped by `map_heap`.
+    pub fn heap_get(&self, addr: C) -> Option<C> {
+        match self.region_get(&addr) {
+            Some(val) => Some(val),
+            None => self.heap.find(&addr).map(|val| val.clone()),
+        }
+    }
+
+    /// Store a value at the address, including regions mapped by `map_heap`.
+    pub fn heap_set(&mut self, addr: C, val: C) {
+        match self.find_region(&addr) {
+            Some((n, offset)) => {
+                let (_, _, ref mut region) = *self.regions.get_mut(n);
+                region.set(offset, val);
+            },
+            None => { self.heap.insert(addr, val); },
+        }
+    }
+
+    /// Map `len` heap addresses from `start` to a region provided by the host.
+    ///
+    /// "STORE", "RETRIEVE" and input instructions on these addresses access the
+    /// region instead of the heap. Mapped values are not part of `heap_iter`,
+    /// snapshots, or the heap passed to extension handlers.
+    pub fn map_heap<R: HeapRegion<C> + 'static>(&mut self, start: i64, len: uint, region: R) {
+        self.regions.push((start, len, box region as Box<HeapRegion<C> + 'static>));
+    }
+
+    fn find_region(&self, addr: &C) -> Option<(uint, uint)> {
+        // regions are mapped at i64 addresses
+        let addr = match addr.to_i64() {
+            Some(addr) => addr,
+            None => return None,
+        };
+        self.regions.iter().position(|&(start, len, _)| {
+            start <= addr && ((addr - start) as u64) < len as u64
+        }).map(|n| {
+            let (start, _, _) = self.regions[n];
+            (n, (addr - start) as uint)
+        })
+    }
+
+    fn region_get(&self, addr: &C) -> Option<C> {
+        match self.find_region(addr) {
+            Some((n, offset)) => {
+                let (_, _, ref region) = self.regions[n];
+                Some(region.get(offset))
+            },
+            None => None,
+        }
+    }
+
+    /// Start collecting an execution profile, discarding the previous one.
+    pub fn enable_profiling(&mut self) { self.profile = Some(Profile::new()); }
+
+    /// Get the execution profile if profiling is enabled.
+    pub fn profile(&self) -> Option<&Profile> { self.profile.as_ref() }
+
+    /// Get the call stack captured when the last executed instruction failed.
+    ///
+    /// The same backtrace is attached to the error returned by `run`, `resume` and `run_steps`.
+    pub fn backtrace(&self) -> Option<&Backtrace> { self.backtrace.as_ref() }
+
+    /// Get the status of the last executed "EXIT".
+    pub fn exit_status(&self) -> i64 { self.exit_status }
+
+    /// Get a mutable reference to the I/O of this machine.
+    pub fn io(&mut self) -> &mut I { &mut self.io }
+
+    /// Run program, returning the exit status.
+    ///
+    /// The status is 0 unless the program exits by "EXIT" with a status operand.
+    ///
+    /// "FORK" copies the stack and call stack of the current thread into a new thread
+    /// sharing the heap, and pushes the new thread number, or 0 in the new thread.
+    /// Threads are scheduled round-robin, and "EXIT" finishes the current thread.
+    /// The program finishes when all threads have finished.
+    pub fn run(&mut self, program: &mut ByteCodeReader) -> MachineResult<i64> {
+        self.reset_run();
+        self.exec(program)
+    }
+
+    /// Run program, collecting execution statistics.
+    ///
+    /// Statistics are returned even if the program fails.
+    pub fn run_with_stats(&mut self, program: &mut ByteCodeReader) -> (MachineResult<i64>, ExecutionStats) {
+        self.stats = Some((ExecutionStats::new(), TreeSet::new()));
+        let start = precise_time_ns();
+        let ret = self.run(program);
+        let (mut stats, touched) = self.stats.take().unwrap();
+        stats.heap_touched = touched.len();
+        stats.wall_time_ns = precise_time_ns() - start;
+        (ret, stats)
+    }
+
+    /// Run program with a label index built by a previous run of the same program.
+    ///
+    /// Labels already in the index are not searched again.
+    pub fn run_with_index(&mut self, program: &mut ByteCodeReader, index: LabelIndex) -> MachineResult<i64> {
+        self.reset_run();
+        self.index = index;
+        self.exec(program)
+    }
+
+    /// Run a decoded module with the positions of its labels, so that no label is searched.
+    pub fn run_module(&mut self, module: &Module) -> MachineResult<i64> {
+        self.run_with_index(&mut module.reader(), module.label_index())
+    }
+
+    /// Store the values of the data segments of the image into the heap.
+    ///
+    /// ```rust
+    /// use whitebase::bytecode::image::{DataSegment, Image};
+    /// use whitebase::machine;
+    ///
+    /// let mut image = Image::new(vec!());
+    /// image.set_data([DataSegment::bytes(100, b"Hi")]);
+    /// let mut machine = machine::sandboxed();
+    /// machine.load_data(&image).unwrap();
+    /// assert_eq!(machine.heap_get(101), Some('i' as i64));
+    /// ```
+    pub fn load_data(&mut self, image: &Image) -> IoResult<()> {
+        for segment in try!(image.data()).iter() {
+            for (i, n) in segment.values().move_iter().enumerate() {
+                self.heap_set(Cell::wrap(segment.address + i as i64), Cell::wrap(n));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the data segments of the image and run it, with the label index if stored,
+    /// returning the exit status.
+    ///
+    /// A label index which doesn't match the instruction stream fails with `MachineIoError`.
+    ///
+    /// ```rust
+    /// use whitebase::bytecode::image::Image;
+    /// use whitebase::ir;
+    /// use whitebase::ir::Program;
+    /// use whitebase::machine;
+    ///
+    /// let program = Program::from_vec(vec!(ir::Jump(1), ir::Mark(1), ir::Exit));
+    /// let mut image = Image::from_program(&program).unwrap();
+    /// image.index_labels().unwrap();
+    /// assert_eq!(machine::sandboxed().run_image(&image), Ok(0));
+    /// ```
+    pub fn run_image(&mut self, image: &Image) -> MachineResult<i64> {
+        let module = try!(Module::from_image(image).map_err(|e| MachineIoError(e)));
+        try!(self.load_data(image).map_err(|e| MachineIoError(e)));
+        self.run_module(&module)
+    }
+
+    /// Get the label index built so far, to be passed to `run_with_index`.
+    pub fn label_index(&self) -> LabelIndex { self.index.clone() }
+
+    /// Continue program from the program counter kept by the machine,
+    /// e.g. after `restore`, returning the exit status.
+    pub fn resume(&mut self, program: &mut ByteCodeReader) -> MachineResult<i64> {
+        try!(self.seek_pc(program));
+        self.exec(program)
+    }
+
+    /// Execute at most `n` instructions from the program counter kept by the machine.
+    ///
+    /// The stack, heap, call stack and program counter are preserved between calls,
+    /// so a program can be executed in bounded slices.
+    ///
+    /// If the I/O reports that no input is available (`ResourceUnavailable`),
+    /// execution is suspended before the input instruction and `NeedsInput` is returned.
+    pub fn run_steps(&mut self, program: &mut ByteCodeReader, n: uint) -> MachineResult<StepOutcome> {
+        try!(self.seek_pc(program));
+        for _ in range(0, n) {
+            match self.step(program) {
+                Ok(true) => continue,
+                Ok(false) => { try!(self.flush_heap()); return Ok(Exited) },
+                Err(MachineIoError(ref e)) if e.kind == ResourceUnavailable => return Ok(NeedsInput),
+                Err(e) => return Err(self.abort_run(e)),
+            }
+        }
+        Ok(Running)
+    }
+
+    /// Take a snapshot of the stack, heap, call stack, program counter and
+    /// threads created by "FORK".
+    pub fn snapshot(&self) -> MachineState<C> {
+        MachineState {
+            stack: self.stack.clone(),
+            heap: self.heap.clone(),
+            caller: self.caller.clone(),
+            pc: self.pc,
+            threads: self.threads.iter().map(|thread| {
+                ThreadState { stack: thread.stack.clone(), caller: thread.caller.clone(), pc: thread.pc }
+            }).collect(),
+            forks: self.forks,
+            slice: self.slice as u64,
+        }
+    }
+
+    /// Restore the machine to a snapshot taken by `snapshot`.
+    ///
+    /// Called labels are not part of a snapshot, so backtraces of restored frames
+    /// show unknown labels.
+    pub fn restore(&mut self, state: MachineState<C>) {
+        self.restore_state(state);
+        self.index.clear();
+    }
+
+    fn restore_state(&mut self, state: MachineState<C>) {
+        self.stack = state.stack;
+        self.heap = state.heap;
+        self.caller = state.caller;
+        self.callees.clear();
+        self.threads = state.threads.move_iter().map(|thread| {
+            Thread { stack: thread.stack, caller: thread.caller, callees: Vec::new(), pc: thread.pc }
+        }).collect();
+        self.forks = state.forks;
+        self.slice = state.slice as uint;
+        self.pc = state.pc;
+    }
+
+    fn reset_run(&mut self) {
+        self.index.clear();
+        self.caller.clear();
+        self.callees.clear();
+        self.threads.clear();
+        self.forks = 0;
+        match self.journal {
+            Some(ref mut journal) => journal.reset(),
+            None => (),
+        }
+    }
+
+    fn seek_pc(&mut self, program: &mut ByteCodeReader) -> MachineResult<()> {
+        match program.seek(self.pc.to_i64().unwrap(), SeekSet) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(MachineIoError(err)),
+        }
+    }
+
+    fn exec(&mut self, program: &mut ByteCodeReader) -> MachineResult<i64> {
+        loop {
+            match self.step(program) {
+                Err(e)    => return Err(self.abort_run(e)),
+                Ok(false) => { try!(self.flush_heap()); return Ok(self.exit_status) },
+                Ok(true)  => continue,
+            }
+        }
+    }
+
+    fn step(&mut self, program: &mut ByteCodeReader) -> MachineResult<bool> {
+        self.backtrace = None;
+        let snapshot = match self.journal {
+            Some(ref journal) => journal.needs_snapshot(),
+            None => false,
+        };
+        if snapshot {
+            let state = self.snapshot();
+            self.journal.as_mut().unwrap().push_snapshot(state);
+        }
+        match self.decode_and_execute(program) {
+            Err(e) => {
+                self.backtrace = Some(self.capture_backtrace());
+                Err(e)
+            },
+            Ok(running) => {
+                match self.journal {
+                    Some(ref mut journal) => journal.advance(),
+                    None => (),
+                }
+                self.schedule(program, running)
+            },
+        }
+    }
+
+    fn is_replaying(&self) -> bool {
+        match self.journal {
+            Some(ref journal) => journal.is_replaying(),
+            None => false,
+        }
+    }
+
+    fn show_prompt(&mut self) -> MachineResult<()> {
+        match self.prompt {
+            Some(ref prompt) => {
+                for c in prompt.as_slice().chars() {
+                    match self.io.put_char(c) {
+                        Ok(_) => (),
+                        Err(err) => return Err(MachineIoError(err)),
+                    }
+                }
+            },
+            None => (),
+        }
+        match self.io.flush() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(MachineIoError(err)),
+        }
+    }
+
+    fn touch(&mut self, addr: &C) {
+        match self.stats {
+            Some((_, ref mut touched)) => { touched.insert(addr.clone()); },
+            None => (),
+        }
+    }
+
+    fn count_io(&mut self, read: uint, written: uint) {
+        match self.stats {
+            Some((ref mut stats, _)) => {
+                stats.bytes_read += read as u64;
+                stats.bytes_written += written as u64;
+            },
+            None => (),
+        }
+    }
+
+    fn input(&mut self, read: |&mut I| -> IoResult<(i64, uint)>) -> MachineResult<i64> {
+        match self.journal {
+            Some(ref journal) => match journal.input() {
+                Some(n) => return Ok(n),
+                None => (),
+            },
+            None => (),
+        }
+        try!(self.show_prompt());
+        let n = match read(&mut self.io) {
+            Ok((n, bytes)) => { self.count_io(bytes, 0); n },
+            Err(ref e) if e.kind == EndOfFile => try!(self.eof_value()),
+            Err(err) => return Err(MachineIoError(err)),
+        };
+        match self.journal {
+            Some(ref mut journal) => journal.record_input(n),
+            None => (),
+        }
+        Ok(n)
+    }
+
+    fn schedule(&mut self, program: &mut ByteCodeReader, running: bool) -> MachineResult<bool> {
+        if self.threads.is_empty() { return Ok(running) }
+        if running {
+            self.slice += 1;
+            if self.slice < self.time_slice { return Ok(true) }
+            let current = Thread {
+                stack: replace(&mut self.stack, Vec::new()),
+                caller: replace(&mut self.caller, Vec::new()),
+                callees: replace(&mut self.callees, Vec::new()),
+                pc: self.pc,
+            };
+            self.threads.push(current);
+        }
+        let next = self.threads.remove(0).unwrap();
+        self.stack = next.stack;
+        self.caller = next.caller;
+        self.callees = next.callees;
+        self.pc = next.pc;
+        self.slice = 0;
+        try!(self.seek_pc(program));
+        Ok(true)
+    }
+
+    // Finish a run failed by the error, flushing the heap as a finished run does.
+    // The error of the program is reported even if flushing fails as well.
+    fn abort_run(&mut self, e: MachineError) -> MachineError {
+        let _ = self.flush_heap();
+        self.traced(e)
+    }
+
+    // Attach the backtrace captured by the failed step to an error.
+    fn traced(&self, e: MachineError) -> MachineError {
+        match self.backtrace {
+            Some(ref backtrace) => Traced(box e, backtrace.clone()),
+            None => e,
+        }
+    }
+
+    fn capture_backtrace(&self) -> Backtrace {
+        let mut callees = self.callees.iter().rev();
+        Backtrace {
+            pc: self.pc,
+            frames: self.caller.iter().rev().map(|pos| {
+                Frame { return_to: *pos, target: callees.next().map(|n| *n) }
+            }).collect(),
+        }
+    }
+
+    fn decode_and_execute(&mut self, program: &mut ByteCodeReader) -> MachineResult<bool> {
+        match self.cancel {
+            Some(ref handle) if handle.is_cancelled() => return Err(Cancelled),
+            _ => (),
+        }
+        let (opcode, operand) = match program.read_inst() {
+            Ok(inst) => inst,
+            Err(ref e) if e.kind == EndOfFile => {
+                if !self.implicit_exit { return Err(MissingExitInstruction) }
+                self.exit_status = 0;
+                return Ok(false)
+            },
+            // an unknown opcode at the program counter, read again to report it
+            Err(ref e) if e.kind == InvalidInput && e.desc == "unknown opcode" => {
+                let read = program.seek(self.pc.to_i64().unwrap(), SeekSet).and_then(|()| program.read_u8());
+                return match read {
+                    Ok(opcode) => Err(UnknownOpcode(opcode, self.pc)),
+                    Err(err) => Err(MachineIoError(err)),
+                }
+            },
+            Err(e) => return Err(MachineIoError(e)),
+        };
+        let cost = match self.gas {
+            Some((gas, ref table)) => {
+                let cost = table.cost(opcode);
+                if cost > gas { return Err(OutOfGas) }
+                cost
+            },
+            None => 0,
+        };
+        let timed = self.profile.is_some() || self.stats.is_some();
+        let start = if timed { precise_time_ns() } else { 0 };
+        // On error the program counter keeps pointing at the failed instruction.
+        let ret = try!(self.execute(program, opcode, operand));
+        let elapsed = if timed { precise_time_ns() - start } else { 0 };
+        match self.stats {
+            Some((ref mut stats, _)) => {
+                stats.instructions += 1;
+                if stats.max_stack_depth < self.stack.len() { stats.max_stack_depth = self.stack.len(); }
+                match stats.imp_mut(opcode) {
+                    Some(imp) => {
+                        imp.instructions += 1;
+                        imp.time_ns += elapsed;
+                    },
+                    None => (),
+                }
+            },
+            None => (),
+        }
+        match self.gas {
+            Some((ref mut gas, _)) => *gas -= cost,
+            None => (),
+        }
+        match program.tell() {
+            Ok(pos) => self.pc = pos,
+            Err(err) => return Err(MachineIoError(err)),
+        }
+        if self.profile.is_some() {
+            self.profile_step(opcode, operand, elapsed);
+        }
+        Ok(ret)
+    }
+
+    fn profile_step(&mut self, opcode: u8, operand: i64, elapsed: u64) {
+        let landed = match self.index.find(&operand) {
+            Some(pos) => *pos == self.pc,
+            None => false,
+        };
+        let profile = self.profile.as_mut().unwrap();
+        profile.record(elapsed);
+        match opcode {
+            bytecode::CMD_MARK => profile.enter(operand),
+            bytecode::CMD_JUMP | bytecode::CMD_JUMPZ | bytecode::CMD_JUMPN if landed => profile.enter(operand),
+            bytecode::CMD_CALL => profile.call(operand),
+            bytecode::CMD_RETURN => profile.ret(),
+            _ => (),
+        }
+    }
+
+    fn execute(&mut self, program: &mut ByteCodeReader, opcode: u8, operand: i64) -> MachineResult<bool> {
+        match (opcode, operand) {
+            (bytecode::CMD_PUSH, n)           => { debug!("PUSH {}", n); try!(self.push(n)); Ok(true) },
+            (bytecode::CMD_DUP, _)            => { debug!("DUP"); try!(self.copy(0)); Ok(true) },
+            (bytecode::CMD_COPY, n)           => { debug!("COPY {}", n); try!(self.copy(n.to_uint().unwrap())); Ok(true) },
+            (bytecode::CMD_SWAP, _)           => { debug!("SWAP"); try!(self.swap()); Ok(true) },
+            (bytecode::CMD_DISCARD, _)        => { debug!("SWAP"); try!(self.discard()); Ok(true) },
+            (bytecode::CMD_SLIDE, n)          => { debug!("SLIDE {}", n); try!(self.slide(n.to_uint().unwrap())); Ok(true) },
+            (bytecode::CMD_ADD, _)            => { debug!("ADD"); try!(self.calc(|x, y| { y + x })); Ok(true) },
+            (bytecode::CMD_SUB, _)            => { debug!("SUB"); try!(self.calc(|x, y| { y - x })); Ok(true) },
+            (bytecode::CMD_MUL, _)            => { debug!("MUL"); try!(self.calc(|x, y| { y * x })); Ok(true) },
+            (bytecode::CMD_DIV, _)            => { debug!("DIV"); try!(self.dcalc(|x, y| { y / x })); Ok(true) },
+            (bytecode::CMD_MOD, _)            => { debug!("MOD"); try!(self.dcalc(|x, y| { y % x })); Ok(true) },
+            (bytecode::CMD_STORE, _)          => { debug!("STORE"); try!(self.store()); Ok(true) },
+            (bytecode::CMD_RETRIEVE, _)       => { debug!("RETREIVE"); try!(self.retrieve()); Ok(true) },
+            (bytecode::CMD_MARK, n)           => { debug!("MARK {}", n); try!(self.mark(program, n)); Ok(true) },
+            (bytecode::CMD_CALL, n)           => { debug!("CALL {}", n); try!(self.call(program, &n)); Ok(true) },
+            (bytecode::CMD_JUMP, n)           => { debug!("JUMP {}", n); try!(self.jump(program, &n)); Ok(true) },
+            (bytecode::CMD_JUMPZ, n)          => { debug!("JUMPZ {}", n); try!(self.jump_if(program, &n, |x| { x.is_zero() })); Ok(true) },
+            (bytecode::CMD_JUMPN, n)          => { debug!("JUMPN {}", n); try!(self.jump_if(program, &n, |x| { x.is_negative() })); Ok(true) },
+            (bytecode::CMD_RETURN, _)         => { debug!("RETURN"); try!(self.do_return(program)); Ok(true) },
+            (bytecode::CMD_EXIT, _)           => { debug!("EXIT ({}, {})", self.stack, self.heap); self.exit_status = 0; Ok(false) },
+            (bytecode::CMD_FORK, _)           => { debug!("FORK"); try!(self.fork(program)); Ok(true) },
+            (bytecode::CMD_NOP, _)            => { debug!("NOP"); Ok(true) },
+            (bytecode::CMD_EXIT_STATUS, n)    => { debug!("EXIT {} ({}, {})", n, self.stack, self.heap); self.exit_status = n; Ok(false) },
+            (bytecode::CMD_PUSH_ADD, n)       => { debug!("PUSHADD {}", n); try!(self.push(n)); try!(self.calc(|x, y| { y + x })); Ok(true) },
+            (bytecode::CMD_RETRIEVE_AT, n)    => { debug!("RETRIEVEAT {}", n); try!(self.push(n)); try!(self.retrieve()); Ok(true) },
+            (bytecode::CMD_STORE_CONST, n)    => { debug!("STORECONST {}", n); try!(self.push(n)); try!(self.store()); Ok(true) },
+            (bytecode::CMD_PUTC, _)           => { debug!("PUTC"); try!(self.put_char()); Ok(true) },
+            (bytecode::CMD_PUTN, _)           => { debug!("PUTN"); try!(self.put_num()); Ok(true) },
+            (bytecode::CMD_GETC, _)           => { debug!("GETC"); try!(self.get_char()); Ok(true) },
+            (bytecode::CMD_GETN, _)           => { debug!("GETN"); try!(self.get_num()); Ok(true) },
+            (op, n) if bytecode::is_extension(op) => { debug!("EXT {:x} {}", op, n); try!(self.ext(op, n)); Ok(true) },
+            (op, _)                           => Err(UnknownOpcode(op, self.pc)),
+        }
+    }
+
+    fn fork(&mut self, program: &mut ByteCodeReader) -> MachineResult<()> {
+        let pc = match program.tell() {
+            Ok(pos) => pos,
+            Err(err) => return Err(MachineIoError(err)),
+        };
+        let mut stack = self.stack.clone();
+        stack.push(Cell::wrap(0));
+        self.threads.push(Thread {
+            stack: stack,
+            caller: self.caller.clone(),
+            callees: self.callees.clone(),
+            pc: pc,
+        });
+        self.forks += 1;
+        self.stack.push(Cell::wrap(self.forks));
+        Ok(())
+    }
+
+    fn ext(&mut self, opcode: u8, operand: i64) -> MachineResult<()> {
+        match self.extensions.find_mut(&opcode) {
+            Some(handler) => handler.execute(operand, &mut self.stack, &mut self.heap),
+            None => Err(OtherMachineError),
+        }
+    }
+
+    fn push(&mut self, n: i64) -> MachineResult<()> {
+        self.stack.push(Cell::wrap(n));
+        Ok(())
+    }
+
+    fn underflow(&mut self) -> MachineResult<C> {
+        match self.underflow_handler {
+            Some(ref mut handler) => handler.underflow(self.pc),
+            None => match self.underflow {
+                UnderflowError => Err(IllegalStackManipulation),
+                UnderflowZero  => Ok(Cell::wrap(0)),
+            },
+        }
+    }
+
+    fn pop(&mut self) -> MachineResult<C> {
+        match self.stack.pop() {
+            Some(n) => Ok(n),
+            None => self.underflow(),
+        }
+    }
+
+    fn copy(&mut self, n: uint) -> MachineResult<()> {
+        if self.stack.len() <= n {
+            let val = try!(self.underflow());
+            self.stack.push(val);
+            return Ok(())
+        }
+        let mut i = 0;
+        let mut tmp = vec!();
+        while i < n {
+            tmp.insert(0, self.stack.pop().unwrap());
+            i += 1;
+        }
+        let val = self.stack.pop().unwrap();
+        self.stack.push(val.clone());
+        self.stack.push_all(tmp.as_slice());
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn swap(&mut self) -> MachineResult<()> {
+        let x = try!(self.pop());
+        let y = try!(self.pop());
+        self.stack.push(x);
+        self.stack.push(y);
+        Ok(())
+    }
+
+    fn discard(&mut self) -> MachineResult<()> {
+        try!(self.pop());
+        Ok(())
+    }
+
+    fn slide(&mut self, n: uint) -> MachineResult<()> {
+        if self.stack.len() < n {
+            try!(self.underflow());
+        }
+        let top = try!(self.pop());
+        let mut i = 0u;
+        while i < n {
+            self.stack.pop();
+            i += 1;
+        }
+        self.stack.push(top);
+        Ok(())
+    }
+
+    fn calc(&mut self, f: |C, C| -> C) -> MachineResult<()> {
+        let x = try!(self.pop());
+        let y = try!(self.pop());
+        self.stack.push(f(x, y));
+        Ok(())
+    }
+
+    fn dcalc(&mut self, divf: |C, C| -> C) -> MachineResult<()> {
+        let x = try!(self.pop());
+        if x.is_zero() { return Err(ZeroDivision) }
+        let y = try!(self.pop());
+        self.stack.push(divf(x, y));
+        Ok(())
+    }
+
+    fn store(&mut self) -> MachineResult<()> {
+        let val = try!(self.pop());
+        let addr = try!(self.pop());
+        self.touch(&addr);
+        self.heap_set(addr, val);
+        Ok(())
+    }
+
+    fn retrieve(&mut self) -> MachineResult<()> {
+        let addr = try!(self.pop());
+        self.touch(&addr);
+        let val = self.heap_get(addr).unwrap_or_else(|| Cell::wrap(0));
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn mark(&mut self, program: &mut ByteCodeReader, label: i64) -> MachineResult<()> {
+        match program.tell() {
+            Ok(pos) => {
+                self.index.insert(label, pos);
+                Ok(())
+            },
+            Err(err) => return Err(MachineIoError(err)),
+        }
+    }
+
+    fn call(&mut self, program: &mut ByteCodeReader, label: &i64) -> MachineResult<()> {
+        match program.tell() {
+            Ok(pos) => {
+                if !(self.tail_calls && try!(self.is_tail_position(program, pos))) {
+                    self.caller.push(pos);
+                    self.callees.push(*label);
+                }
+                self.jump(program, label)
+            },
+            Err(err) => Err(MachineIoError(err)),
+        }
+    }
+
+    fn is_tail_position(&mut self, program: &mut ByteCodeReader, pos: u64) -> MachineResult<bool> {
+        let mut tail = false;
+        for _ in range(0, MAX_TAIL_JUMPS) {
+            match program.read_inst() {
+                Ok((bytecode::CMD_RETURN, _)) => { tail = true; break },
+                Ok((bytecode::CMD_MARK, _)) => continue,
+                Ok((bytecode::CMD_JUMP, label)) => match self.index.find_copy(&label) {
+                    Some(to) => match program.seek(to.to_i64().unwrap(), SeekSet) {
+                        Ok(_) => continue,
+                        Err(err) => return Err(MachineIoError(err)),
+                    },
+                    None => break,
+                },
+                Ok(_) => break,
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(err) => return Err(MachineIoError(err)),
+            }
+        }
+        match program.seek(pos.to_i64().unwrap(), SeekSet) {
+            Ok(_) => Ok(tail),
+            Err(err) => Err(MachineIoError(err)),
+        }
+    }
+
+    fn jump(&mut self, program: &mut ByteCodeReader, label: &i64) -> MachineResult<()> {
+        match self.index.find_copy(label) {
+            Some(pos) => match program.seek(pos.to_i64().unwrap(), SeekSet) {
+                Ok(_) => Ok(()),
+                Err(err) => Err(MachineIoError(err)),
+            },
+            None => {
+                loop {
+                    match program.read_inst() {
+                        Ok((opcode, operand)) if opcode == bytecode::CMD_MARK => {
+                            match program.tell() {
+                                Ok(pos) => {
+                                    self.index.insert(operand, pos);
+                                    if operand == *label { return Ok(()) }
+                                },
+                                Err(err) => return Err(MachineIoError(err)),
+                            }
+                        },
+                        Err(ref e) if e.kind == EndOfFile => return Err(UndefinedLabel),
+                        Err(err) => return Err(MachineIoError(err)),
+                        _ => continue,
+                    }
+                }
+            },
+        }
+    }
+
+    fn jump_if(&mut self, program: &mut ByteCodeReader, label: &i64, test: |C| -> bool) -> MachineResult<()> {
+        let x = try!(self.pop());
+        if test(x) { self.jump(program, label) } else { Ok(()) }
+    }
+
+    fn do_return(&mut self, program: &mut ByteCodeReader) -> MachineResult<()> {
+        self.callees.pop();
+        match self.caller.pop() {
+            Some(to_return) => match program.seek(to_return.to_i64().unwrap(), SeekSet) {
+                Ok(_) => Ok(()),
+                Err(err) => Err(MachineIoError(err)),
+            },
+            None => Err(CallStackEmpty),
+        }
+    }
+
+    fn put_char(&mut self) -> MachineResult<()> {
+        let n = try!(self.pop());
+        if self.is_replaying() { return Ok(()) }
+        let (ret, bytes) = match (self.char_encoding, n.to_u8(), n.to_u32().and_then(|c| from_u32(c))) {
+            (Latin1, Some(b), _) => (self.io.put_char(b as char), (b as char).len_utf8_bytes()),
+            (Utf8, _, Some(c)) => (self.io.put_char(c), c.len_utf8_bytes()),
+            (RawByte, Some(b), _) => (self.io.put_byte(b), 1),
+            _ => return Err(InvalidCharacter),
+        };
+        match ret {
+            Ok(_) => { self.count_io(0, bytes); Ok(()) },
+            Err(e) => Err(MachineIoError(e)),
+        }
+    }
+
+    fn put_num(&mut self) -> MachineResult<()> {
+        let cell = try!(self.pop());
+        if self.is_replaying() { return Ok(()) }
+        match cell.to_i64() {
+            Some(n) if self.number_format.is_plain() => {
+                match self.io.put_number(n) {
+                    Ok(_) => { self.count_io(0, n.to_string().len()); Ok(()) },
+                    Err(e) => Err(MachineIoError(e)),
+                }
+            },
+            Some(n) => {
+                let formatted = self.number_format.format(n);
+                self.put_str(formatted.as_slice())
+            },
+            // beyond the MachineIo interface, written in decimal
+            None if self.number_format.is_plain() => self.put_str(cell.to_string().as_slice()),
+            None => Err(MachineIoError(IoError {
+                kind: InvalidInput,
+                desc: "number out of range of the number format",
+                detail: Some(cell.to_string()),
+            })),
+        }
+    }
+
+    fn put_str(&mut self, s: &str) -> MachineResult<()> {
+        for c in s.chars() {
+            match self.io.put_char(c) {
+                Ok(_) => (),
+                Err(e) => return Err(MachineIoError(e)),
+            }
+        }
+        self.count_io(0, s.len());
+        Ok(())
+    }
+
+    fn get_char(&mut self) -> MachineResult<()> {
+        let c = try!(self.input(|io| io.get_char().map(|c| (c as i64, c.len_utf8_bytes()))));
+        self.stack.push(Cell::wrap(c));
+        self.store()
+    }
+
+    fn get_num(&mut self) -> MachineResult<()> {
+        let n = try!(self.input(|io| io.get_number().map(|n| (n, n.to_string().len()))));
+        self.stack.push(Cell::wrap(n));
+        self.store()
+    }
+
+    fn eof_value(&self) -> MachineResult<i64> {
+        match self.eof_behavior {
+            EofError    => Err(EndOfInput),
+            EofMinusOne => Ok(-1),
+            EofZero     => Ok(0),
+        }
+    }
+}
+
+pub mod backtrace;
+pub mod batch;
+pub mod builder;
+pub mod cancel;
+pub mod cell;
+pub mod debug;
+pub mod ext;
+pub mod gas;
+pub mod io;
+pub mod persist;
+pub mod pipeline;
+pub mod profile;
+pub mod region;
+pub mod session;
+pub mod state;
+pub mod stats;
+mod thread;
+pub mod transcript;
+mod travel;
+pub mod underflow;
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufWriter, IoResult, MemReader, MemWriter, PermissionDenied, SeekSet};
+    use std::io::util::{NullReader, NullWriter};
+    use bytecode;
+    use bytecode::{ByteCodeWriter, Module};
+    use bytecode::image::{DataSegment, Image};
+    use ir;
+    use ir::Program;
+
+    #[test]
+    fn test_stack() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_copy(1).unwrap();
+        bcw.write_swap().unwrap();
+        bcw.write_discard().unwrap();
+        bcw.write_slide(1).unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1, 1));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1, 1, 1));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1, 1, 1));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1, 1));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1));
+        assert!(vm.step(&mut bcr).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut bcw = MemWriter::new();
+        bcw.write_add().unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_mul().unwrap();
+        bcw.write_div().unwrap();
+        bcw.write_mod().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.stack.push_all([2, 19, 2, 5, 1, 1]);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(2, 19, 2, 5, 2));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(2, 19, 2, 3));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(2, 19, 6));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(2, 3));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(2));
+        assert!(vm.step(&mut bcr).is_err());
+    }
+
+    #[test]
+    fn test_heap() {
+        let mut bcw = MemWriter::new();
+        bcw.write_store().unwrap();
+        bcw.write_retrieve().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.stack.push_all([1, 1, 2]);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1));
+        assert_eq!(vm.heap.find(&1), Some(&2));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(2));
+        assert!(vm.step(&mut bcr).is_err());
+    }
+
+    #[test]
+    fn test_flow() {
+        let mut bcw = MemWriter::new();
+        bcw.write_jump(1).unwrap();
+        bcw.write_mark(3).unwrap();
+        bcw.write_call(4).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(2).unwrap();
+        bcw.write_jumpn(3).unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_jumpz(2).unwrap();
+        bcw.write_mark(4).unwrap();
+        bcw.write_return().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.stack.push_all([-1, 0]);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(-1, 0));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(-1));
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!());
+        assert_eq!(vm.caller.len(), 0);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.caller.len(), 1);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.caller.len(), 0);
+        assert_eq!(vm.step(&mut bcr), Ok(false));
+    }
+
+    #[test]
+    fn test_io() {
+        let mut heap = [0, 0];
+        let mut buf  = [0, ..2];
+        {
+            let mut bcw = MemWriter::new();
+            bcw.write_getc().unwrap();
+            bcw.write_getn().unwrap();
+            bcw.write_putc().unwrap();
+            bcw.write_putn().unwrap();
+            let mut bcr = MemReader::new(bcw.unwrap());
+            let input = MemReader::new(vec!(87, 49, 50, 51, 10));
+            let output = BufWriter::new(buf);
+            let mut vm = super::Machine::new(input, output);
+            vm.stack.push_all([5, 66, 2, 1]);
+            vm.step(&mut bcr).unwrap();
+            vm.step(&mut bcr).unwrap();
+            vm.step(&mut bcr).unwrap();
+            vm.step(&mut bcr).unwrap();
+            assert!(vm.step(&mut bcr).is_err());
+
+            heap[0] = *vm.heap.find(&1).unwrap();
+            heap[1] = *vm.heap.find(&2).unwrap();
+        }
+        assert!(heap == [87, 123]);
+        assert!(buf == [66, 53]);
+    }
+
+    #[test]
+    fn test_inspection() {
+        let mut bcw = MemWriter::new();
+        bcw.write_add().unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.stack_push(1);
+        vm.stack_push(2);
+        vm.heap_set(3, 42);
+        vm.heap_set(-1, 7);
+        vm.run(&mut bcr).unwrap();
+        assert_eq!(vm.stack(), [42].as_slice());
+        assert_eq!(vm.heap_get(3), Some(42));
+        assert_eq!(vm.heap_get(4), None);
+        let heap: Vec<(i64, i64)> = vm.heap_iter().map(|(a, v)| (*a, *v)).collect();
+        assert_eq!(heap, vec!((-1, 7), (3, 42)));
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_exit_status(3).unwrap();
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run(&mut MemReader::new(bcw.unwrap())), Ok(3));
+        assert_eq!(vm.exit_status(), 3);
+
+        let mut bcw = MemWriter::new();
+        bcw.write_exit().unwrap();
+        assert_eq!(vm.run(&mut MemReader::new(bcw.unwrap())), Ok(0));
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run(&mut MemReader::new(vec!(0x00))), Err(super::UnknownOpcode(0x00, 0)));
+
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_u8(0x1f).unwrap();
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        let err = vm.run(&mut MemReader::new(bcw.unwrap())).unwrap_err();
+        assert_eq!(err, super::UnknownOpcode(0x1f, 9));
+        assert_eq!(err.backtrace().unwrap().pc, 9);
+    }
+
+    #[test]
+    fn test_fused() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(2).unwrap();
+        bcw.write_push(40).unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_retrieve_at(2).unwrap();
+        bcw.write_push_add(-1).unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.run(&mut MemReader::new(bcw.unwrap())).unwrap();
+        assert_eq!(vm.io().take_output(), "39".to_string());
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut bcw = MemWriter::new();
+        bcw.write_fork().unwrap();
+        bcw.write_jumpz(1).unwrap();
+        bcw.write_push(65).unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(66).unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.run(&mut MemReader::new(program.clone())).unwrap();
+        assert_eq!(vm.io().take_output(), "AB".to_string());
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.set_time_slice(1);
+        vm.run(&mut MemReader::new(program.clone())).unwrap();
+        assert_eq!(vm.io().take_output(), "BA".to_string());
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.set_time_slice(1);
+        assert_eq!(vm.run_steps(&mut MemReader::new(program.clone()), 1), Ok(super::Running));
+        let state = vm.snapshot();
+        assert_eq!(state.threads.len(), 1);
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.set_time_slice(1);
+        vm.restore(state);
+        vm.resume(&mut MemReader::new(program)).unwrap();
+        assert_eq!(vm.io().take_output(), "BA".to_string());
+    }
+
+    #[test]
+    fn test_time_travel() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_getc().unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_add().unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.io().feed("a");
+        vm.io().close();
+        vm.enable_time_travel(3);
+        assert_eq!(vm.run(&mut bcr), Ok(0));
+        assert_eq!(vm.time_step(), Some(10));
+
+        vm.step_back(&mut bcr, 6).unwrap();
+        assert_eq!(vm.time_step(), Some(4));
+        assert_eq!(vm.stack, vec!(97));
+        vm.travel_to(&mut bcr, 1).unwrap();
+        assert_eq!(vm.stack, vec!(0));
+        assert_eq!(vm.resume(&mut bcr), Ok(0));
+        assert_eq!(vm.io().take_output(), "ab".to_string());
+    }
+
+    #[test]
+    fn test_time_travel_fork() {
+        let mut bcw = MemWriter::new();
+        bcw.write_fork().unwrap();
+        bcw.write_jumpz(1).unwrap();
+        bcw.write_push(65).unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(66).unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.set_time_slice(1);
+        assert_eq!(vm.run_steps(&mut MemReader::new(program.clone()), 3), Ok(super::Running));
+        let expected = vm.snapshot();
+
+        let mut bcr = MemReader::new(program);
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.set_time_slice(1);
+        vm.enable_time_travel(2);
+        vm.run(&mut bcr).unwrap();
+        // the snapshot at step 2 has the thread created by "FORK" waiting
+        vm.travel_to(&mut bcr, 3).unwrap();
+        assert_eq!(vm.snapshot(), expected);
+        assert_eq!(vm.threads.len(), 1);
+        assert_eq!(vm.resume(&mut bcr), Ok(0));
+        assert_eq!(vm.io().take_output(), "BA".to_string());
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_getn().unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.io().feed("3\n");
+        vm.set_prompt(Some("> ".to_string()));
+        vm.run(&mut MemReader::new(bcw.unwrap())).unwrap();
+        assert_eq!(vm.io().take_output(), "> 3".to_string());
+    }
+
+    #[test]
+    fn test_flush_before_input() {
+        // records flushes as "|" in the output
+        struct FlushIo { output: String }
+        impl super::MachineIo for FlushIo {
+            fn get_char(&mut self) -> IoResult<char> { Ok('a') }
+            fn get_number(&mut self) -> IoResult<i64> { Ok(1) }
+            fn put_char(&mut self, c: char) -> IoResult<()> { self.output.push_char(c); Ok(()) }
+            fn put_number(&mut self, n: i64) -> IoResult<()> { self.output.push_str(n.to_string().as_slice()); Ok(()) }
+            fn flush(&mut self) -> IoResult<()> { self.output.push_char('|'); Ok(()) }
+        }
+
+        let mut bcw = MemWriter::new();
+        bcw.write_push(65).unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_getc().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut vm = super::Machine::with_io(FlushIo { output: String::new() });
+        vm.run(&mut MemReader::new(bcw.unwrap())).unwrap();
+        assert_eq!(vm.io().output, "A|".to_string());
+    }
+
+    #[test]
+    fn test_sandboxed() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut vm = super::sandboxed();
+        match vm.run(&mut MemReader::new(bcw.unwrap())) {
+            Err(super::MachineIoError(e)) => assert_eq!(e.kind, PermissionDenied),
+            ret => fail!("unexpected result: {}", ret),
+        }
+    }
+
+    #[test]
+    fn test_run_with_index() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(3).unwrap();
+        bcw.write_jump(2).unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(2).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_jumpz(1).unwrap();
+        bcw.write_jump(2).unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.run(&mut MemReader::new(program.clone())).unwrap();
+        let index = vm.label_index();
+        assert_eq!(index.find_copy(&1), Some(27));
+        assert_eq!(index.find_copy(&2), Some(37));
+
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run_with_index(&mut MemReader::new(program.clone()), index.clone()), Ok(0));
+        assert_eq!(vm.stack, vec!(0));
+
+        let module = Module::from_bytes(program).unwrap();
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run_module(&module), Ok(0));
+        assert_eq!(vm.label_index(), index);
+    }
+
+    #[test]
+    fn test_run_image() {
+        let program = Program::from_vec(vec!(ir::StackPush(1), ir::HeapRetrieve, ir::PutNumber, ir::Exit));
+        let mut image = Image::from_program(&program).unwrap();
+        image.set_data([DataSegment::integers(-3, [-1, 42]), DataSegment::bytes(0, b"ab")]);
+        let mut vm = super::Machine::new(NullReader, MemWriter::new());
+        assert_eq!(vm.run_image(&image), Ok(0));
+        assert_eq!(vm.heap_iter().map(|(&a, &n)| (a, n)).collect::<Vec<(i64, i64)>>(),
+                   vec!((-3, -1), (-2, 42), (0, 'a' as i64), (1, 'b' as i64)));
+        let (_, writer) = vm.io.unwrap();
+        assert_eq!(writer.unwrap(), b"98".to_vec());
+
+        image.index_labels().unwrap();
+        let mut code = vec!(bytecode::CMD_MARK, 0, 0, 0, 0, 0, 0, 0, 0);
+        code.push_all(image.code.as_slice());
+        image.code = code;
+        let mut vm = super::Machine::new(NullReader, MemWriter::new());
+        assert!(vm.run_image(&image).is_err());
+    }
+
+    #[test]
+    fn test_implicit_exit() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run(&mut MemReader::new(program.clone())), Err(super::MissingExitInstruction));
+
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.set_implicit_exit(true);
+        assert_eq!(vm.run(&mut MemReader::new(program)), Ok(0));
+        assert_eq!(vm.stack, vec!(1));
+    }
+
+    #[test]
+    fn test_eof_behavior() {
+        let mut bcw = MemWriter::new();
+        bcw.write_getc().unwrap();
+        bcw.write_getn().unwrap();
+        let program = bcw.unwrap();
+
+        let mut bcr = MemReader::new(program.clone());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.stack.push_all([1, 2]);
+        assert_eq!(vm.step(&mut bcr), Err(super::EndOfInput));
+
+        let mut bcr = MemReader::new(program.clone());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.set_eof_behavior(super::EofMinusOne);
+        vm.stack.push_all([1, 2]);
+        vm.step(&mut bcr).unwrap();
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.heap.find(&1), Some(&-1));
+        assert_eq!(vm.heap.find(&2), Some(&-1));
+
+        let mut bcr = MemReader::new(program);
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.set_eof_behavior(super::EofZero);
+        vm.heap.insert(2, 5);
+        vm.stack.push_all([2]);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.heap.find(&2), Some(&0));
+    }
+
+    #[test]
+    fn test_char_encoding() {
+        let mut bcw = MemWriter::new();
+        bcw.write_putc().unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_putc().unwrap();
+        let program = bcw.unwrap();
+
+        let mut bcr = MemReader::new(program.clone());
+        let mut vm = super::Machine::new(NullReader, MemWriter::new());
+        vm.stack.push_all([0x3042, 0xe9]);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.step(&mut bcr), Err(super::InvalidCharacter));
+
+        let mut bcr = MemReader::new(program.clone());
+        let mut vm = super::Machine::new(NullReader, MemWriter::new());
+        vm.set_char_encoding(super::Utf8);
+        vm.stack.push_all([-1, 0x3042, 0xe9]);
+        vm.step(&mut bcr).unwrap();
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.step(&mut bcr), Err(super::InvalidCharacter));
+        let (_, output) = vm.io.unwrap();
+        assert_eq!(output.unwrap(), "éあ".as_bytes().to_vec());
+
+        let mut bcr = MemReader::new(program);
+        let mut vm = super::Machine::new(NullReader, MemWriter::new());
+        vm.set_char_encoding(super::RawByte);
+        vm.stack.push_all([0x100, 0xe9]);
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.step(&mut bcr), Err(super::InvalidCharacter));
+        let (_, output) = vm.io.unwrap();
+        assert_eq!(output.unwrap(), vec!(0xe9));
+    }
+
+    #[test]
+    fn test_number_format() {
+        let mut bcw = MemWriter::new();
+        bcw.write_putn().unwrap();
+        bcw.write_putn().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        let mut format = super::NumberFormat::new();
+        format.set_radix(16).unwrap();
+        format.set_width(2);
+        format.set_separator(Some(" ".to_string()));
+        vm.set_number_format(format);
+        vm.stack.push_all([26, 10]);
+        vm.step(&mut bcr).unwrap();
+        vm.step(&mut bcr).unwrap();
+        assert_eq!(vm.io().take_output(), "0a 1a ".to_string());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_call(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(2).unwrap();
+        bcw.write_return().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.step(&mut bcr).unwrap();
+        vm.step(&mut bcr).unwrap();
+        let state = vm.snapshot();
+        assert_eq!(state.stack, vec!(1));
+        assert_eq!(state.caller, vec!(18));
+        assert_eq!(state.pc, 28);
+
+        vm.run(&mut bcr).unwrap_err();
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.restore(state);
+        vm.resume(&mut bcr).unwrap();
+        assert_eq!(vm.stack, vec!(1, 2));
+    }
+
+    #[test]
+    fn test_run_steps() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(3).unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_jumpz(2).unwrap();
+        bcw.write_jump(1).unwrap();
+        bcw.write_mark(2).unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run_steps(&mut bcr, 5), Ok(super::Running));
+        assert_eq!(vm.stack, vec!(2, 2));
+        bcr.seek(0, SeekSet).unwrap();
+        assert_eq!(vm.run_steps(&mut bcr, 5), Ok(super::Running));
+        assert_eq!(vm.stack, vec!(1, 1));
+        assert_eq!(vm.run_steps(&mut bcr, 100), Ok(super::Exited));
+        assert_eq!(vm.stack, vec!(0));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(100).unwrap();
+        bcw.write_call(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_dup().unwrap();
+        bcw.write_jumpz(2).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_call(1).unwrap();
+        bcw.write_return().unwrap();
+        bcw.write_mark(2).unwrap();
+        bcw.write_discard().unwrap();
+        bcw.write_return().unwrap();
+        let program = bcw.unwrap();
+
+        let mut bcr = MemReader::new(program.clone());
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        assert_eq!(vm.run_steps(&mut bcr, 200), Ok(super::Running));
+        assert!(vm.caller.len() > 10);
+
+        let mut bcr = MemReader::new(program);
+        let mut vm = super::Machine::new(NullReader, NullWriter);
+        vm.set_tail_calls(true);
+        assert_eq!(vm.run_steps(&mut bcr, 200), Ok(super::Running));
+        assert_eq!(vm.caller.len(), 1);
+        assert!(vm.resume(&mut bcr).is_ok());
+        assert_eq!(vm.stack, vec!());
+    }
+
+    #[test]
+    fn test_needs_input() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_getc().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        assert_eq!(vm.run_steps(&mut bcr, 100), Ok(super::NeedsInput));
+        assert_eq!(vm.stack, vec!(1));
+        assert_eq!(vm.run_steps(&mut bcr, 100), Ok(super::NeedsInput));
+        vm.io().feed("A");
+        assert_eq!(vm.run_steps(&mut bcr, 100), Ok(super::Exited));
+        assert_eq!(vm.io().take_output(), "65".to_string());
+    }
+
+    #[test]
+    fn test_underflow_policy() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(3).unwrap();
+        bcw.write_sub().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_swap().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        assert_eq!(vm.run(&mut MemReader::new(program.clone())), Err(super::IllegalStackManipulation));
+
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        vm.set_underflow_policy(super::UnderflowZero);
+        assert_eq!(vm.run(&mut MemReader::new(program)), Ok(0));
+        assert_eq!(vm.io().take_output(), "-300".to_string());
+    }
+
+    #[test]
+    fn test_branch() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_getn().unwrap();
+        bcw.write_exit().unwrap();
+
+        let program = bcw.unwrap();
+        let mut vm = super::Machine::with_io(super::QueueIo::new());
+        assert_eq!(vm.run_steps(&mut MemReader::new(program.clone()), 100), Ok(super::NeedsInput));
+        let mut other = vm.clone();
+        vm.io().feed("5\n");
+        other.io().close();
+        assert_eq!(vm.resume(&mut MemReader::new(program.clone())), Ok(0));
+        assert_eq!(other.resume(&mut MemReader::new(program)), Err(super::EndOfInput));
+        assert_eq!(vm.heap.find(&1), Some(&5));
+        assert_eq!(other.heap.find(&1), Some(&1));
+    }
+}
@@ -0,0 +1,122 @@
+//! Heap persistence of the virtual machine.
+
+#![experimental]
+
+use std::collections::TreeMap;
+use std::io::{InvalidInput, IoError, IoResult};
+
+use machine::Cell;
+
+static MAGIC: &'static [u8] = b"WBHP";
+static FORMAT_VERSION: u8 = 1;
+
+/// Write heap contents in binary format, with the values written by `Cell::write_cell`.
+pub fn write_heap<C: Cell, W: Writer>(heap: &TreeMap<C, C>, output: &mut W) -> IoResult<()> {
+    try!(output.write(MAGIC));
+    try!(output.write_u8(FORMAT_VERSION));
+    try!(output.write_be_u64(heap.len() as u64));
+    for (addr, val) in heap.iter() {
+        try!(addr.write_cell(output));
+        try!(val.write_cell(output));
+    }
+    Ok(())
+}
+
+/// Read heap contents written by `write_heap`.
+pub fn read_heap<C: Cell, R: Reader>(input: &mut R) -> IoResult<TreeMap<C, C>> {
+    let magic = try!(input.read_exact(MAGIC.len()));
+    if magic.as_slice() != MAGIC || try!(input.read_u8()) != FORMAT_VERSION {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "invalid heap file",
+            detail: None,
+        });
+    }
+    let mut heap = TreeMap::new();
+    for _ in range(0, try!(input.read_be_u64())) {
+        let addr: C = try!(Cell::read_cell(input));
+        heap.insert(addr, try!(Cell::read_cell(input)));
+    }
+    Ok(heap)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter, TempDir};
+    use std::io::util::{NullReader, NullWriter};
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, MachineIoError, ZeroDivision};
+
+    #[test]
+    fn test_persistent_heap() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_add().unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let dir = TempDir::new("whitebase").unwrap();
+        let path = dir.path().join("heap");
+        for _ in range(0u, 2) {
+            let mut vm = Machine::new(NullReader, NullWriter).with_persistent_heap(path.clone()).unwrap();
+            vm.run(&mut MemReader::new(program.clone())).unwrap();
+        }
+        let vm = Machine::new(NullReader, NullWriter).with_persistent_heap(path).unwrap();
+        assert_eq!(vm.heap_get(0), Some(2));
+    }
+
+    #[test]
+    fn test_flush_on_error() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_div().unwrap();
+        let program = bcw.unwrap();
+
+        let dir = TempDir::new("whitebase").unwrap();
+        for (i, name) in ["run", "steps"].iter().enumerate() {
+            let path = dir.path().join(*name);
+            let mut vm = Machine::new(NullReader, NullWriter).with_persistent_heap(path.clone()).unwrap();
+            let mut reader = MemReader::new(program.clone());
+            if i == 0 {
+                assert_eq!(vm.run(&mut reader), Err(ZeroDivision));
+            } else {
+                assert_eq!(vm.run_steps(&mut reader, 10), Err(ZeroDivision));
+            }
+            let vm = Machine::new(NullReader, NullWriter).with_persistent_heap(path).unwrap();
+            assert_eq!(vm.heap_get(0), Some(1));
+        }
+    }
+
+    #[test]
+    fn test_flush_error() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_div().unwrap();
+        let failing = bcw.unwrap();
+        let mut bcw = MemWriter::new();
+        bcw.write_exit().unwrap();
+        let exiting = bcw.unwrap();
+
+        let dir = TempDir::new("whitebase").unwrap();
+        let path = dir.path().join("missing").join("heap");
+        // the error of the program is kept even though flushing fails
+        let mut vm = Machine::new(NullReader, NullWriter).with_persistent_heap(path.clone()).unwrap();
+        assert_eq!(vm.run_steps(&mut MemReader::new(failing.clone()), 10), Err(ZeroDivision));
+        let mut vm = Machine::new(NullReader, NullWriter).with_persistent_heap(path).unwrap();
+        assert_eq!(vm.run(&mut MemReader::new(failing)), Err(ZeroDivision));
+        match vm.run(&mut MemReader::new(exiting)) {
+            Err(MachineIoError(_)) => (),
+            ret => fail!("expected flush error, but {}", ret),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Pipelines of virtual machines connected by channels.
+
+#![experimental]
+
+use std::io::{BrokenPipe, BufferedReader, ChanReader, ChanWriter, IoResult, MemReader};
+use std::io::util::copy;
+
+use machine::{Builder, MachineResult, OtherMachineError, StreamIo};
+
+/// Machines connected Unix-style, the output of each stage to the input of the next one.
+///
+/// Each stage runs in its own task. A stage which exits early closes the input of
+/// the next stage, and writing to it from the previous stage fails with `BrokenPipe`.
+///
+/// ```rust
+/// use std::io::{MemReader, MemWriter};
+/// use whitebase::bytecode::ByteCodeWriter;
+/// use whitebase::machine::pipeline::Pipeline;
+///
+/// let mut generator = MemWriter::new();
+/// generator.write_push(42).unwrap();
+/// generator.write_putn().unwrap();
+/// generator.write_exit().unwrap();
+///
+/// let mut filter = MemWriter::new();
+/// filter.write_push(0).unwrap();
+/// filter.write_getn().unwrap();
+/// filter.write_push(0).unwrap();
+/// filter.write_retrieve().unwrap();
+/// filter.write_push(1).unwrap();
+/// filter.write_add().unwrap();
+/// filter.write_putn().unwrap();
+/// filter.write_exit().unwrap();
+///
+/// let mut output = MemWriter::new();
+/// let results = Pipeline::new()
+///     .stage(generator.unwrap())
+///     .stage(filter.unwrap())
+///     .run(&mut MemReader::new(vec!()), &mut output)
+///     .unwrap();
+/// assert_eq!(output.unwrap(), b"43".to_vec());
+/// assert!(results.iter().all(|r| r.is_ok()));
+/// ```
+pub struct Pipeline {
+    stages: Vec<(Builder, Vec<u8>)>,
+}
+
+impl Pipeline {
+    /// Create an empty `Pipeline`.
+    pub fn new() -> Pipeline { Pipeline { stages: Vec::new() } }
+
+    /// Append a stage running the bytecode with the default options.
+    pub fn stage(self, program: Vec<u8>) -> Pipeline {
+        self.stage_with(Builder::new(), program)
+    }
+
+    /// Append a stage running the bytecode on a machine built by `builder`.
+    pub fn stage_with(mut self, builder: Builder, program: Vec<u8>) -> Pipeline {
+        self.stages.push((builder, program));
+        self
+    }
+
+    /// Run all stages, feeding `input` to the first stage and writing the output
+    /// of the last stage to `output`.
+    ///
+    /// Returns the result of each stage in order. A stage whose task died without
+    /// reporting a result fails with `OtherMachineError`.
+    pub fn run<R: Reader, W: Writer>(self, input: &mut R, output: &mut W) -> IoResult<Vec<MachineResult<i64>>> {
+        let (first, mut rx) = channel();
+        let mut results = Vec::new();
+        for (builder, program) in self.stages.move_iter() {
+            let (tx, next) = channel();
+            let (result_tx, result_rx) = channel();
+            spawn(proc() {
+                let io = StreamIo::new(BufferedReader::new(ChanReader::new(rx)), ChanWriter::new(tx));
+                let mut machine = builder.build(io);
+                result_tx.send(machine.run(&mut MemReader::new(program)));
+            });
+            results.push(result_rx);
+            rx = next;
+        }
+        match copy(input, &mut ChanWriter::new(first)) {
+            Err(ref e) if e.kind == BrokenPipe => (),
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        }
+        try!(copy(&mut ChanReader::new(rx), output));
+        Ok(results.iter().map(|r| match r.recv_opt() {
+            Ok(ret) => ret,
+            Err(()) => Err(OtherMachineError),
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Builder, EofMinusOne};
+    use super::Pipeline;
+
+    #[test]
+    fn test_pipeline() {
+        let mut upcase = MemWriter::new();
+        upcase.write_mark(1).unwrap();
+        upcase.write_push(0).unwrap();
+        upcase.write_getc().unwrap();
+        upcase.write_push(0).unwrap();
+        upcase.write_retrieve().unwrap();
+        upcase.write_dup().unwrap();
+        upcase.write_jumpn(2).unwrap();
+        upcase.write_push(32).unwrap();
+        upcase.write_sub().unwrap();
+        upcase.write_putc().unwrap();
+        upcase.write_jump(1).unwrap();
+        upcase.write_mark(2).unwrap();
+        upcase.write_exit().unwrap();
+        let upcase = upcase.unwrap();
+
+        let mut reverse = MemWriter::new();
+        reverse.write_push(0).unwrap();
+        reverse.write_getc().unwrap();
+        reverse.write_push(1).unwrap();
+        reverse.write_getc().unwrap();
+        reverse.write_push(1).unwrap();
+        reverse.write_retrieve().unwrap();
+        reverse.write_putc().unwrap();
+        reverse.write_push(0).unwrap();
+        reverse.write_retrieve().unwrap();
+        reverse.write_putc().unwrap();
+        reverse.write_exit().unwrap();
+
+        let mut output = MemWriter::new();
+        let results = Pipeline::new()
+            .stage_with(Builder::new().eof_behavior(EofMinusOne), upcase)
+            .stage(reverse.unwrap())
+            .run(&mut MemReader::new(b"hi".to_vec()), &mut output)
+            .unwrap();
+        assert_eq!(output.unwrap(), b"IH".to_vec());
+        assert_eq!(results, vec!(Ok(0), Ok(0)));
+    }
+}
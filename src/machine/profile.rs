@@ -0,0 +1,227 @@
+//! Execution profile of the virtual machine.
+
+#![experimental]
+
+use std::collections::TreeMap;
+use std::io::IoResult;
+
+use bytecode::image::SourceMap;
+use machine::LabelIndex;
+
+/// Instructions executed while a label was the latest one passed.
+#[deriving(PartialEq, Clone, Show)]
+pub struct LabelProfile {
+    /// Number of executed instructions.
+    pub instructions: u64,
+    /// Elapsed time in nanoseconds.
+    pub time_ns: u64,
+}
+
+/// Calls to a subroutine, including instructions executed by its callees.
+#[deriving(PartialEq, Clone, Show)]
+pub struct CallProfile {
+    /// Number of calls.
+    pub calls: u64,
+    /// Number of executed instructions until return.
+    pub instructions: u64,
+    /// Elapsed time in nanoseconds until return.
+    pub time_ns: u64,
+}
+
+struct Frame {
+    target: i64,
+    return_label: Option<i64>,
+    instructions: u64,
+    time_ns: u64,
+}
+
+/// Execution profile aggregated per "MARK" label and per "CALL" target.
+///
+/// Every executed instruction is counted for the label marked most recently
+/// on the execution path, or `None` before any label.
+pub struct Profile {
+    /// Flat profile per label.
+    pub labels: TreeMap<Option<i64>, LabelProfile>,
+    /// Inclusive profile per "CALL" target.
+    pub calls: TreeMap<i64, CallProfile>,
+    /// Number of calls per edge from the caller label to the callee label.
+    pub edges: TreeMap<(Option<i64>, i64), u64>,
+    current: Option<i64>,
+    frames: Vec<Frame>,
+    instructions: u64,
+    time_ns: u64,
+}
+
+fn label_name(label: &Option<i64>) -> String {
+    match *label {
+        Some(n) => n.to_string(),
+        None => "<entry>".to_string(),
+    }
+}
+
+impl Profile {
+    /// Create an empty `Profile`.
+    pub fn new() -> Profile {
+        Profile {
+            labels: TreeMap::new(),
+            calls: TreeMap::new(),
+            edges: TreeMap::new(),
+            current: None,
+            frames: Vec::new(),
+            instructions: 0,
+            time_ns: 0,
+        }
+    }
+
+    /// Count an executed instruction for the current label.
+    pub fn record(&mut self, elapsed: u64) {
+        self.instructions += 1;
+        self.time_ns += elapsed;
+        let current = self.current;
+        match self.labels.find_mut(&current) {
+            Some(p) => {
+                p.instructions += 1;
+                p.time_ns += elapsed;
+                return;
+            },
+            None => (),
+        }
+        self.labels.insert(current, LabelProfile { instructions: 1, time_ns: elapsed });
+    }
+
+    /// Enter a label by "MARK" or a taken jump.
+    pub fn enter(&mut self, label: i64) {
+        self.current = Some(label);
+    }
+
+    /// Enter a subroutine by "CALL".
+    pub fn call(&mut self, target: i64) {
+        let edge = (self.current, target);
+        let count = match self.edges.find(&edge) { Some(n) => *n, None => 0 };
+        self.edges.insert(edge, count + 1);
+        let calls = match self.calls.find(&target) { Some(p) => p.calls, None => 0 };
+        if calls == 0 {
+            self.calls.insert(target, CallProfile { calls: 1, instructions: 0, time_ns: 0 });
+        } else {
+            self.calls.find_mut(&target).unwrap().calls += 1;
+        }
+        self.frames.push(Frame {
+            target: target,
+            return_label: self.current,
+            instructions: self.instructions,
+            time_ns: self.time_ns,
+        });
+        self.current = Some(target);
+    }
+
+    /// Leave a subroutine by "RETURN".
+    pub fn ret(&mut self) {
+        match self.frames.pop() {
+            Some(frame) => {
+                let p = self.calls.find_mut(&frame.target).unwrap();
+                p.instructions += self.instructions - frame.instructions;
+                p.time_ns += self.time_ns - frame.time_ns;
+                self.current = frame.return_label;
+            },
+            None => (),
+        }
+    }
+
+    /// Total number of executed instructions.
+    pub fn instructions(&self) -> u64 { self.instructions }
+
+    /// Write a human readable report, hot labels first.
+    pub fn write_report<W: Writer>(&self, output: &mut W) -> IoResult<()> {
+        self.write_report_at(output, None)
+    }
+
+    /// Write the report as `write_report` does, with the position in the source code
+    /// of each label, found by its offset in the label index.
+    pub fn write_report_with_source<W: Writer>(&self, output: &mut W, index: &LabelIndex,
+                                               map: &SourceMap) -> IoResult<()> {
+        self.write_report_at(output, Some((index, map)))
+    }
+
+    fn write_report_at<W: Writer>(&self, output: &mut W, source: Option<(&LabelIndex, &SourceMap)>) -> IoResult<()> {
+        let mut labels: Vec<(&Option<i64>, &LabelProfile)> = self.labels.iter().collect();
+        labels.sort_by(|&(_, a), &(_, b)| b.instructions.cmp(&a.instructions));
+        try!(writeln!(output, "{:>10} {:>14} {:>14}", "label", "instructions", "time(ns)"));
+        for &(label, p) in labels.iter() {
+            try!(write!(output, "{:>10} {:>14} {:>14}", label_name(label), p.instructions, p.time_ns));
+            let pos = match (source, *label) {
+                (Some((index, map)), Some(label)) => index.find(&label).and_then(|&offset| map.describe(offset)),
+                _ => None,
+            };
+            try!(match pos {
+                Some(pos) => writeln!(output, "  {}", pos),
+                None => output.write_line(""),
+            });
+        }
+        try!(output.write_line(""));
+        try!(writeln!(output, "{:>10} {:>14} {:>14} {:>14}", "call", "calls", "instructions", "time(ns)"));
+        for (target, p) in self.calls.iter() {
+            try!(writeln!(output, "{:>10} {:>14} {:>14} {:>14}", *target, p.calls, p.instructions, p.time_ns));
+            for (&(ref caller, callee), count) in self.edges.iter() {
+                if callee == *target {
+                    try!(writeln!(output, "{:>10} {} calls from {}", "", *count, label_name(caller)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+    use std::io::util::{NullReader, NullWriter};
+
+    use std::str::from_utf8;
+
+    use bytecode::ByteCodeWriter;
+    use bytecode::image::{SourceMap, SourcePosition};
+    use machine::Machine;
+
+    #[test]
+    fn test_profile() {
+        let mut bcw = MemWriter::new();
+        bcw.write_call(1).unwrap();
+        bcw.write_call(1).unwrap();
+        bcw.write_exit().unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_discard().unwrap();
+        bcw.write_call(2).unwrap();
+        bcw.write_return().unwrap();
+        bcw.write_mark(2).unwrap();
+        bcw.write_return().unwrap();
+
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let mut vm = Machine::new(NullReader, NullWriter);
+        vm.enable_profiling();
+        vm.run(&mut bcr).unwrap();
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.instructions(), 13);
+        assert_eq!(profile.labels.find(&None).unwrap().instructions, 3);
+        assert_eq!(profile.labels.find(&Some(1)).unwrap().instructions, 8);
+        assert_eq!(profile.labels.find(&Some(2)).unwrap().instructions, 2);
+        assert_eq!(profile.calls.find(&1).unwrap().calls, 2);
+        assert_eq!(profile.calls.find(&1).unwrap().instructions, 10);
+        assert_eq!(profile.calls.find(&2).unwrap().instructions, 2);
+        assert_eq!(profile.edges.find(&(None, 1)), Some(&2));
+        assert_eq!(profile.edges.find(&(Some(1), 2)), Some(&2));
+
+        let mut writer = MemWriter::new();
+        profile.write_report(&mut writer).unwrap();
+        assert!(writer.get_ref().len() > 0);
+
+        let map = SourceMap {
+            file: "sub.ws".to_string(),
+            positions: vec!(SourcePosition { offset: 19, line: 4, column: 1 }),
+        };
+        let mut writer = MemWriter::new();
+        profile.write_report_with_source(&mut writer, &vm.label_index(), &map).unwrap();
+        let report = from_utf8(writer.get_ref()).unwrap().to_string();
+        assert!(report.as_slice().lines().any(|l| l.starts_with("         1") && l.ends_with("  sub.ws:4:1")));
+    }
+}
@@ -0,0 +1,80 @@
+//! Heap regions shared with the host.
+
+#![experimental]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use machine::Cell;
+
+/// A range of heap addresses backed by the host, registered by `Machine::map_heap`.
+///
+/// Addresses are given as offsets from the start of the region.
+pub trait HeapRegion<C = i64> {
+    /// Read the value at the offset for "RETRIEVE".
+    fn get(&self, offset: uint) -> C;
+    /// Write the value at the offset for "STORE" and input instructions.
+    fn set(&mut self, offset: uint, val: C);
+}
+
+/// A buffer shared by the host and the machine.
+///
+/// ```rust
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+/// use whitebase::machine;
+///
+/// let buffer = Rc::new(RefCell::new(vec!(0i64, ..16)));
+/// let mut machine = machine::with_stdio();
+/// machine.map_heap(1000, 16, buffer.clone());
+/// buffer.borrow_mut()[0] = 42;
+/// assert_eq!(machine.heap_get(1000), Some(42));
+/// ```
+pub type SharedBuffer = Rc<RefCell<Vec<i64>>>;
+
+impl<C: Cell> HeapRegion<C> for Rc<RefCell<Vec<C>>> {
+    fn get(&self, offset: uint) -> C {
+        match self.borrow().as_slice().get(offset) {
+            Some(n) => n.clone(),
+            None => Cell::wrap(0),
+        }
+    }
+
+    fn set(&mut self, offset: uint, val: C) {
+        let mut buffer = self.borrow_mut();
+        if offset < buffer.len() {
+            *buffer.get_mut(offset) = val;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{MemReader, MemWriter};
+    use std::io::util::{NullReader, NullWriter};
+    use std::rc::Rc;
+
+    use bytecode::ByteCodeWriter;
+    use machine::Machine;
+
+    #[test]
+    fn test_shared_buffer() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(101).unwrap();
+        bcw.write_push(100).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_add().unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_exit().unwrap();
+
+        let buffer = Rc::new(RefCell::new(vec!(7i64, 0)));
+        let mut vm = Machine::new(NullReader, NullWriter);
+        vm.map_heap(100, 2, buffer.clone());
+        vm.run(&mut MemReader::new(bcw.unwrap())).unwrap();
+        assert_eq!(*buffer.borrow(), vec!(7, 8));
+        assert_eq!(vm.heap_get(101), Some(8));
+        assert_eq!(vm.heap_iter().count(), 0);
+    }
+}
@@ -0,0 +1,102 @@
+//! Incremental execution for interactive use.
+
+#![experimental]
+
+use std::io::{BufReader, MemWriter, ResourceUnavailable};
+
+use machine::{Machine, MachineIo, MachineIoError, MachineResult, MissingExitInstruction};
+use machine::{StepOutcome, Running, Exited, NeedsInput};
+
+/// A machine session which accepts instructions incrementally.
+///
+/// Instructions written to `code()` are executed by the next `run`, starting
+/// where the previous `run` stopped. Running off the end of the code is not an error,
+/// so a REPL can append one line at a time.
+///
+/// ```rust
+/// use std::io::BufReader;
+/// use std::io::util::{NullReader, NullWriter};
+/// use whitebase::machine::{Machine, Session};
+/// use whitebase::syntax::{Assembly, Compiler};
+///
+/// let mut session = Session::new(Machine::new(NullReader, NullWriter));
+/// let asm = Assembly::new();
+/// asm.compile(&mut BufReader::new(b"PUSH 1\nPUSH 2\n"), session.code()).unwrap();
+/// session.run().unwrap();
+/// asm.compile(&mut BufReader::new(b"ADD\nEXIT\n"), session.code()).unwrap();
+/// session.run().unwrap();
+/// ```
+pub struct Session<I> {
+    machine: Machine<I>,
+    code: MemWriter,
+}
+
+impl<I: MachineIo> Session<I> {
+    /// Create a new `Session` with a machine.
+    pub fn new(machine: Machine<I>) -> Session<I> {
+        Session {
+            machine: machine,
+            code: MemWriter::new(),
+        }
+    }
+
+    /// Get a writer to append bytecodes.
+    pub fn code(&mut self) -> &mut MemWriter { &mut self.code }
+
+    /// Get a reference to the underlying machine.
+    pub fn machine(&self) -> &Machine<I> { &self.machine }
+
+    /// Execute appended instructions.
+    ///
+    /// Returns `Running` when all instructions have been executed and the session is waiting
+    /// for more, `Exited` when "EXIT" instruction was executed, or `NeedsInput` when
+    /// the I/O has no input available.
+    pub fn run(&mut self) -> MachineResult<StepOutcome> {
+        let mut program = BufReader::new(self.code.get_ref());
+        try!(self.machine.seek_pc(&mut program));
+        loop {
+            match self.machine.step(&mut program) {
+                Ok(true) => continue,
+                Ok(false) => return Ok(Exited),
+                Err(MissingExitInstruction) => return Ok(Running),
+                Err(MachineIoError(ref e)) if e.kind == ResourceUnavailable => return Ok(NeedsInput),
+                Err(e) => return Err(self.machine.traced(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::util::{NullReader, NullWriter};
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, Running, Exited};
+    use super::Session;
+
+    #[test]
+    fn test_run() {
+        let mut session = Session::new(Machine::new(NullReader, NullWriter));
+        session.code().write_push(1).unwrap();
+        assert_eq!(session.run(), Ok(Running));
+        assert_eq!(session.machine().stack, vec!(1));
+
+        session.code().write_push(2).unwrap();
+        session.code().write_add().unwrap();
+        assert_eq!(session.run(), Ok(Running));
+        assert_eq!(session.machine().stack, vec!(3));
+
+        session.code().write_exit().unwrap();
+        assert_eq!(session.run(), Ok(Exited));
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut session = Session::new(Machine::new(NullReader, NullWriter));
+        session.code().write_push(1).unwrap();
+        session.code().write_exit().unwrap();
+        assert_eq!(session.run(), Ok(Exited));
+        session.code().write_dup().unwrap();
+        assert_eq!(session.run(), Ok(Running));
+        assert_eq!(session.machine().stack, vec!(1, 1));
+    }
+}
@@ -0,0 +1,178 @@
+//! Serializable machine snapshots.
+
+#![experimental]
+
+use std::collections::TreeMap;
+use std::io::{InvalidInput, IoError, IoResult};
+
+use machine::Cell;
+
+static MAGIC: &'static [u8] = b"WBMS";
+static FORMAT_VERSION: u8 = 1;
+
+fn invalid_snapshot(detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid snapshot",
+        detail: Some(detail),
+    }
+}
+
+/// A snapshot of a thread created by "FORK", which is waiting for its time slice.
+#[deriving(PartialEq, Clone, Show)]
+pub struct ThreadState<C = i64> {
+    /// Values on the stack of the thread, bottom first.
+    pub stack: Vec<C>,
+    /// Return positions pushed by "CALL", outermost first.
+    pub caller: Vec<u64>,
+    /// Position of the next instruction of the thread.
+    pub pc: u64,
+}
+
+/// A snapshot of the machine state.
+///
+/// Values are written by `Cell::write_cell`, so a snapshot is read back with the
+/// cell type of the machine which took it.
+#[deriving(PartialEq, Clone, Show)]
+pub struct MachineState<C = i64> {
+    /// Values on the stack, bottom first.
+    pub stack: Vec<C>,
+    /// Heap contents.
+    pub heap: TreeMap<C, C>,
+    /// Return positions pushed by "CALL", outermost first.
+    pub caller: Vec<u64>,
+    /// Position of the next instruction in the bytecode stream.
+    pub pc: u64,
+    /// Waiting threads in the order they are scheduled.
+    pub threads: Vec<ThreadState<C>>,
+    /// Number of threads created by "FORK" so far.
+    pub forks: i64,
+    /// Number of instructions the current thread has executed in its time slice.
+    pub slice: u64,
+}
+
+impl<C: Cell> MachineState<C> {
+    /// Write the snapshot in binary format.
+    pub fn write_to<W: Writer>(&self, output: &mut W) -> IoResult<()> {
+        try!(output.write(MAGIC));
+        try!(output.write_u8(FORMAT_VERSION));
+        try!(output.write_be_u64(self.stack.len() as u64));
+        for n in self.stack.iter() {
+            try!(n.write_cell(output));
+        }
+        try!(output.write_be_u64(self.heap.len() as u64));
+        for (addr, val) in self.heap.iter() {
+            try!(addr.write_cell(output));
+            try!(val.write_cell(output));
+        }
+        try!(write_caller(output, self.caller.as_slice()));
+        try!(output.write_be_u64(self.pc));
+        try!(output.write_be_u64(self.threads.len() as u64));
+        for thread in self.threads.iter() {
+            try!(output.write_be_u64(thread.stack.len() as u64));
+            for n in thread.stack.iter() {
+                try!(n.write_cell(output));
+            }
+            try!(write_caller(output, thread.caller.as_slice()));
+            try!(output.write_be_u64(thread.pc));
+        }
+        try!(output.write_be_i64(self.forks));
+        output.write_be_u64(self.slice)
+    }
+
+    /// Read a snapshot written by `write_to`.
+    pub fn read_from<R: Reader>(input: &mut R) -> IoResult<MachineState<C>> {
+        let magic = try!(input.read_exact(MAGIC.len()));
+        if magic.as_slice() != MAGIC {
+            return Err(invalid_snapshot("bad magic number".to_string()));
+        }
+        let version = try!(input.read_u8());
+        if version != FORMAT_VERSION {
+            return Err(invalid_snapshot(format!("unsupported version {}", version)));
+        }
+        let stack = try!(read_stack(input));
+        let mut heap = TreeMap::new();
+        for _ in range(0, try!(input.read_be_u64())) {
+            let addr: C = try!(Cell::read_cell(input));
+            heap.insert(addr, try!(Cell::read_cell(input)));
+        }
+        let caller = try!(read_caller(input));
+        let pc = try!(input.read_be_u64());
+        let mut threads = Vec::new();
+        for _ in range(0, try!(input.read_be_u64())) {
+            let stack = try!(read_stack(input));
+            let caller = try!(read_caller(input));
+            threads.push(ThreadState { stack: stack, caller: caller, pc: try!(input.read_be_u64()) });
+        }
+        let forks = try!(input.read_be_i64());
+        let slice = try!(input.read_be_u64());
+        Ok(MachineState {
+            stack: stack,
+            heap: heap,
+            caller: caller,
+            pc: pc,
+            threads: threads,
+            forks: forks,
+            slice: slice,
+        })
+    }
+}
+
+fn write_caller<W: Writer>(output: &mut W, caller: &[u64]) -> IoResult<()> {
+    try!(output.write_be_u64(caller.len() as u64));
+    for pos in caller.iter() {
+        try!(output.write_be_u64(*pos));
+    }
+    Ok(())
+}
+
+fn read_stack<C: Cell, R: Reader>(input: &mut R) -> IoResult<Vec<C>> {
+    let mut stack = Vec::new();
+    for _ in range(0, try!(input.read_be_u64())) {
+        stack.push(try!(Cell::read_cell(input)));
+    }
+    Ok(stack)
+}
+
+fn read_caller<R: Reader>(input: &mut R) -> IoResult<Vec<u64>> {
+    let mut caller = Vec::new();
+    for _ in range(0, try!(input.read_be_u64())) {
+        caller.push(try!(input.read_be_u64()));
+    }
+    Ok(caller)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::TreeMap;
+    use std::io::{MemReader, MemWriter};
+    use super::{MachineState, ThreadState};
+
+    #[test]
+    fn test_readwrite() {
+        let mut heap = TreeMap::new();
+        heap.insert(-1, 3);
+        heap.insert(2, 4);
+        let state: MachineState = MachineState {
+            stack: vec!(1, -2, 3),
+            heap: heap,
+            caller: vec!(9, 18),
+            pc: 27,
+            threads: vec!(ThreadState { stack: vec!(0), caller: vec!(9), pc: 36 }),
+            forks: 1,
+            slice: 5,
+        };
+        let mut writer = MemWriter::new();
+        state.write_to(&mut writer).unwrap();
+        let mut reader = MemReader::new(writer.unwrap());
+        assert_eq!(MachineState::read_from(&mut reader), Ok(state));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut reader = MemReader::new(b"WBXX".to_vec());
+        assert!(MachineState::read_from(&mut reader).is_err());
+        let mut reader = MemReader::new(b"WBMS\x02".to_vec());
+        assert!(MachineState::read_from(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Execution statistics of the virtual machine.
+
+#![experimental]
+
+use bytecode;
+
+/// Statistics of instructions in an IMP category.
+#[deriving(PartialEq, Clone, Show)]
+pub struct ImpStats {
+    /// Number of executed instructions.
+    pub instructions: u64,
+    /// Elapsed time of the instructions in nanoseconds.
+    pub time_ns: u64,
+}
+
+impl ImpStats {
+    /// Create an empty `ImpStats`.
+    pub fn new() -> ImpStats {
+        ImpStats { instructions: 0, time_ns: 0 }
+    }
+}
+
+/// Statistics collected by `Machine::run_with_stats`.
+#[deriving(PartialEq, Clone, Show)]
+pub struct ExecutionStats {
+    /// Number of executed instructions.
+    pub instructions: u64,
+    /// Maximum number of values on the stack.
+    pub max_stack_depth: uint,
+    /// Number of distinct heap addresses stored or retrieved.
+    pub heap_touched: uint,
+    /// Bytes read by "GETC" and "GETN", counting characters in UTF-8
+    /// and numbers in decimal.
+    pub bytes_read: u64,
+    /// Bytes written by "PUTC" and "PUTN", counting characters in UTF-8
+    /// and numbers as formatted.
+    pub bytes_written: u64,
+    /// Elapsed wall time in nanoseconds.
+    pub wall_time_ns: u64,
+    /// Stack manipulation instructions.
+    pub stack: ImpStats,
+    /// Arithmetic instructions.
+    pub arithmetic: ImpStats,
+    /// Heap access instructions.
+    pub heap: ImpStats,
+    /// Flow control instructions.
+    pub flow: ImpStats,
+    /// I/O instructions.
+    pub io: ImpStats,
+}
+
+impl ExecutionStats {
+    /// Create an empty `ExecutionStats`.
+    pub fn new() -> ExecutionStats {
+        ExecutionStats {
+            instructions: 0,
+            max_stack_depth: 0,
+            heap_touched: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            wall_time_ns: 0,
+            stack: ImpStats::new(),
+            arithmetic: ImpStats::new(),
+            heap: ImpStats::new(),
+            flow: ImpStats::new(),
+            io: ImpStats::new(),
+        }
+    }
+
+    /// Return the statistics of the IMP category the opcode belongs to,
+    /// or `None` for extension opcodes.
+    pub fn imp_mut(&mut self, opcode: u8) -> Option<&mut ImpStats> {
+        match opcode & 0xF0 {
+            n if n == bytecode::IMP_STACK => Some(&mut self.stack),
+            n if n == bytecode::IMP_ARITHMETIC => Some(&mut self.arithmetic),
+            n if n == bytecode::IMP_HEAP => Some(&mut self.heap),
+            n if n == bytecode::IMP_FLOW => Some(&mut self.flow),
+            n if n == bytecode::IMP_IO => Some(&mut self.io),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode;
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, QueueIo, Utf8};
+    use super::ExecutionStats;
+
+    #[test]
+    fn test_run_with_stats() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(1).unwrap();
+        bcw.write_getn().unwrap();
+        bcw.write_push(2).unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_store().unwrap();
+        bcw.write_push(2).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_push(12354).unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut vm = Machine::with_io(QueueIo::new());
+        vm.set_char_encoding(Utf8);
+        vm.io().feed("-42\n");
+        let (ret, stats) = vm.run_with_stats(&mut MemReader::new(bcw.unwrap()));
+        assert_eq!(ret, Ok(0));
+        assert_eq!(stats.instructions, 12);
+        assert_eq!(stats.max_stack_depth, 2);
+        assert_eq!(stats.heap_touched, 2);
+        assert_eq!(stats.bytes_read, 3);
+        assert_eq!(stats.bytes_written, 6);
+        assert_eq!(stats.stack.instructions, 5);
+        assert_eq!(stats.heap.instructions, 3);
+        assert_eq!(stats.io.instructions, 3);
+        assert_eq!(stats.arithmetic.instructions, 0);
+        assert_eq!(stats.flow.instructions, 1);
+    }
+
+    #[test]
+    fn test_imp_mut() {
+        let mut stats = ExecutionStats::new();
+        stats.imp_mut(bytecode::CMD_ADD).unwrap().instructions += 1;
+        stats.imp_mut(bytecode::CMD_PUTC).unwrap().instructions += 1;
+        assert_eq!(stats.arithmetic.instructions, 1);
+        assert_eq!(stats.io.instructions, 1);
+        assert!(stats.imp_mut(bytecode::CMD_EXT_MIN).is_none());
+    }
+}
@@ -0,0 +1,12 @@
+//! Cooperative threads of the virtual machine.
+
+#![experimental]
+
+/// Execution state of a suspended thread. The heap is shared by all threads.
+#[deriving(Clone)]
+pub struct Thread<C> {
+    pub stack: Vec<C>,
+    pub caller: Vec<u64>,
+    pub callees: Vec<i64>,
+    pub pc: u64,
+}
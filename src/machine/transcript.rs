@@ -0,0 +1,306 @@
+//! Recording and replaying I/O of the virtual machine.
+
+#![experimental]
+
+use std::char::from_u32;
+use std::io::{EndOfFile, InvalidInput, IoError, IoResult, standard_error};
+
+use machine::MachineIo;
+
+fn invalid_transcript(line: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid transcript",
+        detail: Some(format!("unexpected line: {}", line)),
+    }
+}
+
+fn mismatch(expected: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "transcript mismatch",
+        detail: Some(format!("expected {}", expected)),
+    }
+}
+
+/// An I/O event.
+#[deriving(PartialEq, Clone, Show)]
+pub enum Event {
+    /// A character was read.
+    InputChar(char),
+    /// A number was read.
+    InputNumber(i64),
+    /// Reading reached the end of input.
+    InputEnd,
+    /// A character was written.
+    OutputChar(char),
+    /// A number was written.
+    OutputNumber(i64),
+    /// A raw byte was written.
+    OutputByte(u8),
+}
+
+impl Event {
+    /// Return true if this is an input event.
+    pub fn is_input(&self) -> bool {
+        match *self {
+            InputChar(_) | InputNumber(_) | InputEnd => true,
+            _ => false,
+        }
+    }
+}
+
+/// A sequence of I/O events.
+///
+/// Transcripts are written as text, one event per line:
+///
+/// ```text
+/// getc 97
+/// getn -12
+/// eof
+/// putc 98
+/// putn 3
+/// putb 255
+/// ```
+#[deriving(PartialEq, Clone, Show)]
+pub struct Transcript {
+    /// Recorded events in order.
+    pub events: Vec<Event>,
+}
+
+impl Transcript {
+    /// Create an empty `Transcript`.
+    pub fn new() -> Transcript { Transcript { events: Vec::new() } }
+
+    /// Write the transcript in text format.
+    pub fn write_to<W: Writer>(&self, output: &mut W) -> IoResult<()> {
+        for event in self.events.iter() {
+            try!(match *event {
+                InputChar(c)    => writeln!(output, "getc {}", c as u32),
+                InputNumber(n)  => writeln!(output, "getn {}", n),
+                InputEnd        => output.write_line("eof"),
+                OutputChar(c)   => writeln!(output, "putc {}", c as u32),
+                OutputNumber(n) => writeln!(output, "putn {}", n),
+                OutputByte(b)   => writeln!(output, "putb {}", b),
+            });
+        }
+        Ok(())
+    }
+
+    /// Read a transcript written by `write_to`.
+    pub fn read_from<B: Buffer>(input: &mut B) -> IoResult<Transcript> {
+        let mut events = Vec::new();
+        for line in input.lines() {
+            let line = try!(line);
+            let slice = line.as_slice().trim();
+            if slice.len() == 0 { continue }
+            let (kind, val) = match slice.find(' ') {
+                Some(n) => (slice.slice_to(n), slice.slice_from(n + 1)),
+                None => (slice, ""),
+            };
+            let event = match kind {
+                "getc" => from_str::<u32>(val).and_then(|c| from_u32(c)).map(|c| InputChar(c)),
+                "getn" => from_str::<i64>(val).map(|n| InputNumber(n)),
+                "eof"  => Some(InputEnd),
+                "putc" => from_str::<u32>(val).and_then(|c| from_u32(c)).map(|c| OutputChar(c)),
+                "putn" => from_str::<i64>(val).map(|n| OutputNumber(n)),
+                "putb" => from_str::<u8>(val).map(|b| OutputByte(b)),
+                _      => None,
+            };
+            match event {
+                Some(e) => events.push(e),
+                None => return Err(invalid_transcript(slice)),
+            }
+        }
+        Ok(Transcript { events: events })
+    }
+
+    /// Return output events.
+    pub fn outputs(&self) -> Vec<Event> {
+        self.events.iter().filter(|e| !e.is_input()).map(|e| e.clone()).collect()
+    }
+}
+
+/// `MachineIo` wrapper recording all I/O into a `Transcript`.
+pub struct Recorder<I> {
+    io: I,
+    transcript: Transcript,
+}
+
+impl<I: MachineIo> Recorder<I> {
+    /// Create a new `Recorder` wrapping an I/O.
+    pub fn new(io: I) -> Recorder<I> {
+        Recorder { io: io, transcript: Transcript::new() }
+    }
+
+    /// Get the transcript recorded so far.
+    pub fn transcript(&self) -> &Transcript { &self.transcript }
+
+    /// Unwrap this `Recorder`, returning the underlying I/O and the transcript.
+    pub fn unwrap(self) -> (I, Transcript) { (self.io, self.transcript) }
+
+    fn record<T>(&mut self, ret: IoResult<T>, event: |&T| -> Event) -> IoResult<T> {
+        match ret {
+            Ok(ref val) => self.transcript.events.push(event(val)),
+            Err(ref e) if e.kind == EndOfFile => self.transcript.events.push(InputEnd),
+            Err(_) => (),
+        }
+        ret
+    }
+}
+
+impl<I: MachineIo> MachineIo for Recorder<I> {
+    fn get_char(&mut self) -> IoResult<char> {
+        let ret = self.io.get_char();
+        self.record(ret, |c| InputChar(*c))
+    }
+
+    fn get_number(&mut self) -> IoResult<i64> {
+        let ret = self.io.get_number();
+        self.record(ret, |n| InputNumber(*n))
+    }
+
+    fn put_char(&mut self, c: char) -> IoResult<()> {
+        let ret = self.io.put_char(c);
+        self.record(ret, |_| OutputChar(c))
+    }
+
+    fn put_number(&mut self, n: i64) -> IoResult<()> {
+        let ret = self.io.put_number(n);
+        self.record(ret, |_| OutputNumber(n))
+    }
+
+    fn put_byte(&mut self, b: u8) -> IoResult<()> {
+        let ret = self.io.put_byte(b);
+        self.record(ret, |_| OutputByte(b))
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.io.flush()
+    }
+}
+
+/// `MachineIo` implementation feeding input recorded in a `Transcript`.
+///
+/// Output is collected into a new transcript, which can be compared with
+/// the recorded one by `verify`.
+pub struct Replay {
+    recorded: Transcript,
+    pos: uint,
+    output: Transcript,
+}
+
+impl Replay {
+    /// Create a new `Replay` from a recorded transcript.
+    pub fn new(recorded: Transcript) -> Replay {
+        Replay { recorded: recorded, pos: 0, output: Transcript::new() }
+    }
+
+    /// Get output written during replay.
+    pub fn output(&self) -> &Transcript { &self.output }
+
+    /// Return true if output during replay is identical to the recorded output.
+    pub fn verify(&self) -> bool { self.recorded.outputs() == self.output.events }
+
+    fn next_input(&mut self) -> Option<Event> {
+        while self.pos < self.recorded.events.len() {
+            self.pos += 1;
+            let event = &self.recorded.events[self.pos - 1];
+            if event.is_input() { return Some(event.clone()) }
+        }
+        None
+    }
+}
+
+impl MachineIo for Replay {
+    fn get_char(&mut self) -> IoResult<char> {
+        match self.next_input() {
+            Some(InputChar(c)) => Ok(c),
+            Some(InputEnd) | None => Err(standard_error(EndOfFile)),
+            Some(_) => Err(mismatch("a character input")),
+        }
+    }
+
+    fn get_number(&mut self) -> IoResult<i64> {
+        match self.next_input() {
+            Some(InputNumber(n)) => Ok(n),
+            Some(InputEnd) | None => Err(standard_error(EndOfFile)),
+            Some(_) => Err(mismatch("a number input")),
+        }
+    }
+
+    fn put_char(&mut self, c: char) -> IoResult<()> {
+        self.output.events.push(OutputChar(c));
+        Ok(())
+    }
+
+    fn put_number(&mut self, n: i64) -> IoResult<()> {
+        self.output.events.push(OutputNumber(n));
+        Ok(())
+    }
+
+    fn put_byte(&mut self, b: u8) -> IoResult<()> {
+        self.output.events.push(OutputByte(b));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, MemReader, MemWriter};
+    use std::str::from_utf8;
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, StreamIo};
+    use super::{Recorder, Replay, Transcript};
+    use super::{InputChar, InputNumber, InputEnd, OutputChar, OutputNumber, OutputByte};
+
+    #[test]
+    fn test_readwrite() {
+        let transcript = Transcript {
+            events: vec!(InputChar('a'), InputNumber(-12), InputEnd,
+                         OutputChar('あ'), OutputNumber(3), OutputByte(255)),
+        };
+        let mut writer = MemWriter::new();
+        transcript.write_to(&mut writer).unwrap();
+        assert_eq!(from_utf8(writer.get_ref()).unwrap(),
+                   "getc 97\ngetn -12\neof\nputc 12354\nputn 3\nputb 255\n");
+        let mut reader = BufReader::new(writer.get_ref());
+        assert_eq!(Transcript::read_from(&mut reader), Ok(transcript));
+
+        let mut reader = BufReader::new(b"getc x\n");
+        assert!(Transcript::read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_getc().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_getn().unwrap();
+        bcw.write_push(0).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_putc().unwrap();
+        bcw.write_push(1).unwrap();
+        bcw.write_retrieve().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+        let program = bcw.unwrap();
+
+        let transcript = {
+            let input = MemReader::new(b"x42\n".to_vec());
+            let io = Recorder::new(StreamIo::new(input, MemWriter::new()));
+            let mut vm = Machine::with_io(io);
+            vm.run(&mut MemReader::new(program.clone())).unwrap();
+            let (_, transcript) = vm.io.unwrap();
+            transcript
+        };
+        assert_eq!(transcript.events,
+                   vec!(InputChar('x'), InputNumber(42), OutputChar('x'), OutputNumber(42)));
+
+        let mut vm = Machine::with_io(Replay::new(transcript));
+        vm.run(&mut MemReader::new(program)).unwrap();
+        assert!(vm.io.verify());
+    }
+}
@@ -0,0 +1,84 @@
+//! Journal for time-travel debugging.
+
+#![experimental]
+
+use std::collections::TreeMap;
+
+use machine::{Cell, MachineState};
+
+/// Periodic snapshots and inputs recorded by instruction count.
+#[deriving(Clone)]
+pub struct Journal<C> {
+    interval: u64,
+    step: u64,
+    horizon: u64,
+    snapshots: Vec<(u64, MachineState<C>)>,
+    inputs: TreeMap<u64, i64>,
+}
+
+impl<C: Cell> Journal<C> {
+    pub fn new(interval: u64) -> Journal<C> {
+        Journal {
+            interval: if interval == 0 { 1 } else { interval },
+            step: 0,
+            horizon: 0,
+            snapshots: Vec::new(),
+            inputs: TreeMap::new(),
+        }
+    }
+
+    /// Discard all records.
+    pub fn reset(&mut self) {
+        self.step = 0;
+        self.horizon = 0;
+        self.snapshots.clear();
+        self.inputs.clear();
+    }
+
+    /// Number of instructions executed so far.
+    pub fn step(&self) -> u64 { self.step }
+
+    /// Return true while executing instructions which have been executed before.
+    pub fn is_replaying(&self) -> bool { self.step < self.horizon }
+
+    /// Input recorded for the current instruction.
+    pub fn input(&self) -> Option<i64> { self.inputs.find_copy(&self.step) }
+
+    pub fn record_input(&mut self, n: i64) {
+        self.inputs.insert(self.step, n);
+    }
+
+    pub fn needs_snapshot(&self) -> bool {
+        self.step % self.interval == 0 && match self.snapshots.last() {
+            Some(&(step, _)) => step < self.step,
+            None => true,
+        }
+    }
+
+    pub fn push_snapshot(&mut self, state: MachineState<C>) {
+        self.snapshots.push((self.step, state));
+    }
+
+    pub fn advance(&mut self) {
+        self.step += 1;
+        if self.horizon < self.step { self.horizon = self.step; }
+    }
+
+    /// Go back to the latest snapshot not after `target`, discarding later snapshots.
+    pub fn rewind(&mut self, target: u64) -> Option<MachineState<C>> {
+        loop {
+            match self.snapshots.last() {
+                Some(&(step, _)) if step > target && self.snapshots.len() > 1 => (),
+                _ => break,
+            }
+            self.snapshots.pop();
+        }
+        match self.snapshots.last() {
+            Some(&(step, ref state)) if step <= target => {
+                self.step = step;
+                Some(state.clone())
+            },
+            _ => None,
+        }
+    }
+}
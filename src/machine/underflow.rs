@@ -0,0 +1,69 @@
+//! Recovery from stack underflow.
+
+#![experimental]
+
+use machine::MachineResult;
+
+/// A handler called when an instruction pops the empty stack, registered by
+/// `Machine::set_underflow_handler`.
+///
+/// ```rust
+/// use whitebase::machine::{MachineResult, UnderflowHandler};
+///
+/// struct Count(uint);
+///
+/// impl UnderflowHandler for Count {
+///     fn underflow(&mut self, _: u64) -> MachineResult<i64> {
+///         let Count(ref mut n) = *self;
+///         *n += 1;
+///         Ok(0)
+///     }
+/// }
+///
+/// let mut machine = whitebase::machine::with_stdio();
+/// machine.set_underflow_handler(Count(0));
+/// ```
+pub trait UnderflowHandler<C = i64> {
+    /// Return the value used in place of the missing one, or an error to stop
+    /// execution. `pc` is the position of the instruction.
+    fn underflow(&mut self, pc: u64) -> MachineResult<C>;
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use bytecode::ByteCodeWriter;
+    use machine::{Machine, MachineResult, QueueIo, OtherMachineError};
+    use super::UnderflowHandler;
+
+    struct Fill(i64);
+
+    impl UnderflowHandler for Fill {
+        fn underflow(&mut self, pc: u64) -> MachineResult<i64> {
+            let Fill(n) = *self;
+            if pc == 0 { Err(OtherMachineError) } else { Ok(n) }
+        }
+    }
+
+    #[test]
+    fn test_underflow_handler() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push(2).unwrap();
+        bcw.write_add().unwrap();
+        bcw.write_putn().unwrap();
+        bcw.write_exit().unwrap();
+
+        let mut vm = Machine::with_io(QueueIo::new());
+        vm.set_underflow_handler(Fill(40));
+        assert_eq!(vm.run(&mut MemReader::new(bcw.unwrap())), Ok(0));
+        assert_eq!(vm.io().take_output(), "42".to_string());
+
+        let mut bcw = MemWriter::new();
+        bcw.write_discard().unwrap();
+        bcw.write_exit().unwrap();
+        let mut vm = Machine::with_io(QueueIo::new());
+        vm.set_underflow_handler(Fill(40));
+        assert_eq!(vm.run(&mut MemReader::new(bcw.unwrap())), Err(OtherMachineError));
+    }
+}
@@ -2,121 +2,585 @@
 
 #![experimental]
 
-use std::io::{EndOfFile, InvalidInput, IoError, IoResult, standard_error};
+use std::ascii::StrAsciiExt;
+use std::collections::HashMap;
+use std::io::{EndOfFile, InvalidInput, IoError, IoResult, MemWriter, standard_error};
 
 use bytecode;
 use bytecode::{ByteCodeReader, ByteCodeWriter};
+use bytecode::ext::ExtRegistry;
+use bytecode::image::{Image, LabelNames};
+use ir;
+use ir::macros::{Invoke, MacroTable, Plain};
+use ir::symbolic;
+use ir::symbolic::SymbolicInstruction;
+use ir::verify::{check_labels, DuplicateLabel, UndefinedLabel};
 use syntax::{Compiler, Decompiler};
-
-macro_rules! try_number(
-    ($val:expr) => (match from_str($val) {
-        Some(n) => n,
-        None => return Err(IoError {
-            kind: InvalidInput,
-            desc: "invalid value format",
-            detail: Some(format!("expected number, but {}", $val)),
-        }),
-    })
-)
+use syntax::expr;
+use syntax::expr::Constants;
 
 /// Assembler and Disassembler.
-pub struct Assembly;
+///
+/// Operands are decimal numbers, hexadecimal, binary and octal numbers prefixed with
+/// `0x`, `0b` and `0o`, or character literals like `'A'`, which are the code points
+/// of the characters. Literals accept the escapes `\n`, `\t`, `\r`, `\0`, `\\`
+/// and `\'`.
+///
+/// Labels of "MARK", "CALL" and jumps may be names, which can be used before they are
+/// marked. Named labels are numbered apart from numeric ones, and verified so that
+/// a name never marked or marked twice fails to assemble with its line.
+///
+/// An operand may be a constant expression of them with `+`, `-`, `*`, `/`, `%`,
+/// parentheses and constants defined by lines like `%define N 5` before use.
+///
+/// Mnemonics are case-insensitive, and `POP`, `JZ`, `JN`, `RET` and `END` are accepted
+/// as aliases of `DISCARD`, `JUMPZ`, `JUMPN`, `RETURN` and `EXIT`, unless in strict mode.
+/// `EXIT` with an operand exits with it as the status.
+///
+/// Lines starting with a mnemonic other than an instruction invoke a macro,
+/// with space separated numbers as arguments.
+///
+/// ```rust
+/// use std::io::{BufReader, MemReader, MemWriter};
+/// use whitebase::ir;
+/// use whitebase::ir::Program;
+/// use whitebase::ir::macros::MacroTable;
+/// use whitebase::syntax::{Assembly, Compiler};
+///
+/// let mut macros = MacroTable::new();
+/// macros.define("PRINT", &["n"], "push $n\nputn").unwrap();
+/// let mut writer = MemWriter::new();
+/// let mut buffer = BufReader::new("PRINT 42\nEXIT\n".as_bytes());
+/// Assembly::with_macros(macros).compile(&mut buffer, &mut writer).unwrap();
+/// let program = Program::from_bytecode(&mut MemReader::new(writer.unwrap())).unwrap();
+/// assert_eq!(program, Program::from_vec(vec!(ir::StackPush(42), ir::PutNumber, ir::Exit)));
+/// ```
+pub struct Assembly {
+    macros: MacroTable,
+    annotate: bool,
+    strict: bool,
+    check_labels: bool,
+    labels: Option<LabelNames>,
+    extensions: ExtRegistry,
+}
 
 impl Assembly {
     /// Create a new `Assembly`.
-    pub fn new() -> Assembly { Assembly }
+    pub fn new() -> Assembly { Assembly::with_macros(MacroTable::new()) }
+
+    /// Create a new `Assembly` expanding the macros.
+    pub fn with_macros(macros: MacroTable) -> Assembly {
+        Assembly { macros: macros, annotate: false, strict: false, check_labels: false, labels: None,
+                   extensions: ExtRegistry::new() }
+    }
+
+    /// Create a new `Assembly` whose disassembly ends each line with
+    /// a comment of the instruction's stack effect, like `ADD ; pop 2 push 1`.
+    pub fn annotated() -> Assembly {
+        Assembly { macros: MacroTable::new(), annotate: true, strict: false, check_labels: false, labels: None,
+                   extensions: ExtRegistry::new() }
+    }
+
+    /// Set extension instructions, which are assembled and disassembled by their names
+    /// instead of failing.
+    pub fn set_extensions(&mut self, extensions: ExtRegistry) { self.extensions = extensions; }
+
+    /// Set names of labels, so that labels are disassembled by their names, and those
+    /// without a valid name as `L` followed by their number, like `L1` and `L_1` for -1.
+    pub fn set_label_names(&mut self, names: LabelNames) { self.labels = Some(names); }
+
+    /// Set strict mode, which accepts only mnemonics in uppercase and no aliases.
+    pub fn set_strict(&mut self, strict: bool) { self.strict = strict; }
+
+    /// Set whether numeric labels are verified as named labels always are, so that
+    /// jumps to labels never marked and labels marked twice fail to assemble.
+    pub fn set_check_labels(&mut self, check_labels: bool) { self.check_labels = check_labels; }
+
+    // Mnemonic of the instruction in uppercase, resolving aliases unless in strict mode.
+    fn canonical(&self, mnemonic: &str) -> String {
+        if self.strict { return mnemonic.to_string() }
+        unalias(mnemonic.to_ascii_upper().as_slice()).to_string()
+    }
+
+    fn parse_line(&self, mnemonic: &str, val: &str, constants: &Constants) -> IoResult<Statement> {
+        let canonical = self.canonical(mnemonic);
+        let label = if is_identifier(val) && !constants.contains_key(&val.to_string()) { Some(val.to_string()) } else { None };
+        let inst = match (canonical.as_slice(), label) {
+            ("MARK", Some(name))  => return Ok(Inst(symbolic::NamedMark(name))),
+            ("CALL", Some(name))  => return Ok(Inst(symbolic::NamedCall(name))),
+            ("JUMP", Some(name))  => return Ok(Inst(symbolic::NamedJump(name))),
+            ("JUMPZ", Some(name)) => return Ok(Inst(symbolic::NamedJumpIfZero(name))),
+            ("JUMPN", Some(name)) => return Ok(Inst(symbolic::NamedJumpIfNegative(name))),
+            ("PUSH", _)     => ir::StackPush(try!(expr::eval(val, constants))),
+            ("DUP", _)      => ir::StackDuplicate,
+            ("COPY", _)     => ir::StackCopy(try!(expr::eval(val, constants))),
+            ("SWAP", _)     => ir::StackSwap,
+            ("DISCARD", _)  => ir::StackDiscard,
+            ("SLIDE", _)    => ir::StackSlide(try!(expr::eval(val, constants))),
+            ("ADD", _)      => ir::Addition,
+            ("SUB", _)      => ir::Subtraction,
+            ("MUL", _)      => ir::Multiplication,
+            ("DIV", _)      => ir::Division,
+            ("MOD", _)      => ir::Modulo,
+            ("STORE", _)    => ir::HeapStore,
+            ("RETRIEVE", _) => ir::HeapRetrieve,
+            ("MARK", _)     => ir::Mark(try!(expr::eval(val, constants))),
+            ("CALL", _)     => ir::Call(try!(expr::eval(val, constants))),
+            ("JUMP", _)     => ir::Jump(try!(expr::eval(val, constants))),
+            ("JUMPZ", _)    => ir::JumpIfZero(try!(expr::eval(val, constants))),
+            ("JUMPN", _)    => ir::JumpIfNegative(try!(expr::eval(val, constants))),
+            ("RETURN", _)   => ir::Return,
+            ("EXIT", _) if val.is_empty() => ir::Exit,
+            ("EXIT", _)     => ir::ExitWith(try!(expr::eval(val, constants))),
+            ("PUTC", _)     => ir::PutCharactor,
+            ("PUTN", _)     => ir::PutNumber,
+            ("GETC", _)     => ir::GetCharactor,
+            ("GETN", _)     => ir::GetNumber,
+            ("NOP", _)      => ir::Nop,
+            _ if self.extensions.opcode(mnemonic).is_some() => {
+                let opcode = self.extensions.opcode(mnemonic).unwrap();
+                let n = if val.is_empty() { 0 } else { try!(expr::eval(val, constants)) };
+                if self.extensions.get(opcode).unwrap().arity == 0 && n != 0 {
+                    return Err(IoError {
+                        kind: InvalidInput,
+                        desc: "extension instruction takes no operand",
+                        detail: Some(mnemonic.to_string()),
+                    })
+                }
+                ir::Extension(opcode, n)
+            },
+            _ if self.macros.contains(mnemonic) => {
+                let mut args = Vec::new();
+                for arg in val.words() {
+                    args.push(try!(expr::eval(arg, constants)));
+                }
+                return Ok(Macro(mnemonic.to_string(), args))
+            },
+            _               => return Err(standard_error(InvalidInput)),
+        };
+        Ok(Inst(symbolic::Plain(inst)))
+    }
+}
+
+static MNEMONICS: &'static [&'static str] = &[
+    "PUSH", "DUP", "COPY", "SWAP", "DISCARD", "SLIDE", "ADD", "SUB", "MUL", "DIV", "MOD",
+    "STORE", "RETRIEVE", "MARK", "CALL", "JUMP", "JUMPZ", "JUMPN", "RETURN", "EXIT",
+    "PUTC", "PUTN", "GETC", "GETN", "NOP",
+];
+
+fn unalias<'a>(upper: &'a str) -> &'a str {
+    match upper {
+        "POP" => "DISCARD",
+        "JZ"  => "JUMPZ",
+        "JN"  => "JUMPN",
+        "RET" => "RETURN",
+        "END" => "EXIT",
+        name  => name,
+    }
+}
+
+// A line of source, whose labels may be names to be resolved in the second pass.
+enum Statement {
+    Inst(SymbolicInstruction),
+    Macro(String, Vec<i64>),
+}
+
+// Define a constant by a line `%define NAME expression`.
+fn define(constants: &mut Constants, val: &str) -> IoResult<()> {
+    let (name, value) = split_word(val);
+    if !is_identifier(name) {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "invalid constant name",
+            detail: Some(name.to_string()),
+        })
+    }
+    let value = try!(expr::eval(value, constants));
+    constants.insert(name.to_string(), value);
+    Ok(())
+}
+
+// Split the line at the first run of whitespace into a word and the rest.
+fn split_word<'a>(line: &'a str) -> (&'a str, &'a str) {
+    match line.find(|c: char| c.is_whitespace()) {
+        Some(n) => (line.slice_to(n), line.slice_from(n).trim()),
+        None => (line, ""),
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.len() > 0 && s.chars().enumerate().all(|(i, c)| c.is_alphabetic() || c == '_' || (i > 0 && c.is_digit()))
+}
+
+// Remove a comment from the line, except a semicolon quoted as a character literal.
+fn strip_comment<'a>(line: &'a str) -> &'a str {
+    let (code, _) = split_comment(line);
+    code
+}
+
+// Split the line into code and a comment starting with a semicolon.
+fn split_comment<'a>(line: &'a str) -> (&'a str, Option<&'a str>) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return (line.slice_to(i).trim_right(), Some(line.slice_from(i))),
+            _ => (),
+        }
+    }
+    (line, None)
+}
+
+fn label_name(names: &LabelNames, label: i64) -> String {
+    match names.find(&label) {
+        Some(name) => name.clone(),
+        None => label.to_string(),
+    }
+}
+
+fn is_label(opcode: u8) -> bool {
+    opcode == bytecode::CMD_MARK || opcode == bytecode::CMD_CALL || opcode == bytecode::CMD_JUMP ||
+        opcode == bytecode::CMD_JUMPZ || opcode == bytecode::CMD_JUMPN
+}
+
+// Names of labels to disassemble, counting how many labels share each name.
+struct Symbols<'a> {
+    names: &'a LabelNames,
+    counts: HashMap<String, uint>,
+}
+
+impl<'a> Symbols<'a> {
+    fn new(names: &'a LabelNames) -> Symbols<'a> {
+        let mut counts = HashMap::new();
+        for name in names.values() {
+            *counts.find_or_insert(name.clone(), 0u) += 1;
+        }
+        Symbols { names: names, counts: counts }
+    }
+
+    fn taken(&self, name: &str) -> uint { self.counts.find_copy(&name.to_string()).unwrap_or(0) }
+
+    // Name of the label, generated from the number unless it has a name which is
+    // an identifier and not shared with another label.
+    fn symbol(&self, label: i64) -> String {
+        match self.names.find(&label) {
+            Some(name) if is_identifier(name.as_slice()) && self.taken(name.as_slice()) == 1 => return name.clone(),
+            _ => (),
+        }
+        let mut name = if label < 0 { format!("L_{}", (-(label + 1)) as u64 + 1) } else { format!("L{}", label) };
+        while self.taken(name.as_slice()) > 0 { name.push_char('_'); }
+        name
+    }
+}
+
+fn label_error(desc: &'static str, detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: Some(detail),
+    }
 }
 
 impl Compiler for Assembly {
     fn compile<B: Buffer, W: ByteCodeWriter>(&self, input: &mut B, output: &mut W) -> IoResult<()> {
+        self.compile_names(input, output).map(|_| ())
+    }
+}
+
+impl Decompiler for Assembly {
+    fn decompile<R: ByteCodeReader, W: Writer>(&self, input: &mut R, output: &mut W) -> IoResult<()> {
+        self.decompile_labels(input, output, self.labels.as_ref())
+    }
+}
+
+impl Assembly {
+    /// Disassemble the instruction stream of the image, with labels by their names
+    /// in `SECTION_LABEL_NAMES` as `set_label_names` does.
+    ///
+    /// ```rust
+    /// use std::io::MemWriter;
+    /// use std::str::from_utf8;
+    /// use whitebase::bytecode::image::{Image, LabelNames};
+    /// use whitebase::ir;
+    /// use whitebase::ir::Program;
+    /// use whitebase::syntax::Assembly;
+    ///
+    /// let program = Program::from_vec(vec!(ir::Call(0), ir::Exit, ir::Mark(0), ir::Jump(1), ir::Mark(1)));
+    /// let mut image = Image::from_program(&program).unwrap();
+    /// let mut names = LabelNames::new();
+    /// names.insert(0, "main".to_string());
+    /// image.set_label_names(&names);
+    /// let mut writer = MemWriter::new();
+    /// Assembly::new().decompile_image(&image, &mut writer).unwrap();
+    /// assert_eq!(from_utf8(writer.get_ref()).unwrap(), "CALL main\nEXIT\nMARK main\nJUMP L1\nMARK L1\n");
+    /// ```
+    pub fn decompile_image<W: Writer>(&self, image: &Image, output: &mut W) -> IoResult<()> {
+        let names = try!(image.label_names());
+        self.decompile_labels(&mut image.reader(), output, Some(&names))
+    }
+
+    /// Assemble the source into an image, keeping the names of named labels in
+    /// `SECTION_LABEL_NAMES` so that `decompile_image` writes them back.
+    ///
+    /// ```rust
+    /// use std::io::{BufReader, MemWriter};
+    /// use std::str::from_utf8;
+    /// use whitebase::syntax::Assembly;
+    ///
+    /// let source = "CALL print\nEXIT\nMARK print\nRETURN\n";
+    /// let image = Assembly::new().compile_image(&mut BufReader::new(source.as_bytes())).unwrap();
+    /// let mut writer = MemWriter::new();
+    /// Assembly::new().decompile_image(&image, &mut writer).unwrap();
+    /// assert_eq!(from_utf8(writer.get_ref()).unwrap(), source);
+    /// ```
+    pub fn compile_image<B: Buffer>(&self, input: &mut B) -> IoResult<Image> {
+        let mut writer = MemWriter::new();
+        let names = try!(self.compile_names(input, &mut writer));
+        let mut image = Image::new(writer.unwrap());
+        if !names.is_empty() { image.set_label_names(&names); }
+        Ok(image)
+    }
+
+    // Assemble the source, returning the names of named labels by their numbers.
+    fn compile_names<B: Buffer, W: ByteCodeWriter>(&self, input: &mut B, output: &mut W) -> IoResult<LabelNames> {
+        // first pass: parse lines, leaving named labels unresolved
+        let mut statements = Vec::new();
+        let mut constants = Constants::new();
+        let mut lineno = 0u;
         loop {
-            let ret = match input.read_line() {
-                Ok(line) => {
-                    let inst = line.replace("\n","");
-                    let slice = inst.as_slice();
-                    if slice.len() == 0 { continue }
-                    if slice.char_at(0) == ';' { continue }
-                    let (mnemonic, val) = match slice.find(' ') {
-                        Some(n) => (slice.slice_to(n), slice.slice_from(n + 1)),
-                        None => (slice, ""),
-                    };
-                    match mnemonic {
-                        "PUSH"     => output.write_push(try_number!(val)),
-                        "DUP"      => output.write_dup(),
-                        "COPY"     => output.write_copy(try_number!(val)),
-                        "SWAP"     => output.write_swap(),
-                        "DISCARD"  => output.write_discard(),
-                        "SLIDE"    => output.write_slide(try_number!(val)),
-                        "ADD"      => output.write_add(),
-                        "SUB"      => output.write_sub(),
-                        "MUL"      => output.write_mul(),
-                        "DIV"      => output.write_div(),
-                        "MOD"      => output.write_mod(),
-                        "STORE"    => output.write_store(),
-                        "RETRIEVE" => output.write_retrieve(),
-                        "MARK"     => output.write_mark(try_number!(val)),
-                        "CALL"     => output.write_call(try_number!(val)),
-                        "JUMP"     => output.write_jump(try_number!(val)),
-                        "JUMPZ"    => output.write_jumpz(try_number!(val)),
-                        "JUMPN"    => output.write_jumpn(try_number!(val)),
-                        "RETURN"   => output.write_return(),
-                        "EXIT"     => output.write_exit(),
-                        "PUTC"     => output.write_putc(),
-                        "PUTN"     => output.write_putn(),
-                        "GETC"     => output.write_getc(),
-                        "GETN"     => output.write_getn(),
-                        _          => Err(standard_error(InvalidInput)),
-                    }
+            let line = match input.read_line() {
+                Ok(line) => line,
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(e) => return Err(e),
+            };
+            lineno += 1;
+            let inst = line.replace("\n","");
+            let slice = strip_comment(inst.as_slice()).trim();
+            if slice.len() == 0 { continue }
+            let (mnemonic, val) = split_word(slice);
+            if mnemonic == "%define" {
+                try!(define(&mut constants, val));
+                continue
+            }
+            statements.push((lineno, try!(self.parse_line(mnemonic, val, &constants))));
+        }
+
+        // second pass: resolve named labels, and verify labels with the line of each instruction
+        let symbols: Vec<SymbolicInstruction> = statements.iter().filter_map(|&(_, ref statement)| match *statement {
+            Inst(ref inst) => Some(inst.clone()),
+            Macro(..) => None,
+        }).collect();
+        let (resolved, names) = symbolic::resolve_with_names(symbols.as_slice());
+        let mut resolved = resolved.unwrap().move_iter();
+        let mut insts = Vec::new();
+        let mut lines = Vec::new();
+        for &(lineno, ref statement) in statements.iter() {
+            let (inst, len) = match *statement {
+                Inst(_) => (Plain(resolved.next().unwrap()), 1),
+                Macro(ref name, ref args) => {
+                    let inst = Invoke(name.clone(), args.clone());
+                    let len = try!(self.macros.expand([inst.clone()])).len();
+                    (inst, len)
                 },
-                Err(e) => Err(e),
             };
+            insts.push(inst);
+            lines.grow(len, &lineno);
+        }
+        let program = try!(self.macros.expand(insts.as_slice()));
+        for error in check_labels(&program).move_iter() {
+            match error {
+                UndefinedLabel(i, label) if self.check_labels || names.contains_key(&label) => {
+                    return Err(label_error("undefined label", format!("{} at line {}", label_name(&names, label), lines[i])))
+                },
+                DuplicateLabel(label, first, second) if self.check_labels || names.contains_key(&label) => {
+                    return Err(label_error("duplicate label",
+                                           format!("{} at line {} and {}", label_name(&names, label), lines[first], lines[second])))
+                },
+                _ => (),
+            }
+        }
+        try!(program.write_bytecode(output));
+        Ok(names)
+    }
 
-            match ret {
-                Ok(()) => continue,
+    fn decompile_labels<R: ByteCodeReader, W: Writer>(&self, input: &mut R, output: &mut W,
+                                                      names: Option<&LabelNames>) -> IoResult<()> {
+        let symbols = names.map(|names| Symbols::new(names));
+        let symbols = symbols.as_ref();
+        loop {
+            let (opcode, n) = match input.read_inst() {
+                Ok(inst) => inst,
                 Err(ref e) if e.kind == EndOfFile => break,
                 Err(e) => return Err(e),
+            };
+            match bytecode::lower(opcode, n) {
+                Some(((first, m), (second, _))) => {
+                    try!(self.decompile_inst(first, m, output, symbols));
+                    try!(self.decompile_inst(second, 0, output, symbols));
+                },
+                None => try!(self.decompile_inst(opcode, n, output, symbols)),
             }
         }
         Ok(())
     }
+
+    fn decompile_inst<W: Writer>(&self, opcode: u8, n: i64, output: &mut W,
+                                 symbols: Option<&Symbols>) -> IoResult<()> {
+        if bytecode::is_extension(opcode) {
+            match self.extensions.format(opcode, n) {
+                Some(text) => try!(write!(output, "{}\n", text)),
+                None => return Err(standard_error(InvalidInput)),
+            }
+            return Ok(())
+        }
+        if opcode == bytecode::CMD_NOP {
+            try!(output.write_str("; NOP\n"));
+            return Ok(())
+        }
+        let (mnemonic, operand) = match opcode {
+            bytecode::CMD_PUSH     => ("PUSH", Some(n)),
+            bytecode::CMD_DUP      => ("DUP", None),
+            bytecode::CMD_COPY     => ("COPY", Some(n)),
+            bytecode::CMD_SWAP     => ("SWAP", None),
+            bytecode::CMD_DISCARD  => ("DISCARD", None),
+            bytecode::CMD_SLIDE    => ("SLIDE", Some(n)),
+            bytecode::CMD_ADD      => ("ADD", None),
+            bytecode::CMD_SUB      => ("SUB", None),
+            bytecode::CMD_MUL      => ("MUL", None),
+            bytecode::CMD_DIV      => ("DIV", None),
+            bytecode::CMD_MOD      => ("MOD", None),
+            bytecode::CMD_STORE    => ("STORE", None),
+            bytecode::CMD_RETRIEVE => ("RETRIEVE", None),
+            bytecode::CMD_MARK     => ("MARK", Some(n)),
+            bytecode::CMD_CALL     => ("CALL", Some(n)),
+            bytecode::CMD_JUMP     => ("JUMP", Some(n)),
+            bytecode::CMD_JUMPZ    => ("JUMPZ", Some(n)),
+            bytecode::CMD_JUMPN    => ("JUMPN", Some(n)),
+            bytecode::CMD_RETURN   => ("RETURN", None),
+            bytecode::CMD_EXIT     => ("EXIT", None),
+            bytecode::CMD_EXIT_STATUS => ("EXIT", Some(n)),
+            bytecode::CMD_PUTC     => ("PUTC", None),
+            bytecode::CMD_PUTN     => ("PUTN", None),
+            bytecode::CMD_GETC     => ("GETC", None),
+            bytecode::CMD_GETN     => ("GETN", None),
+            _                      => return Err(standard_error(InvalidInput)),
+        };
+        try!(output.write_str(mnemonic));
+        match (operand, symbols) {
+            (Some(n), Some(symbols)) if is_label(opcode) => try!(write!(output, " {}", symbols.symbol(n))),
+            (Some(n), _) => try!(write!(output, " {}", n)),
+            (None, _) => (),
+        }
+        if self.annotate {
+            match bytecode::decode(opcode, n) {
+                Some(inst) => {
+                    let effect = ir::stack_effect(&inst);
+                    try!(write!(output, " ; pop {} push {}", effect.pops, effect.pushes));
+                },
+                None => (),
+            }
+        }
+        output.write_str("\n")
+    }
 }
 
-impl Decompiler for Assembly {
-    fn decompile<R: ByteCodeReader, W: Writer>(&self, input: &mut R, output: &mut W) -> IoResult<()> {
+/// Formatter of assembly source, which canonicalizes sources written by hand
+/// and disassemblies so that their diffs are reviewable.
+///
+/// Mnemonics of instructions are written in uppercase with aliases resolved, "MARK"s
+/// at the start of lines and other instructions indented with operands aligned.
+/// Comments and runs of blank lines are kept, the latter as one blank line.
+///
+/// ```rust
+/// use std::io::{BufReader, MemWriter};
+/// use std::str::from_utf8;
+/// use whitebase::syntax::assembly::Formatter;
+///
+/// let mut writer = MemWriter::new();
+/// let mut buffer = BufReader::new("mark 1\npush 1 ; one\nputn\n  ret\n".as_bytes());
+/// Formatter::new().format(&mut buffer, &mut writer).unwrap();
+/// assert_eq!(from_utf8(writer.get_ref()).unwrap(),
+///            "MARK 1\n    PUSH     1 ; one\n    PUTN\n    RETURN\n");
+/// ```
+pub struct Formatter {
+    separate_blocks: bool,
+}
+
+impl Formatter {
+    /// Create a new `Formatter`.
+    pub fn new() -> Formatter { Formatter { separate_blocks: false } }
+
+    /// Set whether basic blocks are separated by blank lines, i.e. after "CALL", jumps,
+    /// "RETURN" and "EXIT", and before "MARK".
+    pub fn set_separate_blocks(&mut self, separate_blocks: bool) { self.separate_blocks = separate_blocks; }
+
+    /// Format the source.
+    pub fn format<B: Buffer, W: Writer>(&self, input: &mut B, output: &mut W) -> IoResult<()> {
+        let mut comments: Vec<String> = Vec::new();
+        let mut blank = false;
+        let mut written = false;
+        let mut after_mark = false;
         loop {
-            let res = match input.read_inst() {
-                Ok((bytecode::CMD_PUSH, n))     => write!(output, "PUSH {}\n", n),
-                Ok((bytecode::CMD_DUP, _))      => output.write_line("DUP"),
-                Ok((bytecode::CMD_COPY, n))     => write!(output, "COPY {}\n", n),
-                Ok((bytecode::CMD_SWAP, _))     => output.write_line("SWAP"),
-                Ok((bytecode::CMD_DISCARD, _))  => output.write_line("DISCARD"),
-                Ok((bytecode::CMD_SLIDE, n))    => write!(output, "SLIDE {}\n", n),
-                Ok((bytecode::CMD_ADD, _))      => output.write_line("ADD"),
-                Ok((bytecode::CMD_SUB, _))      => output.write_line("SUB"),
-                Ok((bytecode::CMD_MUL, _))      => output.write_line("MUL"),
-                Ok((bytecode::CMD_DIV, _))      => output.write_line("DIV"),
-                Ok((bytecode::CMD_MOD, _))      => output.write_line("MOD"),
-                Ok((bytecode::CMD_STORE, _))    => output.write_line("STORE"),
-                Ok((bytecode::CMD_RETRIEVE, _)) => output.write_line("RETRIEVE"),
-                Ok((bytecode::CMD_MARK, n))     => write!(output, "MARK {}\n", n),
-                Ok((bytecode::CMD_CALL, n))     => write!(output, "CALL {}\n", n),
-                Ok((bytecode::CMD_JUMP, n))     => write!(output, "JUMP {}\n", n),
-                Ok((bytecode::CMD_JUMPZ, n))    => write!(output, "JUMPZ {}\n", n),
-                Ok((bytecode::CMD_JUMPN, n))    => write!(output, "JUMPN {}\n", n),
-                Ok((bytecode::CMD_RETURN, _))   => output.write_line("RETURN"),
-                Ok((bytecode::CMD_EXIT, _))     => output.write_line("EXIT"),
-                Ok((bytecode::CMD_PUTC, _))     => output.write_line("PUTC"),
-                Ok((bytecode::CMD_PUTN, _))     => output.write_line("PUTN"),
-                Ok((bytecode::CMD_GETC, _))     => output.write_line("GETC"),
-                Ok((bytecode::CMD_GETN, _))     => output.write_line("GETN"),
-                Ok(_)                           => Err(standard_error(InvalidInput)),
-                Err(e)                          => Err(e),
-            };
-            match res {
+            let line = match input.read_line() {
+                Ok(line) => line,
                 Err(ref e) if e.kind == EndOfFile => break,
                 Err(e) => return Err(e),
-                _ => continue,
             };
+            let (code, comment) = split_comment(line.as_slice().trim());
+            if code.len() == 0 {
+                match comment {
+                    Some(comment) => comments.push(comment.to_string()),
+                    None if comments.is_empty() => blank = true,
+                    // a blank line after comments belongs to them
+                    None if comments.last().map_or(false, |c| c.len() == 0) => (),
+                    None => comments.push(String::new()),
+                }
+                continue
+            }
+
+            let (mnemonic, operand) = split_word(code);
+            let upper = mnemonic.to_ascii_upper();
+            let name = match unalias(upper.as_slice()) {
+                name if MNEMONICS.iter().any(|m| *m == name) => name,
+                _ => mnemonic,
+            };
+            let is_mark = name == "MARK";
+            if is_mark && self.separate_blocks && !after_mark { blank = true; }
+            if blank && written { try!(output.write_str("\n")); }
+            blank = false;
+
+            let unindented = is_mark || name == "%define";
+            let indent = if unindented { "" } else { "    " };
+            for comment in comments.iter() {
+                if comment.len() == 0 { try!(output.write_str("\n")); continue }
+                try!(write!(output, "{}{}\n", indent, comment));
+            }
+            comments.clear();
+            let text = if unindented || operand.len() == 0 {
+                format!("{}{} {}", indent, name, operand)
+            } else {
+                format!("{}{:<8} {}", indent, name, operand)
+            };
+            try!(output.write_str(text.as_slice().trim_right()));
+            match comment {
+                Some(comment) => try!(write!(output, " {}", comment)),
+                None => (),
+            }
+            try!(output.write_str("\n"));
+            written = true;
+            after_mark = is_mark;
+            match name {
+                "CALL" | "JUMP" | "JUMPZ" | "JUMPN" | "RETURN" | "EXIT" if self.separate_blocks => blank = true,
+                _ => (),
+            }
+        }
+        while comments.last().map_or(false, |c| c.len() == 0) { comments.pop(); }
+        if blank && written && !comments.is_empty() { try!(output.write_str("\n")); }
+        for comment in comments.iter() {
+            try!(write!(output, "{}\n", comment));
         }
         Ok(())
     }
@@ -128,7 +592,9 @@ mod test {
     use std::str::from_utf8;
 
     use bytecode;
-    use bytecode::{ByteCodeReader, ByteCodeWriter};
+    use bytecode::{ByteCodeReader, ByteCodeWriter, Module};
+    use bytecode::ext::ExtRegistry;
+    use bytecode::image::LabelNames;
     use syntax::{Compiler, Decompiler};
 
     #[test]
@@ -158,6 +624,7 @@ mod test {
             "PUTN",
             "GETC",
             "GETN",
+            "EXIT 9",
             ).connect("\n");
         let mut writer = MemWriter::new();
         {
@@ -190,6 +657,7 @@ mod test {
         assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUTN, 0)));
         assert_eq!(reader.read_inst(), Ok((bytecode::CMD_GETC, 0)));
         assert_eq!(reader.read_inst(), Ok((bytecode::CMD_GETN, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_EXIT_STATUS, 9)));
         assert!(reader.read_inst().is_err());
     }
 
@@ -222,6 +690,7 @@ mod test {
             bcw.write_putn().unwrap();
             bcw.write_getc().unwrap();
             bcw.write_getn().unwrap();
+            bcw.write_exit_status(-1).unwrap();
             let mut bcr = MemReader::new(bcw.unwrap());
             let syntax = super::Assembly::new();
             syntax.decompile(&mut bcr, &mut writer).unwrap();
@@ -232,8 +701,338 @@ mod test {
             "ADD", "SUB", "MUL", "DIV", "MOD",
             "STORE", "RETRIEVE",
             "MARK 1", "CALL 15", "JUMP 2", "JUMPZ 16", "JUMPN 32", "RETURN", "EXIT",
-            "PUTC", "PUTN", "GETC", "GETN", ""
+            "PUTC", "PUTN", "GETC", "GETN", "EXIT -1", ""
             ).connect("\n");
         assert_eq!(result, expected.as_slice());
     }
+
+    #[test]
+    fn test_annotated() {
+        let mut writer = MemWriter::new();
+        {
+            let mut bcw = MemWriter::new();
+            bcw.write_push(1).unwrap();
+            bcw.write_copy(0).unwrap();
+            bcw.write_add().unwrap();
+            bcw.write_putn().unwrap();
+            let mut bcr = MemReader::new(bcw.unwrap());
+            super::Assembly::annotated().decompile(&mut bcr, &mut writer).unwrap();
+        }
+        let source = from_utf8(writer.get_ref()).unwrap().to_string();
+        let expected = vec!(
+            "PUSH 1 ; pop 0 push 1", "COPY 0 ; pop 0 push 1",
+            "ADD ; pop 2 push 1", "PUTN ; pop 1 push 0", ""
+            ).connect("\n");
+        assert_eq!(source, expected);
+
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 1)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_COPY, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_ADD, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUTN, 0)));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut registry = ExtRegistry::new();
+        registry.register(0xF1, "SQRT", 0);
+        registry.register(0xF2, "SHL", 1);
+        let mut bcw = MemWriter::new();
+        bcw.write_push(16).unwrap();
+        registry.write(&mut bcw, "SQRT", 0).unwrap();
+        registry.write(&mut bcw, "SHL", 2).unwrap();
+        let code = bcw.unwrap();
+
+        let mut writer = MemWriter::new();
+        assert!(super::Assembly::new().decompile(&mut MemReader::new(code.clone()), &mut writer).is_err());
+
+        let mut writer = MemWriter::new();
+        let mut syntax = super::Assembly::new();
+        syntax.set_extensions(registry);
+        syntax.decompile(&mut MemReader::new(code.clone()), &mut writer).unwrap();
+        let source = from_utf8(writer.get_ref()).unwrap();
+        assert_eq!(source, "PUSH 16\nSQRT\nSHL 2\n");
+
+        let mut bcw = MemWriter::new();
+        syntax.compile(&mut BufReader::new(source.as_bytes()), &mut bcw).unwrap();
+        assert_eq!(bcw.unwrap(), code);
+
+        let mut bcw = MemWriter::new();
+        assert!(syntax.compile(&mut BufReader::new(b"SQRT 1\n"), &mut bcw).is_err());
+        assert!(super::Assembly::new().compile(&mut BufReader::new(b"SQRT\n"), &mut bcw).is_err());
+    }
+
+    #[test]
+    fn test_char_literal() {
+        let source = "PUSH 'A'\nPUSH '\\n' ; newline\nPUSH ';'\nPUSH '\\''\nPUSH '\\\\'\nPUSH 'é'\n";
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 'A' as i64)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, '\n' as i64)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, ';' as i64)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, '\'' as i64)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, '\\' as i64)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 0xE9)));
+        assert!(reader.eof());
+
+        for source in ["PUSH ''", "PUSH 'AB'", "PUSH '\\x'", "PUSH 'A"].iter() {
+            let mut buffer = BufReader::new(source.as_bytes());
+            assert!(super::Assembly::new().compile(&mut buffer, &mut MemWriter::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_radix_literal() {
+        let source = "PUSH 0x1F\nPUSH 0b1010\nPUSH 0o17\nPUSH -0xff\nCOPY 0X0\nPUSH 010\n";
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 31)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 10)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 15)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, -255)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_COPY, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 10)));
+        assert!(reader.eof());
+
+        for source in ["PUSH 0x", "PUSH 0b102", "PUSH 0o8", "PUSH 0x-1", "PUSH 0xG"].iter() {
+            let mut buffer = BufReader::new(source.as_bytes());
+            assert!(super::Assembly::new().compile(&mut buffer, &mut MemWriter::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_aliases() {
+        let source = "push 1\nDup\npop\njz 2\njn 3\nret\nend\n";
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 1)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_DUP, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_DISCARD, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_JUMPZ, 2)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_JUMPN, 3)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_RETURN, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_EXIT, 0)));
+        assert!(reader.eof());
+
+        let mut syntax = super::Assembly::new();
+        syntax.set_strict(true);
+        for source in ["push 1", "POP", "RET"].iter() {
+            let mut buffer = BufReader::new(source.as_bytes());
+            assert!(syntax.compile(&mut buffer, &mut MemWriter::new()).is_err());
+        }
+        let mut buffer = BufReader::new("PUSH 1\nDISCARD\n".as_bytes());
+        assert!(syntax.compile(&mut buffer, &mut MemWriter::new()).is_ok());
+    }
+
+    #[test]
+    fn test_constant_expression() {
+        let source = "%define N 5\n%define SIZE N * 2\nPUSH 2*1024+7\nCOPY N-1\nPUSH (SIZE + 1) % 4 ; 3\nPUSH -'0'\n";
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 2055)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_COPY, 4)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, 3)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_PUSH, -48)));
+        assert!(reader.eof());
+
+        for source in ["PUSH N", "PUSH 1/0", "%define 1N 1", "%define N", "PUSH 1 +"].iter() {
+            let mut buffer = BufReader::new(source.as_bytes());
+            assert!(super::Assembly::new().compile(&mut buffer, &mut MemWriter::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_named_labels() {
+        let source = "CALL print\nJUMP end\nMARK print\nPUSH 0\nPUTN\nRETURN\nMARK end\nMARK 0\nEXIT\n";
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_CALL, 1)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_JUMP, 2)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_MARK, 1)));
+
+        let errors = [
+            ("CALL print\nEXIT\n", "undefined label", "print at line 1"),
+            ("MARK main\nEXIT\nMARK main\n", "duplicate label", "main at line 1 and 3"),
+        ];
+        for &(source, desc, detail) in errors.iter() {
+            let mut buffer = BufReader::new(source.as_bytes());
+            let err = super::Assembly::new().compile(&mut buffer, &mut MemWriter::new()).unwrap_err();
+            assert_eq!(err.desc, desc);
+            assert_eq!(err.detail, Some(detail.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_check_labels() {
+        let source = "PUSH 1\nJUMP 3\n";
+        let mut buffer = BufReader::new(source.as_bytes());
+        assert!(super::Assembly::new().compile(&mut buffer, &mut MemWriter::new()).is_ok());
+
+        let mut syntax = super::Assembly::new();
+        syntax.set_check_labels(true);
+        let mut buffer = BufReader::new(source.as_bytes());
+        let err = syntax.compile(&mut buffer, &mut MemWriter::new()).unwrap_err();
+        assert_eq!(err.detail, Some("3 at line 2".to_string()));
+    }
+
+    #[test]
+    fn test_nop() {
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new("PUSH 1\nNOP\nPUTN\n".as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let code = bcw.unwrap();
+        assert_eq!(code.len(), 11);
+
+        let mut writer = MemWriter::new();
+        super::Assembly::new().decompile(&mut MemReader::new(code), &mut writer).unwrap();
+        assert_eq!(from_utf8(writer.get_ref()).unwrap(), "PUSH 1\n; NOP\nPUTN\n");
+    }
+
+    #[test]
+    fn test_decompile_module() {
+        let mut bcw = MemWriter::new();
+        bcw.write_mark(1).unwrap();
+        bcw.write_jump(1).unwrap();
+        let module = Module::from_bytes(bcw.unwrap()).unwrap();
+        let mut writer = MemWriter::new();
+        super::Assembly::new().decompile_module(&module, &mut writer).unwrap();
+        assert_eq!(from_utf8(writer.get_ref()).unwrap(), "MARK 1\nJUMP 1\n");
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let mut bcw = MemWriter::new();
+        bcw.write_push_add(1).unwrap();
+        bcw.write_retrieve_at(2).unwrap();
+        bcw.write_store_const(3).unwrap();
+        let mut writer = MemWriter::new();
+        super::Assembly::new().decompile(&mut MemReader::new(bcw.unwrap()), &mut writer).unwrap();
+        assert_eq!(from_utf8(writer.get_ref()).unwrap(),
+                   "PUSH 1\nADD\nPUSH 2\nRETRIEVE\nPUSH 3\nSTORE\n");
+    }
+
+    #[test]
+    fn test_format() {
+        let source = vec!(
+            "%define   N 2",
+            "; entry",
+            "",
+            "",
+            "push N",
+            "  call 1   ; subroutine",
+            "end",
+            "mark 1",
+            "MARK 2",
+            "PRINT 42",
+            "  retrieve",
+            "jz 1",
+            "ret",
+            "; done",
+            "",
+            ).connect("\n");
+        let expected = vec!(
+            "%define N 2",
+            "    ; entry",
+            "",
+            "    PUSH     N",
+            "    CALL     1 ; subroutine",
+            "",
+            "    EXIT",
+            "",
+            "MARK 1",
+            "MARK 2",
+            "    PRINT    42",
+            "    RETRIEVE",
+            "    JUMPZ    1",
+            "",
+            "    RETURN",
+            "",
+            "; done",
+            "",
+            ).connect("\n");
+        let mut writer = MemWriter::new();
+        let mut formatter = super::Formatter::new();
+        formatter.set_separate_blocks(true);
+        formatter.format(&mut BufReader::new(source.as_bytes()), &mut writer).unwrap();
+        assert_eq!(from_utf8(writer.get_ref()).unwrap(), expected.as_slice());
+
+        let mut formatted = MemWriter::new();
+        formatter.format(&mut BufReader::new(expected.as_bytes()), &mut formatted).unwrap();
+        assert_eq!(formatted.unwrap(), writer.unwrap());
+    }
+
+    #[test]
+    fn test_format_assemble() {
+        let source = vec!(
+            "%define  N 2",
+            "call main",
+            "end",
+            "mark main",
+            "push   N ; two",
+            "\tputn",
+            "  ret",
+            "",
+            ).connect("\n");
+        let mut formatted = MemWriter::new();
+        super::Formatter::new().format(&mut BufReader::new(source.as_bytes()), &mut formatted).unwrap();
+
+        let mut expected = MemWriter::new();
+        super::Assembly::new().compile(&mut BufReader::new(source.as_bytes()), &mut expected).unwrap();
+        let mut bcw = MemWriter::new();
+        super::Assembly::new().compile(&mut BufReader::new(formatted.get_ref()), &mut bcw).unwrap();
+        assert_eq!(bcw.unwrap(), expected.unwrap());
+    }
+
+    #[test]
+    fn test_label_names() {
+        let mut bcw = MemWriter::new();
+        bcw.write_call(1).unwrap();
+        bcw.write_jumpz(-2).unwrap();
+        bcw.write_jump(3).unwrap();
+        bcw.write_mark(1).unwrap();
+        bcw.write_mark(-2).unwrap();
+        bcw.write_mark(3).unwrap();
+        bcw.write_mark(4).unwrap();
+        bcw.write_mark(5).unwrap();
+        bcw.write_mark(6).unwrap();
+        let code = bcw.unwrap();
+
+        let mut names = LabelNames::new();
+        names.insert(1, "main".to_string());
+        names.insert(4, "L3".to_string());
+        names.insert(5, "shared".to_string());
+        names.insert(6, "shared".to_string());
+        let mut syntax = super::Assembly::new();
+        syntax.set_label_names(names);
+        let mut writer = MemWriter::new();
+        syntax.decompile(&mut MemReader::new(code.clone()), &mut writer).unwrap();
+        let source = from_utf8(writer.get_ref()).unwrap().to_string();
+        let expected = vec!(
+            "CALL main", "JUMPZ L_2", "JUMP L3_",
+            "MARK main", "MARK L_2", "MARK L3_", "MARK L3", "MARK L5", "MARK L6", ""
+            ).connect("\n");
+        assert_eq!(source, expected);
+
+        let mut bcw = MemWriter::new();
+        let mut buffer = BufReader::new(source.as_bytes());
+        super::Assembly::new().compile(&mut buffer, &mut bcw).unwrap();
+        let mut reader = MemReader::new(bcw.unwrap());
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_CALL, 0)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_JUMPZ, 1)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_JUMP, 2)));
+        assert_eq!(reader.read_inst(), Ok((bytecode::CMD_MARK, 0)));
+    }
 }
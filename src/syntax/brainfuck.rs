@@ -15,6 +15,13 @@ pub static BF_FAIL_MARKER: i64 = -1;
 pub static BF_PTR_ADDR: i64 = -1;
 
 /// An iterator that convert to IR from brainfuck tokens on each iteration.
+///
+/// A run of the same "+", "-", ">" or "<" is converted at once.
+///
+/// A loop without nested loops and I/O, which moves the pointer back to where it started
+/// and decrements the current cell by one, is converted to constant-time instructions:
+/// `[-]` sets the cell to 0, and `[->+<]`-style loops add multiples of the cell
+/// to other cells and clear it. This assumes the current cell is not negative.
 pub struct Instructions<T> {
     tokens: T,
     stack: Vec<i64>,
@@ -22,6 +29,7 @@ pub struct Instructions<T> {
     labels: HashMap<String, i64>,
     lcount: Counter<i64>,
     buffer: Vec<IoResult<Instruction>>,
+    lookahead: Vec<IoResult<Token>>,
     parsed: bool,
 }
 
@@ -35,10 +43,127 @@ impl<I: Iterator<IoResult<Token>>> Instructions<I> {
             labels: HashMap::new(),
             lcount: count(1, 1),
             buffer: Vec::new(),
+            lookahead: Vec::new(),
             parsed: false,
         }
     }
 
+    fn next_token(&mut self) -> Option<IoResult<Token>> {
+        match self.lookahead.remove(0) {
+            Some(token) => Some(token),
+            None => self.tokens.next(),
+        }
+    }
+
+    fn repeat(&mut self, token: Token) -> i64 {
+        let mut n = 1;
+        loop {
+            match self.next_token() {
+                Some(Ok(ref t)) if *t == token => n += 1,
+                Some(t) => { self.lookahead.insert(0, t); break },
+                None => break,
+            }
+        }
+        n
+    }
+
+    fn idiom(&mut self) -> Option<Vec<IoResult<Instruction>>> {
+        let mut body = Vec::new();
+        let mut rest = None;
+        let mut closed = false;
+        loop {
+            match self.next_token() {
+                Some(Ok(MoveRight)) => body.push(MoveRight),
+                Some(Ok(MoveLeft)) => body.push(MoveLeft),
+                Some(Ok(Increment)) => body.push(Increment),
+                Some(Ok(Decrement)) => body.push(Decrement),
+                Some(Ok(LoopEnd)) => { closed = true; break },
+                Some(token) => { rest = Some(token); break },
+                None => break,
+            }
+        }
+
+        let mut pos = 0i64;
+        let mut min = 0i64;
+        let mut deltas: Vec<(i64, i64)> = Vec::new();
+        if closed {
+            for token in body.iter() {
+                let delta = match *token {
+                    MoveRight => { pos += 1; continue },
+                    MoveLeft => { pos -= 1; if pos < min { min = pos; } continue },
+                    Increment => 1,
+                    _ => -1,
+                };
+                match deltas.iter().position(|&(offset, _)| offset == pos) {
+                    Some(i) => { let (_, ref mut d) = *deltas.get_mut(i); *d += delta; },
+                    None => deltas.push((pos, delta)),
+                }
+            }
+        }
+        if !closed || pos != 0 || !deltas.iter().any(|&(offset, d)| offset == 0 && d == -1) {
+            match rest {
+                Some(token) => self.lookahead.insert(0, token),
+                None if closed => self.lookahead.insert(0, Ok(LoopEnd)),
+                None => (),
+            }
+            for token in body.move_iter().rev() {
+                self.lookahead.insert(0, Ok(token));
+            }
+            return None
+        }
+
+        let mut insts = Vec::new();
+        let targets: Vec<(i64, i64)> = deltas.move_iter().filter(|&(offset, d)| offset != 0 && d != 0).collect();
+        let skip = if targets.is_empty() { None } else {
+            let l: i64 = self.scount.next().unwrap();
+            let label = self.marker(format!("#{}", l));
+            insts.push_all([
+                Ok(ir::StackPush(BF_PTR_ADDR)),
+                Ok(ir::HeapRetrieve),
+                Ok(ir::HeapRetrieve),
+                Ok(ir::JumpIfZero(label)),
+            ]);
+            if min < 0 {
+                insts.push_all([
+                    Ok(ir::StackPush(BF_PTR_ADDR)),
+                    Ok(ir::HeapRetrieve),
+                    Ok(ir::StackPush(min)),
+                    Ok(ir::Addition),
+                    Ok(ir::JumpIfNegative(BF_FAIL_MARKER)),
+                ]);
+            }
+            Some(label)
+        };
+        for &(offset, d) in targets.iter() {
+            insts.push_all([
+                Ok(ir::StackPush(BF_PTR_ADDR)),
+                Ok(ir::HeapRetrieve),
+                Ok(ir::StackPush(offset)),
+                Ok(ir::Addition),
+                Ok(ir::StackDuplicate),
+                Ok(ir::HeapRetrieve),
+                Ok(ir::StackPush(BF_PTR_ADDR)),
+                Ok(ir::HeapRetrieve),
+                Ok(ir::HeapRetrieve),
+                Ok(ir::StackPush(d)),
+                Ok(ir::Multiplication),
+                Ok(ir::Addition),
+                Ok(ir::HeapStore),
+            ]);
+        }
+        insts.push_all([
+            Ok(ir::StackPush(BF_PTR_ADDR)),
+            Ok(ir::HeapRetrieve),
+            Ok(ir::StackPush(0)),
+            Ok(ir::HeapStore),
+        ]);
+        match skip {
+            Some(label) => insts.push(Ok(ir::Mark(label))),
+            None => (),
+        }
+        Some(insts)
+    }
+
     fn marker(&mut self, label: String) -> i64 {
         match self.labels.find_copy(&label) {
             Some(val) => val,
@@ -56,12 +181,12 @@ impl<I: Iterator<IoResult<Token>>> Iterator<IoResult<Instruction>> for Instructi
         match self.buffer.remove(0) {
             Some(i) => Some(i),
             None => {
-                let ret = match self.tokens.next() {
+                let ret = match self.next_token() {
                     Some(Ok(MoveRight)) => vec!(
                         Ok(ir::StackPush(BF_PTR_ADDR)),
                         Ok(ir::StackDuplicate),
                         Ok(ir::HeapRetrieve),
-                        Ok(ir::StackPush(1)),
+                        Ok(ir::StackPush(self.repeat(MoveRight))),
                         Ok(ir::Addition),
                         Ok(ir::HeapStore),
                     ),
@@ -69,7 +194,7 @@ impl<I: Iterator<IoResult<Token>>> Iterator<IoResult<Instruction>> for Instructi
                         Ok(ir::StackPush(BF_PTR_ADDR)),
                         Ok(ir::StackDuplicate),
                         Ok(ir::HeapRetrieve),
-                        Ok(ir::StackPush(1)),
+                        Ok(ir::StackPush(self.repeat(MoveLeft))),
                         Ok(ir::Subtraction),
                         Ok(ir::StackDuplicate),
                         Ok(ir::JumpIfNegative(BF_FAIL_MARKER)),
@@ -80,7 +205,7 @@ impl<I: Iterator<IoResult<Token>>> Iterator<IoResult<Instruction>> for Instructi
                         Ok(ir::HeapRetrieve),
                         Ok(ir::StackDuplicate),
                         Ok(ir::HeapRetrieve),
-                        Ok(ir::StackPush(1)),
+                        Ok(ir::StackPush(self.repeat(Increment))),
                         Ok(ir::Addition),
                         Ok(ir::HeapStore),
                     ),
@@ -89,7 +214,7 @@ impl<I: Iterator<IoResult<Token>>> Iterator<IoResult<Instruction>> for Instructi
                         Ok(ir::HeapRetrieve),
                         Ok(ir::StackDuplicate),
                         Ok(ir::HeapRetrieve),
-                        Ok(ir::StackPush(1)),
+                        Ok(ir::StackPush(self.repeat(Decrement))),
                         Ok(ir::Subtraction),
                         Ok(ir::HeapStore),
                     ),
@@ -105,17 +230,20 @@ impl<I: Iterator<IoResult<Token>>> Iterator<IoResult<Instruction>> for Instructi
                         Ok(ir::HeapRetrieve),
                         Ok(ir::PutCharactor),
                     ),
-                    Some(Ok(LoopStart)) => {
-                        let l: i64 = self.scount.next().unwrap();
-                        self.stack.push(l);
-                        vec!(
-                            Ok(ir::Mark(self.marker(format!("{}#", l)))),
-                            Ok(ir::StackPush(BF_PTR_ADDR)),
-                            Ok(ir::HeapRetrieve),
-                            Ok(ir::HeapRetrieve),
-                            Ok(ir::JumpIfZero(self.marker(format!("#{}", l)))),
-                        )
-                    }
+                    Some(Ok(LoopStart)) => match self.idiom() {
+                        Some(insts) => insts,
+                        None => {
+                            let l: i64 = self.scount.next().unwrap();
+                            self.stack.push(l);
+                            vec!(
+                                Ok(ir::Mark(self.marker(format!("{}#", l)))),
+                                Ok(ir::StackPush(BF_PTR_ADDR)),
+                                Ok(ir::HeapRetrieve),
+                                Ok(ir::HeapRetrieve),
+                                Ok(ir::JumpIfZero(self.marker(format!("#{}", l)))),
+                            )
+                        },
+                    },
                     Some(Ok(LoopEnd)) => {
                         match self.stack.pop() {
                             Some(l) => vec!(
@@ -235,7 +363,9 @@ impl Compiler for Brainfuck {
 #[cfg(test)]
 mod test {
     use ir::*;
-    use std::io::BufReader;
+    use std::io::{BufReader, MemReader, MemWriter};
+    use machine::{Machine, QueueIo};
+    use syntax::Compiler;
 
     #[test]
     fn test_scan() {
@@ -365,4 +495,62 @@ mod test {
         assert_eq!(it.next(), Some(Ok(Mark(super::BF_FAIL_MARKER))));
         assert!(it.next().is_none());
     }
+
+    #[test]
+    fn test_run_length() {
+        let mut buffer = BufReader::new("+++>>-<".as_bytes());
+        let mut it = super::scan(&mut buffer).tokenize().parse();
+        assert_eq!(it.next(), Some(Ok(StackPush(super::BF_PTR_ADDR))));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackDuplicate)));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackPush(3))));
+        assert_eq!(it.next(), Some(Ok(Addition)));
+        assert_eq!(it.next(), Some(Ok(HeapStore)));
+        assert_eq!(it.next(), Some(Ok(StackPush(super::BF_PTR_ADDR))));
+        assert_eq!(it.next(), Some(Ok(StackDuplicate)));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackPush(2))));
+        assert_eq!(it.next(), Some(Ok(Addition)));
+        assert_eq!(it.next(), Some(Ok(HeapStore)));
+        assert_eq!(it.next(), Some(Ok(StackPush(super::BF_PTR_ADDR))));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackDuplicate)));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackPush(1))));
+        assert_eq!(it.next(), Some(Ok(Subtraction)));
+        assert_eq!(it.next(), Some(Ok(HeapStore)));
+        assert_eq!(it.next(), Some(Ok(StackPush(super::BF_PTR_ADDR))));
+        assert_eq!(it.next(), Some(Ok(StackDuplicate)));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackPush(1))));
+        assert_eq!(it.next(), Some(Ok(Subtraction)));
+        assert_eq!(it.next(), Some(Ok(StackDuplicate)));
+        assert_eq!(it.next(), Some(Ok(JumpIfNegative(super::BF_FAIL_MARKER))));
+        assert_eq!(it.next(), Some(Ok(HeapStore)));
+        assert_eq!(it.next(), Some(Ok(Exit)));
+        assert_eq!(it.next(), Some(Ok(Mark(super::BF_FAIL_MARKER))));
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_idiom() {
+        let mut buffer = BufReader::new("[-]".as_bytes());
+        let mut it = super::scan(&mut buffer).tokenize().parse();
+        assert_eq!(it.next(), Some(Ok(StackPush(super::BF_PTR_ADDR))));
+        assert_eq!(it.next(), Some(Ok(HeapRetrieve)));
+        assert_eq!(it.next(), Some(Ok(StackPush(0))));
+        assert_eq!(it.next(), Some(Ok(HeapStore)));
+        assert_eq!(it.next(), Some(Ok(Exit)));
+        assert_eq!(it.next(), Some(Ok(Mark(super::BF_FAIL_MARKER))));
+        assert!(it.next().is_none());
+
+        let mut buffer = BufReader::new("+++++[->+++++++++++++>++<<]>.>.<<+[-<+>]".as_bytes());
+        let mut writer = MemWriter::new();
+        super::Brainfuck::new().compile(&mut buffer, &mut writer).unwrap();
+        let mut vm = Machine::with_io(QueueIo::new());
+        vm.set_implicit_exit(true);
+        assert!(vm.run(&mut MemReader::new(writer.unwrap())).is_ok());
+        assert_eq!(vm.io().take_output(), "A\n".to_string());
+    }
 }
@@ -134,6 +134,8 @@ impl Decompiler for DT {
                 Ok(ir::PutNumber)         => self.write(output, [T, N, S, T]),
                 Ok(ir::GetCharactor)      => self.write(output, [T, N, T, S]),
                 Ok(ir::GetNumber)         => self.write(output, [T, N, T, T]),
+                Ok(ir::Nop)               => Ok(()),
+                Ok(ir::ExitWith(_)) | Ok(ir::Extension(..)) => Err(standard_error(InvalidInput)),
                 Err(e)                    => Err(e),
             });
         }
@@ -0,0 +1,259 @@
+//! Constant expressions in assembly operands.
+//!
+//! An expression consists of numbers, character literals, named constants, `+`, `-`,
+//! `*`, `/`, `%` and parentheses, and is evaluated by `ir::eval` with checked arithmetic.
+
+#![experimental]
+
+use std::collections::HashMap;
+use std::io::{InvalidInput, IoError, IoResult};
+
+use ir;
+use ir::Instruction;
+use ir::eval::{Overflow, ZeroDivision};
+use machine::io::parse_integer;
+
+/// Values of named constants.
+pub type Constants = HashMap<String, i64>;
+
+#[deriving(PartialEq, Clone, Show)]
+enum Token {
+    // a number or character literal as written
+    Literal(String),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+}
+
+/// Evaluate a constant expression.
+///
+/// # Error
+///
+/// Returns `InvalidInput` if the expression is malformed, refers to an undefined
+/// constant, divides by zero or overflows.
+pub fn eval(source: &str, constants: &Constants) -> IoResult<i64> {
+    let tokens = try!(tokenize(source));
+    let mut parser = Parser { tokens: tokens.as_slice(), pos: 0, constants: constants, insts: Vec::new() };
+    try!(parser.sum().map_err(|detail| invalid(detail)));
+    if parser.pos < tokens.len() {
+        return Err(invalid(format!("unexpected {} in {}", tokens[parser.pos], source)));
+    }
+    match ir::eval::eval(parser.insts.as_slice()) {
+        Ok(stack) => Ok(stack[0]),
+        Err(ZeroDivision(_)) => Err(invalid(format!("division by zero in {}", source))),
+        Err(Overflow(_)) => Err(invalid(format!("overflow in {}", source))),
+        Err(e) => Err(invalid(format!("{} in {}", e, source))),
+    }
+}
+
+/// Parse a number, decimal or prefixed with `0x`, `0b` or `0o`, or a character literal.
+pub fn parse_number(val: &str) -> Option<i64> {
+    if val.starts_with("'") { return parse_char(val) }
+    let (sign, rest) = if val.starts_with("-") { ("-", val.slice_from(1)) } else { ("", val) };
+    let (radix, digits) = match rest.as_bytes() {
+        [b'0', b'x', ..] | [b'0', b'X', ..] => (16, rest.slice_from(2)),
+        [b'0', b'b', ..] | [b'0', b'B', ..] => (2, rest.slice_from(2)),
+        [b'0', b'o', ..] | [b'0', b'O', ..] => (8, rest.slice_from(2)),
+        _ => (10, rest),
+    };
+    if digits.starts_with("+") { return None }
+    parse_integer(format!("{}{}", sign, digits).as_slice(), radix)
+}
+
+fn parse_char(val: &str) -> Option<i64> {
+    if val.len() < 3 || !val.ends_with("'") { return None }
+    let chars: Vec<char> = val.slice(1, val.len() - 1).chars().collect();
+    let c = match chars.as_slice() {
+        [c] if c != '\\' && c != '\'' => c,
+        ['\\', 'n'] => '\n',
+        ['\\', 't'] => '\t',
+        ['\\', 'r'] => '\r',
+        ['\\', '0'] => '\0',
+        ['\\', c] if c == '\\' || c == '\'' => c,
+        _ => return None,
+    };
+    Some(c as i64)
+}
+
+fn tokenize(source: &str) -> IoResult<Vec<Token>> {
+    let chars: Vec<(uint, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        i += 1;
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '+' | '-' | '*' | '/' | '%' => Operator(c),
+            '(' => Open,
+            ')' => Close,
+            '\'' => {
+                let mut escaped = false;
+                while i < chars.len() {
+                    let (_, c) = chars[i];
+                    i += 1;
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '\'' => break,
+                        _ => (),
+                    }
+                }
+                Literal(source.slice(start, offset(chars.as_slice(), i, source)).to_string())
+            },
+            _ if c.is_alphanumeric() || c == '_' => {
+                while i < chars.len() && is_word(chars[i]) { i += 1; }
+                let word = source.slice(start, offset(chars.as_slice(), i, source)).to_string();
+                if c.is_digit() { Literal(word) } else { Name(word) }
+            },
+            _ => return Err(invalid(format!("unexpected {} in {}", c, source))),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Byte offset of the ith character.
+fn offset(chars: &[(uint, char)], i: uint, source: &str) -> uint {
+    match chars.get(i) {
+        Some(&(offset, _)) => offset,
+        None => source.len(),
+    }
+}
+
+fn is_word(&(_, c): (uint, char)) -> bool { c.is_alphanumeric() || c == '_' }
+
+// Recursive descent parser compiling an expression into straight-line instructions.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: uint,
+    constants: &'a Constants,
+    insts: Vec<Instruction>,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|t| t.clone());
+        if token.is_some() { self.pos += 1; }
+        token
+    }
+
+    fn peek(&self) -> Option<&'a Token> { self.tokens.get(self.pos) }
+
+    fn sum(&mut self) -> Result<(), String> {
+        try!(self.product());
+        loop {
+            let inst = match self.peek() {
+                Some(&Operator('+')) => ir::Addition,
+                Some(&Operator('-')) => ir::Subtraction,
+                _ => return Ok(()),
+            };
+            self.pos += 1;
+            try!(self.product());
+            self.insts.push(inst);
+        }
+    }
+
+    fn product(&mut self) -> Result<(), String> {
+        try!(self.unary());
+        loop {
+            let inst = match self.peek() {
+                Some(&Operator('*')) => ir::Multiplication,
+                Some(&Operator('/')) => ir::Division,
+                Some(&Operator('%')) => ir::Modulo,
+                _ => return Ok(()),
+            };
+            self.pos += 1;
+            try!(self.unary());
+            self.insts.push(inst);
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), String> {
+        match self.next() {
+            Some(Operator('-')) => match self.peek() {
+                // fold the sign into a number, so that the minimum value can be written
+                Some(&Literal(ref literal)) if !literal.as_slice().starts_with("'") => {
+                    self.pos += 1;
+                    self.literal(format!("-{}", literal).as_slice())
+                },
+                _ => {
+                    self.insts.push(ir::StackPush(0));
+                    try!(self.unary());
+                    self.insts.push(ir::Subtraction);
+                    Ok(())
+                },
+            },
+            Some(Operator('+')) => self.unary(),
+            Some(Literal(literal)) => self.literal(literal.as_slice()),
+            Some(Name(name)) => match self.constants.find(&name) {
+                Some(&n) => { self.insts.push(ir::StackPush(n)); Ok(()) },
+                None => Err(format!("undefined constant {}", name)),
+            },
+            Some(Open) => {
+                try!(self.sum());
+                match self.next() {
+                    Some(Close) => Ok(()),
+                    _ => Err("expected )".to_string()),
+                }
+            },
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("expected operand".to_string()),
+        }
+    }
+
+    fn literal(&mut self, literal: &str) -> Result<(), String> {
+        match parse_number(literal) {
+            Some(n) => { self.insts.push(ir::StackPush(n)); Ok(()) },
+            None => Err(format!("expected number, but {}", literal)),
+        }
+    }
+}
+
+fn invalid(detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid value format",
+        detail: Some(detail),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+
+    use super::{eval, parse_number, Constants};
+
+    #[test]
+    fn test_eval() {
+        let mut constants = Constants::new();
+        constants.insert("N".to_string(), 5);
+        assert_eq!(eval("2*1024+7", &constants), Ok(2055));
+        assert_eq!(eval("N-1", &constants), Ok(4));
+        assert_eq!(eval("-(N + 1) * 2", &constants), Ok(-12));
+        assert_eq!(eval("17 % 0x10 - 'A'", &constants), Ok(-64));
+        assert_eq!(eval("-9223372036854775808", &constants), Ok(i64::MIN));
+        assert_eq!(eval("-0x8000000000000000", &constants), Ok(i64::MIN));
+        assert_eq!(eval("7 / -2", &constants), Ok(-3));
+    }
+
+    #[test]
+    fn test_errors() {
+        let constants = Constants::new();
+        for source in ["", "1 +", "(1", "1)", "M", "1 / 0", "9223372036854775807 + 1", "1 2", "0xZ", "1 & 2"].iter() {
+            assert!(eval(*source, &constants).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("-0x10"), Some(-16));
+        assert_eq!(parse_number("'\\t'"), Some(9));
+        assert_eq!(parse_number("0b"), None);
+        assert_eq!(parse_number("0x-1"), None);
+        assert_eq!(parse_number("+1"), None);
+        assert_eq!(parse_number("-0x8000000000000000"), Some(i64::MIN));
+        assert_eq!(parse_number("0x8000000000000000"), None);
+    }
+}
@@ -6,10 +6,11 @@ pub use self::assembly::Assembly;
 pub use self::brainfuck::Brainfuck;
 pub use self::dt::DT;
 pub use self::ook::Ook;
+pub use self::rust::Rust;
 pub use self::whitespace::Whitespace;
 
 use std::io::IoResult;
-use bytecode::{ByteCodeWriter, ByteCodeReader};
+use bytecode::{ByteCodeWriter, ByteCodeReader, Module};
 
 /// Convert from source code to bytecodes.
 pub trait Compiler {
@@ -21,10 +22,17 @@ pub trait Compiler {
 pub trait Decompiler {
     /// Generate source code from bytecods.
     fn decompile<R: ByteCodeReader, W: Writer>(&self, &mut R, &mut W) -> IoResult<()>;
+
+    /// Generate source code from a decoded module.
+    fn decompile_module<W: Writer>(&self, module: &Module, output: &mut W) -> IoResult<()> {
+        self.decompile(&mut module.reader(), output)
+    }
 }
 
 pub mod assembly;
 pub mod brainfuck;
 pub mod dt;
+mod expr;
 pub mod ook;
+pub mod rust;
 pub mod whitespace;
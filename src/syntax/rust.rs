@@ -0,0 +1,333 @@
+//! Generator for Rust.
+
+#![experimental]
+
+use std::collections::HashMap;
+use std::io::{InvalidInput, IoError, IoResult, standard_error};
+
+use bytecode::ByteCodeReader;
+use ir;
+use ir::Instruction;
+use syntax::Decompiler;
+
+static PRELUDE: &'static str = "// Generated by whitebase.
+
+use std::char;
+use std::collections::TreeMap;
+use std::io;
+
+fn pop(stack: &mut Vec<i64>) -> i64 {
+    match stack.pop() {
+        Some(n) => n,
+        None => fail!(\"illegal stack manipulation\"),
+    }
+}
+
+fn copy(stack: &mut Vec<i64>, n: i64) {
+    let len = stack.len();
+    if n < 0 || len <= n as uint { fail!(\"illegal stack manipulation\") }
+    let val = stack[len - 1 - n as uint];
+    stack.push(val);
+}
+
+fn swap(stack: &mut Vec<i64>) {
+    let x = pop(stack);
+    let y = pop(stack);
+    stack.push(x);
+    stack.push(y);
+}
+
+fn slide(stack: &mut Vec<i64>, n: i64) {
+    if n < 0 || stack.len() < n as uint { fail!(\"illegal stack manipulation\") }
+    let top = pop(stack);
+    for _ in range(0, n) { stack.pop(); }
+    stack.push(top);
+}
+
+fn calc(stack: &mut Vec<i64>, f: |i64, i64| -> i64) {
+    let x = pop(stack);
+    let y = pop(stack);
+    stack.push(f(x, y));
+}
+
+fn dcalc(stack: &mut Vec<i64>, f: |i64, i64| -> i64) {
+    let x = pop(stack);
+    if x == 0 { fail!(\"zero division\") }
+    let y = pop(stack);
+    stack.push(f(x, y));
+}
+
+fn store(stack: &mut Vec<i64>, heap: &mut TreeMap<i64, i64>) {
+    let val = pop(stack);
+    let addr = pop(stack);
+    heap.insert(addr, val);
+}
+
+fn retrieve(stack: &mut Vec<i64>, heap: &TreeMap<i64, i64>) {
+    let addr = pop(stack);
+    stack.push(match heap.find(&addr) { Some(val) => *val, None => 0 });
+}
+
+fn put_char<W: Writer>(stdout: &mut W, n: i64) {
+    let c = match n.to_u32().and_then(|c| char::from_u32(c)) {
+        Some(c) => c,
+        None => fail!(\"invalid character\"),
+    };
+    (write!(stdout, \"{}\", c)).unwrap();
+}
+
+fn ret(caller: &mut Vec<uint>) -> uint {
+    match caller.pop() {
+        Some(pc) => pc,
+        None => fail!(\"call stack empty\"),
+    }
+}
+
+fn main() {
+    let mut stack: Vec<i64> = Vec::new();
+    let mut heap: TreeMap<i64, i64> = TreeMap::new();
+    let mut caller: Vec<uint> = Vec::new();
+    let mut stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut pc: uint = 0;
+    loop {
+        match pc {
+";
+
+static EPILOGUE: &'static str = "            _ => fail!(\"missing exit instruction\"),
+        }
+    }
+}
+";
+
+fn undefined_label(label: i64) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "undefined label",
+        detail: Some(format!("label {} is not marked", label)),
+    }
+}
+
+fn is_terminator(inst: &Instruction) -> bool {
+    match *inst {
+        ir::Call(_) | ir::Jump(_) | ir::JumpIfZero(_) | ir::JumpIfNegative(_) |
+            ir::Return | ir::Exit | ir::ExitWith(_) => true,
+        _ => false,
+    }
+}
+
+/// Generator for Rust.
+///
+/// The generated program keeps the stack in a `Vec` and the heap in a `TreeMap`,
+/// and runs each basic block as one arm of a `loop` + `match` state machine.
+/// "PUTC" writes a value as a Unicode scalar value, and fails on any other value.
+/// Extension instructions, including "FORK", have no equivalent and fail to generate.
+pub struct Rust;
+
+impl Rust {
+    /// Create a new `Rust`.
+    pub fn new() -> Rust { Rust }
+
+    fn target(&self, labels: &HashMap<i64, uint>, label: i64) -> IoResult<uint> {
+        match labels.find_copy(&label) {
+            Some(state) => Ok(state),
+            None => Err(undefined_label(label)),
+        }
+    }
+
+    fn write_inst<W: Writer>(&self, output: &mut W, labels: &HashMap<i64, uint>, inst: &Instruction, next: uint) -> IoResult<()> {
+        match *inst {
+            ir::StackPush(n)      => writeln!(output, "                stack.push({}i64);", n),
+            ir::StackDuplicate    => output.write_line("                copy(&mut stack, 0);"),
+            ir::StackCopy(n)      => writeln!(output, "                copy(&mut stack, {}i64);", n),
+            ir::StackSwap         => output.write_line("                swap(&mut stack);"),
+            ir::StackDiscard      => output.write_line("                pop(&mut stack);"),
+            ir::StackSlide(n)     => writeln!(output, "                slide(&mut stack, {}i64);", n),
+            ir::Addition          => output.write_line("                calc(&mut stack, |x, y| y + x);"),
+            ir::Subtraction       => output.write_line("                calc(&mut stack, |x, y| y - x);"),
+            ir::Multiplication    => output.write_line("                calc(&mut stack, |x, y| y * x);"),
+            ir::Division          => output.write_line("                dcalc(&mut stack, |x, y| y / x);"),
+            ir::Modulo            => output.write_line("                dcalc(&mut stack, |x, y| y % x);"),
+            ir::HeapStore         => output.write_line("                store(&mut stack, &mut heap);"),
+            ir::HeapRetrieve      => output.write_line("                retrieve(&mut stack, &heap);"),
+            ir::Mark(n)           => writeln!(output, "                // MARK {}", n),
+            ir::Call(n)           => {
+                let state = try!(self.target(labels, n));
+                writeln!(output, "                caller.push({}); pc = {};", next, state)
+            },
+            ir::Jump(n)           => {
+                let state = try!(self.target(labels, n));
+                writeln!(output, "                pc = {};", state)
+            },
+            ir::JumpIfZero(n)     => {
+                let state = try!(self.target(labels, n));
+                writeln!(output, "                pc = if pop(&mut stack) == 0 {{ {} }} else {{ {} }};", state, next)
+            },
+            ir::JumpIfNegative(n) => {
+                let state = try!(self.target(labels, n));
+                writeln!(output, "                pc = if pop(&mut stack) < 0 {{ {} }} else {{ {} }};", state, next)
+            },
+            ir::Return            => output.write_line("                pc = ret(&mut caller);"),
+            ir::Exit              => output.write_line("                return;"),
+            ir::PutCharactor      => output.write_line("                put_char(&mut stdout, pop(&mut stack));"),
+            ir::PutNumber         => output.write_line("                (write!(stdout, \"{}\", pop(&mut stack))).unwrap();"),
+            ir::GetCharactor      => output.write_line("                { let c = stdin.read_char().unwrap(); stack.push(c as i64); store(&mut stack, &mut heap); }"),
+            ir::GetNumber         => output.write_line("                { let n: i64 = from_str(stdin.read_line().unwrap().as_slice().trim()).unwrap(); stack.push(n); store(&mut stack, &mut heap); }"),
+            ir::Nop               => output.write_line("                // NOP"),
+            ir::ExitWith(n)       => writeln!(output, "                std::os::set_exit_status({}i64 as int); return;", n),
+            ir::Extension(..)     => Err(standard_error(InvalidInput)),
+        }
+    }
+}
+
+impl Decompiler for Rust {
+    fn decompile<R: ByteCodeReader, W: Writer>(&self, input: &mut R, output: &mut W) -> IoResult<()> {
+        let mut blocks: Vec<Vec<Instruction>> = vec!(vec!());
+        let mut labels = HashMap::new();
+        for inst in input.disassemble() {
+            let inst = try!(inst);
+            match inst {
+                ir::Mark(n) => {
+                    if !blocks.last().unwrap().is_empty() { blocks.push(vec!()); }
+                    labels.insert(n, blocks.len() - 1);
+                },
+                _ => (),
+            }
+            let terminated = is_terminator(&inst);
+            blocks.mut_last().unwrap().push(inst);
+            if terminated { blocks.push(vec!()); }
+        }
+
+        try!(output.write_str(PRELUDE));
+        for (state, block) in blocks.iter().enumerate() {
+            try!(writeln!(output, "            {} => {{", state));
+            for inst in block.iter() {
+                try!(self.write_inst(output, &labels, inst, state + 1));
+            }
+            match block.last() {
+                Some(inst) if is_terminator(inst) => (),
+                _ => try!(writeln!(output, "                pc = {};", state + 1)),
+            }
+            try!(output.write_line("            },"));
+        }
+        output.write_str(EPILOGUE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+    use std::str::from_utf8;
+
+    use bytecode::ByteCodeWriter;
+    use syntax::Decompiler;
+
+    #[test]
+    fn test_generate() {
+        let mut writer = MemWriter::new();
+        {
+            let mut bcw = MemWriter::new();
+            bcw.write_push(1).unwrap();
+            bcw.write_mark(7).unwrap();
+            bcw.write_dup().unwrap();
+            bcw.write_putn().unwrap();
+            bcw.write_jumpz(7).unwrap();
+            bcw.write_call(8).unwrap();
+            bcw.write_exit().unwrap();
+            bcw.write_mark(8).unwrap();
+            bcw.write_return().unwrap();
+
+            let mut bcr = MemReader::new(bcw.unwrap());
+            let syntax = super::Rust::new();
+            syntax.decompile(&mut bcr, &mut writer).unwrap();
+        }
+        let result = from_utf8(writer.get_ref()).unwrap();
+        let expected = vec!(
+            "            0 => {",
+            "                stack.push(1i64);",
+            "                pc = 1;",
+            "            },",
+            "            1 => {",
+            "                // MARK 7",
+            "                copy(&mut stack, 0);",
+            "                (write!(stdout, \"{}\", pop(&mut stack))).unwrap();",
+            "                pc = if pop(&mut stack) == 0 { 1 } else { 2 };",
+            "            },",
+            "            2 => {",
+            "                caller.push(3); pc = 4;",
+            "            },",
+            "            3 => {",
+            "                return;",
+            "            },",
+            "            4 => {",
+            "                // MARK 8",
+            "                pc = ret(&mut caller);",
+            "            },",
+            "            5 => {",
+            "                pc = 6;",
+            "            },",
+            ).connect("\n");
+        assert!(result.starts_with("// Generated by whitebase."));
+        assert!(result.contains(expected.as_slice()));
+    }
+
+    #[test]
+    fn test_char_io() {
+        let mut writer = MemWriter::new();
+        let mut bcw = MemWriter::new();
+        bcw.write_push(0).unwrap();
+        bcw.write_getc().unwrap();
+        bcw.write_push(0x3042).unwrap();
+        bcw.write_putc().unwrap();
+        let mut bcr = MemReader::new(bcw.unwrap());
+        super::Rust::new().decompile(&mut bcr, &mut writer).unwrap();
+        let result = from_utf8(writer.get_ref()).unwrap();
+        let expected = vec!(
+            "                stack.push(0i64);",
+            "                { let c = stdin.read_char().unwrap(); stack.push(c as i64); store(&mut stack, &mut heap); }",
+            "                stack.push(12354i64);",
+            "                put_char(&mut stdout, pop(&mut stack));",
+            ).connect("\n");
+        assert!(result.contains(expected.as_slice()));
+        // values are converted as Unicode scalar values, failing on others as the machine does
+        assert!(result.contains("n.to_u32().and_then(|c| char::from_u32(c))"));
+        assert!(result.contains("None => fail!(\"invalid character\"),"));
+        assert!(!result.contains("as u8 as char"));
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let mut writer = MemWriter::new();
+        let mut bcw = MemWriter::new();
+        bcw.write_exit_status(3).unwrap();
+        let mut bcr = MemReader::new(bcw.unwrap());
+        super::Rust::new().decompile(&mut bcr, &mut writer).unwrap();
+        let result = from_utf8(writer.get_ref()).unwrap();
+        let expected = vec!(
+            "            0 => {",
+            "                std::os::set_exit_status(3i64 as int); return;",
+            "            },",
+            "            1 => {",
+            ).connect("\n");
+        assert!(result.contains(expected.as_slice()));
+    }
+
+    #[test]
+    fn test_extension() {
+        let mut bcw = MemWriter::new();
+        bcw.write_fork().unwrap();
+        bcw.write_exit().unwrap();
+        let mut bcr = MemReader::new(bcw.unwrap());
+        assert!(super::Rust::new().decompile(&mut bcr, &mut MemWriter::new()).is_err());
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let mut writer = MemWriter::new();
+        let mut bcw = MemWriter::new();
+        bcw.write_jump(1).unwrap();
+        let mut bcr = MemReader::new(bcw.unwrap());
+        let syntax = super::Rust::new();
+        assert!(syntax.decompile(&mut bcr, &mut writer).is_err());
+    }
+}
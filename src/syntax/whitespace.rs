@@ -8,8 +8,9 @@ use std::iter::{Counter, count};
 use std::num::from_str_radix;
 
 use bytecode::{ByteCodeReader, ByteCodeWriter};
+use bytecode::image::Image;
 use ir;
-use ir::Instruction;
+use ir::{Instruction, Program, SourceLocation};
 use syntax::{Compiler, Decompiler};
 
 macro_rules! write_num (
@@ -255,7 +256,11 @@ impl<I: Iterator<IoResult<char>>> Iterator<IoResult<Token>> for Tokens<I> {
 }
 
 struct Scan<'r, T> {
-    buffer: &'r mut T
+    buffer: &'r mut T,
+    line: uint,
+    column: uint,
+    start: (uint, uint),
+    text: String,
 }
 
 impl<'r, B: Buffer> Scan<'r, B> {
@@ -265,20 +270,23 @@ impl<'r, B: Buffer> Scan<'r, B> {
 impl<'r, B: Buffer> Iterator<IoResult<char>> for Scan<'r, B> {
     fn next(&mut self) -> Option<IoResult<char>> {
         loop {
+            let (line, column) = (self.line, self.column);
             let ret = match self.buffer.read_char() {
-                Ok(' ') => ' ',
-                Ok('\t') => '\t',
-                Ok('\n') => '\n',
-                Ok(_) => continue,
+                Ok('\n') => { self.line += 1; self.column = 1; '\n' },
+                Ok(c) => { self.column += 1; if c == ' ' || c == '\t' { c } else { continue } },
                 Err(IoError { kind: EndOfFile, ..}) => return None,
                 Err(e) => return Some(Err(e)),
             };
+            if self.text.is_empty() { self.start = (line, column); }
+            self.text.push_char(match ret { ' ' => 'S', '\t' => 'T', _ => 'N' });
             return Some(Ok(ret));
         }
     }
 }
 
-fn scan<'r, B: Buffer>(buffer: &'r mut B) -> Scan<'r, B> { Scan { buffer: buffer } }
+fn scan<'r, B: Buffer>(buffer: &'r mut B) -> Scan<'r, B> {
+    Scan { buffer: buffer, line: 1, column: 1, start: (1, 1), text: String::new() }
+}
 
 /// Compiler and Decompiler for Whitespace.
 pub struct Whitespace;
@@ -286,6 +294,62 @@ pub struct Whitespace;
 impl Whitespace {
     /// Create a new `Whitespace`.
     pub fn new() -> Whitespace { Whitespace }
+
+    /// Parse source code into a `Program`, recording the source location of each instruction.
+    /// The token of a location is written with "S", "T" and "N".
+    ///
+    /// ```rust
+    /// use std::io::BufReader;
+    /// use whitebase::ir;
+    /// use whitebase::syntax::Whitespace;
+    ///
+    /// let mut buffer = BufReader::new("   \t\n\n\n\n".as_bytes());
+    /// let program = Whitespace::new().parse_program(&mut buffer).unwrap();
+    /// assert_eq!(program[0], ir::StackPush(1));
+    /// assert_eq!(program.location(1).unwrap().line, 2);
+    /// assert_eq!(program.location(1).unwrap().token, "NNN".to_string());
+    /// ```
+    pub fn parse_program<B: Buffer>(&self, input: &mut B) -> IoResult<Program> {
+        parse_located(&mut scan(input).tokenize().parse())
+    }
+
+    /// Compile source code into a bytecode image, with the source map of instructions
+    /// and the token strings of labels written with "S" and "T" as their names.
+    ///
+    /// ```rust
+    /// use std::io::BufReader;
+    /// use whitebase::syntax::Whitespace;
+    ///
+    /// let mut buffer = BufReader::new("\n   \t\n\n\n\n".as_bytes());
+    /// let image = Whitespace::new().compile_image(&mut buffer).unwrap();
+    /// assert_eq!(image.label_names().unwrap().find(&1), Some(&"ST".to_string()));
+    /// assert_eq!(image.source_map().unwrap().unwrap().find(9).unwrap().line, 3);
+    /// ```
+    pub fn compile_image<B: Buffer>(&self, input: &mut B) -> IoResult<Image> {
+        let mut it = scan(input).tokenize().parse();
+        let program = try!(parse_located(&mut it));
+        let names = it.labels.iter().map(|(token, &n)| {
+            let name = token.as_slice().chars().map(|c| if c == '0' { 'S' } else { 'T' }).collect();
+            (n, name)
+        }).collect();
+        let mut image = try!(Image::from_program(&program));
+        image.set_label_names(&names);
+        Ok(image)
+    }
+}
+
+fn parse_located<'r, B: Buffer>(it: &mut Instructions<Tokens<Scan<'r, B>>>) -> IoResult<Program> {
+    let mut program = Program::new();
+    loop {
+        it.tokens.lexemes.text.truncate(0);
+        let inst = match it.next() {
+            Some(inst) => try!(inst),
+            None => return Ok(program),
+        };
+        let scan = &it.tokens.lexemes;
+        let (line, column) = scan.start;
+        program.push_located(inst, SourceLocation { line: line, column: column, token: scan.text.clone() });
+    }
 }
 
 impl Compiler for Whitespace {
@@ -323,6 +387,8 @@ impl Decompiler for Whitespace {
                 Ok(ir::PutNumber)          => write!(output, "\t\n \t"),
                 Ok(ir::GetCharactor)       => write!(output, "\t\n\t "),
                 Ok(ir::GetNumber)          => write!(output, "\t\n\t\t"),
+                Ok(ir::Nop)                => Ok(()),
+                Ok(ir::ExitWith(_)) | Ok(ir::Extension(..)) => Err(standard_error(InvalidInput)),
                 Err(e)                     => Err(e),
             })
         }
@@ -332,9 +398,9 @@ impl Decompiler for Whitespace {
 
 #[cfg(test)]
 mod test {
-    use std::io::{MemReader, MemWriter};
+    use std::io::{IoResult, MemReader, MemWriter};
     use std::str::from_utf8;
-    use bytecode::ByteCodeWriter;
+    use bytecode::{ByteCodeReader, ByteCodeWriter};
     use ir::*;
     use syntax::Decompiler;
 
@@ -350,6 +416,19 @@ mod test {
         assert!(it.next().is_none());
     }
 
+    #[test]
+    fn test_parse_program() {
+        let mut buffer = BufReader::new("   \t\nabc\t\n \t\n\n\n".as_bytes());
+        let program = super::Whitespace::new().parse_program(&mut buffer).unwrap();
+        assert_eq!(program.as_slice(), [StackPush(1), PutNumber, Exit].as_slice());
+        let loc = program.location(0).unwrap();
+        assert_eq!((loc.line, loc.column, loc.token.as_slice()), (1, 1, "SSSTN"));
+        let loc = program.location(1).unwrap();
+        assert_eq!((loc.line, loc.column, loc.token.as_slice()), (2, 4, "TNST"));
+        let loc = program.location(2).unwrap();
+        assert_eq!((loc.line, loc.column, loc.token.as_slice()), (3, 3, "NNN"));
+    }
+
     #[test]
     fn test_tokenize() {
         let mut buffer = BufReader::new(" [\t饂飩]\n".as_bytes());
@@ -446,6 +525,7 @@ mod test {
             bcw.write_putn().unwrap();
             bcw.write_getc().unwrap();
             bcw.write_getn().unwrap();
+            bcw.write_nop().unwrap();
 
             let mut bcr = MemReader::new(bcw.unwrap());
             let syntax = super::Whitespace::new();
@@ -460,5 +540,31 @@ mod test {
             "\t\n  ", "\t\n \t", "\t\n\t ", "\t\n\t\t"
             ).concat().replace(" ", "S").replace("\t", "T").replace("\n", "N");
         assert_eq!(result, expected);
+
+        // an exit status has no Whitespace encoding
+        let mut bcw = MemWriter::new();
+        bcw.write_exit_status(1).unwrap();
+        let mut bcr = MemReader::new(bcw.unwrap());
+        assert!(super::Whitespace::new().decompile(&mut bcr, &mut MemWriter::new()).is_err());
+    }
+
+    #[test]
+    fn test_compile_image() {
+        let source = vec!(
+            "\n   \t\n",    // MARK 01
+            "\n \t\t \n",   // CALL 10
+            "\n\n\n",       // EXIT
+            ).concat();
+        let mut buffer = BufReader::new(source.as_slice().as_bytes());
+        let image = super::Whitespace::new().compile_image(&mut buffer).unwrap();
+        let mut bcr = MemReader::new(image.code.clone());
+        assert_eq!(bcr.disassemble().collect::<Vec<IoResult<Instruction>>>(),
+                   vec!(Ok(Mark(1)), Ok(Call(2)), Ok(Exit)));
+        let names = image.label_names().unwrap();
+        assert_eq!(names.find(&1), Some(&"ST".to_string()));
+        assert_eq!(names.find(&2), Some(&"TS".to_string()));
+        let map = image.source_map().unwrap().unwrap();
+        assert_eq!(map.positions.iter().map(|pos| (pos.offset, pos.line)).collect::<Vec<(u64, uint)>>(),
+                   vec!((0, 1), (9, 3), (18, 5)));
     }
 }